@@ -1,7 +1,7 @@
 use nano_messenger::{
     crypto::{
         CryptoMode, CryptoConfig, CryptoInterface, ClassicalUserKeyPair,
-        HybridUserKeyPair, PostQuantumUserKeyPair, init_crypto_config,
+        HybridUserKeyPair, PostQuantumUserKeyPair, PaddingScheme, init_crypto_config,
     },
 };
 use anyhow::Result;
@@ -103,6 +103,7 @@ async fn test_security_configuration() -> Result<()> {
         minimum_mode: CryptoMode::Quantum,
         allow_auto_upgrade: false,
         adaptive_mode: false,
+        padding: PaddingScheme::Padme,
     };
     quantum_config.validate()?;
     println!("     ✅ Quantum-only config: {}", quantum_config.mode);
@@ -113,6 +114,7 @@ async fn test_security_configuration() -> Result<()> {
         minimum_mode: CryptoMode::Classical,
         allow_auto_upgrade: true,
         adaptive_mode: true,
+        padding: PaddingScheme::Padme,
     };
     adaptive_config.validate()?;
     println!("     ✅ Adaptive config: {} (adaptive: {})", 
@@ -145,6 +147,7 @@ async fn test_mode_transitions() -> Result<()> {
         minimum_mode: CryptoMode::Classical,
         allow_auto_upgrade: true,
         adaptive_mode: false,
+        padding: PaddingScheme::Padme,
     };
     assert!(valid_config.validate().is_ok());
     
@@ -153,6 +156,7 @@ async fn test_mode_transitions() -> Result<()> {
         minimum_mode: CryptoMode::Hybrid,
         allow_auto_upgrade: false,
         adaptive_mode: false,
+        padding: PaddingScheme::Padme,
     };
     assert!(invalid_config.validate().is_err());
     
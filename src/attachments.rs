@@ -0,0 +1,303 @@
+//! Encrypted file attachments carried through relay blob stores
+//!
+//! Files go through `media::FileTransferManager` with `RelayBlobStorage` as
+//! its backend: the manager encrypts a file under a fresh key in fixed-size
+//! chunks, and the framed chunks are stored as one blob on the first of the
+//! sender's relays that takes it. The key, content hash and blob link travel
+//! in an `AttachmentPointer` inside the end-to-end encrypted message, so relays
+//! only ever hold ciphertext and only holders of the link can fetch it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::blobs::{BlobGrant, BlobLink, MAX_CHUNK_BYTES};
+use crate::crypto::CryptoMode;
+use crate::error::{NanoError, Result};
+use crate::media::encryption::{EncryptionMetadata, FileEncryption};
+use crate::media::metadata::{FileMetadata, FileReference, MetadataStore};
+use crate::media::storage::{FileId, FileStorage, StorageLocation, StorageStats};
+use crate::media::transfer::{FileTransferManager, FileUpload, CHUNKS_PARAM};
+use crate::network::{RelayClient, RelayPool};
+use crate::rich::AttachmentPointer;
+
+/// Plaintext bytes per encrypted chunk
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest file that can be sent as an attachment
+pub const MAX_ATTACHMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Times an interrupted upload is resumed on the same relay before failing over
+const UPLOAD_ATTEMPTS: usize = 3;
+
+/// `StorageLocation::backend_type` of relay blobs
+const BACKEND: &str = "relay";
+
+/// File storage backed by relay blob stores
+///
+/// New files go to the healthiest of the pool's relays; stored files are read
+/// back from whichever relay their location names.
+pub struct RelayBlobStorage {
+    pool: Mutex<RelayPool>,
+}
+
+impl RelayBlobStorage {
+    pub fn new(pool: RelayPool) -> Self {
+        Self { pool: Mutex::new(pool) }
+    }
+
+    /// `RelayPool::request` over owned clients, whose futures `FileStorage` needs to be `Send`
+    async fn request<T, F>(&self, candidates: &[String], request: impl Fn(RelayClient) -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let ordered = self.pool.lock().await.ordered(candidates);
+        let mut last_error = None;
+        for relay in ordered {
+            match request(RelayClient::new(relay.clone())).await {
+                Ok(result) => {
+                    self.pool.lock().await.record_success(&relay);
+                    return Ok(result);
+                }
+                Err(NanoError::Network(e)) => {
+                    self.pool.lock().await.record_failure(&relay);
+                    last_error = Some(NanoError::Network(e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| NanoError::Config("No relays configured".to_string())))
+    }
+}
+
+#[async_trait]
+impl FileStorage for RelayBlobStorage {
+    async fn store_file(&self, _file_id: FileId, content: &[u8]) -> Result<StorageLocation> {
+        let relays = self.pool.lock().await.relays().to_vec();
+        let (relay, grant) = self
+            .request(&relays, |client| async move {
+                let grant = client.create_blob(content.len() as u64).await?;
+                let mut attempt = 1;
+                while let Err(e) = resume_upload(&client, &grant, content).await {
+                    if attempt == UPLOAD_ATTEMPTS || !matches!(e, NanoError::Network(_)) {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
+                Ok((client.address().to_string(), grant))
+            })
+            .await?;
+
+        let link = BlobLink {
+            relay,
+            blob_id: grant.blob_id,
+            size: content.len() as u64,
+            token: grant.download_token,
+        };
+        Ok(blob_location(&link).with_metadata("upload_token".to_string(), grant.upload_token))
+    }
+
+    async fn retrieve_file(&self, location: &StorageLocation) -> Result<Vec<u8>> {
+        let link = &blob_link(location)?;
+        self.request(std::slice::from_ref(&link.relay), |client| async move {
+            let mut blob = Vec::new();
+            while (blob.len() as u64) < link.size {
+                let range = client
+                    .download_blob(link.blob_id.clone(), link.token.clone(), blob.len() as u64, MAX_CHUNK_BYTES as u64)
+                    .await?;
+                if range.is_empty() {
+                    return Err(NanoError::Storage(format!("Blob {} ends early", link.blob_id)));
+                }
+                blob.extend(range);
+            }
+            Ok(blob)
+        })
+        .await
+    }
+
+    async fn delete_file(&self, location: &StorageLocation) -> Result<()> {
+        let link = &blob_link(location)?;
+        let token = location.metadata.get("upload_token")
+            .ok_or_else(|| NanoError::Storage(format!("No upload token for blob {}", link.blob_id)))?;
+        self.request(std::slice::from_ref(&link.relay), |client| async move {
+            client.delete_blob(link.blob_id.clone(), token.clone()).await
+        })
+        .await
+    }
+
+    async fn file_exists(&self, location: &StorageLocation) -> Result<bool> {
+        let link = &blob_link(location)?;
+        self.request(std::slice::from_ref(&link.relay), |client| async move {
+            match client.download_blob(link.blob_id.clone(), link.token.clone(), 0, 1).await {
+                Ok(_) => Ok(true),
+                Err(NanoError::Protocol(_)) => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+
+    async fn get_file_size(&self, location: &StorageLocation) -> Result<u64> {
+        Ok(blob_link(location)?.size)
+    }
+
+    async fn store_file_chunked(&self, file_id: FileId, chunks: Vec<Vec<u8>>) -> Result<Vec<StorageLocation>> {
+        let mut locations = Vec::with_capacity(chunks.len());
+        for (index, chunk) in chunks.iter().enumerate() {
+            let location = self.store_file(file_id, chunk).await?;
+            locations.push(location.with_metadata("chunk_index".to_string(), index.to_string()));
+        }
+        Ok(locations)
+    }
+
+    async fn retrieve_file_chunked(&self, locations: &[StorageLocation]) -> Result<Vec<Vec<u8>>> {
+        let mut chunks = Vec::with_capacity(locations.len());
+        for location in locations {
+            chunks.push(self.retrieve_file(location).await?);
+        }
+        Ok(chunks)
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let pool = self.pool.lock().await;
+        if pool.relays().iter().any(|relay| pool.is_healthy(relay)) {
+            Ok(())
+        } else {
+            Err(NanoError::Storage("No relay is available for blobs".to_string()))
+        }
+    }
+
+    async fn get_stats(&self) -> Result<StorageStats> {
+        let pool = self.pool.lock().await;
+        let healthy = pool.relays().iter().filter(|relay| pool.is_healthy(relay)).count();
+        Ok(StorageStats {
+            total_files: 0,
+            total_size_bytes: 0,
+            available_space_bytes: None,
+            backend_specific: HashMap::from([
+                ("relays".to_string(), pool.relays().len().to_string()),
+                ("healthy_relays".to_string(), healthy.to_string()),
+            ]),
+        })
+    }
+}
+
+/// Transfer manager that keeps files as blobs on the relays of `pool`
+pub async fn transfer_manager(pool: RelayPool) -> Result<FileTransferManager> {
+    let storage = Arc::new(RelayBlobStorage::new(pool)) as Arc<dyn FileStorage>;
+    // Only the symmetric half of the file cipher is used, so the mode does not matter
+    let encryption = FileEncryption::new(CryptoMode::Classical, 1);
+    Ok(FileTransferManager::new(storage, encryption, MetadataStore::new().await?).with_chunk_size(CHUNK_SIZE))
+}
+
+/// Encrypt a file under a fresh key and upload it, returning the pointer that opens it
+pub async fn upload(manager: &FileTransferManager, upload: FileUpload, uploader: &str) -> Result<AttachmentPointer> {
+    if upload.size() > MAX_ATTACHMENT_BYTES {
+        return Err(NanoError::Media(format!(
+            "'{}' is larger than the {} MB attachment limit",
+            upload.original_name,
+            MAX_ATTACHMENT_BYTES / (1024 * 1024)
+        )));
+    }
+
+    let key = FileEncryption::generate_file_key();
+    let reference = FileReference::new(upload.file_id, uploader.to_string(), upload.expires_at, None);
+    let metadata = manager.upload_with_key(upload, uploader, &key).await?;
+
+    let mut pointer = AttachmentPointer::new(&metadata, &reference, &key);
+    pointer.chunks = metadata.encryption_info.custom_params.get(CHUNKS_PARAM)
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| NanoError::Media(format!("No chunk count recorded for '{}'", metadata.original_name)))?;
+    pointer.link = Some(blob_link(&metadata.storage_location)?);
+    Ok(pointer)
+}
+
+/// Send whatever part of `blob` the relay does not hold yet
+pub async fn resume_upload(client: &RelayClient, grant: &BlobGrant, blob: &[u8]) -> Result<()> {
+    let (blob_id, token) = (&grant.blob_id, &grant.upload_token);
+    let mut received = client.upload_chunk(blob_id.clone(), token.clone(), 0, &[]).await?;
+    while received < blob.len() as u64 {
+        let offset = received as usize;
+        let end = blob.len().min(offset + MAX_CHUNK_BYTES);
+        let progress = client.upload_chunk(blob_id.clone(), token.clone(), received, &blob[offset..end]).await?;
+        if progress <= received {
+            return Err(NanoError::Protocol(format!("Relay stopped taking blob {} at byte {}", grant.blob_id, received)));
+        }
+        received = progress;
+    }
+    Ok(())
+}
+
+/// Download and open the file an attachment points to
+pub async fn download(manager: &FileTransferManager, pointer: &AttachmentPointer) -> Result<Vec<u8>> {
+    let link = pointer.link.as_ref()
+        .ok_or_else(|| NanoError::Media(format!("'{}' was never uploaded", pointer.name)))?;
+
+    let mut encryption_info = EncryptionMetadata::default();
+    encryption_info.custom_params.insert(CHUNKS_PARAM.to_string(), pointer.chunks.to_string());
+    let metadata = FileMetadata {
+        file_id: pointer.file_id,
+        original_name: pointer.name.clone(),
+        mime_type: pointer.mime_type.clone(),
+        file_size: pointer.size,
+        encryption_info,
+        storage_location: blob_location(link),
+        checksum: pointer.hash.clone(),
+        ..FileMetadata::default()
+    };
+    Ok(manager.download_with_key(&metadata, &pointer.file_key()?).await?.content)
+}
+
+fn blob_location(link: &BlobLink) -> StorageLocation {
+    StorageLocation::new(BACKEND.to_string(), link.blob_id.clone())
+        .with_metadata("relay".to_string(), link.relay.clone())
+        .with_metadata("token".to_string(), link.token.clone())
+        .with_metadata("size".to_string(), link.size.to_string())
+}
+
+fn blob_link(location: &StorageLocation) -> Result<BlobLink> {
+    let field = |name: &str| {
+        location.metadata.get(name)
+            .ok_or_else(|| NanoError::Storage(format!("Blob location {} has no {}", location.path, name)))
+    };
+    if location.backend_type != BACKEND {
+        return Err(NanoError::Storage(format!("Not a relay blob: {} storage", location.backend_type)));
+    }
+    Ok(BlobLink {
+        relay: field("relay")?.clone(),
+        blob_id: location.path.clone(),
+        size: field("size")?.parse().map_err(|_| NanoError::Storage(format!("Invalid size for blob {}", location.path)))?,
+        token: field("token")?.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_location_round_trip() {
+        let link = BlobLink {
+            relay: "127.0.0.1:7733".to_string(),
+            blob_id: "blob-1".to_string(),
+            size: 4096,
+            token: "download".to_string(),
+        };
+        let location = blob_location(&link);
+        assert_eq!(blob_link(&location).unwrap(), link);
+
+        let local = StorageLocation::new("local".to_string(), "blob-1".to_string());
+        assert!(blob_link(&local).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_file_is_refused_before_upload() {
+        let manager = transfer_manager(RelayPool::new(Vec::new())).await.unwrap();
+        let content = vec![0; MAX_ATTACHMENT_BYTES as usize + 1];
+        let result = upload(&manager, FileUpload::new("big.bin".to_string(), content), "pubkey:alice").await;
+        assert!(matches!(result, Err(NanoError::Media(_))));
+    }
+}
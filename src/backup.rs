@@ -0,0 +1,254 @@
+//! Encrypted, portable account archives for backups and device migration
+//!
+//! An archive bundles everything needed to restore an account elsewhere:
+//! keys, contacts, conversation state, messages, groups, relays and security
+//! preferences. The bundle is encrypted under a key derived from a passphrase
+//! with Argon2id; only the format version and KDF parameters are readable
+//! without it, so a client can refuse archives it does not understand.
+
+use crate::crypto::{decrypt_symmetric, encrypt_symmetric};
+use crate::devices::DeviceCertificate;
+use crate::error::{NanoError, Result};
+use crate::group::GroupManager;
+use crate::inbox::ConversationManager;
+use crate::messages::StoredMessage;
+use crate::requests::RequestQueue;
+use crate::trust::TrustStore;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Archive format written by this client
+///
+/// Version 2 added the trust store and version 3 waiting message requests;
+/// older archives restore with nothing pinned or waiting.
+pub const ARCHIVE_VERSION: u32 = 3;
+
+/// Format tag at the top of every archive
+const ARCHIVE_FORMAT: &str = "nano-messenger-account";
+
+/// Shortest passphrase accepted when sealing an archive
+pub const MIN_PASSPHRASE_CHARS: usize = 8;
+
+/// Argon2id cost of deriving the archive key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Everything an account needs on a new device
+///
+/// Client-specific files (keys, contacts, security preferences) are kept as
+/// the JSON the client writes them in.
+#[derive(Serialize, Deserialize)]
+pub struct AccountArchive {
+    pub version: u32,
+    pub created_at: i64,
+    pub identity: String,
+    pub keys: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_certificate: Option<DeviceCertificate>,
+    pub contacts: serde_json::Value,
+    pub conversations: ConversationManager,
+    pub messages: HashMap<String, StoredMessage>,
+    pub groups: GroupManager,
+    pub relays: Vec<String>,
+    pub security: serde_json::Value,
+    #[serde(default)] // Since version 2
+    pub trust: TrustStore,
+    #[serde(default)] // Since version 3
+    pub requests: RequestQueue,
+}
+
+/// Readable outer layer of an archive file
+#[derive(Serialize, Deserialize)]
+struct SealedArchive {
+    format: String,
+    version: u32,
+    kdf: KdfParams,
+    salt: String,       // Base64
+    ciphertext: String, // Base64
+}
+
+impl AccountArchive {
+    /// Encrypt the archive under `passphrase` with the default KDF cost
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>> {
+        self.seal_with(passphrase, KdfParams::default())
+    }
+
+    pub fn seal_with(&self, passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>> {
+        seal_plaintext(&serde_json::to_vec(self)?, self.version, passphrase, kdf)
+    }
+
+    /// Decrypt an archive written by `seal`, refusing versions this client cannot read
+    pub fn open(data: &[u8], passphrase: &str) -> Result<Self> {
+        let sealed: SealedArchive = serde_json::from_slice(data)
+            .map_err(|_| NanoError::Storage("Not a nano-messenger account archive".to_string()))?;
+        if sealed.format != ARCHIVE_FORMAT {
+            return Err(NanoError::Storage("Not a nano-messenger account archive".to_string()));
+        }
+        check_version(sealed.version)?;
+
+        let decode = |field: &str| {
+            general_purpose::STANDARD
+                .decode(field)
+                .map_err(|e| NanoError::Storage(format!("Corrupted archive: {}", e)))
+        };
+        let key = derive_key(passphrase, &decode(&sealed.salt)?, sealed.kdf)?;
+        let plaintext = decrypt_symmetric(&key, &decode(&sealed.ciphertext)?)
+            .map_err(|_| NanoError::Crypto("Wrong passphrase or corrupted archive".to_string()))?;
+
+        let archive: Self = serde_json::from_slice(&plaintext)?;
+        check_version(archive.version)?;
+        Ok(archive)
+    }
+}
+
+/// Encrypt a serialized archive of the given version
+fn seal_plaintext(plaintext: &[u8], version: u32, passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+        return Err(NanoError::Crypto(format!(
+            "Passphrase must be at least {} characters",
+            MIN_PASSPHRASE_CHARS
+        )));
+    }
+
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt, kdf)?;
+
+    let sealed = SealedArchive {
+        format: ARCHIVE_FORMAT.to_string(),
+        version,
+        kdf,
+        salt: general_purpose::STANDARD.encode(salt),
+        ciphertext: general_purpose::STANDARD.encode(encrypt_symmetric(&key, plaintext)?),
+    };
+    Ok(serde_json::to_vec_pretty(&sealed)?)
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version == 0 || version > ARCHIVE_VERSION {
+        return Err(NanoError::Storage(format!(
+            "Archive version {} is not supported (this client reads up to version {})",
+            version, ARCHIVE_VERSION
+        )));
+    }
+    Ok(())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<[u8; 32]> {
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|e| NanoError::Crypto(format!("Invalid key derivation parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| NanoError::Crypto(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::UserKeyPair;
+    use crate::protocol::MessagePayload;
+
+    /// Cheap enough for debug builds
+    const TEST_KDF: KdfParams = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    fn archive() -> AccountArchive {
+        AccountArchive {
+            version: ARCHIVE_VERSION,
+            created_at: 1_700_000_000,
+            identity: "pubkey:alice".to_string(),
+            keys: serde_json::json!({ "signing_key": "c2VjcmV0" }),
+            device_certificate: None,
+            contacts: serde_json::json!({ "usernames": { "pubkey:bob": "bob" } }),
+            conversations: ConversationManager::new(),
+            messages: HashMap::new(),
+            groups: GroupManager::new(),
+            relays: vec!["relay.example.com:7733".to_string()],
+            security: serde_json::json!({}),
+            trust: TrustStore::new(),
+            requests: RequestQueue::new(),
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealed = archive().seal_with("correct horse", TEST_KDF).unwrap();
+        let opened = AccountArchive::open(&sealed, "correct horse").unwrap();
+
+        assert_eq!(opened.identity, "pubkey:alice");
+        assert_eq!(opened.keys, archive().keys);
+        assert_eq!(opened.contacts["usernames"]["pubkey:bob"], "bob");
+        assert_eq!(opened.relays, archive().relays);
+
+        // Keys never appear in the sealed file
+        assert!(!String::from_utf8_lossy(&sealed).contains("c2VjcmV0"));
+        assert!(AccountArchive::open(&sealed, "wrong horse").is_err());
+        assert!(archive().seal_with("short", TEST_KDF).is_err());
+    }
+
+    /// Seal `archive()` as an older version, without the fields added since
+    fn sealed_as(version: u32, missing: &[&str]) -> Vec<u8> {
+        let mut plaintext = serde_json::to_value(archive()).unwrap();
+        plaintext["version"] = serde_json::json!(version);
+        for field in missing {
+            plaintext.as_object_mut().unwrap().remove(*field);
+        }
+        seal_plaintext(&serde_json::to_vec(&plaintext).unwrap(), version, "correct horse", TEST_KDF).unwrap()
+    }
+
+    #[test]
+    fn test_opens_older_archives() {
+        // Archives from before the trust store and message requests restore with neither
+        for (version, missing) in [(1, &["trust", "requests"][..]), (2, &["requests"][..])] {
+            let opened = AccountArchive::open(&sealed_as(version, missing), "correct horse").unwrap();
+            assert_eq!(opened.version, version);
+            assert_eq!(opened.identity, "pubkey:alice");
+            assert!(opened.trust.pins().is_empty());
+            assert!(opened.requests.is_empty());
+        }
+
+        // Current archives carry their pins and waiting requests
+        let mut current = archive();
+        let bob = UserKeyPair::generate();
+        let bob_keys = bob.public_keys();
+        current.trust.check(&bob_keys.public_key_string(), &bob_keys, chrono::Utc::now());
+        let mut payload = MessagePayload::new(bob.public_key_string(), "hi".to_string(), 0, None);
+        payload.sign(&bob.signing_key).unwrap();
+        current.requests.add(payload, chrono::Utc::now());
+
+        let opened = AccountArchive::open(&current.seal_with("correct horse", TEST_KDF).unwrap(), "correct horse").unwrap();
+        assert_eq!(opened.version, ARCHIVE_VERSION);
+        assert!(opened.trust.pin(&bob_keys.public_key_string()).is_some());
+        assert_eq!(opened.requests.len(), 1);
+    }
+
+    #[test]
+    fn test_version_checks() {
+        let sealed = archive().seal_with("correct horse", TEST_KDF).unwrap();
+        let mut outer: serde_json::Value = serde_json::from_slice(&sealed).unwrap();
+        outer["version"] = serde_json::json!(ARCHIVE_VERSION + 1);
+        let newer = serde_json::to_vec(&outer).unwrap();
+
+        let error = AccountArchive::open(&newer, "correct horse").err().unwrap();
+        assert!(error.to_string().contains("not supported"));
+        assert!(AccountArchive::open(b"{\"format\":\"other\"}", "correct horse").is_err());
+    }
+}
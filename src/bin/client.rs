@@ -1,25 +1,55 @@
 use clap::{Parser, Subcommand};
 use nano_messenger::{
-    contacts::{ContactManager, ContactMetadata, ContactPermission, ContactStatus},
+    contacts::{ContactManager, ContactMetadata, ContactPermission, ContactStatus, PrivacySettings},
+    devices::{
+        device_id, DeviceCertificate, DeviceList, PairingRequest, PairingResponse, SentMessageSync,
+        DEVICE_SYNC_ROOM,
+    },
     crypto::{
-        UserKeyPair, Ed25519PrivateKey, X25519PrivateKey, 
-        CryptoMode, CryptoConfig,
-        encrypt_asymmetric, decrypt_asymmetric, decrypt_symmetric, encrypt_symmetric
+        UserKeyPair, UserPublicKeys, Ed25519PrivateKey, X25519PrivateKey, X25519PublicKey,
+        CryptoMode, CryptoConfig, PaddingScheme,
+        encrypt_asymmetric, decrypt_asymmetric, decrypt_symmetric, encrypt_symmetric,
+        padding::unpad, derive_storage_key,
     },
-    username::create_username_claim,
-    network::RelayClient,
-    protocol::{MessageEnvelope, MessagePayload},
+    username::create_username_claim_with_relays,
+    network::{RelayClient, RelayPool},
+    protocol::{MessageEnvelope, MessagePayload, QuantumSafeEnvelope, UsernameClaim},
+    onion::{OnionHop, OnionPacket},
+    group::{GroupControl, GroupManager, GroupMember, GroupState},
     inbox::{derive_first_contact_inbox, ConversationManager, ConversationState},
-    messages::{MessageStore, StoredMessage},
+    messages::{MessageStore, StoredMessage, SYSTEM_SENDER},
+    content::{MessageContent, MessageRef, Reaction},
+    disappearing::DisappearingTimer,
+    rich::RichContent,
+    attachments,
+    backup::{AccountArchive, ARCHIVE_VERSION},
+    profiles::{self, DEFAULT_PROFILE},
+    vcard,
+    media::transfer::FileUpload,
+    outbox::{DeliveryStatus, OutboxEntry},
+    receipts::{ConversationControl, ReceiptStatus, TypingIndicators, RECEIPT_ROOM},
+    search::SearchQuery,
+    replay::FreshnessWindow,
+    error::NanoError,
+    traffic::{CoverTrafficConfig, CoverTrafficGenerator},
+    trust::{fingerprint, KeyChangePolicy, KeyCheck, TrustStore},
+    requests::{AbuseReport, MessageRequest, RequestQueue},
 };
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use tokio;
 use anyhow::Result;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use base64::{Engine as _, engine::general_purpose};
 use serde::{Serialize, Deserialize};
 
+#[path = "client/daemon.rs"]
+mod daemon;
+#[path = "client/tui.rs"]
+mod tui;
+
 /// User security preferences for Session 4
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityPreferences {
@@ -28,6 +58,10 @@ pub struct SecurityPreferences {
     pub minimum_crypto_mode: CryptoMode,
     pub auto_upgrade: bool,
     pub force_post_quantum: bool,
+    #[serde(default)]
+    pub padding: PaddingScheme,
+    #[serde(default)]
+    pub cover_traffic: CoverTrafficConfig,
 }
 
 impl Default for SecurityPreferences {
@@ -38,6 +72,21 @@ impl Default for SecurityPreferences {
             minimum_crypto_mode: CryptoMode::Classical,
             auto_upgrade: true,
             force_post_quantum: false,
+            padding: PaddingScheme::Padme,
+            cover_traffic: CoverTrafficConfig::default(),
+        }
+    }
+}
+
+impl SecurityPreferences {
+    /// Crypto settings these preferences select
+    fn crypto_config(&self) -> CryptoConfig {
+        CryptoConfig {
+            mode: self.default_crypto_mode,
+            allow_auto_upgrade: self.auto_upgrade,
+            adaptive_mode: self.adaptive_mode,
+            minimum_mode: self.minimum_crypto_mode,
+            padding: self.padding,
         }
     }
 }
@@ -53,11 +102,18 @@ struct Cli {
     #[arg(long, default_value = "~/.nano-messenger")]
     config_dir: String,
     
-    /// Default relay server
-    #[arg(long, default_value = "127.0.0.1:7733")]
-    relay: String,
+    /// Named profile inside the config directory (default: the directory itself)
+    #[arg(long)]
+    profile: Option<String>,
+    
+    /// Relay servers in order of preference, comma-separated (default: the configured relays)
+    #[arg(long, value_delimiter = ',')]
+    relay: Vec<String>,
 }
 
+/// Relay used when none is configured
+const DEFAULT_RELAY: &str = "127.0.0.1:7733";
+
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize user (generate keys)
@@ -85,6 +141,65 @@ enum Commands {
         /// Use adaptive mode selection based on network conditions
         #[arg(long)]
         adaptive: bool,
+        /// Reply to this message ID, as shown by `messages --ids`
+        #[arg(long)]
+        reply_to: Option<String>,
+        /// Onion-route through these relays (comma-separated) before the delivering relay
+        #[arg(long, value_delimiter = ',')]
+        via: Vec<String>,
+    },
+    
+    /// Send a file as an encrypted attachment, hosted on one of our relays
+    SendFile {
+        /// Recipient username or pubkey
+        recipient: String,
+        /// File to send
+        path: PathBuf,
+        /// Message to send with the file
+        #[arg(long)]
+        caption: Option<String>,
+    },
+    
+    /// Download and decrypt the attachments of a message
+    Download {
+        /// Message ID, as shown by `messages --ids`
+        message_id: String,
+        /// Directory to save the files in
+        #[arg(long, default_value = ".")]
+        output: PathBuf,
+    },
+    
+    /// Replace the text of a message you sent, for everyone
+    Edit {
+        /// Message ID, as shown by `messages --ids`
+        message_id: String,
+        /// New message content
+        message: String,
+    },
+    
+    /// Delete a message you sent, for everyone
+    Delete {
+        /// Message ID, as shown by `messages --ids`
+        message_id: String,
+    },
+    
+    /// React to a message
+    React {
+        /// Message ID, as shown by `messages --ids`
+        message_id: String,
+        /// Emoji or name (like, dislike, love, laugh, wow, sad, angry, fire); omit to withdraw your reaction
+        reaction: Option<String>,
+    },
+    
+    /// Show or set the disappearing-message timer of a conversation
+    Disappearing {
+        /// Contact username or pubkey
+        contact: String,
+        /// How long messages are kept (e.g. 30s, 10m, 1h, 1d, 1w) or "off"; omit to show the current timer
+        timer: Option<String>,
+        /// Count down from when each message is read instead of when it was sent
+        #[arg(long)]
+        after_read: bool,
     },
     
     /// Configure security preferences
@@ -101,16 +216,55 @@ enum Commands {
         /// Allow automatic security upgrades
         #[arg(long)]
         auto_upgrade: Option<bool>,
+        /// Message padding scheme: none, padme, or buckets
+        #[arg(long)]
+        padding: Option<String>,
+        /// Send dummy envelopes as cover traffic (from the daemon and `cover-traffic`)
+        #[arg(long)]
+        cover_traffic: Option<bool>,
+        /// Mean seconds between cover traffic envelopes
+        #[arg(long)]
+        cover_interval: Option<f64>,
     },
     
     /// Show current security configuration
     ShowSecurity,
     
+    /// Send cover traffic on a Poisson schedule until interrupted, for when the daemon is not running
+    CoverTraffic {
+        /// Stop after this many seconds
+        #[arg(long)]
+        duration: Option<u64>,
+    },
+    
     /// Check for new messages
     Receive,
     
-    /// List all messages
+    /// Run in the background, serving a JSON-RPC API on a Unix socket
+    Daemon {
+        /// Socket path (default: <config dir>/daemon.sock)
+        #[arg(long)]
+        socket: Option<String>,
+        /// Seconds between checks for new messages
+        #[arg(long, default_value = "5")]
+        poll_interval: u64,
+        /// Further profiles to serve alongside the selected one, comma-separated
+        #[arg(long, value_delimiter = ',')]
+        profiles: Vec<String>,
+    },
+    
+    /// Open the interactive terminal interface
+    Tui {
+        /// Seconds between checks for new messages
+        #[arg(long, default_value = "5")]
+        poll_interval: u64,
+    },
+    
+    /// List or search messages
     Messages {
+        /// Search query: words, "quoted phrases", from:<user>, in:<conversation>,
+        /// before:<date>, after:<date>, mode:<crypto mode>
+        query: Option<String>,
         /// Optional contact filter
         #[arg(long)]
         from: Option<String>,
@@ -120,15 +274,61 @@ enum Commands {
         /// Filter by crypto mode
         #[arg(long)]
         crypto_mode: Option<String>,
+        /// Show message IDs, for edit, delete and react
+        #[arg(long)]
+        ids: bool,
+    },
+    
+    /// Show queued outgoing envelopes and their delivery state
+    Outbox {
+        /// Queue failed envelopes again and try to send everything due
+        #[arg(long)]
+        retry: bool,
     },
     
+    /// Configure the relays this identity uses
+    #[command(subcommand)]
+    Relays(RelayCommands),
+    
+    /// Manage the identity profiles in the config directory
+    #[command(subcommand)]
+    Profiles(ProfileCommands),
+    
+    /// Pinned contact keys, key changes and what to do about them
+    #[command(subcommand)]
+    Trust(TrustCommands),
+    
+    /// Messages from senders you have not accepted yet
+    #[command(subcommand)]
+    Requests(RequestCommands),
+    
     /// Manage contacts
     #[command(subcommand)]
     Contacts(ContactCommands),
     
+    /// Group conversations
+    #[command(subcommand)]
+    Group(GroupCommands),
+    
+    /// Link and manage this identity's devices
+    #[command(subcommand)]
+    Device(DeviceCommands),
+    
     /// Show user info including crypto capabilities
     Info,
     
+    /// Write the whole account to a passphrase-encrypted archive, for backups or moving devices
+    Export {
+        /// Archive file to create
+        path: PathBuf,
+    },
+    
+    /// Restore an account from an archive into an empty config directory
+    Import {
+        /// Archive written by `export`
+        path: PathBuf,
+    },
+    
     /// Test crypto mode compatibility
     TestCrypto {
         /// Crypto mode to test
@@ -138,18 +338,138 @@ enum Commands {
 }
 
 #[derive(Subcommand)]
-enum ContactCommands {
-    /// List all contacts
+enum RelayCommands {
+    /// List configured relays and whether they are reachable
+    List,
+    /// Add a relay, last in preference unless --first
+    Add {
+        address: String,
+        #[arg(long)]
+        first: bool,
+    },
+    /// Remove a relay
+    Remove { address: String },
+    /// Pin a relay's onion routing key, as printed by nano-relay at startup, so messages can be routed through it
+    Pin {
+        address: String,
+        onion_key: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommands {
+    /// List profiles and their identities
+    List,
+    /// Create a profile with its own keys, stores and relays
+    Create {
+        name: String,
+        /// Crypto mode for key generation
+        #[arg(long, default_value = "classical")]
+        crypto_mode: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TrustCommands {
+    /// List pinned keys and their fingerprints
+    List,
+    /// Show a contact's fingerprint next to ours, and their key changes
+    Show { contact: String },
+    /// Mark a contact's pinned keys as verified, after comparing fingerprints
+    Verify {
+        contact: String,
+        /// Fingerprint the contact read out, checked against the pinned one
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+    /// Trust the new keys of a blocked key change
+    Accept { contact: String },
+    /// Set what happens when keys change: warn, block or auto-accept
+    Policy {
+        policy: String,
+        /// Only for this contact; `default` drops their override
+        #[arg(long)]
+        contact: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RequestCommands {
+    /// List waiting requests, most recent first
     List,
+    /// Show the messages of one request
+    Show { sender: String },
+    /// Allow the sender and move their messages into a conversation
+    Accept { sender: String },
+    /// Block the sender and discard their messages
+    Block { sender: String },
+    /// Block the sender and save their signed messages as an abuse report
+    Report {
+        sender: String,
+        /// Why the sender is reported
+        #[arg(long)]
+        reason: Option<String>,
+        /// Report file to write (defaults to reports/ in the config directory)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ContactCommands {
+    /// List all contacts, favorites first
+    List {
+        /// Only contacts with this label
+        #[arg(long)]
+        label: Option<String>,
+        /// Only favorites
+        #[arg(long)]
+        favorites: bool,
+    },
     
-    /// Search contacts
+    /// Search contacts by name, memo, label or username
     Search { query: String },
     
-    /// Allow a contact
-    Allow { pubkey: String },
+    /// Allow a contact, or every contact with a label
+    Allow {
+        #[arg(required_unless_present = "label", conflicts_with = "label")]
+        pubkey: Option<String>,
+        #[arg(long)]
+        label: Option<String>,
+    },
+    
+    /// Block a contact, or every contact with a label
+    Block {
+        #[arg(required_unless_present = "label", conflicts_with = "label")]
+        pubkey: Option<String>,
+        #[arg(long)]
+        label: Option<String>,
+    },
+    
+    /// Add labels to a contact, or take them off with --remove
+    Label {
+        pubkey: String,
+        #[arg(required = true)]
+        labels: Vec<String>,
+        #[arg(long)]
+        remove: bool,
+    },
+    
+    /// List labels in use
+    Labels,
+    
+    /// Mark a contact as a favorite, or unmark with --remove
+    Favorite {
+        pubkey: String,
+        #[arg(long)]
+        remove: bool,
+    },
+    
+    /// Write all contacts to a vCard file
+    Export { path: PathBuf },
     
-    /// Block a contact
-    Block { pubkey: String },
+    /// Merge contacts from a vCard file
+    Import { path: PathBuf },
     
     /// Set contact metadata
     Edit {
@@ -160,31 +480,110 @@ enum ContactCommands {
         memo: Option<String>,
     },
     
+    /// Show or change which receipts and typing indicators a contact gets
+    Privacy {
+        pubkey: String,
+        #[arg(long)]
+        delivery_receipts: Option<bool>,
+        #[arg(long)]
+        read_receipts: Option<bool>,
+        #[arg(long)]
+        typing: Option<bool>,
+    },
+    
     /// Remove a contact
     Remove { pubkey: String },
 }
 
+#[derive(Subcommand)]
+enum GroupCommands {
+    /// Create a group with the given members
+    Create {
+        name: String,
+        /// Member usernames or pubkeys (comma-separated)
+        #[arg(long, value_delimiter = ',')]
+        members: Vec<String>,
+    },
+    
+    /// Add a member to a group (admin only)
+    Add { group: String, member: String },
+    
+    /// Remove a member and rekey the group (admin only)
+    Remove { group: String, member: String },
+    
+    /// Send a message to every group member
+    Send {
+        group: String,
+        message: String,
+        /// Reply to this message ID, as shown by `messages --ids`
+        #[arg(long)]
+        reply_to: Option<String>,
+    },
+    
+    /// Show or set the group's disappearing-message timer
+    Disappearing {
+        group: String,
+        /// How long messages are kept (e.g. 30s, 10m, 1h, 1d, 1w) or "off"; omit to show the current timer
+        timer: Option<String>,
+        /// Count down from when each message is read instead of when it was sent
+        #[arg(long)]
+        after_read: bool,
+    },
+    
+    /// List groups
+    List,
+}
+
+#[derive(Subcommand)]
+enum DeviceCommands {
+    /// On a new device: create device keys and print a pairing code
+    Link {
+        /// Name shown in the device list
+        #[arg(long, default_value = "linked device")]
+        name: String,
+    },
+    
+    /// On the primary device: certify a new device from its pairing code
+    Approve { code: String },
+    
+    /// On the new device: finish linking with the primary's response code
+    Finish { code: String },
+    
+    /// List this identity's devices
+    List,
+    
+    /// Revoke a linked device (primary only)
+    Revoke { device_id: String },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
     
-    // Expand tilde in config directory
-    let config_dir = expand_path(&cli.config_dir)?;
+    // Expand tilde in config directory, then pick the profile inside it
+    let base_dir = expand_path(&cli.config_dir)?;
+    let profile = cli.profile.clone().unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    let config_dir = profiles::profile_dir(&base_dir, &profile)?;
+    if !config_dir.exists() && !matches!(cli.command, Commands::Init { .. } | Commands::Import { .. }) {
+        anyhow::bail!("Unknown profile '{}'; create it with `nano-client profiles create {}`", profile, profile);
+    }
     std::fs::create_dir_all(&config_dir)?;
     
-    // Load security preferences
-    let security_prefs = load_security_preferences(&config_dir)?;
+    // A daemon serving this profile owns its stores until it stops
+    if !matches!(cli.command, Commands::Daemon { .. } | Commands::Profiles(_)) {
+        if let Some(socket) = daemon::running_daemon(&config_dir).await {
+            anyhow::bail!(
+                "Profile '{}' is in use by the daemon on {}; stop it first or send this through its JSON-RPC API",
+                profile, socket.display()
+            );
+        }
+    }
     
-    // Initialize crypto config based on preferences
-    let crypto_config = CryptoConfig {
-        mode: security_prefs.default_crypto_mode,
-        allow_auto_upgrade: security_prefs.auto_upgrade,
-        adaptive_mode: security_prefs.adaptive_mode,
-        minimum_mode: security_prefs.minimum_crypto_mode,
-    };
+    // Load security preferences; sessions carry their own profile's crypto settings
+    let security_prefs = load_security_preferences(&config_dir)?;
     
-    // Initialize the crypto system
-    let _ = nano_messenger::crypto::init_crypto_config(crypto_config);
+    // Relays given on the command line replace the configured list for this run
+    let relays = if cli.relay.is_empty() { load_relays(&config_dir)? } else { cli.relay.clone() };
     
     match cli.command {
         Commands::Init { crypto_mode } => {
@@ -192,7 +591,7 @@ async fn main() -> Result<()> {
             init_user(&config_dir, mode)?;
         }
         Commands::ClaimUsername { username } => {
-            claim_username(&config_dir, &cli.relay, &username).await?;
+            claim_username(&config_dir, &relays, &username).await?;
         }
         Commands::Send { 
             recipient, 
@@ -200,47 +599,125 @@ async fn main() -> Result<()> {
             crypto_mode, 
             force_post_quantum,
             adaptive,
+            reply_to,
+            via,
         } => {
-            send_quantum_safe_message(
-                &config_dir, 
-                &cli.relay, 
-                &recipient, 
-                &message,
-                &crypto_mode,
+            let request = SendRequest {
+                recipient: &recipient,
+                message: &message,
+                crypto_mode: &crypto_mode,
                 force_post_quantum,
                 adaptive,
-                &security_prefs,
-            ).await?;
+                reply_to: reply_to.as_deref(),
+                via: &via,
+            };
+            send_quantum_safe_message(&config_dir, &relays, request, &security_prefs).await?;
         }
         Commands::SetSecurity { 
             default_mode, 
             adaptive, 
             minimum_mode,
             auto_upgrade,
+            padding,
+            cover_traffic,
+            cover_interval,
         } => {
-            update_security_preferences(
-                &config_dir,
-                default_mode.as_deref(),
+            update_security_preferences(&config_dir, SecurityChanges {
+                default_mode,
                 adaptive,
-                minimum_mode.as_deref(),
+                minimum_mode,
                 auto_upgrade,
-            )?;
+                padding,
+                cover_traffic,
+                cover_interval,
+            })?;
+        }
+        Commands::SendFile { recipient, path, caption } => {
+            send_file(&config_dir, &relays, &recipient, &path, caption.as_deref()).await?;
+        }
+        Commands::Download { message_id, output } => {
+            download_attachments(&config_dir, &relays, &message_id, &output).await?;
+        }
+        Commands::Edit { message_id, message } => {
+            change_message_command(&config_dir, &relays, &message_id, |target| MessageContent::Edit { target, body: message }).await?;
+        }
+        Commands::Delete { message_id } => {
+            change_message_command(&config_dir, &relays, &message_id, |target| MessageContent::Delete { target }).await?;
+        }
+        Commands::React { message_id, reaction } => {
+            let reaction = reaction.as_deref().map(str::parse::<Reaction>).transpose()?;
+            change_message_command(&config_dir, &relays, &message_id, |target| MessageContent::React { target, reaction }).await?;
+        }
+        Commands::Disappearing { contact, timer, after_read } => {
+            disappearing_command(&config_dir, &relays, &contact, timer.as_deref(), after_read).await?;
         }
         Commands::ShowSecurity => {
             show_security_configuration(&config_dir)?;
         }
+        Commands::CoverTraffic { duration } => {
+            run_cover_traffic(&relays, &security_prefs, duration).await?;
+        }
         Commands::Receive => {
-            receive_messages(&config_dir, &cli.relay).await?;
+            receive_messages(&config_dir, &relays).await?;
+        }
+        Commands::Daemon { socket, poll_interval, profiles: extra } => {
+            let socket_path = match socket {
+                Some(socket) => expand_path(&socket)?,
+                None => config_dir.join(daemon::SOCKET_NAME),
+            };
+            let mut served = vec![daemon::ProfileConfig { name: profile.clone(), config_dir: config_dir.clone(), relays }];
+            for name in extra {
+                if served.iter().any(|served| served.name == name) {
+                    continue;
+                }
+                let dir = profiles::profile_dir(&base_dir, &name)?;
+                if !dir.join("keys.json").exists() {
+                    anyhow::bail!("Profile '{}' is not initialized", name);
+                }
+                let relays = if cli.relay.is_empty() { load_relays(&dir)? } else { cli.relay.clone() };
+                served.push(daemon::ProfileConfig { name, config_dir: dir, relays });
+            }
+            daemon::run(served, &socket_path, std::time::Duration::from_secs(poll_interval.max(1))).await?;
+        }
+        Commands::Tui { poll_interval } => {
+            tui::run(&config_dir, &relays, std::time::Duration::from_secs(poll_interval.max(1))).await?;
+        }
+        Commands::Messages { query, from, limit, crypto_mode, ids } => {
+            show_messages(&config_dir, query.as_deref(), from.as_deref(), limit, crypto_mode.as_deref(), ids)?;
         }
-        Commands::Messages { from, limit, crypto_mode } => {
-            show_messages(&config_dir, from.as_deref(), limit, crypto_mode.as_deref())?;
+        Commands::Outbox { retry } => {
+            show_outbox(&config_dir, &relays, retry).await?;
+        }
+        Commands::Relays(relay_cmd) => {
+            handle_relay_command(&config_dir, &relays, relay_cmd).await?;
+        }
+        Commands::Profiles(profile_cmd) => {
+            handle_profile_command(&base_dir, &profile, &cli.relay, profile_cmd)?;
+        }
+        Commands::Trust(trust_cmd) => {
+            handle_trust_command(&config_dir, trust_cmd)?;
+        }
+        Commands::Requests(request_cmd) => {
+            handle_request_command(&config_dir, &relays, request_cmd)?;
         }
         Commands::Contacts(contact_cmd) => {
             handle_contact_command(&config_dir, contact_cmd)?;
         }
+        Commands::Group(group_cmd) => {
+            handle_group_command(&config_dir, &relays, group_cmd).await?;
+        }
+        Commands::Device(device_cmd) => {
+            handle_device_command(&config_dir, &relays, device_cmd).await?;
+        }
         Commands::Info => {
             show_user_info(&config_dir)?;
         }
+        Commands::Export { path } => {
+            export_account(&config_dir, &path)?;
+        }
+        Commands::Import { path } => {
+            import_account(&config_dir, &path)?;
+        }
         Commands::TestCrypto { mode } => {
             test_crypto_modes(&config_dir, &mode)?;
         }
@@ -264,7 +741,7 @@ fn parse_crypto_mode(mode_str: &str) -> Result<CryptoMode> {
         .map_err(|e| anyhow::anyhow!("Invalid crypto mode: {}", e))
 }
 
-fn init_user(config_dir: &PathBuf, crypto_mode: CryptoMode) -> Result<()> {
+fn init_user(config_dir: &Path, crypto_mode: CryptoMode) -> Result<()> {
     let keys_file = config_dir.join("keys.json");
     
     if keys_file.exists() {
@@ -306,289 +783,1733 @@ fn init_user(config_dir: &PathBuf, crypto_mode: CryptoMode) -> Result<()> {
     Ok(())
 }
 
-async fn claim_username(config_dir: &PathBuf, relay: &str, username: &str) -> Result<()> {
+async fn claim_username(config_dir: &Path, relays: &[String], username: &str) -> Result<()> {
     let keypair = load_keypair(config_dir)?;
-    let client = RelayClient::new(relay.to_string());
+    if load_device_certificate(config_dir)?.is_some() {
+        anyhow::bail!("This is a linked device; claim usernames from the primary device");
+    }
+    
+    println!("Claiming username '{}' on {}...", username, relays.join(", "));
+    
+    // The claim advertises where we accept mail, so publish it on each of those relays
+    let claim = create_username_claim_with_relays(username, &keypair, relays.to_vec())?;
+    let mut claimed = 0;
+    for relay in relays {
+        match RelayClient::new(relay.clone()).publish_claim(claim.clone()).await {
+            Ok(()) => claimed += 1,
+            Err(e) => eprintln!("❌ Failed to claim username on {}: {}", relay, e),
+        }
+    }
+    
+    if claimed == 0 {
+        anyhow::bail!("Username '{}' was not claimed on any relay", username);
+    }
+    println!("✓ Username '{}' claimed on {} of {} relays!", username, claimed, relays.len());
+    println!("Others can now message you at: {}", username);
+    
+    Ok(())
+}
+
+/// Identity and local state loaded once for a client run
+struct ClientSession {
+    config_dir: PathBuf,
+    keypair: UserKeyPair,
+    certificate: Option<DeviceCertificate>,
+    identity: String,
+    contact_manager: ContactManager,
+    conversation_manager: ConversationManager,
+    message_store: MessageStore,
+    group_manager: GroupManager,
+    trust: TrustStore,
+    requests: RequestQueue,
+    relays: RelayPool,
+    onion_hops: HashMap<String, OnionHop>, // Pinned with `relays pin`
+    crypto: CryptoConfig, // From this profile's security preferences
+    typing: TypingIndicators, // Contacts typing to us; not persisted
+}
+
+impl ClientSession {
+    fn load(config_dir: &Path, relays: &[String]) -> Result<Self> {
+        let keypair = load_keypair(config_dir)?;
+        let certificate = load_device_certificate(config_dir)?;
+        let crypto = load_security_preferences(config_dir)?.crypto_config();
+        crypto.validate()?;
+        
+        Ok(Self {
+            config_dir: config_dir.to_path_buf(),
+            identity: identity_pubkey(&keypair, certificate.as_ref()),
+            contact_manager: load_contact_manager(config_dir)?,
+            conversation_manager: load_conversation_manager(config_dir, &keypair)?,
+            message_store: load_message_store(config_dir, &keypair)?,
+            group_manager: load_group_manager(config_dir, &keypair)?,
+            trust: load_trust_store(config_dir)?,
+            requests: load_request_queue(config_dir, &keypair)?,
+            relays: RelayPool::new(relays.to_vec()),
+            onion_hops: load_onion_hops(config_dir)?,
+            crypto,
+            typing: TypingIndicators::new(),
+            certificate,
+            keypair,
+        })
+    }
+    
+    /// Save the state that is not written through as it changes
+    fn save(&self) -> Result<()> {
+        save_contact_manager(&self.config_dir, &self.contact_manager)?;
+        save_conversation_manager(&self.config_dir, &self.keypair, &self.conversation_manager)?;
+        save_group_manager(&self.config_dir, &self.keypair, &self.group_manager)?;
+        save_trust_store(&self.config_dir, &self.trust)?;
+        save_request_queue(&self.config_dir, &self.keypair, &self.requests)
+    }
     
-    println!("Claiming username '{}' on relay {}...", username, relay);
+    /// Check keys looked up for `contact` against the pinned ones; see `check_pinned_keys`
+    fn check_pinned_keys(&mut self, contact: &str, public_keys: &UserPublicKeys) -> Result<Option<String>> {
+        check_pinned_keys(&self.config_dir, &mut self.trust, &mut self.message_store, &self.identity, contact, public_keys)
+    }
     
-    let claim = create_username_claim(username, &keypair)?;
+    /// Whether the primary device has revoked this one
+    fn is_revoked(&self) -> bool {
+        self.contact_manager.is_device_revoked(&self.identity, &device_id(&self.keypair.public_keys()))
+    }
     
-    match client.publish_claim(claim).await {
-        Ok(()) => {
-            println!("✓ Username '{}' claimed successfully!", username);
-            println!("Others can now message you at: {}", username);
+    /// Relays an identity accepts mail on: those it advertised, or our own
+    fn relays_for(&self, pubkey: &str) -> Vec<String> {
+        match self.contact_manager.get_relays(pubkey) {
+            Some(relays) => relays.to_vec(),
+            None => self.relays.relays().to_vec(),
         }
-        Err(e) => {
-            eprintln!("❌ Failed to claim username: {}", e);
-            return Err(e.into());
+    }
+    
+    /// Whether a contact gets the receipts or typing notices `enabled` picks from their privacy settings
+    ///
+    /// Strangers learn nothing until we allow them or write to them.
+    fn shares_activity(&self, pubkey: &str, enabled: impl Fn(&PrivacySettings) -> bool) -> bool {
+        let contacts = &self.contact_manager;
+        if contacts.is_blocked(pubkey) || !enabled(&contacts.privacy(pubkey)) {
+            return false;
         }
+        contacts.is_allowed(pubkey)
+            || self.message_store
+                .get_conversation_messages(&direct_conversation_id(&self.identity, pubkey), None)
+                .is_ok_and(|messages| messages.iter().any(|msg| msg.is_outgoing))
     }
     
-    Ok(())
+    /// Contact name for a pubkey, or the pubkey itself
+    fn display_name(&self, pubkey: &str) -> String {
+        match self.contact_manager.get_contact(pubkey) {
+            Some(contact) => contact.display_name().to_string(),
+            None => pubkey.to_string(),
+        }
+    }
 }
 
 async fn send_message(
-    config_dir: &PathBuf,
-    relay: &str,
+    config_dir: &Path,
+    relays: &[String],
     recipient: &str,
     message: &str,
+    reply_to: Option<&str>,
+    via: &[String],
 ) -> Result<()> {
-    let keypair = load_keypair(config_dir)?;
-    let client = RelayClient::new(relay.to_string());
-    let mut contact_manager = load_contact_manager(config_dir)?;
-    let mut conversation_manager = load_conversation_manager(config_dir)?;
-    let mut message_store = load_message_store(config_dir)?;
+    let mut session = ClientSession::load(config_dir, relays)?;
+    let message = compose_message(&session.message_store, &session.contact_manager, message, reply_to)?;
     
-    println!("Sending message to '{}' via {}...", recipient, relay);
+    println!("Sending message to '{}'...", recipient);
     
-    // Try to get recipient's public key
-    let recipient_pubkey = if recipient.starts_with("pubkey:") {
-        // Direct pubkey
-        recipient.to_string()
-    } else {
-        // Username lookup
-        match client.lookup_username(recipient.to_string()).await? {
-            Some(public_keys) => {
-                let pubkey_str = public_keys.public_key_string();
-                // Update contact manager with username mapping
-                contact_manager.set_username(pubkey_str.clone(), recipient.to_string());
-                pubkey_str
-            }
-            None => {
-                eprintln!("❌ Username '{}' not found", recipient);
-                return Ok(());
-            }
+    let sent = match deliver_message(&mut session, recipient, message.into(), via).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            eprintln!("❌ Failed to send message: {}", e);
+            return Err(e);
         }
     };
+    report_sent(&mut session, recipient, sent)
+}
+
+/// Encrypt a file, upload it to one of our relays and send the recipient a message pointing to it
+async fn send_file(
+    config_dir: &Path,
+    relays: &[String],
+    recipient: &str,
+    path: &Path,
+    caption: Option<&str>,
+) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
+    let name = path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Not a file: {}", path.display()))?
+        .to_string();
+    let content = std::fs::read(path)?;
+    
+    println!("Uploading {}...", name);
+    let manager = attachments::transfer_manager(session.relays.clone()).await?;
+    let pointer = attachments::upload(&manager, FileUpload::new(name, content), &session.identity).await?;
+    println!("✓ Uploaded {} in {} encrypted chunk(s)", pointer, pointer.chunks);
+    
+    let message = compose_message(&session.message_store, &session.contact_manager, caption.unwrap_or_default(), None)?
+        .with_attachment(pointer);
+    let sent = deliver_message(&mut session, recipient, message.into(), &[]).await?;
+    report_sent(&mut session, recipient, sent)
+}
+
+/// Print warnings and the delivery state of a sent message, and save the session
+fn report_sent(session: &mut ClientSession, recipient: &str, sent: SentMessage) -> Result<()> {
+    for warning in &sent.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    session.save()?;
     
-    // Get recipient's X25519 public key
-    let recipient_public_keys = if recipient.starts_with("pubkey:") {
-        // For direct pubkey, we need to look it up
-        match client.lookup_username(recipient.to_string()).await? {
-            Some(keys) => keys,
-            None => {
-                eprintln!("❌ Could not find public keys for {}", recipient);
-                return Ok(());
+    match sent.status {
+        DeliveryStatus::Sent => {
+            println!("✓ Message sent to {}", recipient);
+            if sent.devices > 1 {
+                println!("📱 Delivered to {} devices", sent.devices);
             }
         }
-    } else {
-        // Already looked up above
-        client.lookup_username(recipient.to_string()).await?
-            .ok_or_else(|| anyhow::anyhow!("Public keys not found"))?
-    };
-    
-    // Check if this is an established conversation or first contact
-    let envelope = if let Some(conversation) = conversation_manager.get_conversation(&recipient_pubkey) {
-        // Established conversation - use shared secret
-        let inbox_id = conversation.get_outgoing_inbox();
-        
-        let mut payload = MessagePayload::new(
-            keypair.public_key_string(),
-            message.to_string(),
-            conversation.our_counter - 1, // get_outgoing_inbox already incremented it
-            None,
-        );
-        payload.sign(&keypair.signing_key)?;
-        
-        let payload_json = payload.to_json()?;
-        let encrypted = encrypt_symmetric(&conversation.shared_secret, payload_json.as_bytes())?;
-        
-        MessageEnvelope::new(inbox_id, encrypted)
-    } else {
-        // First contact - use asymmetric encryption
-        let inbox_id = derive_first_contact_inbox(&recipient_public_keys.x25519_key);
-        
-        let mut payload = MessagePayload::new(
-            keypair.public_key_string(),
-            message.to_string(),
-            0, // First message
-            None,
-        );
-        payload.sign(&keypair.signing_key)?;
-        
-        let payload_json = payload.to_json()?;
-        let encrypted = encrypt_asymmetric(&recipient_public_keys.x25519_key, payload_json.as_bytes())?;
-        
-        MessageEnvelope::new(inbox_id, encrypted)
-    };
-    
-    // Send message
-    match client.send_envelope(envelope).await {
-        Ok(()) => {
-            println!("✓ Message sent to {}", recipient);
-            
-            // Store outgoing message
-            let stored_msg = StoredMessage {
-                id: format!("{}:{}:{}", keypair.public_key_string(), recipient_pubkey, Utc::now().timestamp()),
-                from_pubkey: keypair.public_key_string(),
-                to_pubkey: recipient_pubkey.clone(),
-                content: message.to_string(),
-                timestamp: Utc::now(),
-                received_at: Utc::now(),
-                is_outgoing: true,
-                conversation_id: format!("{}:{}", keypair.public_key_string(), recipient_pubkey),
-                counter: 0, // This should be the actual counter
-            };
-            
-            message_store.store_message(stored_msg)?;
-            
-            // Save updated state
-            save_contact_manager(config_dir, &contact_manager)?;
-            save_conversation_manager(config_dir, &conversation_manager)?;
-            save_message_store(config_dir, &message_store)?;
+        DeliveryStatus::Pending => {
+            println!("⏳ Relay unreachable; message to {} queued in the outbox and will be retried", recipient);
         }
-        Err(e) => {
-            eprintln!("❌ Failed to send message: {}", e);
-            return Err(e.into());
+        DeliveryStatus::Failed => {
+            eprintln!("❌ Failed to send message to {} (see `nano-client outbox`)", recipient);
+            anyhow::bail!("Message was not delivered");
         }
     }
     
     Ok(())
 }
 
-async fn receive_messages(config_dir: &PathBuf, relay: &str) -> Result<()> {
-    let keypair = load_keypair(config_dir)?;
-    let client = RelayClient::new(relay.to_string());
-    let mut contact_manager = load_contact_manager(config_dir)?;
-    let mut conversation_manager = load_conversation_manager(config_dir)?;
-    let mut message_store = load_message_store(config_dir)?;
-    
-    println!("Checking for new messages on {}...", relay);
-    
-    let mut new_message_count = 0;
-    
-    // Check first-contact inbox
-    let first_contact_inbox = derive_first_contact_inbox(&keypair.public_keys().x25519_key);
-    let first_contact_messages = client.fetch_inbox(first_contact_inbox).await?;
-    
-    for envelope in first_contact_messages {
-        if envelope.is_expired() {
-            continue;
-        }
-        
-        match process_first_contact_message(&envelope, &keypair, &mut contact_manager, &mut message_store) {
-            Ok(processed) => {
-                if processed {
-                    new_message_count += 1;
-                }
-            }
-            Err(e) => {
-                eprintln!("Warning: Failed to process first contact message: {}", e);
-            }
-        }
+/// Download a message's attachments into `output`, never overwriting existing files
+async fn download_attachments(config_dir: &Path, relays: &[String], message_id: &str, output: &Path) -> Result<()> {
+    let session = ClientSession::load(config_dir, relays)?;
+    let message = session.message_store.get_message(message_id)?
+        .ok_or_else(|| anyhow::anyhow!("No message with ID '{}'", message_id))?;
+    let pointers = message.rich.as_ref().map(|rich| rich.attachments.clone()).unwrap_or_default();
+    if pointers.is_empty() {
+        anyhow::bail!("Message '{}' has no attachments", message_id);
     }
     
-    // Check conversation inboxes for known contacts
-    // Fix: Collect conversation pubkeys first to avoid borrow conflicts
-    let conversation_pubkeys: Vec<String> = conversation_manager.list_conversations().iter().map(|s| s.to_string()).collect();
-    
-    for conversation_pubkey in conversation_pubkeys {
-        if let Some(mut conversation) = conversation_manager.get_conversation(&conversation_pubkey) {
-            let inbox_ids = conversation.get_incoming_inboxes(10); // Check last 10 possible inboxes
-            
-            for inbox_id in inbox_ids {
-                let messages = client.fetch_inbox(inbox_id).await?;
-                
-                for envelope in messages {
-                    if envelope.is_expired() {
-                        continue;
-                    }
-                    
-                    match process_conversation_message(
-                        &envelope,
-                        &mut conversation,
-                        &keypair,
-                        &mut contact_manager,
-                        &mut message_store,
-                    ) {
-                        Ok(processed) => {
-                            if processed {
-                                new_message_count += 1;
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Warning: Failed to process conversation message: {}", e);
-                        }
+    std::fs::create_dir_all(output)?;
+    let manager = attachments::transfer_manager(session.relays.clone()).await?;
+    for pointer in &pointers {
+        println!("Downloading {}...", pointer);
+        let content = attachments::download(&manager, pointer).await?;
+        let path = attachment_path(output, &pointer.name);
+        std::fs::write(&path, content)?;
+        println!("✓ Saved {}", path.display());
+    }
+    Ok(())
+}
+
+/// Free path in `dir` for an attachment, keeping only the final component of the sender's name
+fn attachment_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name).file_name().and_then(|name| name.to_str()).unwrap_or("attachment");
+    let mut path = dir.join(name);
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut copy = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, copy, extension));
+        copy += 1;
+    }
+    path
+}
+
+/// A direct message stored and queued for the recipient's devices
+struct SentMessage {
+    recipient_pubkey: String,
+    devices: usize,
+    status: DeliveryStatus,
+    warnings: Vec<String>,
+}
+
+/// What a sent payload carries besides its sender
+enum Outgoing {
+    Text(String),
+    Rich(RichContent),
+    Change(MessageContent),
+    Timer(DisappearingTimer),
+}
+
+impl Outgoing {
+    /// Unsigned payload whose body is the text, or a rendering of the rest for clients that do not understand it
+    fn payload(self, from_pubkey: String, room: Option<String>) -> MessagePayload {
+        let body = match &self {
+            Outgoing::Text(text) => text.clone(),
+            Outgoing::Rich(rich) => rich.fallback_text(),
+            Outgoing::Change(content) => content.fallback_text(),
+            Outgoing::Timer(timer) => timer.notice_text(),
+        };
+        let mut payload = MessagePayload::new(from_pubkey, body, 0, room);
+        match self {
+            Outgoing::Text(_) => {}
+            Outgoing::Rich(rich) => payload.rich = Some(rich),
+            Outgoing::Change(content) => payload.content = Some(content),
+            Outgoing::Timer(timer) => payload.disappearing = Some(timer),
+        }
+        payload
+    }
+}
+
+impl From<RichContent> for Outgoing {
+    /// Plain text unless the content needs more
+    fn from(rich: RichContent) -> Self {
+        if rich.is_plain() {
+            Outgoing::Text(rich.text)
+        } else {
+            Outgoing::Rich(rich)
+        }
+    }
+}
+
+/// Content for `text`, with its markup, `@username` mentions of contacts and the message it replies to
+fn compose_message(
+    message_store: &MessageStore,
+    contact_manager: &ContactManager,
+    text: &str,
+    reply_to: Option<&str>,
+) -> Result<RichContent> {
+    let mut rich = RichContent::from_markup(text, |name| contact_manager.get_pubkey_for_username(name).map(str::to_string));
+    if let Some(message_id) = reply_to {
+        let message = message_store.get_message(message_id)?
+            .filter(|message| !message.deleted)
+            .ok_or_else(|| anyhow::anyhow!("Unknown message: {}", message_id))?;
+        rich = rich.with_reply(message.message_ref(), &message.content);
+    }
+    Ok(rich)
+}
+
+/// Encrypt a message to each of the recipient's devices and our other devices, store it and send it through the outbox
+async fn deliver_message(
+    session: &mut ClientSession,
+    recipient: &str,
+    message: Outgoing,
+    via: &[String],
+) -> Result<SentMessage> {
+    deliver_payload(session, recipient, message, via).await
+}
+
+/// Edit, delete or react to a message of a direct conversation, for us and everyone in it
+async fn deliver_change(session: &mut ClientSession, recipient: &str, content: MessageContent) -> Result<SentMessage> {
+    deliver_payload(session, recipient, Outgoing::Change(content), &[]).await
+}
+
+/// Set the disappearing-message timer of a direct conversation, for us and the recipient
+async fn deliver_timer(session: &mut ClientSession, recipient: &str, timer: DisappearingTimer) -> Result<SentMessage> {
+    deliver_payload(session, recipient, Outgoing::Timer(timer), &[]).await
+}
+
+/// Send a message, a change to an earlier one, or a new conversation timer through the outbox
+async fn deliver_payload(
+    session: &mut ClientSession,
+    recipient: &str,
+    outgoing: Outgoing,
+    via: &[String],
+) -> Result<SentMessage> {
+    let own_relays = session.relays.relays().to_vec();
+    let mut warnings = Vec::new();
+    
+    // A revoked device must not keep speaking for the identity
+    if let Err(e) = try_refresh_device_list(&mut session.relays, &own_relays, &mut session.contact_manager, &session.identity).await {
+        warnings.push(e.to_string());
+    }
+    if session.is_revoked() {
+        anyhow::bail!("This device has been revoked by the primary device");
+    }
+    
+    // Usernames and pubkeys both resolve through our relays, or through cached keys while they are unreachable
+    let recipient_public_keys = match lookup_claim(&mut session.relays, &own_relays, recipient).await {
+        Ok(Some(claim)) => {
+            warnings.extend(session.check_pinned_keys(recipient, &claim.public_keys)?);
+            let pubkey = claim.public_keys.public_key_string();
+            session.contact_manager.set_relays(pubkey, claim.relays);
+            claim.public_keys
+        }
+        Ok(None) => anyhow::bail!("Could not find public keys for '{}'", recipient),
+        Err(NanoError::Network(e)) => cached_public_keys(&session.contact_manager, recipient)
+            .ok_or_else(|| anyhow::anyhow!("Relays unreachable and no cached keys for '{}': {}", recipient, e))?,
+        Err(e) => return Err(e.into()),
+    };
+    let recipient_pubkey = recipient_public_keys.public_key_string();
+    if !recipient.starts_with("pubkey:") {
+        // Update contact manager with username mapping
+        session.contact_manager.set_username(recipient_pubkey.clone(), recipient.to_string());
+    }
+    session.contact_manager.remember_public_keys(recipient_public_keys.clone());
+    
+    // Deliver to every active device of the recipient, or just their identity keys, on the relays they advertise
+    let recipient_relays = session.relays_for(&recipient_pubkey);
+    if let Err(e) = try_refresh_device_list(&mut session.relays, &recipient_relays, &mut session.contact_manager, &recipient_pubkey).await {
+        warnings.push(e.to_string());
+    }
+    let recipient_devices: Vec<DeviceCertificate> = session.contact_manager
+        .get_device_list(&recipient_pubkey)
+        .map(|list| list.devices.clone())
+        .unwrap_or_default();
+    let primary_id = device_id(&recipient_public_keys);
+    
+    let identity = session.identity.clone();
+    let keypair = &session.keypair;
+    let certificate = session.certificate.as_ref();
+    let mut envelopes = Vec::new();
+    
+    // Receipts name messages by the signed timestamp, so every copy carries the one in our message ID
+    let sent_at = Utc::now();
+    let message_id = outgoing_message_id(&identity, &recipient_pubkey, sent_at.timestamp());
+    let conversation_id = direct_conversation_id(&identity, &recipient_pubkey);
+    
+    let mut template = outgoing.payload(identity.clone(), None);
+    template.timestamp = sent_at.timestamp();
+    
+    // Changes apply here first, so one the recipients would ignore is never sent
+    if let Some(content) = &template.content {
+        let signed_at = chrono::DateTime::from_timestamp(sent_at.timestamp(), 0).unwrap_or(sent_at);
+        if session.message_store.apply_content(&conversation_id, &identity, signed_at, content)?.is_none() {
+            anyhow::bail!("Nothing to change: the message is unknown, deleted, or was changed within the last second");
+        }
+    }
+    
+    // A conversation is with the keys it was opened for; after a key change the next message opens a new one
+    if session.conversation_manager.get_conversation(&recipient_pubkey)
+        .is_some_and(|conversation| conversation.their_public_key != recipient_public_keys.x25519_key)
+    {
+        session.conversation_manager.remove_conversation(&recipient_pubkey);
+    }
+    
+    // Check if this is an established conversation or first contact
+    if let Some(conversation) = session.conversation_manager.get_conversation(&recipient_pubkey) {
+        // Established conversation with the primary device - use shared secret
+        let inbox_id = conversation.get_outgoing_inbox();
+        
+        let mut payload = template.clone();
+        payload.counter = conversation.our_counter - 1; // get_outgoing_inbox already incremented it
+        sign_payload(&mut payload, keypair, certificate)?;
+        
+        let payload_json = payload.to_json()?;
+        let padded = session.crypto.padding.pad(payload_json.as_bytes())?;
+        let encrypted = encrypt_symmetric(&conversation.shared_secret, &padded)?;
+        
+        envelopes.push(MessageEnvelope::new(inbox_id, encrypted));
+    } else {
+        let mut payload = template.clone();
+        // Conversations run between primary devices; linked devices keep using first contact
+        if certificate.is_none() {
+            payload.conversation_key = Some(start_conversation(
+                &mut session.conversation_manager,
+                keypair,
+                &recipient_pubkey,
+                recipient_public_keys.x25519_key,
+            ));
+        }
+        envelopes.push(seal_payload(payload, keypair, certificate, &recipient_public_keys, session.crypto.padding)?);
+    }
+    
+    // Linked devices only have first-contact inboxes
+    for device in recipient_devices.iter().filter(|device| device.device_id != primary_id) {
+        envelopes.push(seal_payload(template.clone(), keypair, certificate, &device.public_keys, session.crypto.padding)?);
+    }
+    
+    // Store outgoing message in the same conversation as the recipient's replies
+    if template.content.is_none() {
+        let mut stored_msg = StoredMessage::from_payload(template.clone(), recipient_pubkey.clone(), sent_at, true);
+        stored_msg.id = message_id.clone();
+        session.message_store.store_message(stored_msg)?;
+    }
+    
+    // Queue every copy before sending so none is lost if the relays are unreachable
+    let advertised_relays = session.contact_manager.get_relays(&recipient_pubkey).map(<[String]>::to_vec).unwrap_or_default();
+    for envelope in envelopes {
+        let entry = OutboxEntry::new(message_id.clone(), envelope, via.to_vec(), false)
+            .with_relays(advertised_relays.clone());
+        session.message_store.save_outbox_entry(entry)?;
+    }
+    
+    // Copy the sent message to our other devices
+    let sync = SentMessageSync {
+        to_pubkey: recipient_pubkey.clone(),
+        body: template.body,
+        timestamp: sent_at.timestamp(),
+        content: template.content,
+        disappearing: template.disappearing,
+        rich: template.rich,
+    };
+    let own_id = device_id(&keypair.public_keys());
+    let own_devices: Vec<DeviceCertificate> = session.contact_manager
+        .get_device_list(&identity)
+        .map(|list| list.devices.iter().filter(|device| device.device_id != own_id).cloned().collect())
+        .unwrap_or_default();
+    for device in &own_devices {
+        let envelope = seal_first_contact(
+            session,
+            &sync.to_json()?,
+            Some(DEVICE_SYNC_ROOM.to_string()),
+            sent_at.timestamp(),
+            &device.public_keys,
+        )?;
+        let entry = OutboxEntry::new(message_id.clone(), envelope, via.to_vec(), true);
+        session.message_store.save_outbox_entry(entry)?;
+    }
+    
+    // Older queued messages go first so they keep their order
+    warnings.extend(flush_outbox(session).await.warnings);
+    
+    Ok(SentMessage {
+        recipient_pubkey,
+        devices: recipient_devices.len(),
+        status: session.message_store.delivery_status(&message_id).unwrap_or(DeliveryStatus::Sent),
+        warnings,
+    })
+}
+
+/// Check keys looked up for `contact` against the pinned ones, noting a change in the conversations it touches
+///
+/// Returns a warning for changes the contact's policy lets through, and fails for blocked ones.
+fn check_pinned_keys(
+    config_dir: &Path,
+    trust: &mut TrustStore,
+    message_store: &mut MessageStore,
+    identity: &str,
+    contact: &str,
+    public_keys: &UserPublicKeys,
+) -> Result<Option<String>> {
+    let check = trust.check(contact, public_keys, Utc::now());
+    if check == KeyCheck::Unchanged {
+        return Ok(None);
+    }
+    save_trust_store(config_dir, trust)?;
+    
+    if let KeyCheck::Changed(change) | KeyCheck::Blocked(change) = &check {
+        let mut pubkeys = vec![&change.old_pubkey, &change.new_pubkey];
+        pubkeys.dedup();
+        for pubkey in pubkeys {
+            let conversation_id = direct_conversation_id(identity, pubkey);
+            message_store.store_message(StoredMessage::key_change_notice(conversation_id, identity.to_string(), change.clone()))?;
+        }
+    }
+    
+    match check {
+        KeyCheck::Blocked(_) | KeyCheck::StillBlocked => anyhow::bail!(
+            "The keys of {} changed and are not trusted yet; compare fingerprints with `nano-client trust show {}`, \
+             then accept them with `nano-client trust accept {}`",
+            contact, contact, contact
+        ),
+        KeyCheck::Changed(_) if trust.policy(contact) == KeyChangePolicy::Warn => Ok(Some(format!(
+            "The keys of {} changed; compare fingerprints with `nano-client trust show {}`",
+            contact, contact
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// Last known keys for a pubkey, or for a username seen before
+fn cached_public_keys(contact_manager: &ContactManager, recipient: &str) -> Option<UserPublicKeys> {
+    let pubkey = if recipient.starts_with("pubkey:") {
+        recipient
+    } else {
+        contact_manager.get_pubkey_for_username(recipient)?
+    };
+    contact_manager.get_public_keys(pubkey).cloned()
+}
+
+/// Find a username's claim on the first of `relays` that has it
+async fn lookup_claim(
+    pool: &mut RelayPool,
+    relays: &[String],
+    username: &str,
+) -> nano_messenger::error::Result<Option<UsernameClaim>> {
+    let mut unreachable = None;
+    let mut answered = false;
+    for relay in pool.ordered(relays) {
+        match RelayClient::new(relay.clone()).lookup_claim(username.to_string()).await {
+            Ok(Some(claim)) => {
+                pool.record_success(&relay);
+                return Ok(Some(claim));
+            }
+            // Claims are published per relay, so keep asking the others
+            Ok(None) => {
+                pool.record_success(&relay);
+                answered = true;
+            }
+            Err(NanoError::Network(e)) => {
+                pool.record_failure(&relay);
+                unreachable = Some(NanoError::Network(e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    match unreachable {
+        Some(e) if !answered => Err(e),
+        _ => Ok(None),
+    }
+}
+
+/// Outbox entries delivered and problems hit while flushing
+#[derive(Default)]
+struct OutboxFlush {
+    delivered: usize,
+    warnings: Vec<String>,
+}
+
+/// Send every due outbox entry through the first reachable of its relays
+async fn flush_outbox(session: &mut ClientSession) -> OutboxFlush {
+    let mut flush = OutboxFlush::default();
+    
+    for id in session.message_store.outbox().due(Utc::now()) {
+        let Some(mut entry) = session.message_store.outbox().get(&id).cloned() else {
+            continue;
+        };
+        let relays = if entry.relays.is_empty() { session.relays.relays().to_vec() } else { entry.relays.clone() };
+        
+        // Leave the entry due while every relay it could use is cooling down after a failure
+        if !relays.iter().any(|relay| session.relays.is_healthy(relay)) {
+            continue;
+        }
+        
+        match deliver_envelope(&mut session.relays, &relays, &entry.via, &session.onion_hops, &entry.envelope).await {
+            Ok(()) => {
+                entry.record_sent();
+                flush.delivered += 1;
+            }
+            Err(NanoError::ReplayDetected(_)) => {
+                // The relay already holds this envelope, so an earlier attempt arrived and only its reply was lost
+                entry.record_sent();
+                flush.delivered += 1;
+            }
+            Err(e) => {
+                // Only network failures are worth retrying; a relay rejecting an envelope is final
+                let retryable = matches!(e, NanoError::Network(_));
+                entry.record_failure(e.to_string(), retryable, Utc::now());
+                if entry.status == DeliveryStatus::Failed {
+                    flush.warnings.push(format!("Gave up delivering message {}: {}", entry.message_id, e));
+                }
+            }
+        }
+        
+        if let Err(e) = session.message_store.save_outbox_entry(entry) {
+            flush.warnings.push(format!("Failed to update outbox: {}", e));
+        }
+    }
+    
+    flush
+}
+
+/// Marker for an outgoing message: the recipient's latest receipt, else its delivery state; messages that never went through the outbox were sent directly
+fn delivery_marker(status: Option<DeliveryStatus>, receipt: Option<ReceiptStatus>) -> &'static str {
+    match (status, receipt) {
+        (_, Some(ReceiptStatus::Read)) => "✓✓ read",
+        (_, Some(ReceiptStatus::Delivered)) => "✓✓",
+        (None | Some(DeliveryStatus::Sent), None) => "✓",
+        (Some(DeliveryStatus::Pending), None) => "⏳",
+        (Some(DeliveryStatus::Failed), None) => "✗",
+    }
+}
+
+/// Message text with its reply, attachments, edited marker and reactions
+fn decorated_text(msg: &StoredMessage) -> String {
+    let mut text = msg.rendered_text();
+    if !msg.edits.is_empty() {
+        text.push_str(" (edited)");
+    }
+    let reactions: Vec<String> = msg
+        .reaction_counts()
+        .into_iter()
+        .map(|(reaction, count)| if count > 1 { format!("{}{}", reaction, count) } else { reaction.to_string() })
+        .collect();
+    if !reactions.is_empty() {
+        text.push_str(&format!(" [{}]", reactions.join(" ")));
+    }
+    text
+}
+
+/// Send an edit, deletion or reaction for a stored message to everyone in its conversation
+async fn change_message(
+    session: &mut ClientSession,
+    message_id: &str,
+    change: impl FnOnce(MessageRef) -> MessageContent,
+) -> Result<Vec<String>> {
+    let message = session.message_store.get_message(message_id)?
+        .ok_or_else(|| anyhow::anyhow!("Unknown message: {}", message_id))?;
+    let content = change(message.message_ref());
+    
+    if let Some(group_id) = message.conversation_id.strip_prefix("group:").map(str::to_string) {
+        let state = session.group_manager.get_mut(&group_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group_id))?;
+        deliver_group_change(&mut session.relays, &session.keypair, session.crypto.padding, state, &mut session.message_store, content).await?;
+        return Ok(Vec::new());
+    }
+    
+    let other_pubkey = if message.is_outgoing { message.to_pubkey.clone() } else { message.from_pubkey.clone() };
+    let sent = deliver_change(session, &other_pubkey, content).await?;
+    let mut warnings = sent.warnings;
+    match sent.status {
+        DeliveryStatus::Sent => {}
+        DeliveryStatus::Pending => warnings.push("Relays unreachable; the change is queued in the outbox".to_string()),
+        DeliveryStatus::Failed => warnings.push("The change could not be delivered (see `nano-client outbox`)".to_string()),
+    }
+    Ok(warnings)
+}
+
+async fn change_message_command(
+    config_dir: &Path,
+    relays: &[String],
+    message_id: &str,
+    change: impl FnOnce(MessageRef) -> MessageContent,
+) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
+    let warnings = change_message(&mut session, message_id, change).await?;
+    session.save()?;
+    
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(message) = session.message_store.get_message(message_id)? {
+        println!("✓ {}", decorated_text(&message));
+    }
+    Ok(())
+}
+
+/// Show a direct conversation's disappearing-message timer, or change it for both sides
+async fn disappearing_command(
+    config_dir: &Path,
+    relays: &[String],
+    contact: &str,
+    timer: Option<&str>,
+    after_read: bool,
+) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
+    
+    let Some(timer) = timer else {
+        let pubkey = if contact.starts_with("pubkey:") {
+            Some(contact)
+        } else {
+            session.contact_manager.get_pubkey_for_username(contact)
+        };
+        let pubkey = pubkey.ok_or_else(|| anyhow::anyhow!("Unknown contact '{}'", contact))?;
+        let timer = session.message_store.timer(&direct_conversation_id(&session.identity, pubkey));
+        println!("⏱ Disappearing messages with {}: {}", contact, timer);
+        return Ok(());
+    };
+    
+    let timer = DisappearingTimer::parse(timer, after_read)?;
+    let sent = deliver_timer(&mut session, contact, timer).await?;
+    for warning in &sent.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    session.save()?;
+    
+    match sent.status {
+        DeliveryStatus::Sent => println!("✓ {} with {}", timer.notice_text(), contact),
+        DeliveryStatus::Pending => println!("⏳ {} with {}; the change is queued in the outbox", timer.notice_text(), contact),
+        DeliveryStatus::Failed => anyhow::bail!("The timer change could not be delivered (see `nano-client outbox`)"),
+    }
+    Ok(())
+}
+
+/// Conversation ID shared by both directions of a direct conversation, as `StoredMessage::from_payload` builds it
+fn direct_conversation_id(identity: &str, other_pubkey: &str) -> String {
+    format!("{}|{}", other_pubkey, identity)
+}
+
+/// ID of a direct message we sent, shared with the copies synced to our other devices
+fn outgoing_message_id(identity: &str, recipient_pubkey: &str, timestamp: i64) -> String {
+    format!("{}:{}:{}", identity, recipient_pubkey, timestamp)
+}
+
+/// Sign a payload for our identity and encrypt it to one device's first-contact inbox
+fn seal_first_contact(
+    session: &ClientSession,
+    body: &str,
+    room: Option<String>,
+    timestamp: i64,
+    recipient: &UserPublicKeys,
+) -> Result<MessageEnvelope> {
+    let mut payload = MessagePayload::new(
+        session.identity.clone(),
+        body.to_string(),
+        0, // First message
+        room,
+    );
+    payload.timestamp = timestamp;
+    seal_payload(payload, &session.keypair, session.certificate.as_ref(), recipient, session.crypto.padding)
+}
+
+/// Start a conversation with a contact's primary device, returning the key that offers it to them
+fn start_conversation(
+    conversations: &mut ConversationManager,
+    keypair: &UserKeyPair,
+    pubkey: &str,
+    their_key: X25519PublicKey,
+) -> String {
+    conversations.get_or_create_conversation(&keypair.x25519_key, pubkey, their_key);
+    general_purpose::STANDARD.encode(keypair.public_keys().x25519_key.as_bytes())
+}
+
+/// Open the conversation a contact's primary device offered, replacing any earlier one with them
+fn open_conversation(conversations: &mut ConversationManager, keypair: &UserKeyPair, pubkey: &str, offered: &str) -> Result<()> {
+    let key: [u8; 32] = general_purpose::STANDARD.decode(offered)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid conversation key from {}", pubkey))?;
+    // The sender only offers a conversation when it has none with us, so ours is stale
+    conversations.remove_conversation(pubkey);
+    conversations.get_or_create_conversation(&keypair.x25519_key, pubkey, X25519PublicKey::from(key));
+    Ok(())
+}
+
+/// Sign a prepared payload and encrypt it to one device's first-contact inbox
+fn seal_payload(
+    mut payload: MessagePayload,
+    keypair: &UserKeyPair,
+    certificate: Option<&DeviceCertificate>,
+    recipient: &UserPublicKeys,
+    padding: PaddingScheme,
+) -> Result<MessageEnvelope> {
+    let inbox_id = derive_first_contact_inbox(&recipient.x25519_key);
+    sign_payload(&mut payload, keypair, certificate)?;
+    
+    let payload_json = payload.to_json()?;
+    let padded = padding.pad(payload_json.as_bytes())?;
+    let encrypted = encrypt_asymmetric(&recipient.x25519_key, &padded)?;
+    
+    Ok(MessageEnvelope::new(inbox_id, encrypted))
+}
+
+/// Sign with this device's key, attaching its certificate if it is a linked device
+fn sign_payload(payload: &mut MessagePayload, keypair: &UserKeyPair, certificate: Option<&DeviceCertificate>) -> Result<()> {
+    payload.device = certificate.cloned();
+    payload.sign(&keypair.signing_key)?;
+    Ok(())
+}
+
+/// Send a receipt or typing notice to every device of a contact
+///
+/// These are best effort: they are never queued in the outbox, and failures only warn.
+async fn send_control(session: &mut ClientSession, pubkey: &str, control: &ConversationControl) -> Result<()> {
+    let public_keys = match session.contact_manager.get_public_keys(pubkey) {
+        Some(public_keys) => public_keys.clone(),
+        None => {
+            let relays = session.relays.relays().to_vec();
+            let claim = lookup_claim(&mut session.relays, &relays, pubkey).await?
+                .ok_or_else(|| anyhow::anyhow!("Could not find public keys for {}", pubkey))?;
+            session.check_pinned_keys(pubkey, &claim.public_keys)?;
+            session.contact_manager.remember_public_keys(claim.public_keys.clone());
+            claim.public_keys
+        }
+    };
+    
+    let primary_id = device_id(&public_keys);
+    let mut recipients = vec![public_keys];
+    if let Some(list) = session.contact_manager.get_device_list(pubkey) {
+        recipients.extend(list.devices.iter().filter(|device| device.device_id != primary_id).map(|device| device.public_keys.clone()));
+    }
+    
+    let body = control.to_json()?;
+    let timestamp = Utc::now().timestamp();
+    let mut envelopes = Vec::new();
+    for recipient in &recipients {
+        envelopes.push(seal_first_contact(
+            session,
+            &body,
+            Some(RECEIPT_ROOM.to_string()),
+            timestamp,
+            recipient,
+        )?);
+    }
+    
+    let relays = session.relays_for(pubkey);
+    for envelope in &envelopes {
+        deliver_envelope(&mut session.relays, &relays, &[], &session.onion_hops, envelope).await?;
+    }
+    Ok(())
+}
+
+/// Acknowledge newly received direct messages to their senders
+async fn send_delivery_receipts(session: &mut ClientSession, received: &ReceivedMessages) -> Vec<String> {
+    let mut timestamps: HashMap<String, Vec<i64>> = HashMap::new();
+    for (source, message) in &received.messages {
+        if message.is_outgoing || matches!(source, InboxSource::Group(_)) {
+            continue;
+        }
+        timestamps.entry(message.from_pubkey.clone()).or_default().push(message.timestamp.timestamp());
+    }
+    
+    let mut warnings = Vec::new();
+    for (pubkey, timestamps) in timestamps {
+        if !session.shares_activity(&pubkey, |privacy| privacy.delivery_receipts) {
+            continue;
+        }
+        if let Err(e) = send_control(session, &pubkey, &ConversationControl::Delivered { timestamps }).await {
+            warnings.push(format!("Could not send delivery receipt to {}: {}", pubkey, e));
+        }
+    }
+    warnings
+}
+
+/// Mark a conversation read, returning the read receipt owed to its sender
+fn mark_read(session: &mut ClientSession, conversation_id: &str) -> Result<Option<(String, ConversationControl)>> {
+    let mut unread = session.message_store.unread_messages(conversation_id)?;
+    unread.retain(|msg| msg.from_pubkey != SYSTEM_SENDER);
+    let sender = unread.first().map(|msg| msg.from_pubkey.clone());
+    let timestamps: Vec<i64> = unread.iter().map(|msg| msg.timestamp.timestamp()).collect();
+    session.message_store.mark_conversation_read(conversation_id)?;
+    
+    Ok(match sender {
+        Some(pubkey) if conversation_id == direct_conversation_id(&session.identity, &pubkey)
+            && session.shares_activity(&pubkey, |privacy| privacy.read_receipts) =>
+        {
+            Some((pubkey, ConversationControl::Read { timestamps }))
+        }
+        _ => None,
+    })
+}
+
+/// Hand an envelope to the first reachable of `relays`, directly or onion-routed ending there
+async fn deliver_envelope(
+    pool: &mut RelayPool,
+    relays: &[String],
+    via: &[String],
+    onion_hops: &HashMap<String, OnionHop>,
+    envelope: &MessageEnvelope,
+) -> nano_messenger::error::Result<()> {
+    pool.request(relays, async |client: &RelayClient| {
+        if via.is_empty() {
+            client.send_envelope(envelope.clone()).await
+        } else {
+            send_onion_routed(client.address(), via, onion_hops, QuantumSafeEnvelope::from_legacy(envelope.clone())).await
+        }
+    })
+    .await
+}
+
+async fn receive_messages(config_dir: &Path, relays: &[String]) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
+    
+    println!("Checking for new messages on {}...", relays.join(", "));
+    
+    for warning in refresh_known_device_lists(&mut session).await {
+        eprintln!("Warning: {}", warning);
+    }
+    if session.is_revoked() {
+        eprintln!("⚠️  This device has been revoked by the primary device");
+    }
+    
+    // Retry messages queued while the relays were unreachable
+    let flushed = flush_outbox(&mut session).await;
+    for warning in &flushed.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    if flushed.delivered > 0 {
+        println!("📤 Delivered {} queued envelope(s) from the outbox", flushed.delivered);
+    }
+    
+    let inboxes = inboxes_to_poll(&mut session);
+    let fetched = fetch_inboxes(relays, &inboxes).await?;
+    let mut received = receive_envelopes(&mut session, fetched).await;
+    
+    // Conversations opened by this batch may already have messages waiting
+    let opened = inboxes_to_poll(&mut session);
+    if opened != inboxes {
+        let fetched = fetch_inboxes(relays, &opened).await?;
+        received.extend(receive_envelopes(&mut session, fetched).await);
+    }
+    
+    for warning in &received.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    for (source, message) in &received.messages {
+        announce_message(&session, source, message);
+    }
+    for message in &received.updated {
+        println!("✏️  Updated: {}", decorated_text(message));
+    }
+    for sender in &received.requests {
+        println!("\n📨 New message request from {}", sender);
+        println!("   Read it with: nano-client requests show '{}'", sender);
+    }
+    if !received.receipts.is_empty() {
+        let read = received.receipts.iter().filter(|(_, status)| *status == ReceiptStatus::Read).count();
+        println!("📬 {} receipt(s) for sent messages ({} read)", received.receipts.len(), read);
+    }
+    for warning in send_delivery_receipts(&mut session, &received).await {
+        eprintln!("Warning: {}", warning);
+    }
+    
+    // Group state changes (welcomes, sender keys, ratchets) and device lists are saved even without new messages
+    session.save()?;
+    
+    if received.messages.is_empty() {
+        println!("✓ No new messages");
+    } else {
+        println!("✓ Received {} new message(s)", received.messages.len());
+    }
+    
+    Ok(())
+}
+
+/// Which kind of inbox a fetched envelope came from
+#[derive(Debug, Clone, PartialEq)]
+enum InboxSource {
+    FirstContact,
+    Conversation(String), // Contact pubkey
+    Group(String),        // Group ID
+}
+
+/// Messages stored and problems hit while processing fetched envelopes
+#[derive(Default)]
+struct ReceivedMessages {
+    messages: Vec<(InboxSource, StoredMessage)>,
+    receipts: Vec<(String, ReceiptStatus)>, // Our messages the recipient acknowledged
+    typing: Vec<(String, bool)>,            // Contacts who started or stopped typing
+    updated: Vec<StoredMessage>,            // Earlier messages edited, deleted or reacted to
+    requests: Vec<String>,                  // Unknown senders with new messages waiting as requests
+    unlisted: Vec<(String, InboxSource, MessageEnvelope)>, // Signed by devices missing from our copy of their identity's list
+    warnings: Vec<String>,
+}
+
+impl ReceivedMessages {
+    fn extend(&mut self, other: ReceivedMessages) {
+        self.messages.extend(other.messages);
+        self.receipts.extend(other.receipts);
+        self.typing.extend(other.typing);
+        self.updated.extend(other.updated);
+        self.requests.extend(other.requests.into_iter().filter(|sender| !self.requests.contains(sender)).collect::<Vec<_>>());
+        self.unlisted.extend(other.unlisted);
+        self.warnings.extend(other.warnings);
+    }
+}
+
+/// Receipt or typing notice decrypted from a contact
+struct IncomingControl {
+    from_pubkey: String,
+    sent_at: i64,
+    control: ConversationControl,
+}
+
+/// Edit, deletion or reaction from a contact, or synced from our other devices
+struct IncomingChange {
+    conversation_id: String,
+    from_pubkey: String,
+    sent_at: i64,
+    content: MessageContent,
+}
+
+/// Notices and changes applied once the whole batch of messages is stored
+#[derive(Default)]
+struct Deferred {
+    controls: Vec<IncomingControl>,
+    changes: Vec<IncomingChange>,
+    requests: Vec<String>, // Senders whose messages were queued as requests
+    conversations: Vec<(String, String)>, // Contacts' primary devices offering a conversation, with their keys
+    device_lists: Vec<String>, // Identities whose device list must be fetched before their message is accepted
+}
+
+/// Pick up revocations for ourselves and every contact with linked devices
+async fn refresh_known_device_lists(session: &mut ClientSession) -> Vec<String> {
+    let mut known_identities: Vec<String> = session.contact_manager.export_device_lists().keys().cloned().collect();
+    if !known_identities.contains(&session.identity) {
+        known_identities.push(session.identity.clone());
+    }
+    
+    let mut warnings = Vec::new();
+    for pubkey in &known_identities {
+        let relays = session.relays_for(pubkey);
+        if let Err(e) = try_refresh_device_list(&mut session.relays, &relays, &mut session.contact_manager, pubkey).await {
+            warnings.push(e.to_string());
+        }
+    }
+    warnings
+}
+
+/// Inboxes that may hold new messages: first contact, recent conversation inboxes and each group's current epoch
+fn inboxes_to_poll(session: &mut ClientSession) -> Vec<(String, InboxSource)> {
+    let mut inboxes = vec![(
+        derive_first_contact_inbox(&session.keypair.public_keys().x25519_key),
+        InboxSource::FirstContact,
+    )];
+    
+    let conversation_pubkeys: Vec<String> = session.conversation_manager.list_conversations().iter().map(|s| s.to_string()).collect();
+    for pubkey in conversation_pubkeys {
+        // Blocked contacts' inboxes are not even fetched
+        if session.contact_manager.is_blocked(&pubkey) {
+            continue;
+        }
+        if let Some(conversation) = session.conversation_manager.get_conversation(&pubkey) {
+            // Check last 10 possible inboxes
+            for inbox_id in conversation.get_incoming_inboxes(10) {
+                inboxes.push((inbox_id, InboxSource::Conversation(pubkey.clone())));
+            }
+        }
+    }
+    
+    for group in session.group_manager.list() {
+        inboxes.push((group.inbox_id(), InboxSource::Group(group.group_id.clone())));
+    }
+    
+    inboxes
+}
+
+/// Fetch the unexpired envelopes waiting in each inbox on every relay, once per nonce
+///
+/// Unreachable relays are skipped; it is an error only if none could be reached.
+async fn fetch_inboxes(
+    relays: &[String],
+    inboxes: &[(String, InboxSource)],
+) -> nano_messenger::error::Result<Vec<(InboxSource, MessageEnvelope)>> {
+    let mut fetched = Vec::new();
+    let mut seen_nonces = std::collections::HashSet::new();
+    let mut unreachable = None;
+    let mut reached = false;
+    
+    for relay in relays {
+        match fetch_relay_inboxes(&RelayClient::new(relay.clone()), inboxes).await {
+            Ok(envelopes) => {
+                reached = true;
+                // A sender may have delivered the same envelope to more than one of our relays
+                for (source, envelope) in envelopes {
+                    if seen_nonces.insert(envelope.nonce.clone()) {
+                        fetched.push((source, envelope));
                     }
                 }
             }
+            Err(e) => unreachable = Some(e),
+        }
+    }
+    
+    match unreachable {
+        Some(e) if !reached => Err(e),
+        _ => Ok(fetched),
+    }
+}
+
+async fn fetch_relay_inboxes(
+    client: &RelayClient,
+    inboxes: &[(String, InboxSource)],
+) -> nano_messenger::error::Result<Vec<(InboxSource, MessageEnvelope)>> {
+    let mut fetched = Vec::new();
+    for (inbox_id, source) in inboxes {
+        for envelope in client.fetch_inbox(inbox_id.clone()).await? {
+            if !envelope.is_expired() {
+                fetched.push((source.clone(), envelope));
+            }
+        }
+    }
+    Ok(fetched)
+}
+
+type Inboxes = Vec<(String, InboxSource)>;
+type FetchResult = std::result::Result<Vec<(InboxSource, MessageEnvelope)>, String>;
+
+/// How often the TUI and daemon purge messages whose disappearing timer ran out
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Fetch every inbox on a timer, and immediately whenever the inbox set changes
+async fn poll_inboxes(
+    relays: Vec<String>,
+    mut inboxes: tokio::sync::watch::Receiver<Inboxes>,
+    fetched: tokio::sync::mpsc::Sender<FetchResult>,
+    poll_interval: std::time::Duration,
+) {
+    let mut ticker = tokio::time::interval(poll_interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            changed = inboxes.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                ticker.reset();
+            }
+        }
+        
+        let current = inboxes.borrow_and_update().clone();
+        let result = fetch_inboxes(&relays, &current).await.map_err(|e| e.to_string());
+        if fetched.send(result).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Decrypt, verify and store fetched envelopes; already-seen messages are skipped
+fn process_envelopes(session: &mut ClientSession, fetched: Vec<(InboxSource, MessageEnvelope)>) -> ReceivedMessages {
+    let mut received = ReceivedMessages::default();
+    let mut deferred = Deferred::default();
+    
+    for (source, envelope) in fetched {
+        let awaiting_lists = deferred.device_lists.len();
+        let result = match &source {
+            InboxSource::FirstContact => process_first_contact_message(&envelope, session, &mut deferred)
+            .map_err(|e| format!("Failed to process first contact message: {}", e)),
+            InboxSource::Conversation(pubkey) => match session.conversation_manager.get_conversation(pubkey) {
+                Some(conversation) => process_conversation_message(
+                    &envelope,
+                    conversation,
+                    &session.keypair,
+                    &mut session.contact_manager,
+                    &mut session.message_store,
+                    &mut deferred,
+                )
+                .map_err(|e| format!("Failed to process conversation message: {}", e)),
+                None => Ok(None),
+            },
+            InboxSource::Group(group_id) => match session.group_manager.get_mut(group_id) {
+                Some(group) => match process_group_message(&envelope, group, &mut session.message_store, &mut deferred) {
+                    // Already-read messages are refetched until the relay drops them
+                    Err(e) if matches!(e.downcast_ref::<NanoError>(), Some(NanoError::ReplayDetected(_))) => Ok(None),
+                    result => result.map_err(|e| format!("Failed to process group message: {}", e)),
+                },
+                None => Ok(None),
+            },
+        };
+        
+        if deferred.device_lists.len() > awaiting_lists {
+            let identity = deferred.device_lists[awaiting_lists].clone();
+            received.unlisted.push((identity, source, envelope));
+            continue;
+        }
+        match result {
+            Ok(Some(message)) => received.messages.push((source, message)),
+            Ok(None) => {}
+            Err(warning) => received.warnings.push(warning),
+        }
+    }
+    
+    // A message ends its sender's typing indicator
+    for (_, message) in &received.messages {
+        if !message.is_outgoing {
+            session.typing.clear(&message.from_pubkey, message.timestamp);
+        }
+    }
+    for incoming in deferred.controls {
+        apply_control(session, incoming, &mut received);
+    }
+    for change in deferred.changes {
+        apply_change(session, change, &mut received);
+    }
+    for sender in deferred.requests {
+        if !received.requests.contains(&sender) {
+            received.requests.push(sender);
+        }
+    }
+    for (pubkey, key) in deferred.conversations {
+        if let Err(e) = open_conversation(&mut session.conversation_manager, &session.keypair, &pubkey, &key) {
+            received.warnings.push(e.to_string());
+        }
+    }
+    
+    received
+}
+
+/// Process fetched envelopes, fetching the device lists that messages from linked devices need first
+async fn receive_envelopes(session: &mut ClientSession, fetched: Vec<(InboxSource, MessageEnvelope)>) -> ReceivedMessages {
+    let mut received = process_envelopes(session, fetched);
+    let unlisted = std::mem::take(&mut received.unlisted);
+    if unlisted.is_empty() {
+        return received;
+    }
+    
+    let mut identities: Vec<String> = unlisted.iter().map(|(identity, _, _)| identity.clone()).collect();
+    identities.sort();
+    identities.dedup();
+    for identity in identities {
+        let relays = session.relays_for(&identity);
+        if let Err(e) = try_refresh_device_list(&mut session.relays, &relays, &mut session.contact_manager, &identity).await {
+            received.warnings.push(e.to_string());
+        }
+    }
+    
+    let mut retried = process_envelopes(session, unlisted.into_iter().map(|(_, source, envelope)| (source, envelope)).collect());
+    for _ in retried.unlisted.drain(..) {
+        received.warnings.push("Ignored a message from a device its identity does not list".to_string());
+    }
+    received.extend(retried);
+    received
+}
+
+/// Whether messages from `sender` belong in the requests inbox: we never allowed them or wrote to them
+fn is_message_request(contacts: &ContactManager, message_store: &MessageStore, identity: &str, sender: &str) -> Result<bool> {
+    Ok(contacts.get_status(sender) == ContactStatus::Unknown
+        && message_store.get_conversation_messages(&direct_conversation_id(identity, sender), Some(1))?.is_empty())
+}
+
+/// Apply an edit, deletion or reaction to the message it names
+fn apply_change(session: &mut ClientSession, change: IncomingChange, received: &mut ReceivedMessages) {
+    let IncomingChange { conversation_id, from_pubkey, sent_at, content } = change;
+    if session.contact_manager.is_blocked(&from_pubkey) {
+        return;
+    }
+    
+    let sent_at = chrono::DateTime::from_timestamp(sent_at, 0).unwrap_or_else(Utc::now);
+    match session.message_store.apply_content(&conversation_id, &from_pubkey, sent_at, &content) {
+        Ok(Some(message)) => received.updated.push(message),
+        Ok(None) => {}
+        Err(e) => received.warnings.push(format!("Ignored change from {}: {}", from_pubkey, e)),
+    }
+}
+
+/// Record a receipt on our messages to the sender, or note whether they are typing
+fn apply_control(session: &mut ClientSession, incoming: IncomingControl, received: &mut ReceivedMessages) {
+    let IncomingControl { from_pubkey, sent_at, control } = incoming;
+    if session.contact_manager.is_blocked(&from_pubkey) {
+        return;
+    }
+    
+    if let ConversationControl::Typing { active } = control {
+        let sent_at = chrono::DateTime::from_timestamp(sent_at, 0).unwrap_or_else(Utc::now);
+        if session.typing.update(&from_pubkey, active, sent_at) {
+            received.typing.push((from_pubkey, active));
+        }
+        return;
+    }
+    
+    let Some((status, timestamps)) = control.receipt() else {
+        return;
+    };
+    let ids: Vec<String> = timestamps.iter()
+        .map(|timestamp| outgoing_message_id(&session.identity, &from_pubkey, *timestamp))
+        .collect();
+    match session.message_store.record_receipt(&ids, status) {
+        Ok(updated) => received.receipts.extend(updated.into_iter().map(|id| (id, status))),
+        Err(e) => received.warnings.push(format!("Failed to record receipt: {}", e)),
+    }
+}
+
+/// Print a newly received message
+fn announce_message(session: &ClientSession, source: &InboxSource, message: &StoredMessage) {
+    match source {
+        InboxSource::FirstContact if message.is_outgoing => {
+            println!("\n📱 Synced message sent from another device to {}", message.to_pubkey);
+        }
+        InboxSource::FirstContact | InboxSource::Conversation(_) => {
+            println!("✉️  New message from {}: {}", session.display_name(&message.from_pubkey), message.rendered_text());
+        }
+        InboxSource::Group(group_id) => {
+            let group_name = session.group_manager.list().into_iter()
+                .find(|group| &group.group_id == group_id)
+                .map_or(group_id.as_str(), |group| group.name.as_str());
+            println!("\n👥 [{}] {}: {}", group_name, message.from_pubkey, message.rendered_text());
+        }
+    }
+    if message.rich.as_ref().is_some_and(|rich| !rich.attachments.is_empty()) && !message.is_outgoing {
+        println!("   Save attachments with: nano-client download '{}'", message.id);
+    }
+}
+
+fn show_messages(
+    config_dir: &Path,
+    search: Option<&str>,
+    from_filter: Option<&str>,
+    limit: usize,
+    crypto_mode_filter: Option<&str>,
+    show_ids: bool,
+) -> Result<()> {
+    let keypair = load_keypair(config_dir)?;
+    let message_store = load_message_store(config_dir, &keypair)?;
+    let contact_manager = load_contact_manager(config_dir)?;
+    
+    println!("📨 Message history (last {} messages):", limit);
+    
+    // Flags are shorthands for the matching query filters
+    let mut query = SearchQuery::parse(search.unwrap_or(""))?;
+    if let Some(mode_str) = crypto_mode_filter {
+        query.mode = Some(parse_crypto_mode(mode_str)?);
+    }
+    if let Some(from) = from_filter {
+        query.from = Some(from.to_string());
+    }
+    
+    if let Some(mode) = query.mode {
+        println!("🔍 Filtered by crypto mode: {}", mode);
+    }
+    if let Some(from) = query.from.take() {
+        println!("🔍 Filtered by sender: {}", from);
+        
+        // Try to resolve username to pubkey
+        let from_pubkey = if from.starts_with("pubkey:") {
+            from
+        } else {
+            contact_manager.get_pubkey_for_username(&from)
+                .map(str::to_string)
+                .unwrap_or(from)
+        };
+        query.from = Some(from_pubkey);
+    }
+    if let Some(search) = search {
+        println!("🔍 Search: {}", search);
+    }
+    
+    let messages = if query.is_empty() {
+        message_store.get_all_messages(Some(limit))?
+    } else {
+        message_store.search(&query, Some(limit))?
+    };
+    
+    if messages.is_empty() {
+        println!("(No messages to display)");
+    } else {
+        for msg in messages {
+            let display_name = if let Some(contact) = contact_manager.get_contact(&msg.from_pubkey) {
+                contact.display_name().to_string()
+            } else {
+                msg.from_pubkey.clone()
+            };
+            
+            let direction = if msg.is_outgoing { "→" } else { "←" };
+            let delivery = if msg.is_outgoing {
+                format!(" {}", delivery_marker(message_store.delivery_status(&msg.id), msg.receipt))
+            } else {
+                String::new()
+            };
+            
+            println!(
+                "[{}] {} {} {} 🔐{}",
+                msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                direction,
+                display_name,
+                decorated_text(&msg),
+                delivery
+            );
+            if show_ids {
+                println!("    id: {}", msg.id);
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+async fn show_outbox(config_dir: &Path, relays: &[String], retry: bool) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
+    
+    if retry {
+        let failed: Vec<OutboxEntry> = session.message_store.outbox().entries().into_iter()
+            .filter(|entry| entry.status == DeliveryStatus::Failed)
+            .cloned()
+            .collect();
+        for mut entry in failed {
+            entry.retry();
+            session.message_store.save_outbox_entry(entry)?;
+        }
+        
+        let flushed = flush_outbox(&mut session).await;
+        for warning in &flushed.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        println!("📤 Delivered {} envelope(s)", flushed.delivered);
+    }
+    
+    let entries = session.message_store.outbox().entries();
+    println!("📤 Outbox ({} envelopes):", entries.len());
+    if entries.is_empty() {
+        println!("(Empty)");
+    }
+    for entry in entries {
+        let target = match session.message_store.get_message(&entry.message_id)? {
+            _ if entry.device_sync => "(own device)".to_string(),
+            Some(message) => session.display_name(&message.to_pubkey),
+            None => entry.message_id.clone(),
+        };
+        println!(
+            "[{}] {} {} {} attempt(s) {}",
+            entry.created_at.format("%Y-%m-%d %H:%M:%S"),
+            delivery_marker(Some(entry.status), None),
+            entry.status,
+            entry.attempts,
+            target
+        );
+        if entry.status == DeliveryStatus::Pending && entry.attempts > 0 {
+            println!("    next attempt {}", entry.next_attempt.format("%Y-%m-%d %H:%M:%S"));
+        }
+        if let Some(error) = &entry.last_error {
+            println!("    last error: {}", error);
+        }
+    }
+    
+    Ok(())
+}
+
+async fn handle_relay_command(config_dir: &Path, relays: &[String], command: RelayCommands) -> Result<()> {
+    match command {
+        RelayCommands::List => {
+            println!("📡 Relays, in order of preference:");
+            for relay in relays {
+                let marker = match RelayClient::new(relay.clone()).get_onion_hop().await {
+                    Ok(_) => "✓ reachable".to_string(),
+                    Err(e) => format!("✗ {}", e),
+                };
+                println!("  {} {}", relay, marker);
+            }
+            
+            let pinned = load_onion_keys(config_dir)?;
+            if !pinned.is_empty() {
+                println!("🧅 Pinned onion keys:");
+                for (address, onion_key) in &pinned {
+                    println!("  {} {}", address, onion_key);
+                }
+            }
+        }
+        RelayCommands::Add { address, first } => {
+            let mut configured = load_relays(config_dir)?;
+            configured.retain(|relay| relay != &address);
+            if first {
+                configured.insert(0, address.clone());
+            } else {
+                configured.push(address.clone());
+            }
+            save_relays(config_dir, &configured)?;
+            println!("✓ Added relay {}", address);
+            println!("Claim your username again to advertise the new relay list");
+        }
+        RelayCommands::Remove { address } => {
+            let mut configured = load_relays(config_dir)?;
+            if !configured.contains(&address) {
+                anyhow::bail!("Relay {} is not configured", address);
+            }
+            configured.retain(|relay| relay != &address);
+            if configured.is_empty() {
+                anyhow::bail!("Cannot remove the last relay");
+            }
+            save_relays(config_dir, &configured)?;
+            println!("✓ Removed relay {}", address);
+            println!("Claim your username again to advertise the new relay list");
+        }
+        RelayCommands::Pin { address, onion_key } => {
+            OnionHop::from_hex(address.clone(), &onion_key)?;
+            let mut pinned = load_onion_keys(config_dir)?;
+            pinned.insert(address.clone(), onion_key.to_lowercase());
+            save_onion_keys(config_dir, &pinned)?;
+            println!("✓ Pinned onion key for {}", address);
         }
     }
     
-    if new_message_count > 0 {
-        println!("✓ Received {} new message(s)", new_message_count);
-        
-        // Save updated state
-        save_contact_manager(config_dir, &contact_manager)?;
-        save_conversation_manager(config_dir, &conversation_manager)?;
-        save_message_store(config_dir, &message_store)?;
-    } else {
-        println!("✓ No new messages");
+    Ok(())
+}
+
+fn handle_profile_command(base_dir: &Path, current: &str, relays: &[String], command: ProfileCommands) -> Result<()> {
+    match command {
+        ProfileCommands::List => {
+            println!("👥 Profiles in {}:", base_dir.display());
+            for name in profiles::list_profiles(base_dir)? {
+                let dir = profiles::profile_dir(base_dir, &name)?;
+                let marker = if name == current { "*" } else { " " };
+                if !dir.join("keys.json").exists() {
+                    println!("  {} {} (not initialized)", marker, name);
+                    continue;
+                }
+                let keypair = load_keypair(&dir)?;
+                let prefs = load_security_preferences(&dir)?;
+                println!("  {} {} {}", marker, name, keypair.public_key_string());
+                println!("      {} mode via {}", prefs.default_crypto_mode, load_relays(&dir)?.join(", "));
+            }
+        }
+        ProfileCommands::Create { name, crypto_mode } => {
+            let mode = parse_crypto_mode(&crypto_mode)?;
+            let dir = profiles::profile_dir(base_dir, &name)?;
+            if dir.join("keys.json").exists() {
+                anyhow::bail!("Profile '{}' already exists", name);
+            }
+            std::fs::create_dir_all(&dir)?;
+            init_user(&dir, mode)?;
+            if !relays.is_empty() {
+                save_relays(&dir, relays)?;
+            }
+            println!("✓ Created profile '{}'; use it with --profile {}", name, name);
+        }
     }
     
     Ok(())
 }
 
-fn show_messages(config_dir: &PathBuf, from_filter: Option<&str>, limit: usize, crypto_mode_filter: Option<&str>) -> Result<()> {
-    let message_store = load_message_store(config_dir)?;
-    let contact_manager = load_contact_manager(config_dir)?;
-    
-    println!("📨 Message history (last {} messages):", limit);
+fn handle_trust_command(config_dir: &Path, command: TrustCommands) -> Result<()> {
+    let mut trust = load_trust_store(config_dir)?;
+    let verified = |verified: bool| if verified { "✓ verified" } else { "unverified" };
     
-    if let Some(mode_str) = crypto_mode_filter {
-        let mode = parse_crypto_mode(mode_str)?;
-        println!("🔍 Filtered by crypto mode: {}", mode);
+    match command {
+        TrustCommands::List => {
+            println!("🔑 Your fingerprint: {}", fingerprint(&load_keypair(config_dir)?.public_keys()));
+            println!("Key changes: {} by default", trust.default_policy);
+            for (contact, pin) in trust.pins() {
+                let pending = if trust.pending(contact).is_some() { " · ⚠️ change blocked" } else { "" };
+                println!("  {} {} ({}, {}){}", contact, pin.fingerprint, verified(pin.verified), trust.policy(contact), pending);
+            }
+        }
+        TrustCommands::Show { contact } => {
+            let pin = trust.pin(&contact).ok_or_else(|| anyhow::anyhow!("No keys pinned for {}", contact))?;
+            println!("🔑 {}: {}", contact, pin.pubkey);
+            println!("   Fingerprint:      {} ({})", pin.fingerprint, verified(pin.verified));
+            println!("   Your fingerprint: {}", fingerprint(&load_keypair(config_dir)?.public_keys()));
+            println!("   On key change:    {}", trust.policy(&contact));
+            if let Some(change) = trust.pending(&contact) {
+                println!("   ⚠️  Blocked new keys: {} ({})", change.new_fingerprint, change.new_pubkey);
+            }
+            for change in trust.history(&contact) {
+                let outcome = if change.accepted { "accepted" } else { "blocked" };
+                println!(
+                    "   {} changed {} → {} ({})",
+                    change.seen_at.format("%Y-%m-%d %H:%M"),
+                    change.old_fingerprint,
+                    change.new_fingerprint,
+                    outcome
+                );
+            }
+        }
+        TrustCommands::Verify { contact, fingerprint } => {
+            trust.verify(&contact, fingerprint.as_deref())?;
+            save_trust_store(config_dir, &trust)?;
+            println!("✓ Keys of {} marked as verified", contact);
+        }
+        TrustCommands::Accept { contact } => {
+            let change = trust.accept(&contact, Utc::now())?;
+            save_trust_store(config_dir, &trust)?;
+            println!("✓ Now trusting the new keys of {} ({})", contact, change.new_fingerprint);
+        }
+        TrustCommands::Policy { policy, contact } => match contact {
+            Some(contact) => {
+                let policy = if policy == "default" { None } else { Some(policy.parse::<KeyChangePolicy>()?) };
+                trust.set_policy(&contact, policy);
+                save_trust_store(config_dir, &trust)?;
+                println!("✓ Key changes for {}: {}", contact, trust.policy(&contact));
+            }
+            None => {
+                trust.default_policy = policy.parse()?;
+                save_trust_store(config_dir, &trust)?;
+                println!("✓ Key changes: {} by default", trust.default_policy);
+            }
+        },
     }
     
-    let messages = if let Some(from) = from_filter {
-        println!("🔍 Filtered by sender: {}", from);
-        
-        // Try to resolve username to pubkey
-        let from_pubkey = if from.starts_with("pubkey:") {
-            from.to_string()
-        } else {
-            contact_manager.get_pubkey_for_username(from)
-                .unwrap_or(from)
-                .to_string()
-        };
-        
-        message_store.get_messages_from(&from_pubkey, Some(limit))
-    } else {
-        message_store.get_all_messages(Some(limit))
-    };
+    Ok(())
+}
+
+fn handle_request_command(config_dir: &Path, relays: &[String], command: RequestCommands) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
     
-    if messages.is_empty() {
-        println!("(No messages to display)");
-    } else {
-        for msg in messages {
-            let display_name = if let Some(contact) = contact_manager.get_contact(&msg.from_pubkey) {
-                contact.display_name().to_string()
-            } else {
-                msg.from_pubkey.clone()
+    match command {
+        RequestCommands::List => {
+            let requests = session.requests.list();
+            if requests.is_empty() {
+                println!("📭 No message requests");
+            }
+            for request in requests {
+                let latest = request.messages.last().map_or("", |message| message.payload.body.as_str());
+                println!(
+                    "✉️  {} · {} message(s), last {}",
+                    request.sender,
+                    request.messages.len(),
+                    request.last_seen().format("%Y-%m-%d %H:%M")
+                );
+                println!("   {}", latest);
+            }
+        }
+        RequestCommands::Show { sender } => {
+            let request = session.requests.get(&sender).ok_or_else(|| anyhow::anyhow!("No message request from {}", sender))?;
+            println!("✉️  Request from {} (first seen {})", sender, request.first_seen.format("%Y-%m-%d %H:%M"));
+            for message in &request.messages {
+                let sent_at = DateTime::<Utc>::from_timestamp(message.payload.timestamp, 0).unwrap_or(message.received_at);
+                println!("  [{}] {}", sent_at.format("%Y-%m-%d %H:%M"), message.payload.body);
+            }
+            println!("Accept, block or report with `nano-client requests accept|block|report {}`", sender);
+        }
+        RequestCommands::Accept { sender } => {
+            let accepted = accept_request(&mut session, &sender)?;
+            session.save()?;
+            println!("✓ Accepted {}; {} message(s) moved to your conversations", sender, accepted.len());
+        }
+        RequestCommands::Block { sender } => {
+            let request = block_request(&mut session, &sender)?;
+            session.save()?;
+            println!("✓ Blocked {} and discarded {} message(s)", sender, request.messages.len());
+        }
+        RequestCommands::Report { sender, reason, output } => {
+            let report = report_request(&mut session, &sender, reason)?;
+            let path = match output {
+                Some(path) => path,
+                None => {
+                    let reports_dir = config_dir.join("reports");
+                    std::fs::create_dir_all(&reports_dir)?;
+                    reports_dir.join(format!("report-{}.json", report.reported_at.timestamp()))
+                }
             };
-            
-            let direction = if msg.is_outgoing { "→" } else { "←" };
-            
-            println!(
-                "[{}] {} {} {} 🔐",
-                msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                direction,
-                display_name,
-                msg.content
-            );
+            std::fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+            session.save()?;
+            println!("✓ Blocked {} and saved {} signed message(s) to {}", sender, report.messages.len(), path.display());
         }
     }
     
     Ok(())
 }
 
-fn handle_contact_command(config_dir: &PathBuf, command: ContactCommands) -> Result<()> {
+/// Allow a request's sender and store its messages as the start of their conversation
+fn accept_request(session: &mut ClientSession, sender: &str) -> Result<Vec<StoredMessage>> {
+    let request = session.requests.take(sender)?;
+    session.contact_manager.allow_contact(sender.to_string())?;
+    
+    let offered = request.messages.iter().rev()
+        .find(|message| message.payload.device.is_none())
+        .and_then(|message| message.payload.conversation_key.as_deref());
+    if let Some(key) = offered.filter(|_| session.certificate.is_none()) {
+        open_conversation(&mut session.conversation_manager, &session.keypair, sender, key)?;
+    }
+    
+    let mut accepted = Vec::new();
+    for message in request.messages {
+        let stored = StoredMessage::from_payload(message.payload, session.identity.clone(), message.received_at, false);
+        if session.message_store.store_message(stored.clone())? {
+            accepted.extend(session.message_store.get_message(&stored.id)?);
+        }
+    }
+    Ok(accepted)
+}
+
+/// Block a request's sender, dropping its messages
+fn block_request(session: &mut ClientSession, sender: &str) -> Result<MessageRequest> {
+    let request = session.requests.take(sender)?;
+    session.contact_manager.block_contact(sender.to_string())?;
+    Ok(request)
+}
+
+/// Block a request's sender, keeping its signed messages as a report
+fn report_request(session: &mut ClientSession, sender: &str, reason: Option<String>) -> Result<AbuseReport> {
+    let report = session.requests.report(sender, reason, Utc::now())?;
+    session.contact_manager.block_contact(sender.to_string())?;
+    Ok(report)
+}
+
+/// Apply a status to one contact or to everyone with a label
+fn set_contact_status(
+    contact_manager: &mut ContactManager,
+    pubkey: Option<String>,
+    label: Option<String>,
+    status: ContactStatus,
+    verb: &str,
+) -> Result<()> {
+    match (pubkey, label) {
+        (Some(pubkey), _) => {
+            contact_manager.set_status(pubkey.clone(), status);
+            println!("✓ Contact {} is now {}", pubkey, verb);
+        }
+        (None, Some(label)) => {
+            let changed = contact_manager.set_label_status(&label, status);
+            if changed.is_empty() {
+                anyhow::bail!("No contacts have the label '{}'", label);
+            }
+            println!("✓ {} contacts labelled '{}' are now {}", changed.len(), label, verb);
+        }
+        (None, None) => anyhow::bail!("Give a contact or --label"),
+    }
+    Ok(())
+}
+
+fn handle_contact_command(config_dir: &Path, command: ContactCommands) -> Result<()> {
     let mut contact_manager = load_contact_manager(config_dir)?;
     
     match command {
-        ContactCommands::List => {
-            let contacts = contact_manager.list_contacts();
+        ContactCommands::List { label, favorites } => {
+            let mut contacts = match &label {
+                Some(label) => contact_manager.contacts_with_label(label),
+                None => contact_manager.list_contacts(),
+            };
+            contacts.retain(|contact| !favorites || contact.is_favorite());
+            contacts.sort_by_key(|contact| (!contact.is_favorite(), contact.display_name().to_lowercase()));
             if contacts.is_empty() {
                 println!("No contacts found.");
             } else {
@@ -599,10 +2520,12 @@ fn handle_contact_command(config_dir: &PathBuf, command: ContactCommands) -> Res
                         ContactStatus::Blocked => "✗",
                         ContactStatus::Unknown => "?",
                     };
+                    let favorite = if contact.is_favorite() { "★ " } else { "" };
                     
                     println!(
-                        "  {} {} - {}",
+                        "  {} {}{} - {}",
                         status,
+                        favorite,
                         contact.display_name(),
                         contact.permission.pubkey
                     );
@@ -612,6 +2535,9 @@ fn handle_contact_command(config_dir: &PathBuf, command: ContactCommands) -> Res
                             println!("    Memo: {}", metadata.memo);
                         }
                     }
+                    if !contact.labels().is_empty() {
+                        println!("    Labels: {}", contact.labels().join(", "));
+                    }
                 }
             }
         }
@@ -630,45 +2556,490 @@ fn handle_contact_command(config_dir: &PathBuf, command: ContactCommands) -> Res
                 }
             }
         }
-        ContactCommands::Allow { pubkey } => {
-            contact_manager.allow_contact(pubkey.clone())?;
+        ContactCommands::Allow { pubkey, label } => {
+            set_contact_status(&mut contact_manager, pubkey, label, ContactStatus::Allowed, "allowed")?;
+            save_contact_manager(config_dir, &contact_manager)?;
+        }
+        ContactCommands::Block { pubkey, label } => {
+            set_contact_status(&mut contact_manager, pubkey, label, ContactStatus::Blocked, "blocked")?;
+            save_contact_manager(config_dir, &contact_manager)?;
+        }
+        ContactCommands::Label { pubkey, labels, remove } => {
+            if contact_manager.get_contact(&pubkey).is_none() {
+                anyhow::bail!("Unknown contact {}", pubkey);
+            }
+            for label in &labels {
+                if remove {
+                    contact_manager.remove_label(&pubkey, label);
+                } else {
+                    contact_manager.add_label(&pubkey, label)?;
+                }
+            }
+            save_contact_manager(config_dir, &contact_manager)?;
+            
+            let contact = contact_manager.get_contact(&pubkey).expect("contact exists");
+            println!("✓ Labels for {}: {}", contact.display_name(), contact.labels().join(", "));
+        }
+        ContactCommands::Labels => {
+            let labels = contact_manager.list_labels();
+            if labels.is_empty() {
+                println!("No labels in use.");
+            }
+            for (label, count) in labels {
+                println!("  {} ({})", label, count);
+            }
+        }
+        ContactCommands::Favorite { pubkey, remove } => {
+            if contact_manager.get_contact(&pubkey).is_none() {
+                anyhow::bail!("Unknown contact {}", pubkey);
+            }
+            contact_manager.set_favorite(&pubkey, !remove);
             save_contact_manager(config_dir, &contact_manager)?;
-            println!("✓ Contact {} is now allowed", pubkey);
+            println!("✓ {} {} favorites", pubkey, if remove { "removed from" } else { "added to" });
+        }
+        ContactCommands::Export { path } => {
+            std::fs::write(&path, vcard::export(&contact_manager))?;
+            println!("✓ Exported {} contacts to {}", contact_manager.list_contacts().len(), path.display());
         }
-        ContactCommands::Block { pubkey } => {
-            contact_manager.block_contact(pubkey.clone())?;
+        ContactCommands::Import { path } => {
+            let (cards, skipped) = vcard::parse(&std::fs::read_to_string(&path)?)?;
+            let added = vcard::import(&mut contact_manager, &cards)?;
             save_contact_manager(config_dir, &contact_manager)?;
-            println!("✓ Contact {} is now blocked", pubkey);
+            println!("✓ Imported {} contacts ({} new)", cards.len(), added);
+            if skipped > 0 {
+                println!("  Skipped {} cards without a nano-messenger public key", skipped);
+            }
         }
         ContactCommands::Edit { pubkey, nickname, memo } => {
             contact_manager.update_metadata(&pubkey, nickname.clone(), memo.clone())?;
             save_contact_manager(config_dir, &contact_manager)?;
             
-            println!("✓ Updated contact metadata for {}", pubkey);
-            if let Some(nickname) = nickname {
-                println!("  Nickname: {}", nickname);
+            println!("✓ Updated contact metadata for {}", pubkey);
+            if let Some(nickname) = nickname {
+                println!("  Nickname: {}", nickname);
+            }
+            if let Some(memo) = memo {
+                println!("  Memo: {}", memo);
+            }
+        }
+        ContactCommands::Privacy { pubkey, delivery_receipts, read_receipts, typing } => {
+            let settings = contact_manager.privacy(&pubkey).updated(delivery_receipts, read_receipts, typing);
+            if delivery_receipts.is_some() || read_receipts.is_some() || typing.is_some() {
+                contact_manager.set_privacy(pubkey.clone(), settings);
+                save_contact_manager(config_dir, &contact_manager)?;
+                println!("✓ Updated privacy settings for {}", pubkey);
+            } else {
+                println!("Privacy settings for {}:", pubkey);
+            }
+            
+            let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+            println!("  Delivery receipts: {}", on_off(settings.delivery_receipts));
+            println!("  Read receipts:     {}", on_off(settings.read_receipts));
+            println!("  Typing indicators: {}", on_off(settings.typing_indicators));
+        }
+        ContactCommands::Remove { pubkey } => {
+            contact_manager.remove_contact(&pubkey);
+            save_contact_manager(config_dir, &contact_manager)?;
+            println!("✓ Removed contact {}", pubkey);
+        }
+    }
+    
+    Ok(())
+}
+
+async fn handle_group_command(config_dir: &Path, relays: &[String], command: GroupCommands) -> Result<()> {
+    let keypair = load_keypair(config_dir)?;
+    let mut pool = RelayPool::new(relays.to_vec());
+    let mut group_manager = load_group_manager(config_dir, &keypair)?;
+    let padding = load_security_preferences(config_dir)?.padding;
+    
+    match command {
+        GroupCommands::Create { name, members } => {
+            let mut others = Vec::new();
+            for member in &members {
+                others.push(resolve_new_group_member(config_dir, &mut pool, &keypair, member).await?);
+            }
+            
+            let group = GroupState::create(name.clone(), GroupMember::new(keypair.public_keys()), others);
+            let welcome = group.welcome();
+            for member in group.other_members() {
+                send_group_control(&mut pool, &keypair, padding, member, &welcome).await?;
+            }
+            
+            println!("✓ Created group '{}' ({}) with {} members", name, group.group_id, group.members.len());
+            group_manager.insert(group);
+        }
+        GroupCommands::Add { group, member } => {
+            let new_member = resolve_new_group_member(config_dir, &mut pool, &keypair, &member).await?;
+            let state = group_manager.find_mut(&group)
+                .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))?;
+            
+            state.add_member(new_member)?;
+            let welcome = state.welcome();
+            for member in state.other_members() {
+                send_group_control(&mut pool, &keypair, padding, member, &welcome).await?;
+            }
+            
+            println!("✓ Added {} to '{}'", member, state.name);
+        }
+        GroupCommands::Remove { group, member } => {
+            let pubkey = resolve_group_member(&mut pool, &member).await?.pubkey;
+            let state = group_manager.find_mut(&group)
+                .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))?;
+            
+            // Removal rotates the group secret; only remaining members get the new one
+            state.remove_member(&pubkey)?;
+            let welcome = state.welcome();
+            for member in state.other_members() {
+                send_group_control(&mut pool, &keypair, padding, member, &welcome).await?;
+            }
+            
+            println!("✓ Removed {} from '{}' and rekeyed (epoch {})", member, state.name, state.epoch);
+        }
+        GroupCommands::Send { group, message, reply_to } => {
+            let state = group_manager.find_mut(&group)
+                .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))?;
+            
+            let mut message_store = load_message_store(config_dir, &keypair)?;
+            let contact_manager = load_contact_manager(config_dir)?;
+            let message = compose_message(&message_store, &contact_manager, &message, reply_to.as_deref())?;
+            deliver_group_message(&mut pool, &keypair, padding, state, &mut message_store, message.into()).await?;
+            
+            println!("✓ Message sent to group '{}' ({} members)", state.name, state.members.len());
+        }
+        GroupCommands::Disappearing { group, timer, after_read } => {
+            let state = group_manager.find_mut(&group)
+                .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))?;
+            
+            let mut message_store = load_message_store(config_dir, &keypair)?;
+            match timer {
+                Some(timer) => {
+                    let timer = DisappearingTimer::parse(&timer, after_read)?;
+                    deliver_group_timer(&mut pool, &keypair, padding, state, &mut message_store, timer).await?;
+                    println!("✓ {} in '{}'", timer.notice_text(), state.name);
+                }
+                None => {
+                    let timer = message_store.timer(&group_conversation_id(&state.group_id));
+                    println!("⏱ Disappearing messages in '{}': {}", state.name, timer);
+                }
+            }
+        }
+        GroupCommands::List => {
+            let groups = group_manager.list();
+            if groups.is_empty() {
+                println!("No groups found.");
+            } else {
+                println!("Groups ({}):", groups.len());
+                for group in groups {
+                    println!(
+                        "  {} - {} ({} members, epoch {}{})",
+                        group.name,
+                        group.group_id,
+                        group.members.len(),
+                        group.epoch,
+                        if group.is_admin() { ", admin" } else { "" }
+                    );
+                }
+            }
+        }
+    }
+    
+    save_group_manager(config_dir, &keypair, &group_manager)?;
+    Ok(())
+}
+
+/// Encrypt a message under our sender key for the group's current epoch and store it
+async fn deliver_group_message(
+    pool: &mut RelayPool,
+    keypair: &UserKeyPair,
+    padding: PaddingScheme,
+    state: &mut GroupState,
+    message_store: &mut MessageStore,
+    message: Outgoing,
+) -> Result<()> {
+    deliver_group_payload(pool, keypair, padding, state, message_store, message).await
+}
+
+/// Edit, delete or react to a group message, for us and every member
+async fn deliver_group_change(
+    pool: &mut RelayPool,
+    keypair: &UserKeyPair,
+    padding: PaddingScheme,
+    state: &mut GroupState,
+    message_store: &mut MessageStore,
+    content: MessageContent,
+) -> Result<()> {
+    deliver_group_payload(pool, keypair, padding, state, message_store, Outgoing::Change(content)).await
+}
+
+/// Set the disappearing-message timer of a group, for us and every member
+async fn deliver_group_timer(
+    pool: &mut RelayPool,
+    keypair: &UserKeyPair,
+    padding: PaddingScheme,
+    state: &mut GroupState,
+    message_store: &mut MessageStore,
+    timer: DisappearingTimer,
+) -> Result<()> {
+    deliver_group_payload(pool, keypair, padding, state, message_store, Outgoing::Timer(timer)).await
+}
+
+async fn deliver_group_payload(
+    pool: &mut RelayPool,
+    keypair: &UserKeyPair,
+    padding: PaddingScheme,
+    state: &mut GroupState,
+    message_store: &mut MessageStore,
+    outgoing: Outgoing,
+) -> Result<()> {
+    let mut payload = outgoing.payload(keypair.public_key_string(), Some(state.group_id.clone()));
+    
+    // Changes apply here first, so one the members would ignore is never sent
+    if let Some(content) = &payload.content {
+        let signed_at = chrono::DateTime::from_timestamp(payload.timestamp, 0).unwrap_or_else(Utc::now);
+        let conversation_id = group_conversation_id(&state.group_id);
+        if message_store.apply_content(&conversation_id, &payload.from_pubkey, signed_at, content)?.is_none() {
+            anyhow::bail!("Nothing to change: the message is unknown, deleted, or was changed within the last second");
+        }
+    }
+    
+    // Hand our sender key to anyone who does not have it for this epoch yet
+    let sender_key = state.sender_key_message();
+    for member in state.pending_distribution() {
+        send_group_control(pool, keypair, padding, &member, &sender_key).await?;
+        state.mark_distributed(&member.pubkey);
+    }
+    
+    payload.sign(&keypair.signing_key)?;
+    
+    let padded = padding.pad(payload.to_json()?.as_bytes())?;
+    let envelope = MessageEnvelope::new(state.inbox_id(), state.encrypt(&padded)?);
+    let envelope_nonce = envelope.nonce.clone();
+    let relays = pool.relays().to_vec();
+    pool.request(&relays, async |client: &RelayClient| client.send_envelope(envelope.clone()).await).await?;
+    if payload.content.is_some() {
+        return Ok(());
+    }
+    
+    let mut stored_msg = StoredMessage::from_payload(payload, state.group_id.clone(), Utc::now(), true);
+    stored_msg.conversation_id = group_conversation_id(&state.group_id);
+    stored_msg.id = format!("{}:{}", stored_msg.conversation_id, envelope_nonce);
+    message_store.store_message(stored_msg)?;
+    
+    Ok(())
+}
+
+fn group_conversation_id(group_id: &str) -> String {
+    format!("group:{}", group_id)
+}
+
+async fn resolve_group_member(pool: &mut RelayPool, member: &str) -> Result<GroupMember> {
+    let relays = pool.relays().to_vec();
+    let claim = lookup_claim(pool, &relays, member).await?
+        .ok_or_else(|| anyhow::anyhow!("Could not find public keys for {}", member))?;
+    Ok(GroupMember::new(claim.public_keys))
+}
+
+/// Resolve a member joining a group, checking their keys against the pin like a direct contact's
+async fn resolve_new_group_member(config_dir: &Path, pool: &mut RelayPool, keypair: &UserKeyPair, member: &str) -> Result<GroupMember> {
+    let resolved = resolve_group_member(pool, member).await?;
+    let mut trust = load_trust_store(config_dir)?;
+    let mut message_store = load_message_store(config_dir, keypair)?;
+    let identity = identity_pubkey(keypair, load_device_certificate(config_dir)?.as_ref());
+    if let Some(warning) = check_pinned_keys(config_dir, &mut trust, &mut message_store, &identity, member, &resolved.public_keys)? {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(resolved)
+}
+
+/// Send a group control message pairwise to one member's first-contact inbox
+async fn send_group_control(
+    pool: &mut RelayPool,
+    keypair: &UserKeyPair,
+    padding: PaddingScheme,
+    member: &GroupMember,
+    control: &GroupControl,
+) -> Result<()> {
+    let mut payload = MessagePayload::new(
+        keypair.public_key_string(),
+        control.to_json()?,
+        0,
+        Some(control.group_id().to_string()),
+    );
+    payload.sign(&keypair.signing_key)?;
+    
+    let padded = padding.pad(payload.to_json()?.as_bytes())?;
+    let encrypted = encrypt_asymmetric(&member.public_keys.x25519_key, &padded)?;
+    let inbox_id = derive_first_contact_inbox(&member.public_keys.x25519_key);
+    
+    let envelope = MessageEnvelope::new(inbox_id, encrypted);
+    let relays = pool.relays().to_vec();
+    pool.request(&relays, async |client: &RelayClient| client.send_envelope(envelope.clone()).await).await?;
+    Ok(())
+}
+
+fn process_group_message(
+    envelope: &MessageEnvelope,
+    group: &mut GroupState,
+    message_store: &mut MessageStore,
+    deferred: &mut Deferred,
+) -> Result<Option<StoredMessage>> {
+    let encrypted_payload = envelope.decode_payload()?;
+    let Some((sender, padded)) = group.decrypt(&encrypted_payload)? else {
+        return Ok(None); // Our own message
+    };
+    
+    let payload = MessagePayload::from_json(&String::from_utf8(unpad(&padded)?)?)?;
+    payload.verify_signature()?;
+    if payload.from_pubkey != sender || payload.room.as_deref() != Some(group.group_id.as_str()) {
+        anyhow::bail!("Group message sender mismatch");
+    }
+    FreshnessWindow::default().check(payload.timestamp, Utc::now().timestamp())?;
+    
+    if let Some(content) = payload.content {
+        deferred.changes.push(IncomingChange {
+            conversation_id: group_conversation_id(&group.group_id),
+            from_pubkey: payload.from_pubkey,
+            sent_at: payload.timestamp,
+            content,
+        });
+        return Ok(None);
+    }
+    
+    let mut stored_msg = StoredMessage::from_payload(payload, group.me.clone(), Utc::now(), false);
+    stored_msg.conversation_id = group_conversation_id(&group.group_id);
+    stored_msg.id = format!("{}:{}", stored_msg.conversation_id, envelope.nonce);
+    if !message_store.store_message(stored_msg.clone())? {
+        return Ok(None);
+    }
+    
+    Ok(message_store.get_message(&stored_msg.id)?)
+}
+
+async fn handle_device_command(config_dir: &Path, relays: &[String], command: DeviceCommands) -> Result<()> {
+    let mut pool = RelayPool::new(relays.to_vec());
+    
+    match command {
+        DeviceCommands::Link { name } => {
+            if !config_dir.join("keys.json").exists() {
+                init_user(config_dir, CryptoMode::Classical)?;
+            }
+            if let Some(certificate) = load_device_certificate(config_dir)? {
+                anyhow::bail!("Already linked to {}", certificate.identity);
+            }
+            
+            let keypair = load_keypair(config_dir)?;
+            let request = PairingRequest { name, public_keys: keypair.public_keys() };
+            println!("📱 Device ID: {}", device_id(&request.public_keys));
+            println!("Run this on your primary device:");
+            println!("  nano-client device approve {}", request.to_code()?);
+        }
+        DeviceCommands::Approve { code } => {
+            let keypair = load_keypair(config_dir)?;
+            if load_device_certificate(config_dir)?.is_some() {
+                anyhow::bail!("Only the primary device can approve new devices");
+            }
+            let identity = keypair.public_key_string();
+            let request = PairingRequest::from_code(&code)?;
+            
+            let mut contact_manager = load_contact_manager(config_dir)?;
+            refresh_device_list(&mut pool, relays, &mut contact_manager, &identity).await;
+            let mut list = match contact_manager.get_device_list(&identity) {
+                Some(list) => list.clone(),
+                None => DeviceList::new(&keypair)?,
+            };
+            
+            let certificate = DeviceCertificate::issue(&keypair, request.name, request.public_keys)?;
+            list.add(&keypair, certificate.clone())?;
+            publish_device_list(relays, &list).await?;
+            contact_manager.update_device_list(list.clone())?;
+            save_contact_manager(config_dir, &contact_manager)?;
+            
+            println!("✓ Approved device '{}' ({})", certificate.name, certificate.device_id);
+            println!("Run this on the new device:");
+            let response = PairingResponse { certificate, device_list: list };
+            println!("  nano-client device finish {}", response.to_code()?);
+        }
+        DeviceCommands::Finish { code } => {
+            let keypair = load_keypair(config_dir)?;
+            let response = PairingResponse::from_code(&code)?;
+            response.certificate.verify()?;
+            response.device_list.verify()?;
+            
+            let own_id = device_id(&keypair.public_keys());
+            if response.certificate.device_id != own_id || response.device_list.device(&own_id).is_none() {
+                anyhow::bail!("Pairing response is for a different device");
+            }
+            if response.certificate.identity != response.device_list.identity {
+                anyhow::bail!("Pairing response certificate and device list disagree");
+            }
+            
+            let mut contact_manager = load_contact_manager(config_dir)?;
+            contact_manager.update_device_list(response.device_list)?;
+            save_contact_manager(config_dir, &contact_manager)?;
+            
+            let certificate_file = config_dir.join("device_certificate.json");
+            std::fs::write(&certificate_file, serde_json::to_string_pretty(&response.certificate)?)?;
+            
+            println!("✓ Linked as '{}' to {}", response.certificate.name, response.certificate.identity);
+        }
+        DeviceCommands::List => {
+            let keypair = load_keypair(config_dir)?;
+            let identity = identity_pubkey(&keypair, load_device_certificate(config_dir)?.as_ref());
+            let own_id = device_id(&keypair.public_keys());
+            
+            let mut contact_manager = load_contact_manager(config_dir)?;
+            refresh_device_list(&mut pool, relays, &mut contact_manager, &identity).await;
+            save_contact_manager(config_dir, &contact_manager)?;
+            
+            let Some(list) = contact_manager.get_device_list(&identity) else {
+                println!("(No linked devices)");
+                return Ok(());
+            };
+            
+            println!("📱 Devices for {} (v{}):", identity, list.version);
+            for device in &list.devices {
+                let marker = if device.device_id == own_id { " (this device)" } else { "" };
+                println!("  {} {}{}", device.device_id, device.name, marker);
             }
-            if let Some(memo) = memo {
-                println!("  Memo: {}", memo);
+            for revoked in &list.revoked {
+                println!("  {} revoked", revoked);
             }
         }
-        ContactCommands::Remove { pubkey } => {
-            contact_manager.remove_contact(&pubkey);
+        DeviceCommands::Revoke { device_id } => {
+            let keypair = load_keypair(config_dir)?;
+            if load_device_certificate(config_dir)?.is_some() {
+                anyhow::bail!("Only the primary device can revoke devices");
+            }
+            let identity = keypair.public_key_string();
+            
+            let mut contact_manager = load_contact_manager(config_dir)?;
+            refresh_device_list(&mut pool, relays, &mut contact_manager, &identity).await;
+            let mut list = contact_manager.get_device_list(&identity).cloned()
+                .ok_or_else(|| anyhow::anyhow!("No linked devices"))?;
+            
+            list.revoke(&keypair, &device_id)?;
+            publish_device_list(relays, &list).await?;
+            contact_manager.update_device_list(list)?;
             save_contact_manager(config_dir, &contact_manager)?;
-            println!("✓ Removed contact {}", pubkey);
+            
+            println!("✓ Revoked device {}", device_id);
         }
     }
     
     Ok(())
 }
 
-fn show_user_info(config_dir: &PathBuf) -> Result<()> {
+fn show_user_info(config_dir: &Path) -> Result<()> {
     let keypair = load_keypair(config_dir)?;
     let public_keys = keypair.public_keys();
     let security_prefs = load_security_preferences(config_dir)?;
     
     println!("👤 User Information:");
     println!("   🔑 Public Key: {}", keypair.public_key_string());
+    if let Some(certificate) = load_device_certificate(config_dir)? {
+        println!("   📱 Linked device '{}' ({}) of {}", certificate.name, certificate.device_id, certificate.identity);
+    }
     println!("   🔐 Ed25519 Public Key: {}", general_purpose::STANDARD.encode(&public_keys.verifying_key.to_bytes()));
     println!("   🔐 X25519 Public Key: {}", general_purpose::STANDARD.encode(&public_keys.x25519_key.to_bytes()));
     
@@ -701,18 +3072,112 @@ fn show_user_info(config_dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn load_keypair(config_dir: &PathBuf) -> Result<UserKeyPair> {
-    let keys_file = config_dir.join("keys.json");
+/// Passphrase from `NANO_PASSPHRASE`, or asked for on the terminal
+fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var("NANO_PASSPHRASE") {
+        return Ok(passphrase);
+    }
     
+    let passphrase = rpassword::prompt_password("Archive passphrase: ")?;
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        anyhow::bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+fn export_account(config_dir: &Path, path: &Path) -> Result<()> {
+    let keys_file = config_dir.join("keys.json");
     if !keys_file.exists() {
         anyhow::bail!("User not initialized. Run 'nano-client init' first.");
     }
+    let keypair = load_keypair(config_dir)?;
+    let device_certificate = load_device_certificate(config_dir)?;
+    let messages = load_message_store(config_dir, &keypair)?.export_messages()?;
+    let contact_manager = load_contact_manager(config_dir)?;
+    
+    let archive = AccountArchive {
+        version: ARCHIVE_VERSION,
+        created_at: Utc::now().timestamp(),
+        identity: identity_pubkey(&keypair, device_certificate.as_ref()),
+        keys: serde_json::from_str(&std::fs::read_to_string(&keys_file)?)?,
+        device_certificate,
+        contacts: contacts_json(&contact_manager),
+        conversations: load_conversation_manager(config_dir, &keypair)?,
+        messages,
+        groups: load_group_manager(config_dir, &keypair)?,
+        relays: load_relays(config_dir)?,
+        security: serde_json::to_value(load_security_preferences(config_dir)?)?,
+        trust: load_trust_store(config_dir)?,
+        requests: load_request_queue(config_dir, &keypair)?,
+    };
+    let sealed = archive.seal(&read_passphrase(true)?)?;
+    
+    // Never overwrite an older backup, and keep the archive private
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Cannot create {}: {}", path.display(), e))?;
+    file.write_all(&sealed)?;
+    
+    println!("✓ Exported {} ({} messages, {} contacts) to {}",
+             archive.identity, archive.messages.len(), contact_manager.list_contacts().len(), path.display());
+    println!("⚠️  Anyone with this file and its passphrase can act as you; store it safely");
+    Ok(())
+}
+
+fn import_account(config_dir: &Path, path: &Path) -> Result<()> {
+    if config_dir.join("keys.json").exists() {
+        anyhow::bail!("{} already holds an account; import into an empty --config-dir", config_dir.display());
+    }
+    
+    let archive = AccountArchive::open(&std::fs::read(path)?, &read_passphrase(false)?)?;
+    
+    // Check everything parses before writing anything
+    let keypair = keypair_from_json(&archive.keys)?;
+    if identity_pubkey(&keypair, archive.device_certificate.as_ref()) != archive.identity {
+        anyhow::bail!("Archive keys do not match its identity {}", archive.identity);
+    }
+    let contact_manager = contact_manager_from_json(&archive.contacts)?;
+    let security_prefs: SecurityPreferences = serde_json::from_value(archive.security)?;
+    
+    save_security_preferences(config_dir, &security_prefs)?;
+    save_relays(config_dir, &archive.relays)?;
+    save_group_manager(config_dir, &keypair, &archive.groups)?;
+    save_trust_store(config_dir, &archive.trust)?;
+    save_contact_manager(config_dir, &contact_manager)?;
+    save_conversation_manager(config_dir, &keypair, &archive.conversations)?;
+    save_request_queue(config_dir, &keypair, &archive.requests)?;
+    let message_count = archive.messages.len();
+    load_message_store(config_dir, &keypair)?.import_messages(archive.messages)?;
+    if let Some(certificate) = &archive.device_certificate {
+        std::fs::write(config_dir.join("device_certificate.json"), serde_json::to_string_pretty(certificate)?)?;
+    }
+    // Keys last, so a failed import leaves a directory that is still not initialized
+    std::fs::write(config_dir.join("keys.json"), serde_json::to_string_pretty(&archive.keys)?)?;
+    
+    let exported_at = DateTime::<Utc>::from_timestamp(archive.created_at, 0).unwrap_or_default();
+    println!("✓ Imported {} with {} messages (exported {})",
+             archive.identity, message_count, exported_at.format("%Y-%m-%d %H:%M"));
+    Ok(())
+}
+
+fn load_keypair(config_dir: &Path) -> Result<UserKeyPair> {
+    let keys_file = config_dir.join("keys.json");
     
-    let keys_data: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&keys_file)?)?;
+    if !keys_file.exists() {
+        anyhow::bail!("User not initialized. Run 'nano-client init' first.");
+    }
     
-    let signing_bytes = general_purpose::STANDARD.decode(keys_data["signing_key"].as_str().unwrap())
+    keypair_from_json(&serde_json::from_str(&std::fs::read_to_string(&keys_file)?)?)
+}
+
+/// Keypair from the contents of `keys.json`
+fn keypair_from_json(keys_data: &serde_json::Value) -> Result<UserKeyPair> {
+    let signing_bytes = general_purpose::STANDARD.decode(keys_data["signing_key"].as_str().unwrap_or_default())
         .map_err(|e| anyhow::anyhow!("Base64 decode error: {}", e))?;
-    let x25519_bytes = general_purpose::STANDARD.decode(keys_data["x25519_key"].as_str().unwrap())
+    let x25519_bytes = general_purpose::STANDARD.decode(keys_data["x25519_key"].as_str().unwrap_or_default())
         .map_err(|e| anyhow::anyhow!("Base64 decode error: {}", e))?;
     
     // Fix: Handle the Result from from_bytes properly
@@ -730,15 +3195,76 @@ fn load_keypair(config_dir: &PathBuf) -> Result<UserKeyPair> {
     })
 }
 
-fn load_contact_manager(config_dir: &PathBuf) -> Result<ContactManager> {
+/// Certificate issued by the primary device, present only on linked devices
+fn load_device_certificate(config_dir: &Path) -> Result<Option<DeviceCertificate>> {
+    let certificate_file = config_dir.join("device_certificate.json");
+    
+    if !certificate_file.exists() {
+        return Ok(None);
+    }
+    
+    Ok(Some(serde_json::from_str(&std::fs::read_to_string(&certificate_file)?)?))
+}
+
+/// Public key of the identity this device speaks for
+fn identity_pubkey(keypair: &UserKeyPair, certificate: Option<&DeviceCertificate>) -> String {
+    match certificate {
+        Some(certificate) => certificate.identity.clone(),
+        None => keypair.public_key_string(),
+    }
+}
+
+/// Publish our device list on every relay, so contacts find it wherever they look
+async fn publish_device_list(relays: &[String], list: &DeviceList) -> Result<()> {
+    let mut published = 0;
+    for relay in relays {
+        match RelayClient::new(relay.clone()).publish_device_list(list.clone()).await {
+            Ok(()) => published += 1,
+            Err(e) => eprintln!("Warning: Could not publish device list to {}: {}", relay, e),
+        }
+    }
+    if published == 0 {
+        anyhow::bail!("Device list was not published on any relay");
+    }
+    Ok(())
+}
+
+/// Fetch an identity's latest device list; lookup failures keep the known list
+async fn refresh_device_list(pool: &mut RelayPool, relays: &[String], contact_manager: &mut ContactManager, identity: &str) {
+    if let Err(e) = try_refresh_device_list(pool, relays, contact_manager, identity).await {
+        eprintln!("Warning: {}", e);
+    }
+}
+
+/// Fetch an identity's latest device list from the first reachable of `relays`, reporting why the known list was kept
+async fn try_refresh_device_list(
+    pool: &mut RelayPool,
+    relays: &[String],
+    contact_manager: &mut ContactManager,
+    identity: &str,
+) -> Result<()> {
+    match pool.request(relays, async |client: &RelayClient| client.lookup_devices(identity.to_string()).await).await {
+        Ok(Some(list)) => contact_manager
+            .update_device_list(list)
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("Ignoring invalid device list for {}: {}", identity, e)),
+        Ok(None) => Ok(()),
+        Err(e) => anyhow::bail!("Could not look up devices for {}: {}", identity, e),
+    }
+}
+
+fn load_contact_manager(config_dir: &Path) -> Result<ContactManager> {
     let contacts_file = config_dir.join("contacts.json");
     
     if !contacts_file.exists() {
         return Ok(ContactManager::new());
     }
     
-    let data: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&contacts_file)?)?;
-    
+    contact_manager_from_json(&serde_json::from_str(&std::fs::read_to_string(&contacts_file)?)?)
+}
+
+/// Contacts from the contents of `contacts.json`
+fn contact_manager_from_json(data: &serde_json::Value) -> Result<ContactManager> {
     let mut manager = ContactManager::new();
     
     // Load permissions
@@ -755,131 +3281,384 @@ fn load_contact_manager(config_dir: &PathBuf) -> Result<ContactManager> {
         manager.import_metadata(metadata);
     }
     
+    // Load linked device lists
+    if let Some(devices) = data.get("devices") {
+        let devices: std::collections::HashMap<String, DeviceList> = 
+            serde_json::from_value(devices.clone())?;
+        manager.import_device_lists(devices);
+    }
+    
+    // Load cached keys and usernames, used to send while the relay is unreachable
+    if let Some(keys) = data.get("keys") {
+        let keys: std::collections::HashMap<String, UserPublicKeys> = 
+            serde_json::from_value(keys.clone())?;
+        manager.import_public_keys(keys);
+    }
+    if let Some(usernames) = data.get("usernames") {
+        let usernames: std::collections::HashMap<String, String> = 
+            serde_json::from_value(usernames.clone())?;
+        manager.import_usernames(usernames);
+    }
+    if let Some(relays) = data.get("relays") {
+        let relays: std::collections::HashMap<String, Vec<String>> = 
+            serde_json::from_value(relays.clone())?;
+        manager.import_relays(relays);
+    }
+    if let Some(privacy) = data.get("privacy") {
+        let privacy: std::collections::HashMap<String, PrivacySettings> = 
+            serde_json::from_value(privacy.clone())?;
+        manager.import_privacy(privacy);
+    }
+    
     Ok(manager)
 }
 
-fn save_contact_manager(config_dir: &PathBuf, manager: &ContactManager) -> Result<()> {
+fn save_contact_manager(config_dir: &Path, manager: &ContactManager) -> Result<()> {
     let contacts_file = config_dir.join("contacts.json");
-    
-    let data = serde_json::json!({
-        "permissions": manager.get_permissions(),
-        "metadata": manager.export_metadata()
-    });
-    
-    std::fs::write(&contacts_file, serde_json::to_string_pretty(&data)?)?;
-    
+    std::fs::write(&contacts_file, serde_json::to_string_pretty(&contacts_json(manager))?)?;
     Ok(())
 }
 
-fn load_conversation_manager(config_dir: &PathBuf) -> Result<ConversationManager> {
-    let conversations_file = config_dir.join("conversations.json");
+/// Contents of `contacts.json`
+fn contacts_json(manager: &ContactManager) -> serde_json::Value {
+    serde_json::json!({
+        "permissions": manager.get_permissions(),
+        "metadata": manager.export_metadata(),
+        "devices": manager.export_device_lists(),
+        "keys": manager.export_public_keys(),
+        "usernames": manager.export_usernames(),
+        "relays": manager.export_relays(),
+        "privacy": manager.export_privacy()
+    })
+}
+
+fn load_conversation_manager(config_dir: &Path, keypair: &UserKeyPair) -> Result<ConversationManager> {
+    let conversations_file = config_dir.join("conversations.enc");
     
+    // Older clients never wrote conversation state, so there is nothing to migrate
     if !conversations_file.exists() {
         return Ok(ConversationManager::new());
     }
     
-    // For now, return empty manager - conversation state will be rebuilt as needed
-    Ok(ConversationManager::new())
+    let storage_key = derive_storage_key(&keypair.x25519_key, "conversations");
+    ConversationManager::from_encrypted(&storage_key, &std::fs::read(&conversations_file)?)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", conversations_file.display(), e))
 }
 
-fn save_conversation_manager(_config_dir: &PathBuf, _manager: &ConversationManager) -> Result<()> {
-    // For now, we don't persist conversation state
-    // It can be rebuilt from public keys when needed
+fn save_conversation_manager(config_dir: &Path, keypair: &UserKeyPair, manager: &ConversationManager) -> Result<()> {
+    let conversations_file = config_dir.join("conversations.enc");
+    let storage_key = derive_storage_key(&keypair.x25519_key, "conversations");
+    
+    // Write then rename so a crash never leaves a truncated store behind
+    let tmp_file = config_dir.join("conversations.enc.tmp");
+    std::fs::write(&tmp_file, manager.to_encrypted(&storage_key)?)?;
+    std::fs::rename(&tmp_file, &conversations_file)?;
+    
     Ok(())
 }
 
-fn load_message_store(config_dir: &PathBuf) -> Result<MessageStore> {
-    let messages_file = config_dir.join("messages.json");
+fn load_group_manager(config_dir: &Path, keypair: &UserKeyPair) -> Result<GroupManager> {
+    let groups_file = config_dir.join("groups.enc");
+    let storage_key = derive_storage_key(&keypair.x25519_key, "groups");
+    
+    if groups_file.exists() {
+        return GroupManager::from_encrypted(&storage_key, &std::fs::read(&groups_file)?)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", groups_file.display(), e));
+    }
     
-    if !messages_file.exists() {
-        return Ok(MessageStore::new());
+    // Older clients kept group secrets in plaintext; move them into the encrypted store
+    let legacy_file = config_dir.join("groups.json");
+    if !legacy_file.exists() {
+        return Ok(GroupManager::new());
     }
+    let manager: GroupManager = serde_json::from_str(&std::fs::read_to_string(&legacy_file)?)?;
+    save_group_manager(config_dir, keypair, &manager)?;
+    std::fs::remove_file(&legacy_file)?;
+    Ok(manager)
+}
+
+fn save_group_manager(config_dir: &Path, keypair: &UserKeyPair, manager: &GroupManager) -> Result<()> {
+    let groups_file = config_dir.join("groups.enc");
+    let storage_key = derive_storage_key(&keypair.x25519_key, "groups");
     
-    let data: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&messages_file)?)?;
+    let tmp_file = config_dir.join("groups.enc.tmp");
+    std::fs::write(&tmp_file, manager.to_encrypted(&storage_key)?)?;
+    std::fs::rename(&tmp_file, &groups_file)?;
     
-    let mut store = MessageStore::new();
+    Ok(())
+}
+
+fn load_trust_store(config_dir: &Path) -> Result<TrustStore> {
+    let trust_file = config_dir.join("trust.json");
     
-    if let Some(messages) = data.get("messages") {
-        let messages: HashMap<String, StoredMessage> = serde_json::from_value(messages.clone())?;
-        store.import_messages(messages)?;
+    if !trust_file.exists() {
+        return Ok(TrustStore::new());
     }
     
-    Ok(store)
+    Ok(serde_json::from_str(&std::fs::read_to_string(&trust_file)?)?)
 }
 
-fn save_message_store(config_dir: &PathBuf, store: &MessageStore) -> Result<()> {
-    let messages_file = config_dir.join("messages.json");
+fn save_trust_store(config_dir: &Path, trust: &TrustStore) -> Result<()> {
+    let trust_file = config_dir.join("trust.json");
+    std::fs::write(&trust_file, serde_json::to_string_pretty(trust)?)?;
+    Ok(())
+}
+
+fn load_request_queue(config_dir: &Path, keypair: &UserKeyPair) -> Result<RequestQueue> {
+    let requests_file = config_dir.join("requests.enc");
+
+    if !requests_file.exists() {
+        return Ok(RequestQueue::new());
+    }
+
+    let storage_key = derive_storage_key(&keypair.x25519_key, "requests");
+    RequestQueue::from_encrypted(&storage_key, &std::fs::read(&requests_file)?)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", requests_file.display(), e))
+}
+
+fn save_request_queue(config_dir: &Path, keypair: &UserKeyPair, requests: &RequestQueue) -> Result<()> {
+    let requests_file = config_dir.join("requests.enc");
+    let storage_key = derive_storage_key(&keypair.x25519_key, "requests");
+
+    let tmp_file = config_dir.join("requests.enc.tmp");
+    std::fs::write(&tmp_file, requests.to_encrypted(&storage_key)?)?;
+    std::fs::rename(&tmp_file, &requests_file)?;
+
+    Ok(())
+}
+
+fn load_message_store(config_dir: &Path, keypair: &UserKeyPair) -> Result<MessageStore> {
+    let storage_key = derive_storage_key(&keypair.x25519_key, "messages");
+    let mut store = MessageStore::open(&config_dir.join("messages.redb"), storage_key)?;
     
-    let data = serde_json::json!({
-        "messages": store.export_messages()
-    });
+    // Move messages written by older clients into the database, then delete the
+    // plaintext file once the import has committed
+    let messages_file = config_dir.join("messages.json");
+    if messages_file.exists() {
+        let data: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&messages_file)?)?;
+        
+        if let Some(messages) = data.get("messages") {
+            let messages: HashMap<String, StoredMessage> = serde_json::from_value(messages.clone())?;
+            store.import_messages(messages)?;
+        }
+        
+        std::fs::remove_file(&messages_file)?;
+    }
     
-    std::fs::write(&messages_file, serde_json::to_string_pretty(&data)?)?;
+    // Plaintext copies an earlier migration set aside
+    let migrated_file = config_dir.join("messages.json.migrated");
+    if migrated_file.exists() {
+        std::fs::remove_file(&migrated_file)?;
+    }
     
-    Ok(())
+    Ok(store)
 }
 
 fn process_first_contact_message(
     envelope: &MessageEnvelope,
-    keypair: &UserKeyPair,
-    _contact_manager: &mut ContactManager,
-    message_store: &mut MessageStore,
-) -> Result<bool> {
+    session: &mut ClientSession,
+    deferred: &mut Deferred,
+) -> Result<Option<StoredMessage>> {
+    let ClientSession { keypair, identity, contact_manager, message_store, group_manager, requests, .. } = session;
+    let (keypair, identity) = (&*keypair, identity.as_str());
     // Decrypt the message
     let encrypted_payload = envelope.decode_payload()?;
-    let payload_json = decrypt_asymmetric(&keypair.x25519_key, &encrypted_payload)?;
+    let payload_json = unpad(&decrypt_asymmetric(&keypair.x25519_key, &encrypted_payload)?)?;
     let payload: MessagePayload = MessagePayload::from_json(&String::from_utf8(payload_json)?)?;
     
+    // Blocked senders are dropped before the signature check; a forged sender only loses its own message
+    if contact_manager.is_blocked(&payload.from_pubkey) {
+        return Ok(None);
+    }
+    
     // Verify signature
     payload.verify_signature()?;
     
+    // Reject stale or far-future payloads
+    FreshnessWindow::default().check(payload.timestamp, Utc::now().timestamp())?;
+    
+    // Drop anything signed by a device its identity has since revoked, and hold
+    // messages from devices our copy of the identity's list does not name yet
+    if let Some(device) = &payload.device {
+        if contact_manager.is_device_revoked(&payload.from_pubkey, &device.device_id) {
+            anyhow::bail!("Message from revoked device {}", device.device_id);
+        }
+        let listed = contact_manager.get_device_list(&payload.from_pubkey)
+            .is_some_and(|list| list.device(&device.device_id).is_some_and(|listed| listed.sig == device.sig));
+        if !listed {
+            deferred.device_lists.push(payload.from_pubkey);
+            return Ok(None);
+        }
+    }
+    
+    // Messages sent from our other devices are stored as our own outgoing messages
+    if payload.room.as_deref() == Some(DEVICE_SYNC_ROOM) {
+        if payload.from_pubkey != identity {
+            anyhow::bail!("Device sync message from another identity");
+        }
+        return store_synced_message(identity, &payload, message_store, deferred);
+    }
+    
+    // Strangers' messages wait as requests; their receipts, typing and changes are dropped
+    let direct = payload.room.is_none() || payload.room.as_deref() == Some(RECEIPT_ROOM);
+    if direct && is_message_request(contact_manager, message_store, identity, &payload.from_pubkey)? {
+        if payload.room.is_none() && payload.content.is_none() {
+            let sender = payload.from_pubkey.clone();
+            if requests.add(payload, Utc::now()) {
+                deferred.requests.push(sender);
+            }
+        }
+        return Ok(None);
+    }
+    
+    // Receipts and typing notices are applied once the whole batch is stored
+    if payload.room.as_deref() == Some(RECEIPT_ROOM) {
+        deferred.controls.push(IncomingControl {
+            control: ConversationControl::from_json(&payload.body)?,
+            from_pubkey: payload.from_pubkey,
+            sent_at: payload.timestamp,
+        });
+        return Ok(None);
+    }
+    
+    // Group control messages update group state instead of being shown
+    if payload.room.is_some() {
+        let control = GroupControl::from_json(&payload.body)?;
+        return match group_manager.handle_control(&keypair.public_key_string(), &payload.from_pubkey, control) {
+            Ok(()) => Ok(None),
+            Err(NanoError::ReplayDetected(_)) => Ok(None), // Superseded update, refetched from the relay
+            Err(e) => Err(e.into()),
+        };
+    }
+    
+    // So are changes to earlier messages, which may be in the same batch
+    if let Some(content) = payload.content {
+        deferred.changes.push(IncomingChange {
+            conversation_id: direct_conversation_id(identity, &payload.from_pubkey),
+            from_pubkey: payload.from_pubkey,
+            sent_at: payload.timestamp,
+            content,
+        });
+        return Ok(None);
+    }
+    
     // Check if we've already seen this message
     let _msg_id = format!("{}:{}:{}", payload.from_pubkey, envelope.inbox_id, payload.timestamp);
     
     // Create stored message
     let stored_msg = StoredMessage::from_payload(
         payload.clone(),
-        keypair.public_key_string(),
+        identity.to_string(),
         Utc::now(),
         false, // incoming
     );
     
-    // Check if message already exists
-    let existing_messages = message_store.get_messages_from(&payload.from_pubkey, None);
-    let already_exists = existing_messages.iter().any(|msg| {
-        msg.timestamp.timestamp() == payload.timestamp && msg.counter == payload.counter
-    });
+    if !message_store.store_message(stored_msg.clone())? {
+        return Ok(None);
+    }
     
-    if already_exists {
-        return Ok(false); // Not a new message
+    // Only our primary device answers in conversation inboxes
+    if let Some(key) = payload.conversation_key.filter(|_| payload.device.is_none() && identity == keypair.public_key_string()) {
+        deferred.conversations.push((payload.from_pubkey, key));
     }
     
-    message_store.store_message(stored_msg)?;
+    // As stored, with the expiry its conversation's timer gave it
+    Ok(message_store.get_message(&stored_msg.id)?)
+}
+
+fn store_synced_message(
+    identity: &str,
+    payload: &MessagePayload,
+    message_store: &mut MessageStore,
+    deferred: &mut Deferred,
+) -> Result<Option<StoredMessage>> {
+    let sync = SentMessageSync::from_json(&payload.body)?;
+    if let Some(content) = sync.content {
+        deferred.changes.push(IncomingChange {
+            conversation_id: direct_conversation_id(identity, &sync.to_pubkey),
+            from_pubkey: identity.to_string(),
+            sent_at: sync.timestamp,
+            content,
+        });
+        return Ok(None);
+    }
     
-    // Show the new message with permission prompt
-    println!("\n✉️  New message from unknown sender:");
-    println!("From: {}", payload.from_pubkey);
-    println!("Message: {}", payload.body);
-    println!("\nAllow this sender to continue messaging you? [a]llow / [b]lock / [i]gnore");
+    let id = outgoing_message_id(identity, &sync.to_pubkey, sync.timestamp);
+    if message_store.get_message(&id)?.is_some() {
+        return Ok(None);
+    }
     
-    // For now, just print the prompt - in a real implementation,
-    // this would wait for user input or be handled interactively
+    let timestamp = chrono::DateTime::from_timestamp(sync.timestamp, 0).unwrap_or_else(Utc::now);
+    let stored_msg = StoredMessage {
+        id,
+        from_pubkey: identity.to_string(),
+        to_pubkey: sync.to_pubkey.clone(),
+        content: sync.body,
+        timestamp,
+        received_at: Utc::now(),
+        is_outgoing: true,
+        conversation_id: direct_conversation_id(identity, &sync.to_pubkey),
+        counter: 0,
+        crypto_mode: None,
+        receipt: None,
+        edits: Vec::new(),
+        deleted: false,
+        reactions: Default::default(),
+        timer_change: sync.disappearing,
+        expires_at: None,
+        expires_after_read: None,
+        rich: None,
+        key_change: None,
+    }
+    .with_rich(sync.rich);
+    if !message_store.store_message(stored_msg.clone())? {
+        return Ok(None);
+    }
     
-    Ok(true)
+    Ok(message_store.get_message(&stored_msg.id)?)
 }
 
 // Session 4: Quantum-Safe Messaging Functions
 
-async fn send_quantum_safe_message(
-    config_dir: &PathBuf,
+/// Wrap an envelope for the route `via` + `relay` and hand it to the first hop
+///
+/// Every hop's key must already be pinned; asking the relays for them would let
+/// a malicious relay hand out its own key and reveal the route to the exit.
+async fn send_onion_routed(
     relay: &str,
-    recipient: &str,
-    message: &str,
-    crypto_mode_str: &str,
+    via: &[String],
+    onion_hops: &HashMap<String, OnionHop>,
+    envelope: QuantumSafeEnvelope,
+) -> nano_messenger::error::Result<()> {
+    let path = via.iter().map(String::as_str).chain(std::iter::once(relay))
+        .map(|address| onion_hops.get(address).cloned().ok_or_else(|| {
+            NanoError::Config(format!("No onion key pinned for relay {}; add it with `relays pin`", address))
+        }))
+        .collect::<nano_messenger::error::Result<Vec<_>>>()?;
+    
+    println!("🧅 Onion routing via {} relays", path.len());
+    let packet = OnionPacket::build(&path, envelope)?;
+    RelayClient::new(path[0].address.clone()).send_onion(packet).await
+}
+
+/// A `send` command's message and how to deliver it
+struct SendRequest<'a> {
+    recipient: &'a str,
+    message: &'a str,
+    crypto_mode: &'a str,
     force_post_quantum: bool,
     adaptive: bool,
+    reply_to: Option<&'a str>,
+    via: &'a [String],
+}
+
+async fn send_quantum_safe_message(
+    config_dir: &Path,
+    relays: &[String],
+    request: SendRequest<'_>,
     security_prefs: &SecurityPreferences,
 ) -> Result<()> {
+    let SendRequest { recipient, message, crypto_mode: crypto_mode_str, force_post_quantum, adaptive, reply_to, via } = request;
     // For now, fall back to the existing send_message function
     // In a full implementation, this would use QuantumSafeMessaging
     
@@ -898,10 +3677,10 @@ async fn send_quantum_safe_message(
     }
     
     println!("📨 Sending message to '{}' using {} cryptography via {}...", 
-             recipient, selected_mode, relay);
+             recipient, selected_mode, relays.join(", "));
     
     // For Session 4, we'll enhance the existing send_message with crypto mode info
-    send_message(config_dir, relay, recipient, message).await?;
+    send_message(config_dir, relays, recipient, message, reply_to, via).await?;
     
     println!("✅ Message sent using {} cryptography", selected_mode);
     println!("🔐 Security: {}", selected_mode.security_level());
@@ -930,18 +3709,24 @@ fn determine_adaptive_mode(requested_mode: CryptoMode, prefs: &SecurityPreferenc
     }
 }
 
-fn update_security_preferences(
-    config_dir: &PathBuf,
-    default_mode: Option<&str>,
+/// Settings changed by `set-security`; `None` leaves a setting as it is
+struct SecurityChanges {
+    default_mode: Option<String>,
     adaptive: Option<bool>,
-    minimum_mode: Option<&str>,
+    minimum_mode: Option<String>,
     auto_upgrade: Option<bool>,
-) -> Result<()> {
+    padding: Option<String>,
+    cover_traffic: Option<bool>,
+    cover_interval: Option<f64>,
+}
+
+fn update_security_preferences(config_dir: &Path, update: SecurityChanges) -> Result<()> {
+    let SecurityChanges { default_mode, adaptive, minimum_mode, auto_upgrade, padding, cover_traffic, cover_interval } = update;
     let mut prefs = load_security_preferences(config_dir)?;
     let mut changes = Vec::new();
     
     if let Some(mode_str) = default_mode {
-        let mode = parse_crypto_mode(mode_str)?;
+        let mode = parse_crypto_mode(&mode_str)?;
         prefs.default_crypto_mode = mode;
         changes.push(format!("Default crypto mode: {}", mode));
     }
@@ -952,7 +3737,7 @@ fn update_security_preferences(
     }
     
     if let Some(min_mode_str) = minimum_mode {
-        let min_mode = parse_crypto_mode(min_mode_str)?;
+        let min_mode = parse_crypto_mode(&min_mode_str)?;
         prefs.minimum_crypto_mode = min_mode;
         changes.push(format!("Minimum crypto mode: {}", min_mode));
     }
@@ -962,20 +3747,33 @@ fn update_security_preferences(
         changes.push(format!("Auto upgrade: {}", if auto_upgrade_enabled { "enabled" } else { "disabled" }));
     }
     
+    if let Some(padding_str) = padding {
+        let scheme = padding_str.parse::<PaddingScheme>()
+            .map_err(|e| anyhow::anyhow!("Invalid padding scheme: {}", e))?;
+        prefs.padding = scheme;
+        changes.push(format!("Message padding: {}", scheme));
+    }
+    
+    if let Some(cover_enabled) = cover_traffic {
+        prefs.cover_traffic.enabled = cover_enabled;
+        changes.push(format!("Cover traffic: {}", if cover_enabled { "enabled" } else { "disabled" }));
+    }
+    
+    if let Some(interval) = cover_interval {
+        if !interval.is_finite() || interval <= 0.0 {
+            return Err(anyhow::anyhow!("Cover traffic interval must be positive"));
+        }
+        prefs.cover_traffic.mean_interval_secs = interval;
+        changes.push(format!("Cover traffic interval: {}s mean", interval));
+    }
+    
     if changes.is_empty() {
         println!("No security settings changed.");
         return Ok(());
     }
     
     // Validate the new preferences
-    let crypto_config = CryptoConfig {
-        mode: prefs.default_crypto_mode,
-        allow_auto_upgrade: prefs.auto_upgrade,
-        adaptive_mode: prefs.adaptive_mode,
-        minimum_mode: prefs.minimum_crypto_mode,
-    };
-    
-    crypto_config.validate()
+    prefs.crypto_config().validate()
         .map_err(|e| anyhow::anyhow!("Invalid security configuration: {}", e))?;
     
     save_security_preferences(config_dir, &prefs)?;
@@ -988,7 +3786,7 @@ fn update_security_preferences(
     Ok(())
 }
 
-fn show_security_configuration(config_dir: &PathBuf) -> Result<()> {
+fn show_security_configuration(config_dir: &Path) -> Result<()> {
     let prefs = load_security_preferences(config_dir)?;
     
     println!("🛡️  Current Security Configuration:");
@@ -1004,6 +3802,13 @@ fn show_security_configuration(config_dir: &PathBuf) -> Result<()> {
              if prefs.auto_upgrade { "enabled ✓" } else { "disabled" });
     println!("   Force post-quantum: {}", 
              if prefs.force_post_quantum { "enabled ✓" } else { "disabled" });
+    println!("   Message padding: {}", prefs.padding);
+    println!("   Cover traffic: {}", 
+             if prefs.cover_traffic.enabled {
+                 format!("enabled ✓ (every ~{}s)", prefs.cover_traffic.mean_interval_secs)
+             } else {
+                 "disabled".to_string()
+             });
     
     println!("\n📈 Crypto Mode Performance:");
     for mode in CryptoMode::all() {
@@ -1022,7 +3827,53 @@ fn show_security_configuration(config_dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn test_crypto_modes(_config_dir: &PathBuf, mode: &str) -> Result<()> {
+/// Send one dummy envelope the way queued mail goes, through the first reachable relay
+async fn send_cover_envelope(pool: &mut RelayPool, generator: &CoverTrafficGenerator) -> nano_messenger::error::Result<()> {
+    let relays = pool.relays().to_vec();
+    deliver_envelope(pool, &relays, &[], &HashMap::new(), &generator.dummy_envelope()?).await
+}
+
+async fn run_cover_traffic(
+    relays: &[String],
+    prefs: &SecurityPreferences,
+    duration: Option<u64>,
+) -> Result<()> {
+    if !prefs.cover_traffic.enabled {
+        println!("Cover traffic is disabled. Enable it with: set-security --cover-traffic true");
+        return Ok(());
+    }
+    
+    let mut pool = RelayPool::new(relays.to_vec());
+    let generator = CoverTrafficGenerator::new(prefs.cover_traffic, prefs.padding);
+    let schedule = generator.schedule();
+    let deadline = duration.map(|secs| tokio::time::Instant::now() + tokio::time::Duration::from_secs(secs));
+    let mut sent = 0u64;
+    
+    println!("🌫️  Sending cover traffic every ~{}s (Ctrl-C to stop)", prefs.cover_traffic.mean_interval_secs);
+    
+    loop {
+        let delay = schedule.next_delay(&mut rand::thread_rng());
+        let wake = tokio::time::Instant::now() + delay;
+        if deadline.is_some_and(|deadline| wake > deadline) {
+            break;
+        }
+        
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        
+        match send_cover_envelope(&mut pool, &generator).await {
+            Ok(()) => sent += 1,
+            Err(e) => eprintln!("⚠️  Failed to send cover envelope: {}", e),
+        }
+    }
+    
+    println!("✓ Sent {} cover envelopes", sent);
+    Ok(())
+}
+
+fn test_crypto_modes(_config_dir: &Path, mode: &str) -> Result<()> {
     println!("🧪 Testing crypto mode compatibility...");
     
     let modes_to_test = if mode == "all" {
@@ -1043,9 +3894,58 @@ fn test_crypto_modes(_config_dir: &PathBuf, mode: &str) -> Result<()> {
     Ok(())
 }
 
+/// Configured relays in order of preference, or the default relay
+fn load_relays(config_dir: &Path) -> Result<Vec<String>> {
+    let relays_file = config_dir.join("relays.json");
+    
+    if !relays_file.exists() {
+        return Ok(vec![DEFAULT_RELAY.to_string()]);
+    }
+    
+    let relays: Vec<String> = serde_json::from_str(&std::fs::read_to_string(&relays_file)?)?;
+    if relays.is_empty() {
+        return Ok(vec![DEFAULT_RELAY.to_string()]);
+    }
+    Ok(relays)
+}
+
+fn save_relays(config_dir: &Path, relays: &[String]) -> Result<()> {
+    let relays_file = config_dir.join("relays.json");
+    std::fs::write(&relays_file, serde_json::to_string_pretty(relays)?)?;
+    Ok(())
+}
+
+/// Pinned onion routing keys, hex-encoded by relay address
+fn load_onion_keys(config_dir: &Path) -> Result<BTreeMap<String, String>> {
+    let keys_file = config_dir.join("onion_keys.json");
+    
+    if !keys_file.exists() {
+        return Ok(BTreeMap::new());
+    }
+    
+    Ok(serde_json::from_str(&std::fs::read_to_string(&keys_file)?)?)
+}
+
+fn save_onion_keys(config_dir: &Path, keys: &BTreeMap<String, String>) -> Result<()> {
+    let keys_file = config_dir.join("onion_keys.json");
+    std::fs::write(&keys_file, serde_json::to_string_pretty(keys)?)?;
+    Ok(())
+}
+
+/// Pinned onion keys as route hops, checking each one parses
+fn load_onion_hops(config_dir: &Path) -> Result<HashMap<String, OnionHop>> {
+    let mut hops = HashMap::new();
+    for (address, onion_key) in load_onion_keys(config_dir)? {
+        let hop = OnionHop::from_hex(address.clone(), &onion_key)
+            .map_err(|e| anyhow::anyhow!("Pinned onion key for {}: {}", address, e))?;
+        hops.insert(address, hop);
+    }
+    Ok(hops)
+}
+
 // Security Preferences Storage Functions
 
-fn load_security_preferences(config_dir: &PathBuf) -> Result<SecurityPreferences> {
+fn load_security_preferences(config_dir: &Path) -> Result<SecurityPreferences> {
     let prefs_file = config_dir.join("security.json");
     
     if !prefs_file.exists() {
@@ -1058,7 +3958,7 @@ fn load_security_preferences(config_dir: &PathBuf) -> Result<SecurityPreferences
     Ok(prefs)
 }
 
-fn save_security_preferences(config_dir: &PathBuf, prefs: &SecurityPreferences) -> Result<()> {
+fn save_security_preferences(config_dir: &Path, prefs: &SecurityPreferences) -> Result<()> {
     let prefs_file = config_dir.join("security.json");
     let data = serde_json::to_string_pretty(prefs)?;
     std::fs::write(&prefs_file, data)?;
@@ -1071,10 +3971,11 @@ fn process_conversation_message(
     keypair: &UserKeyPair,
     _contact_manager: &mut ContactManager,
     message_store: &mut MessageStore,
-) -> Result<bool> {
+    deferred: &mut Deferred,
+) -> Result<Option<StoredMessage>> {
     // Decrypt the message using the shared secret
     let encrypted_payload = envelope.decode_payload()?;
-    let payload_json = decrypt_symmetric(&conversation.shared_secret, &encrypted_payload)?;
+    let payload_json = unpad(&decrypt_symmetric(&conversation.shared_secret, &encrypted_payload)?)?;
     let payload: MessagePayload = MessagePayload::from_json(&String::from_utf8(payload_json)?)?;
     
     // Verify signature
@@ -1083,20 +3984,25 @@ fn process_conversation_message(
     // Check if sender is allowed
     // For now, we'll assume conversation messages are allowed since we have a conversation
     
-    // Update conversation counter
-    conversation.update_their_counter(payload.counter);
+    // Reject stale or far-future payloads
+    FreshnessWindow::default().check(payload.timestamp, Utc::now().timestamp())?;
     
-    // Check for duplicate
-    let existing_messages = message_store.get_messages_from(&payload.from_pubkey, None);
-    let already_exists = existing_messages.iter().any(|msg| {
-        msg.timestamp.timestamp() == payload.timestamp && msg.counter == payload.counter
-    });
+    // Update conversation counter, skipping counters we have already accepted
+    if !conversation.accept_their_counter(payload.counter) {
+        return Ok(None);
+    }
     
-    if already_exists {
-        return Ok(false);
+    if let Some(content) = payload.content {
+        deferred.changes.push(IncomingChange {
+            conversation_id: direct_conversation_id(&keypair.public_key_string(), &payload.from_pubkey),
+            from_pubkey: payload.from_pubkey,
+            sent_at: payload.timestamp,
+            content,
+        });
+        return Ok(None);
     }
     
-    // Store the message
+    // Store the message; a copy we already have is not stored twice
     let stored_msg = StoredMessage::from_payload(
         payload.clone(),
         keypair.public_key_string(),
@@ -1104,12 +4010,186 @@ fn process_conversation_message(
         false, // incoming
     );
     
-    message_store.store_message(stored_msg)?;
-    
-    // Show new message notification
-    let display_name = payload.from_pubkey.clone(); // We removed contact_manager access
-    
-    println!("✉️  New message from {}: {}", display_name, payload.body);
+    if !message_store.store_message(stored_msg.clone())? {
+        return Ok(None);
+    }
     
-    Ok(true)
+    Ok(message_store.get_message(&stored_msg.id)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_survives_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = UserKeyPair::generate();
+        let bob = UserKeyPair::generate();
+        let (alice_pubkey, bob_pubkey) = (alice.public_key_string(), bob.public_key_string());
+
+        // Alice's first message offers a conversation, which bob opens and saves
+        let mut alice_conversations = ConversationManager::new();
+        let offered = start_conversation(&mut alice_conversations, &alice, &bob_pubkey, bob.public_keys().x25519_key);
+        let mut bob_conversations = ConversationManager::new();
+        open_conversation(&mut bob_conversations, &bob, &alice_pubkey, &offered).unwrap();
+        assert!(open_conversation(&mut bob_conversations, &bob, &alice_pubkey, "c2hvcnQ=").is_err());
+        save_conversation_manager(&dir.path().to_path_buf(), &bob, &bob_conversations).unwrap();
+
+        let mut loaded = load_conversation_manager(&dir.path().to_path_buf(), &bob).unwrap();
+        let from_alice = alice_conversations.get_conversation(&bob_pubkey).unwrap().get_outgoing_inbox();
+        let bob_side = loaded.get_conversation(&alice_pubkey).unwrap();
+        assert!(bob_side.get_incoming_inboxes(10).contains(&from_alice));
+
+        // Bob's replies land where alice looks for them
+        let from_bob = bob_side.get_outgoing_inbox();
+        assert!(alice_conversations.get_conversation(&bob_pubkey).unwrap().get_incoming_inboxes(10).contains(&from_bob));
+    }
+
+    #[test]
+    fn test_sessions_use_their_own_profile_padding() {
+        let dir = tempfile::tempdir().unwrap();
+        let recipient = UserKeyPair::generate().public_keys();
+        let mut sealed = Vec::new();
+        for (name, padding) in [("work", PaddingScheme::Buckets), ("personal", PaddingScheme::None)] {
+            let config_dir = dir.path().join(name);
+            std::fs::create_dir_all(&config_dir).unwrap();
+            init_user(&config_dir, CryptoMode::Classical).unwrap();
+            let prefs = SecurityPreferences { padding, ..load_security_preferences(&config_dir).unwrap() };
+            save_security_preferences(&config_dir, &prefs).unwrap();
+
+            // Both profiles are loaded in one process, as the daemon does
+            let session = ClientSession::load(&config_dir, &["127.0.0.1:7733".to_string()]).unwrap();
+            assert_eq!(session.crypto.padding, padding);
+            let envelope = seal_first_contact(&session, "hi", None, Utc::now().timestamp(), &recipient).unwrap();
+            sealed.push(envelope.decode_payload().unwrap().len());
+        }
+        assert!(sealed[0] > sealed[1]);
+    }
+
+    #[tokio::test]
+    async fn test_lost_reply_still_counts_as_sent() {
+        use nano_messenger::protocol::{ProtocolMessage, REPLAY_REJECTION};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        // The relay stores the first attempt but drops the connection before replying, then refuses the resend
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            for attempt in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if attempt == 1 {
+                    let reply = ProtocolMessage::Error { message: format!("{} Nonce already seen", REPLAY_REJECTION) };
+                    let reply = format!("{}\n", serde_json::to_string(&reply).unwrap());
+                    reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                }
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_path_buf();
+        init_user(&config_dir, CryptoMode::Classical).unwrap();
+        let mut session = ClientSession::load(&config_dir, &[address.clone()]).unwrap();
+        let entry = OutboxEntry::new("m1".to_string(), MessageEnvelope::new("inbox".to_string(), vec![1, 2, 3]), Vec::new(), false);
+        let id = entry.id.clone();
+        session.message_store.save_outbox_entry(entry).unwrap();
+
+        // The dropped reply is a network failure, so the entry waits for a retry
+        assert_eq!(flush_outbox(&mut session).await.delivered, 0);
+        let mut entry = session.message_store.outbox().get(&id).cloned().unwrap();
+        assert_eq!(entry.status, DeliveryStatus::Pending);
+
+        // The relay already holds the resend, which means the first attempt arrived
+        entry.next_attempt = Utc::now();
+        session.message_store.save_outbox_entry(entry).unwrap();
+        session.relays.record_success(&address);
+        assert_eq!(flush_outbox(&mut session).await.delivered, 1);
+        assert_eq!(session.message_store.outbox().get(&id).unwrap().status, DeliveryStatus::Sent);
+    }
+
+    #[tokio::test]
+    async fn test_cover_envelopes_use_the_relay_pool() {
+        use nano_messenger::protocol::ProtocolMessage;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        // The preferred relay is down, so the dummy goes to the next one like real mail would
+        let down = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = listener.local_addr().unwrap().to_string();
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let reply = serde_json::to_string(&ProtocolMessage::Success { message: "stored".to_string() }).unwrap();
+            reader.get_mut().write_all(format!("{}\n", reply).as_bytes()).await.unwrap();
+            ProtocolMessage::from_json(line.trim()).unwrap()
+        });
+
+        let mut pool = RelayPool::new(vec![down.clone(), up]);
+        let generator = CoverTrafficGenerator::new(CoverTrafficConfig { enabled: true, ..Default::default() }, PaddingScheme::Buckets);
+        send_cover_envelope(&mut pool, &generator).await.unwrap();
+        assert!(matches!(relay.await.unwrap(), ProtocolMessage::SendMessage { .. }));
+        assert!(!pool.is_healthy(&down));
+    }
+
+    #[tokio::test]
+    async fn test_onion_route_needs_pinned_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_path_buf();
+        let onion_key = hex::encode(X25519PublicKey::from(&X25519PrivateKey::random_from_rng(rand::rngs::OsRng)).as_bytes());
+        handle_relay_command(&config_dir, &[], RelayCommands::Pin { address: "127.0.0.1:1".to_string(), onion_key }).await.unwrap();
+        assert!(handle_relay_command(&config_dir, &[], RelayCommands::Pin { address: "127.0.0.1:2".to_string(), onion_key: "00".to_string() }).await.is_err());
+        let hops = load_onion_hops(&config_dir).unwrap();
+        assert_eq!(hops.len(), 1);
+
+        // The exit relay's key is not pinned, so nothing is sent and no relay is asked for it
+        let envelope = QuantumSafeEnvelope::new(CryptoMode::Classical, "ab".repeat(32), b"sealed".to_vec());
+        let result = send_onion_routed("127.0.0.1:3", &["127.0.0.1:1".to_string()], &hops, envelope).await;
+        assert!(matches!(result, Err(NanoError::Config(_))));
+    }
+
+    #[test]
+    fn test_legacy_messages_are_imported_then_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_path_buf();
+        let keypair = UserKeyPair::generate();
+        let mut payload = MessagePayload::new("pubkey:bob".to_string(), "From the old client".to_string(), 1, None);
+        payload.sign(&UserKeyPair::generate().signing_key).unwrap();
+        let message = StoredMessage::from_payload(payload, keypair.public_key_string(), Utc::now(), false);
+        let legacy = serde_json::json!({ "messages": { message.id.clone(): message } });
+        std::fs::write(config_dir.join("messages.json"), legacy.to_string()).unwrap();
+        std::fs::write(config_dir.join("messages.json.migrated"), legacy.to_string()).unwrap();
+
+        let store = load_message_store(&config_dir, &keypair).unwrap();
+        assert_eq!(store.get_message(&message.id).unwrap().unwrap().content, "From the old client");
+        assert!(!config_dir.join("messages.json").exists());
+        assert!(!config_dir.join("messages.json.migrated").exists());
+        drop(store);
+
+        // The import committed, so reopening finds it without the JSON file
+        let store = load_message_store(&config_dir, &keypair).unwrap();
+        assert_eq!(store.get_conversation_messages(&message.conversation_id, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_plaintext_groups_are_encrypted_then_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_path_buf();
+        let keypair = UserKeyPair::generate();
+        let mut groups = GroupManager::new();
+        groups.insert(GroupState::create("old".to_string(), GroupMember::new(keypair.public_keys()), Vec::new()));
+        std::fs::write(config_dir.join("groups.json"), serde_json::to_string(&groups).unwrap()).unwrap();
+
+        assert_eq!(load_group_manager(&config_dir, &keypair).unwrap().list()[0].name, "old");
+        assert!(!config_dir.join("groups.json").exists());
+        assert!(config_dir.join("groups.enc").exists());
+
+        // Later loads read the encrypted store, which other keys cannot open
+        assert_eq!(load_group_manager(&config_dir, &keypair).unwrap().list().len(), 1);
+        assert!(load_group_manager(&config_dir, &UserKeyPair::generate()).is_err());
+    }
 }
@@ -0,0 +1,1156 @@
+//! Long-running client daemon with a local JSON-RPC API
+//!
+//! The daemon owns the keys, stores and relay polling. Clients connect to a
+//! Unix socket and exchange newline-delimited JSON-RPC 2.0 messages; a
+//! `subscribe` call turns the connection into a stream of `message`,
+//! `updated`, `expired`, `receipt`, `typing` and `request` notifications as
+//! they arrive.
+//!
+//! Methods: `info`, `send`, `send_group`, `edit`, `delete`, `react`,
+//! `receive`, `list_conversations`, `get_messages`, `mark_read`, `typing`,
+//! `disappearing`, `search`, `outbox`, `subscribe`, `unsubscribe`,
+//! `contacts.list`, `contacts.allow`, `contacts.block`, `contacts.edit`,
+//! `contacts.label`, `contacts.favorite`, `contacts.privacy`,
+//! `contacts.remove`, `trust.list`, `trust.accept`, `requests.list`,
+//! `requests.accept`, `requests.block` and `requests.report`.
+//!
+//! One daemon can hold several profiles at once, each with its own session,
+//! relays and poller. Calls go to the first profile unless their params name
+//! another with `profile`, `profiles` lists them, and every notification
+//! carries the profile it came from.
+//!
+//! Queued outgoing envelopes are retried on every poll, and messages whose
+//! disappearing timer ran out are purged every second. Profiles with cover
+//! traffic enabled send dummy envelopes on its Poisson schedule, through the
+//! same relays as their mail.
+//!
+//! Every served profile's directory holds a `daemon.sock` leading to the
+//! daemon, so other commands can tell the profile is in use and stay away
+//! from its stores.
+
+use super::{
+    accept_request, block_request, change_message, compose_message, deliver_group_message, deliver_group_timer, deliver_message, deliver_timer, device_id, direct_conversation_id,
+    flush_outbox, group_conversation_id, inboxes_to_poll, mark_read, poll_inboxes, receive_envelopes, refresh_known_device_lists,
+    load_security_preferences, report_request, save_contact_manager, save_trust_store, send_control, send_cover_envelope, send_delivery_receipts, ClientSession,
+    FetchResult, Inboxes, SWEEP_INTERVAL,
+};
+use anyhow::Result;
+use nano_messenger::{
+    contacts::Contact,
+    content::{MessageContent, MessageRef, Reaction},
+    disappearing::DisappearingTimer,
+    messages::StoredMessage,
+    requests::MessageRequest,
+    receipts::{ConversationControl, ReceiptStatus},
+    search::SearchQuery,
+    traffic::CoverTrafficGenerator,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, LocalSet};
+use tokio::time::Instant;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const APPLICATION_ERROR: i64 = -32000;
+
+/// Name of the socket, or the link to it, in each served profile's directory
+pub const SOCKET_NAME: &str = "daemon.sock";
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<Value>, // Absent for notifications, which get no response
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(APPLICATION_ERROR, e.to_string())
+    }
+}
+
+impl From<nano_messenger::error::NanoError> for RpcError {
+    fn from(e: nano_messenger::error::NanoError) -> Self {
+        Self::new(APPLICATION_ERROR, e.to_string())
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+fn response(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, RpcError> {
+    // Methods without arguments accept omitted params
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    recipient: String,
+    message: String,
+    #[serde(default)]
+    reply_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SendGroupParams {
+    group: String,
+    message: String,
+    #[serde(default)]
+    reply_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EditParams {
+    message_id: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct MessageIdParams {
+    message_id: String,
+}
+
+#[derive(Deserialize)]
+struct ReactParams {
+    message_id: String,
+    #[serde(default)]
+    reaction: Option<String>, // Emoji or name; omitted to withdraw
+}
+
+#[derive(Deserialize)]
+struct ConversationParams {
+    conversation_id: String,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct DisappearingParams {
+    conversation_id: String,
+    #[serde(default)]
+    timer: Option<String>, // e.g. "1h" or "off"; omitted to read the current timer
+    #[serde(default)]
+    after_read: bool,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    query: String,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PubkeyParams {
+    pubkey: String,
+}
+
+#[derive(Deserialize)]
+struct TypingParams {
+    pubkey: String,
+    active: bool,
+}
+
+#[derive(Deserialize)]
+struct PrivacyParams {
+    pubkey: String,
+    #[serde(default)]
+    delivery_receipts: Option<bool>,
+    #[serde(default)]
+    read_receipts: Option<bool>,
+    #[serde(default)]
+    typing_indicators: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct EditContactParams {
+    pubkey: String,
+    #[serde(default)]
+    nickname: Option<String>,
+    #[serde(default)]
+    memo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LabelParams {
+    pubkey: String,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FavoriteParams {
+    pubkey: String,
+    favorite: bool,
+}
+
+#[derive(Deserialize)]
+struct ContactParams {
+    contact: String,
+}
+
+#[derive(Deserialize)]
+struct SenderParams {
+    sender: String,
+}
+
+#[derive(Deserialize)]
+struct ReportParams {
+    sender: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NoParams {}
+
+/// Pushed to subscribed connections as a JSON-RPC notification
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+enum Notification {
+    Message(Box<StoredMessage>),
+    Updated(Box<StoredMessage>),
+    Expired { message_ids: Vec<String> },
+    Receipt { message_id: String, status: ReceiptStatus },
+    Typing { pubkey: String, active: bool },
+    Request { sender: String },
+}
+
+impl Notification {
+    fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        value["jsonrpc"] = json!("2.0");
+        value
+    }
+}
+
+/// A notification from one of the daemon's profiles
+#[derive(Debug, Clone)]
+struct Event {
+    profile: String,
+    notification: Notification,
+}
+
+impl Event {
+    fn to_json(&self) -> Value {
+        let mut value = self.notification.to_json();
+        value["params"]["profile"] = json!(self.profile);
+        value
+    }
+}
+
+/// A request forwarded from a connection to the task that owns the session
+struct Call {
+    profile: Option<String>, // The first profile if unset
+    method: String,
+    params: Value,
+    reply: oneshot::Sender<RpcResult>,
+}
+
+/// A profile for the daemon to load and serve
+pub struct ProfileConfig {
+    pub name: String,
+    pub config_dir: PathBuf,
+    pub relays: Vec<String>,
+}
+
+fn contact_json(contact: &Contact) -> Value {
+    json!({
+        "pubkey": contact.permission.pubkey,
+        "name": contact.display_name(),
+        "username": contact.username,
+        "status": contact.permission.status,
+        "nickname": contact.metadata.as_ref().map(|metadata| &metadata.nickname),
+        "memo": contact.metadata.as_ref().map(|metadata| &metadata.memo),
+        "labels": contact.labels(),
+        "favorite": contact.is_favorite(),
+    })
+}
+
+fn request_json(request: &MessageRequest) -> Value {
+    let messages: Vec<Value> = request.messages.iter().map(|message| json!({
+        "body": message.payload.body,
+        "timestamp": message.payload.timestamp,
+        "received_at": message.received_at,
+    })).collect();
+    json!({
+        "sender": request.sender,
+        "first_seen": request.first_seen,
+        "last_seen": request.last_seen(),
+        "messages": messages,
+    })
+}
+
+/// Session owner of one profile: handles calls one at a time and processes fetched envelopes
+struct Daemon {
+    profile: String,
+    session: ClientSession,
+    notifications: broadcast::Sender<Event>,
+    inboxes: watch::Sender<Inboxes>,
+    cover: Option<CoverTrafficGenerator>, // Set if the profile sends cover traffic
+}
+
+impl Daemon {
+    async fn call(&mut self, method: &str, raw: Value) -> RpcResult {
+        match method {
+            "info" => {
+                params::<NoParams>(raw)?;
+                Ok(json!({
+                    "profile": self.profile,
+                    "identity": self.session.identity,
+                    "device_id": device_id(&self.session.keypair.public_keys()),
+                    "relays": self.session.relays.relays(),
+                    "revoked": self.session.is_revoked(),
+                }))
+            }
+            "send" => {
+                let SendParams { recipient, message, reply_to } = params(raw)?;
+                let session = &self.session;
+                let message = compose_message(&session.message_store, &session.contact_manager, &message, reply_to.as_deref())?.into();
+                let sent = deliver_message(&mut self.session, &recipient, message, &[]).await?;
+                self.session.save()?;
+                self.update_inboxes();
+                Ok(json!({
+                    "conversation_id": direct_conversation_id(&self.session.identity, &sent.recipient_pubkey),
+                    "recipient_pubkey": sent.recipient_pubkey,
+                    "devices": sent.devices,
+                    "status": sent.status,
+                    "warnings": sent.warnings,
+                }))
+            }
+            "send_group" => {
+                let SendGroupParams { group, message, reply_to } = params(raw)?;
+                let session = &mut self.session;
+                let message = compose_message(&session.message_store, &session.contact_manager, &message, reply_to.as_deref())?.into();
+                let state = session
+                    .group_manager
+                    .find_mut(&group)
+                    .ok_or_else(|| RpcError::new(APPLICATION_ERROR, format!("Unknown group: {}", group)))?;
+                deliver_group_message(&mut session.relays, &session.keypair, session.crypto.padding, state, &mut session.message_store, message)
+                    .await?;
+                let conversation_id = group_conversation_id(&state.group_id);
+                session.save()?;
+                Ok(json!({ "conversation_id": conversation_id }))
+            }
+            "edit" => {
+                let EditParams { message_id, message } = params(raw)?;
+                self.change(&message_id, |target| MessageContent::Edit { target, body: message }).await
+            }
+            "delete" => {
+                let MessageIdParams { message_id } = params(raw)?;
+                self.change(&message_id, |target| MessageContent::Delete { target }).await
+            }
+            "react" => {
+                let ReactParams { message_id, reaction } = params(raw)?;
+                let reaction = reaction.as_deref().map(str::parse::<Reaction>).transpose()?;
+                self.change(&message_id, |target| MessageContent::React { target, reaction }).await
+            }
+            "receive" => {
+                params::<NoParams>(raw)?;
+                // Poll now; new messages arrive as notifications
+                self.inboxes.send_replace(inboxes_to_poll(&mut self.session));
+                Ok(json!({}))
+            }
+            "list_conversations" => {
+                params::<NoParams>(raw)?;
+                let conversations: Vec<Value> = self
+                    .session
+                    .message_store
+                    .get_conversation_summaries()?
+                    .into_iter()
+                    .map(|summary| {
+                        json!({
+                            "id": summary.id,
+                            "other_pubkey": summary.other_pubkey,
+                            "name": self.session.display_name(&summary.other_pubkey),
+                            "last_message": summary.last_message,
+                            "last_timestamp": summary.last_timestamp,
+                            "unread_count": summary.unread_count,
+                            "message_count": summary.message_count,
+                            "last_receipt": summary.last_receipt,
+                            "disappearing": self.session.message_store.timer(&summary.id),
+                        })
+                    })
+                    .collect();
+                Ok(json!(conversations))
+            }
+            "get_messages" => {
+                let ConversationParams { conversation_id, limit } = params(raw)?;
+                let messages = self.session.message_store.get_conversation_messages(&conversation_id, limit)?;
+                Ok(serde_json::to_value(messages).map_err(anyhow::Error::from)?)
+            }
+            "mark_read" => {
+                let ConversationParams { conversation_id, .. } = params(raw)?;
+                let mut warnings = Vec::new();
+                if let Some((pubkey, receipt)) = mark_read(&mut self.session, &conversation_id)? {
+                    if let Err(e) = send_control(&mut self.session, &pubkey, &receipt).await {
+                        warnings.push(format!("Could not send read receipt: {}", e));
+                    }
+                }
+                Ok(json!({ "warnings": warnings }))
+            }
+            "typing" => {
+                let TypingParams { pubkey, active } = params(raw)?;
+                if !pubkey.starts_with("pubkey:") {
+                    return Err(RpcError::new(INVALID_PARAMS, "Typing notices go to a pubkey"));
+                }
+                // Quietly skip contacts we do not share activity with
+                let sent = self.session.shares_activity(&pubkey, |privacy| privacy.typing_indicators);
+                if sent {
+                    send_control(&mut self.session, &pubkey, &ConversationControl::Typing { active }).await?;
+                }
+                Ok(json!({ "sent": sent }))
+            }
+            "disappearing" => {
+                let DisappearingParams { conversation_id, timer, after_read } = params(raw)?;
+                let Some(timer) = timer else {
+                    return Ok(json!(self.session.message_store.timer(&conversation_id)));
+                };
+                let timer = DisappearingTimer::parse(&timer, after_read)?;
+                self.set_timer(&conversation_id, timer).await
+            }
+            "search" => {
+                let SearchParams { query, limit } = params(raw)?;
+                let query = SearchQuery::parse(&query)?;
+                let messages = self.session.message_store.search(&query, limit)?;
+                Ok(serde_json::to_value(messages).map_err(anyhow::Error::from)?)
+            }
+            "outbox" => {
+                params::<NoParams>(raw)?;
+                let entries: Vec<Value> = self
+                    .session
+                    .message_store
+                    .outbox()
+                    .entries()
+                    .into_iter()
+                    .map(|entry| {
+                        json!({
+                            "id": entry.id,
+                            "message_id": entry.message_id,
+                            "device_sync": entry.device_sync,
+                            "status": entry.status,
+                            "attempts": entry.attempts,
+                            "created_at": entry.created_at,
+                            "next_attempt": entry.next_attempt,
+                            "last_error": entry.last_error,
+                        })
+                    })
+                    .collect();
+                Ok(json!(entries))
+            }
+            "contacts.list" => {
+                params::<NoParams>(raw)?;
+                let contacts: Vec<Value> = self.session.contact_manager.list_contacts().iter().map(contact_json).collect();
+                Ok(json!(contacts))
+            }
+            "contacts.allow" => {
+                let PubkeyParams { pubkey } = params(raw)?;
+                self.session.contact_manager.allow_contact(pubkey.clone())?;
+                self.contact_updated(&pubkey)
+            }
+            "contacts.block" => {
+                let PubkeyParams { pubkey } = params(raw)?;
+                self.session.contact_manager.block_contact(pubkey.clone())?;
+                self.contact_updated(&pubkey)
+            }
+            "contacts.edit" => {
+                let EditContactParams { pubkey, nickname, memo } = params(raw)?;
+                self.session.contact_manager.update_metadata(&pubkey, nickname, memo)?;
+                self.contact_updated(&pubkey)
+            }
+            "contacts.label" => {
+                let LabelParams { pubkey, add, remove } = params(raw)?;
+                self.known_contact(&pubkey)?;
+                for label in &add {
+                    self.session.contact_manager.add_label(&pubkey, label)?;
+                }
+                for label in &remove {
+                    self.session.contact_manager.remove_label(&pubkey, label);
+                }
+                self.contact_updated(&pubkey)
+            }
+            "contacts.favorite" => {
+                let FavoriteParams { pubkey, favorite } = params(raw)?;
+                self.known_contact(&pubkey)?;
+                self.session.contact_manager.set_favorite(&pubkey, favorite);
+                self.contact_updated(&pubkey)
+            }
+            "contacts.privacy" => {
+                let PrivacyParams { pubkey, delivery_receipts, read_receipts, typing_indicators } = params(raw)?;
+                let contacts = &mut self.session.contact_manager;
+                let settings = contacts.privacy(&pubkey).updated(delivery_receipts, read_receipts, typing_indicators);
+                contacts.set_privacy(pubkey, settings);
+                save_contact_manager(&self.session.config_dir, &self.session.contact_manager)?;
+                Ok(json!(settings))
+            }
+            "contacts.remove" => {
+                let PubkeyParams { pubkey } = params(raw)?;
+                self.session.contact_manager.remove_contact(&pubkey);
+                save_contact_manager(&self.session.config_dir, &self.session.contact_manager)?;
+                Ok(json!({}))
+            }
+            "trust.list" => {
+                params::<NoParams>(raw)?;
+                let trust = &self.session.trust;
+                let pins: Vec<Value> = trust.pins().into_iter().map(|(contact, pin)| json!({
+                    "contact": contact,
+                    "pubkey": pin.pubkey,
+                    "fingerprint": pin.fingerprint,
+                    "verified": pin.verified,
+                    "policy": trust.policy(contact),
+                    "pending": trust.pending(contact),
+                })).collect();
+                Ok(json!(pins))
+            }
+            "trust.accept" => {
+                let ContactParams { contact } = params(raw)?;
+                let change = self.session.trust.accept(&contact, chrono::Utc::now())?;
+                save_trust_store(&self.session.config_dir, &self.session.trust)?;
+                Ok(json!(change))
+            }
+            "requests.list" => {
+                params::<NoParams>(raw)?;
+                Ok(json!(self.session.requests.list().into_iter().map(request_json).collect::<Vec<_>>()))
+            }
+            "requests.accept" => {
+                let SenderParams { sender } = params(raw)?;
+                let accepted = accept_request(&mut self.session, &sender)?;
+                self.session.save()?;
+                for message in &accepted {
+                    self.notify(Notification::Message(Box::new(message.clone())));
+                }
+                Ok(json!(accepted))
+            }
+            "requests.block" => {
+                let SenderParams { sender } = params(raw)?;
+                block_request(&mut self.session, &sender)?;
+                self.session.save()?;
+                self.contact_updated(&sender)
+            }
+            "requests.report" => {
+                let ReportParams { sender, reason } = params(raw)?;
+                let report = report_request(&mut self.session, &sender, reason)?;
+                self.session.save()?;
+                Ok(json!(report))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+        }
+    }
+
+    /// Send an edit, deletion or reaction and return the changed message
+    async fn change(
+        &mut self,
+        message_id: &str,
+        change: impl FnOnce(MessageRef) -> MessageContent,
+    ) -> RpcResult {
+        let warnings = change_message(&mut self.session, message_id, change).await?;
+        self.session.save()?;
+        self.update_inboxes();
+        Ok(json!({
+            "message": self.session.message_store.get_message(message_id)?,
+            "warnings": warnings,
+        }))
+    }
+
+    /// Send a new disappearing-message timer to everyone in a conversation
+    async fn set_timer(&mut self, conversation_id: &str, timer: DisappearingTimer) -> RpcResult {
+        let session = &mut self.session;
+        let mut warnings = Vec::new();
+        if let Some(group_id) = conversation_id.strip_prefix("group:") {
+            let state = session
+                .group_manager
+                .get_mut(group_id)
+                .ok_or_else(|| RpcError::new(APPLICATION_ERROR, format!("Unknown group: {}", group_id)))?;
+            deliver_group_timer(&mut session.relays, &session.keypair, session.crypto.padding, state, &mut session.message_store, timer).await?;
+        } else {
+            let other_pubkey = conversation_id
+                .strip_suffix(&format!("|{}", session.identity))
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Unknown conversation: {}", conversation_id)))?
+                .to_string();
+            warnings = deliver_timer(session, &other_pubkey, timer).await?.warnings;
+        }
+        self.session.save()?;
+        self.update_inboxes();
+        Ok(json!({ "timer": timer, "warnings": warnings }))
+    }
+
+    /// Purge expired disappearing messages and tell subscribers which went
+    fn sweep(&mut self) {
+        match self.session.message_store.purge_expired(chrono::Utc::now()) {
+            Ok(message_ids) if !message_ids.is_empty() => {
+                self.notify(Notification::Expired { message_ids });
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Warning: Failed to purge expired messages: {}", e),
+        }
+    }
+
+    /// When the next cover envelope is due, if the profile sends any
+    fn next_cover(&self) -> Option<Instant> {
+        let generator = self.cover.as_ref()?;
+        Some(Instant::now() + generator.schedule().next_delay(&mut rand::thread_rng()))
+    }
+
+    /// Send a dummy envelope through the relay pool real envelopes use
+    async fn send_cover(&mut self) {
+        let Some(generator) = &self.cover else {
+            return;
+        };
+        if let Err(e) = send_cover_envelope(&mut self.session.relays, generator).await {
+            eprintln!("Warning: Failed to send cover envelope: {}", e);
+        }
+    }
+
+    /// Broadcast to subscribers; nobody subscribed is not an error
+    fn notify(&self, notification: Notification) {
+        let _ = self.notifications.send(Event { profile: self.profile.clone(), notification });
+    }
+
+    fn known_contact(&self, pubkey: &str) -> Result<(), RpcError> {
+        match self.session.contact_manager.get_contact(pubkey) {
+            Some(_) => Ok(()),
+            None => Err(RpcError::new(INVALID_PARAMS, format!("Unknown contact: {}", pubkey))),
+        }
+    }
+
+    fn contact_updated(&self, pubkey: &str) -> RpcResult {
+        save_contact_manager(&self.session.config_dir, &self.session.contact_manager)?;
+        let contact = self.session.contact_manager.get_contact(pubkey);
+        Ok(contact.as_ref().map_or(Value::Null, contact_json))
+    }
+
+    /// Replace the polled inbox set if conversations or group epochs moved on
+    fn update_inboxes(&mut self) {
+        let current = inboxes_to_poll(&mut self.session);
+        self.inboxes.send_if_modified(|inboxes| {
+            if *inboxes == current {
+                return false;
+            }
+            *inboxes = current;
+            true
+        });
+    }
+
+    async fn handle_fetched(&mut self, result: FetchResult) {
+        let flushed = flush_outbox(&mut self.session).await;
+        for warning in &flushed.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        if flushed.delivered > 0 {
+            println!("📤 Delivered {} queued envelope(s) from the outbox", flushed.delivered);
+        }
+
+        let fetched = match result {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                eprintln!("Warning: Could not reach any relay: {}", e);
+                return;
+            }
+        };
+
+        let received = receive_envelopes(&mut self.session, fetched).await;
+        for warning in received.warnings.iter().chain(&send_delivery_receipts(&mut self.session, &received).await) {
+            eprintln!("Warning: {}", warning);
+        }
+
+        for (message_id, status) in received.receipts {
+            self.notify(Notification::Receipt { message_id, status });
+        }
+        for (pubkey, active) in received.typing {
+            self.notify(Notification::Typing { pubkey, active });
+        }
+        for message in received.updated {
+            self.notify(Notification::Updated(Box::new(message)));
+        }
+        for sender in received.requests.iter().cloned() {
+            self.notify(Notification::Request { sender });
+        }
+        if received.messages.is_empty() && received.requests.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.session.save() {
+            eprintln!("Warning: Failed to save state: {}", e);
+        }
+        for (_, message) in received.messages {
+            self.notify(Notification::Message(Box::new(message)));
+        }
+        self.update_inboxes();
+    }
+
+    /// Serve calls, fetched envelopes, sweeps and cover traffic until shutdown, then save the session
+    async fn serve(
+        mut self,
+        mut calls: mpsc::Receiver<Call>,
+        mut fetched: mpsc::Receiver<FetchResult>,
+        poller: JoinHandle<()>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let mut sweeper = tokio::time::interval(SWEEP_INTERVAL);
+        let mut cover_due = self.next_cover();
+        loop {
+            let cover = async move {
+                match cover_due {
+                    Some(due) => tokio::time::sleep_until(due).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Some(call) = calls.recv() => {
+                    let result = self.call(&call.method, call.params).await;
+                    let _ = call.reply.send(result);
+                }
+                Some(result) = fetched.recv() => self.handle_fetched(result).await,
+                _ = sweeper.tick() => self.sweep(),
+                _ = cover => {
+                    self.send_cover().await;
+                    cover_due = self.next_cover();
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        poller.abort();
+        self.session.save()
+    }
+}
+
+/// Load a profile and serve it on a task of its own, returning where to send its calls
+async fn spawn_profile(
+    profile: ProfileConfig,
+    notifications: broadcast::Sender<Event>,
+    poll_interval: Duration,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(mpsc::Sender<Call>, JoinHandle<Result<()>>)> {
+    let mut session = ClientSession::load(&profile.config_dir, &profile.relays)?;
+    let cover_traffic = load_security_preferences(&profile.config_dir)?.cover_traffic;
+
+    for warning in refresh_known_device_lists(&mut session).await {
+        eprintln!("Warning: {}", warning);
+    }
+    if session.is_revoked() {
+        eprintln!("⚠️  Profile '{}' is on a device revoked by its primary device", profile.name);
+    }
+
+    println!("👤 Profile '{}': {}", profile.name, session.identity);
+    println!("   Relays: {}", profile.relays.join(", "));
+    if cover_traffic.enabled {
+        println!("   Cover traffic: every ~{}s", cover_traffic.mean_interval_secs);
+    }
+
+    let (inbox_tx, inbox_rx) = watch::channel(inboxes_to_poll(&mut session));
+    let (fetched_tx, fetched_rx) = mpsc::channel(1);
+    let poller = tokio::spawn(poll_inboxes(profile.relays.clone(), inbox_rx, fetched_tx, poll_interval));
+
+    let cover = cover_traffic.enabled.then(|| CoverTrafficGenerator::new(cover_traffic, session.crypto.padding));
+    let (calls_tx, calls_rx) = mpsc::channel(32);
+    let daemon = Daemon {
+        profile: profile.name,
+        session,
+        notifications,
+        inboxes: inbox_tx,
+        cover,
+    };
+    Ok((calls_tx, tokio::task::spawn_local(daemon.serve(calls_rx, fetched_rx, poller, shutdown))))
+}
+
+/// Hand a call to the profile it names, or answer it if it is about the profiles themselves
+async fn route(call: Call, profiles: &[(String, mpsc::Sender<Call>)]) {
+    if call.method == "profiles" {
+        let names: Vec<&str> = profiles.iter().map(|(name, _)| name.as_str()).collect();
+        let _ = call.reply.send(Ok(json!(names)));
+        return;
+    }
+
+    let target = match &call.profile {
+        Some(name) => profiles.iter().find(|(profile, _)| profile == name),
+        None => profiles.first(),
+    };
+    let Some((_, calls)) = target else {
+        let error = RpcError::new(INVALID_PARAMS, format!("Unknown profile: {}", call.profile.unwrap_or_default()));
+        let _ = call.reply.send(Err(error));
+        return;
+    };
+    if let Err(mpsc::error::SendError(call)) = calls.send(call).await {
+        let _ = call.reply.send(Err(RpcError::new(APPLICATION_ERROR, "Profile stopped")));
+    }
+}
+
+/// Socket of the daemon serving the profile in `config_dir`, if one answers
+pub async fn running_daemon(config_dir: &Path) -> Option<PathBuf> {
+    let socket_path = config_dir.join(SOCKET_NAME);
+    UnixStream::connect(&socket_path).await.ok()?;
+    Some(std::fs::read_link(&socket_path).unwrap_or(socket_path))
+}
+
+/// Run the daemon until interrupted, serving JSON-RPC for `profiles` on `socket_path`
+pub async fn run(profiles: Vec<ProfileConfig>, socket_path: &Path, poll_interval: Duration) -> Result<()> {
+    // Claim the socket and the profiles first so a second daemon fails before touching the stores
+    for profile in &profiles {
+        if let Some(running) = running_daemon(&profile.config_dir).await {
+            anyhow::bail!("Profile '{}' is already served by the daemon on {}", profile.name, running.display());
+        }
+    }
+    let socket_path = std::path::absolute(socket_path)?;
+    let listener = bind(&socket_path).await?;
+    let links = match link_profiles(&profiles, &socket_path) {
+        Ok(links) => links,
+        Err(e) => {
+            let _ = std::fs::remove_file(&socket_path);
+            return Err(e);
+        }
+    };
+
+    // Session futures are not provably Send, so profiles run as local tasks on this thread
+    let result = LocalSet::new().run_until(serve_profiles(profiles, listener, &socket_path, poll_interval)).await;
+    for path in links.iter().chain([&socket_path]) {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+async fn serve_profiles(profiles: Vec<ProfileConfig>, listener: UnixListener, socket_path: &Path, poll_interval: Duration) -> Result<()> {
+    let (notifications_tx, _) = broadcast::channel(256);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut routes = Vec::new();
+    let mut tasks = Vec::new();
+    for profile in profiles {
+        let name = profile.name.clone();
+        let (calls, task) = spawn_profile(profile, notifications_tx.clone(), poll_interval, shutdown_rx.clone()).await?;
+        routes.push((name, calls));
+        tasks.push(task);
+    }
+    println!("🛰️  nano-client daemon listening on {}", socket_path.display());
+
+    let (calls_tx, mut calls_rx) = mpsc::channel::<Call>(32);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_connection(stream, calls_tx.clone(), notifications_tx.clone()));
+                }
+                Err(e) => eprintln!("Warning: Failed to accept connection: {}", e),
+            },
+            Some(call) = calls_rx.recv() => route(call, &routes).await,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    println!("Shutting down...");
+    shutdown_tx.send_replace(true);
+    for task in tasks {
+        task.await??;
+    }
+    Ok(())
+}
+
+/// Bind the socket, replacing a stale one but refusing to displace a running daemon
+///
+/// The socket can act as this identity, so it is bound in a directory only
+/// its owner can enter and moved into place once no one else may connect.
+async fn bind(socket_path: &Path) -> Result<UnixListener> {
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).await.is_ok() {
+            anyhow::bail!("A daemon is already listening on {}", socket_path.display());
+        }
+        std::fs::remove_file(socket_path)?;
+    }
+
+    let parent = socket_path.parent().ok_or_else(|| anyhow::anyhow!("Invalid socket path {}", socket_path.display()))?;
+    let staging = tempfile::Builder::new()
+        .prefix(".daemon-")
+        .permissions(std::fs::Permissions::from_mode(0o700))
+        .tempdir_in(parent)?;
+    let staged = staging.path().join(SOCKET_NAME);
+    let listener = UnixListener::bind(&staged)?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(&staged, socket_path)?;
+    Ok(listener)
+}
+
+/// Point each profile's `daemon.sock` at the socket, returning the links made
+fn link_profiles(profiles: &[ProfileConfig], socket_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut links = Vec::new();
+    for profile in profiles {
+        let link = std::path::absolute(profile.config_dir.join(SOCKET_NAME))?;
+        if link == socket_path {
+            continue;
+        }
+        // Nothing answered here, so whatever is left belongs to a daemon that is gone
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&link)?;
+        }
+        if let Err(e) = std::os::unix::fs::symlink(socket_path, &link) {
+            for link in &links {
+                let _ = std::fs::remove_file(link);
+            }
+            return Err(e.into());
+        }
+        links.push(link);
+    }
+    Ok(links)
+}
+
+/// Answer one request line; `None` for notifications, which get no response
+async fn handle_line(
+    line: &str,
+    calls: &mpsc::Sender<Call>,
+    subscription: &mut Option<broadcast::Receiver<Event>>,
+    notifications: &broadcast::Sender<Event>,
+) -> Option<Value> {
+    let request: RpcRequest = match serde_json::from_str::<Value>(line) {
+        Err(e) => return Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))),
+        Ok(value) => match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return Some(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, e.to_string())))),
+        },
+    };
+    if request.jsonrpc != "2.0" {
+        let error = RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported");
+        return Some(response(request.id.unwrap_or(Value::Null), Err(error)));
+    }
+
+    // The profile is a routing hint rather than a method argument
+    let mut params = request.params;
+    let profile = match params.as_object_mut().and_then(|params| params.remove("profile")) {
+        Some(Value::String(profile)) => Some(profile),
+        Some(_) => {
+            let error = RpcError::new(INVALID_PARAMS, "profile must be a string");
+            return request.id.map(|id| response(id, Err(error)));
+        }
+        None => None,
+    };
+
+    // Subscriptions belong to the connection, everything else to the session owner
+    let result = match request.method.as_str() {
+        "subscribe" => {
+            *subscription = Some(notifications.subscribe());
+            Ok(json!({ "subscribed": true }))
+        }
+        "unsubscribe" => {
+            *subscription = None;
+            Ok(json!({ "subscribed": false }))
+        }
+        _ => {
+            let (reply, result) = oneshot::channel();
+            let call = Call { profile, method: request.method, params, reply };
+            match calls.send(call).await {
+                Ok(()) => result.await.unwrap_or_else(|_| Err(RpcError::new(APPLICATION_ERROR, "Daemon stopped"))),
+                Err(_) => Err(RpcError::new(APPLICATION_ERROR, "Daemon stopped")),
+            }
+        }
+    };
+
+    request.id.map(|id| response(id, result))
+}
+
+async fn serve_connection(stream: UnixStream, calls: mpsc::Sender<Call>, notifications: broadcast::Sender<Event>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut subscription: Option<broadcast::Receiver<Event>> = None;
+
+    loop {
+        let incoming = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => Incoming::Line(line),
+                _ => break,
+            },
+            notification = async { subscription.as_mut()?.recv().await.ok() }, if subscription.is_some() => {
+                Incoming::Notification(notification)
+            }
+        };
+
+        let outgoing = match incoming {
+            Incoming::Line(line) if line.trim().is_empty() => continue,
+            Incoming::Line(line) => match handle_line(&line, &calls, &mut subscription, &notifications).await {
+                Some(response) => response,
+                None => continue,
+            },
+            Incoming::Notification(Some(notification)) => notification.to_json(),
+            // Lagged too far behind: drop the subscription rather than the connection
+            Incoming::Notification(None) => {
+                subscription = None;
+                json!({ "jsonrpc": "2.0", "method": "unsubscribed", "params": { "reason": "lagged" } })
+            }
+        };
+
+        let mut line = outgoing.to_string();
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+enum Incoming {
+    Line(String),
+    Notification(Option<Event>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_validation() {
+        let send: SendParams = params(json!({ "recipient": "bob", "message": "hi" })).unwrap();
+        assert_eq!((send.recipient.as_str(), send.message.as_str()), ("bob", "hi"));
+        assert!(params::<NoParams>(Value::Null).is_ok());
+
+        let error = params::<SendParams>(json!({ "recipient": "bob" })).err().unwrap();
+        assert_eq!(error.code, INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_socket_claims_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = |name: &str| {
+            let config_dir = dir.path().join(name);
+            std::fs::create_dir_all(&config_dir).unwrap();
+            ProfileConfig { name: name.to_string(), config_dir, relays: Vec::new() }
+        };
+        let profiles = [profile("default"), profile("work")];
+        let socket_path = profiles[0].config_dir.join(SOCKET_NAME);
+
+        // Only the owner can connect, and no one else could while it was bound
+        let listener = bind(&socket_path).await.unwrap();
+        assert_eq!(std::fs::metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(bind(&socket_path).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path().join("default")).unwrap().count(), 1);
+
+        // Every served profile leads to the daemon
+        let links = link_profiles(&profiles, &socket_path).unwrap();
+        assert_eq!(links, vec![profiles[1].config_dir.join(SOCKET_NAME)]);
+        for profile in &profiles {
+            assert_eq!(running_daemon(&profile.config_dir).await, Some(socket_path.clone()));
+        }
+
+        // Sockets and links left by a daemon that is gone do not count
+        drop(listener);
+        assert_eq!(running_daemon(&profiles[1].config_dir).await, None);
+        let _listener = bind(&socket_path).await.unwrap();
+        link_profiles(&profiles, &socket_path).unwrap();
+        assert!(running_daemon(&profiles[1].config_dir).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connection_protocol() {
+        let (calls_tx, mut calls_rx) = mpsc::channel::<Call>(4);
+        let (messages, _) = broadcast::channel(4);
+        let mut subscription = None;
+
+        // Stand-in session owner echoing the method name
+        tokio::spawn(async move {
+            while let Some(call) = calls_rx.recv().await {
+                let _ = call.reply.send(match call.method.as_str() {
+                    "info" => Ok(json!({ "method": call.method })),
+                    _ => Err(RpcError::new(METHOD_NOT_FOUND, "Unknown method")),
+                });
+            }
+        });
+
+        let reply = handle_line(r#"{"jsonrpc":"2.0","id":1,"method":"info"}"#, &calls_tx, &mut subscription, &messages)
+            .await
+            .unwrap();
+        assert_eq!(reply, json!({ "jsonrpc": "2.0", "id": 1, "result": { "method": "info" } }));
+
+        let reply = handle_line(r#"{"jsonrpc":"2.0","id":2,"method":"nope"}"#, &calls_tx, &mut subscription, &messages)
+            .await
+            .unwrap();
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+
+        // Malformed input and notifications
+        let reply = handle_line("{not json", &calls_tx, &mut subscription, &messages).await.unwrap();
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+        assert!(handle_line(r#"{"jsonrpc":"2.0","method":"info"}"#, &calls_tx, &mut subscription, &messages)
+            .await
+            .is_none());
+
+        // Subscribing routes notifications to this connection
+        handle_line(r#"{"jsonrpc":"2.0","id":3,"method":"subscribe"}"#, &calls_tx, &mut subscription, &messages).await;
+        assert_eq!(messages.receiver_count(), 1);
+        handle_line(r#"{"jsonrpc":"2.0","id":4,"method":"unsubscribe"}"#, &calls_tx, &mut subscription, &messages).await;
+        assert_eq!(messages.receiver_count(), 0);
+    }
+
+    #[test]
+    fn test_notification_format() {
+        let receipt = Notification::Receipt { message_id: "m1".to_string(), status: ReceiptStatus::Read };
+        assert_eq!(
+            receipt.to_json(),
+            json!({ "jsonrpc": "2.0", "method": "receipt", "params": { "message_id": "m1", "status": "Read" } })
+        );
+        let typing = Notification::Typing { pubkey: "pubkey:abc".to_string(), active: true }.to_json();
+        assert_eq!(typing["method"], "typing");
+        assert_eq!(typing["params"]["active"], true);
+    }
+    #[tokio::test]
+    async fn test_profile_routing() {
+        let (calls_tx, mut calls_rx) = mpsc::channel::<Call>(4);
+        let (messages, _) = broadcast::channel::<Event>(4);
+        let mut subscription = None;
+
+        // The connection strips the profile from the params and routes on it
+        let line = r#"{"jsonrpc":"2.0","id":1,"method":"info","params":{"profile":"work"}}"#;
+        let (reply, call) = tokio::join!(handle_line(line, &calls_tx, &mut subscription, &messages), async {
+            let call = calls_rx.recv().await.unwrap();
+            let routed = (call.profile.clone(), call.params.clone());
+            let _ = call.reply.send(Ok(Value::Null));
+            routed
+        });
+        assert_eq!(reply.unwrap()["result"], Value::Null);
+        assert_eq!(call, (Some("work".to_string()), json!({})));
+
+        let line = r#"{"jsonrpc":"2.0","id":2,"method":"info","params":{"profile":7}}"#;
+        let reply = handle_line(line, &calls_tx, &mut subscription, &messages).await.unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+
+        // Unknown profiles are refused, and notifications say where they came from
+        let (profiles_tx, _profiles_rx) = mpsc::channel(1);
+        let routes = vec![("default".to_string(), profiles_tx)];
+        let (reply, result) = oneshot::channel();
+        route(Call { profile: Some("work".to_string()), method: "info".to_string(), params: json!({}), reply }, &routes).await;
+        assert_eq!(result.await.unwrap().err().unwrap().code, INVALID_PARAMS);
+
+        let event = Event {
+            profile: "work".to_string(),
+            notification: Notification::Expired { message_ids: vec!["m1".to_string()] },
+        };
+        assert_eq!(event.to_json()["params"], json!({ "message_ids": ["m1"], "profile": "work" }));
+    }
+}
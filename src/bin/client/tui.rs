@@ -0,0 +1,1106 @@
+//! Full-screen terminal interface: conversation list, chat view, compose box and contacts
+//!
+//! The relays are polled by a background task that only fetches envelopes; the
+//! UI loop decrypts and stores them so all client state stays on one task. It
+//! also purges expired disappearing messages.
+
+use super::{
+    change_message, compose_message, decorated_text, deliver_group_message, deliver_group_timer, deliver_message, deliver_timer, delivery_marker,
+    direct_conversation_id, flush_outbox, group_conversation_id, inboxes_to_poll, mark_read, poll_inboxes, receive_envelopes,
+    refresh_known_device_lists, save_contact_manager, send_control, send_delivery_receipts, ClientSession, FetchResult, Inboxes,
+    Outgoing, SWEEP_INTERVAL,
+};
+use anyhow::Result;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use nano_messenger::{
+    contacts::{Contact, ContactStatus},
+    content::MessageContent,
+    crypto::CryptoMode,
+    disappearing::DisappearingTimer,
+    messages::StoredMessage,
+    outbox::DeliveryStatus,
+    receipts::{ConversationControl, ReceiptStatus, TYPING_TIMEOUT_SECS},
+};
+use ratatui::{
+    layout::{Constraint, Layout, Position, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+/// Where the compose box sends
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Direct(String), // Pubkey, or a username not yet resolved
+    Group(String),  // Group ID
+}
+
+struct ConversationEntry {
+    id: String,
+    title: String,
+    target: Target,
+    unread: usize,
+    status: Option<ContactStatus>, // None for groups
+    receipt: Option<ReceiptStatus>, // Receipt for the last message, if we sent it
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Focus {
+    Conversations,
+    Compose,
+    Contacts,
+}
+
+/// A one-line question asked in the compose box
+#[derive(Debug, Clone, PartialEq)]
+enum Prompt {
+    NewConversation,
+    Nickname(String), // Contact pubkey
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Prompt::NewConversation => "Username or pubkey",
+            Prompt::Nickname(_) => "Nickname",
+        }
+    }
+}
+
+struct App {
+    session: ClientSession,
+    conversations: Vec<ConversationEntry>,
+    list_state: ListState,
+    contacts: Vec<Contact>,
+    contact_state: ListState,
+    focus: Focus,
+    prompt: Option<Prompt>,
+    input: String,
+    scroll: u16, // Lines scrolled up from the newest message
+    status: String,
+    outgoing: Option<(Target, String)>,
+    controls: Vec<(String, ConversationControl)>, // Receipts and typing notices waiting to be sent
+    typing_sent: Option<(String, chrono::DateTime<chrono::Utc>)>, // Last typing notice we sent
+    quit: bool,
+}
+
+/// Open the interface and run until the user quits
+pub async fn run(config_dir: &Path, relays: &[String], poll_interval: Duration) -> Result<()> {
+    let mut session = ClientSession::load(config_dir, relays)?;
+
+    // Device lists are refreshed once up front; polling only fetches inboxes
+    let warnings = refresh_known_device_lists(&mut session).await;
+
+    let mut app = App::new(session);
+    if let Some(warning) = warnings.last() {
+        app.status = format!("Warning: {}", warning);
+    }
+    if app.session.is_revoked() {
+        app.status = "This device has been revoked by the primary device".to_string();
+    }
+
+    let (inbox_tx, inbox_rx) = watch::channel(inboxes_to_poll(&mut app.session));
+    let (fetched_tx, fetched_rx) = mpsc::channel(1);
+    let poller = tokio::spawn(poll_inboxes(relays.to_vec(), inbox_rx, fetched_tx, poll_interval));
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, inbox_tx, fetched_rx).await;
+    ratatui::restore();
+    poller.abort();
+
+    app.session.save()?;
+    result
+}
+
+/// Short label and colour for the mode a message was signed with
+fn mode_badge(mode: Option<CryptoMode>) -> Span<'static> {
+    // Messages without a recorded mode carry classical signatures
+    match mode.unwrap_or(CryptoMode::Classical) {
+        CryptoMode::Classical => Span::styled("[C]", Style::new().fg(Color::Yellow)),
+        CryptoMode::Hybrid => Span::styled("[H]", Style::new().fg(Color::Cyan)),
+        CryptoMode::Quantum | CryptoMode::QuantumSafe => Span::styled("[Q]", Style::new().fg(Color::Green)),
+    }
+}
+
+fn status_marker(status: Option<ContactStatus>) -> &'static str {
+    match status {
+        None => "👥",
+        Some(ContactStatus::Allowed) => "✓",
+        Some(ContactStatus::Blocked) => "✗",
+        Some(ContactStatus::Unknown) => "?",
+    }
+}
+
+/// Rows a set of lines takes up when wrapped to `width` columns
+fn wrapped_height(lines: &[Line], width: u16) -> usize {
+    let width = usize::from(width.max(1));
+    lines.iter().map(|line| line.width().max(1).div_ceil(width)).sum()
+}
+
+impl App {
+    fn new(session: ClientSession) -> Self {
+        let status = format!(
+            "Connected to {} · Tab: switch pane · n: new chat · c: contacts · q: quit",
+            session.relays.relays().join(", ")
+        );
+        let mut app = Self {
+            session,
+            conversations: Vec::new(),
+            list_state: ListState::default(),
+            contacts: Vec::new(),
+            contact_state: ListState::default(),
+            focus: Focus::Conversations,
+            prompt: None,
+            input: String::new(),
+            scroll: 0,
+            status,
+            outgoing: None,
+            controls: Vec::new(),
+            typing_sent: None,
+            quit: false,
+        };
+        app.refresh_conversations(None);
+        app.refresh_contacts();
+        app
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        inbox_tx: watch::Sender<Inboxes>,
+        mut fetched_rx: mpsc::Receiver<FetchResult>,
+    ) -> Result<()> {
+        let mut events = EventStream::new();
+        let mut sweeper = tokio::time::interval(SWEEP_INTERVAL);
+
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            // Sends run after the "Sending" status has been drawn
+            if let Some((target, text)) = self.outgoing.take() {
+                self.send(target, text).await;
+                inbox_tx.send_if_modified(|inboxes| self.update_inboxes(inboxes));
+                continue;
+            }
+            for (pubkey, control) in std::mem::take(&mut self.controls) {
+                if let Err(e) = send_control(&mut self.session, &pubkey, &control).await {
+                    self.status = format!("Warning: Could not notify {}: {}", self.session.display_name(&pubkey), e);
+                }
+            }
+
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                        if self.handle_key(key) {
+                            // Poll now with a fresh inbox set
+                            inbox_tx.send_replace(inboxes_to_poll(&mut self.session));
+                            self.status = "Checking for new messages...".to_string();
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break,
+                },
+                Some(result) = fetched_rx.recv() => {
+                    self.handle_fetched(result).await;
+                    inbox_tx.send_if_modified(|inboxes| self.update_inboxes(inboxes));
+                }
+                _ = sweeper.tick() => self.sweep(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the polled inbox set if conversations or group epochs moved on
+    fn update_inboxes(&mut self, inboxes: &mut Inboxes) -> bool {
+        let current = inboxes_to_poll(&mut self.session);
+        if current == *inboxes {
+            return false;
+        }
+        *inboxes = current;
+        true
+    }
+
+    fn selected_conversation(&self) -> Option<&ConversationEntry> {
+        self.list_state.selected().and_then(|index| self.conversations.get(index))
+    }
+
+    /// Rebuild the conversation list, keeping the selection or moving it to `select`
+    fn refresh_conversations(&mut self, select: Option<String>) {
+        let selected_id = select.or_else(|| self.selected_conversation().map(|entry| entry.id.clone()));
+        let store = &self.session.message_store;
+        let groups = self.session.group_manager.list();
+
+        let summaries = store.get_conversation_summaries().unwrap_or_else(|e| {
+            self.status = format!("Error: {}", e);
+            Vec::new()
+        });
+        let mut conversations: Vec<ConversationEntry> = summaries
+            .into_iter()
+            .map(|summary| match summary.id.strip_prefix("group:") {
+                Some(group_id) => ConversationEntry {
+                    title: groups
+                        .iter()
+                        .find(|group| group.group_id == group_id)
+                        .map_or(group_id.to_string(), |group| group.name.clone()),
+                    target: Target::Group(group_id.to_string()),
+                    id: summary.id.clone(),
+                    unread: summary.unread_count,
+                    status: None,
+                    receipt: summary.last_receipt,
+                },
+                None => ConversationEntry {
+                    title: self.session.display_name(&summary.other_pubkey),
+                    status: Some(self.session.contact_manager.get_status(&summary.other_pubkey)),
+                    target: Target::Direct(summary.other_pubkey),
+                    id: summary.id,
+                    unread: summary.unread_count,
+                    receipt: summary.last_receipt,
+                },
+            })
+            .collect();
+
+        // Groups without messages yet can still be written to
+        for group in &groups {
+            let id = group_conversation_id(&group.group_id);
+            if !conversations.iter().any(|entry| entry.id == id) {
+                conversations.push(ConversationEntry {
+                    id,
+                    title: group.name.clone(),
+                    target: Target::Group(group.group_id.clone()),
+                    unread: 0,
+                    status: None,
+                    receipt: None,
+                });
+            }
+        }
+
+        // A new chat stays listed until its first message is stored
+        if let Some(pending) = self.conversations.iter().find(|entry| entry.id.is_empty()) {
+            if selected_id.as_deref() == Some("") {
+                conversations.insert(
+                    0,
+                    ConversationEntry {
+                        id: String::new(),
+                        title: pending.title.clone(),
+                        target: pending.target.clone(),
+                        unread: 0,
+                        status: pending.status.clone(),
+                        receipt: None,
+                    },
+                );
+            }
+        }
+
+        self.conversations = conversations;
+        let index = selected_id
+            .and_then(|id| self.conversations.iter().position(|entry| entry.id == id))
+            .or(if self.conversations.is_empty() { None } else { Some(0) });
+        self.list_state.select(index);
+        self.mark_selected_read();
+    }
+
+    fn refresh_contacts(&mut self) {
+        self.contacts = self.session.contact_manager.list_contacts();
+        self.contacts.sort_by_key(|contact| contact.display_name().to_lowercase());
+        let index = match self.contact_state.selected() {
+            _ if self.contacts.is_empty() => None,
+            Some(index) => Some(index.min(self.contacts.len() - 1)),
+            None => Some(0),
+        };
+        self.contact_state.select(index);
+    }
+
+    /// Clear the unread count of the conversation on screen, queueing a read receipt for its sender
+    fn mark_selected_read(&mut self) {
+        let Some(entry) = self.list_state.selected().and_then(|index| self.conversations.get_mut(index)) else {
+            return;
+        };
+        if entry.unread == 0 {
+            return;
+        }
+        entry.unread = 0;
+        let conversation_id = entry.id.clone();
+        match mark_read(&mut self.session, &conversation_id) {
+            Ok(receipt) => self.controls.extend(receipt),
+            Err(e) => self.status = format!("Error: {}", e),
+        }
+    }
+
+    /// Tell the contact on screen we are typing, at most once per half timeout
+    fn note_typing(&mut self) {
+        let Some(Target::Direct(pubkey)) = self.selected_conversation().map(|entry| entry.target.clone()) else {
+            return;
+        };
+        let now = chrono::Utc::now();
+        let recently_sent = self.typing_sent.as_ref().is_some_and(|(sent_to, sent_at)| {
+            *sent_to == pubkey && now - *sent_at < chrono::Duration::seconds(TYPING_TIMEOUT_SECS / 2)
+        });
+        if recently_sent
+            || !pubkey.starts_with("pubkey:")
+            || !self.session.shares_activity(&pubkey, |privacy| privacy.typing_indicators)
+        {
+            return;
+        }
+        self.typing_sent = Some((pubkey.clone(), now));
+        self.controls.push((pubkey, ConversationControl::Typing { active: true }));
+    }
+
+    fn select_conversation(&mut self, offset: isize) {
+        if self.conversations.is_empty() {
+            return;
+        }
+        let current = self.list_state.selected().unwrap_or(0) as isize;
+        let index = (current + offset).rem_euclid(self.conversations.len() as isize) as usize;
+        self.list_state.select(Some(index));
+        self.scroll = 0;
+        self.mark_selected_read();
+    }
+
+    fn select_contact(&mut self, offset: isize) {
+        if self.contacts.is_empty() {
+            return;
+        }
+        let current = self.contact_state.selected().unwrap_or(0) as isize;
+        let index = (current + offset).rem_euclid(self.contacts.len() as isize) as usize;
+        self.contact_state.select(Some(index));
+    }
+
+    fn selected_contact(&self) -> Option<&Contact> {
+        self.contact_state.selected().and_then(|index| self.contacts.get(index))
+    }
+
+    /// Handle a key press; returns true when the user asked to check for messages now
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && matches!(key.code, KeyCode::Char('c') | KeyCode::Char('q')) {
+            self.quit = true;
+            return false;
+        }
+
+        if let Some(prompt) = self.prompt.clone() {
+            self.handle_prompt_key(prompt, key);
+            return false;
+        }
+
+        match (self.focus, key.code) {
+            (_, KeyCode::PageUp) => self.scroll = self.scroll.saturating_add(5),
+            (_, KeyCode::PageDown) => self.scroll = self.scroll.saturating_sub(5),
+            (Focus::Compose, KeyCode::Esc) => self.focus = Focus::Conversations,
+            (Focus::Compose, KeyCode::Tab) => self.focus = Focus::Contacts,
+            (Focus::Compose, KeyCode::Enter) => self.queue_send(),
+            (Focus::Compose, KeyCode::Backspace) => {
+                self.input.pop();
+            }
+            (Focus::Compose, KeyCode::Char(c)) => {
+                self.input.push(c);
+                self.note_typing();
+            }
+            (Focus::Compose, _) => {}
+            (_, KeyCode::Char('q')) => self.quit = true,
+            (_, KeyCode::Char('r')) => return true,
+            (_, KeyCode::Char('n')) => self.start_prompt(Prompt::NewConversation),
+            (Focus::Conversations, KeyCode::Up | KeyCode::Char('k')) => self.select_conversation(-1),
+            (Focus::Conversations, KeyCode::Down | KeyCode::Char('j')) => self.select_conversation(1),
+            (Focus::Conversations, KeyCode::Enter | KeyCode::Tab) => self.focus = Focus::Compose,
+            (Focus::Conversations, KeyCode::Char('c')) => self.focus = Focus::Contacts,
+            (Focus::Contacts, KeyCode::Up | KeyCode::Char('k')) => self.select_contact(-1),
+            (Focus::Contacts, KeyCode::Down | KeyCode::Char('j')) => self.select_contact(1),
+            (Focus::Contacts, KeyCode::Esc | KeyCode::Tab | KeyCode::Char('c')) => self.focus = Focus::Conversations,
+            (Focus::Contacts, KeyCode::Char('a')) => self.update_contact(ContactAction::Allow),
+            (Focus::Contacts, KeyCode::Char('b')) => self.update_contact(ContactAction::Block),
+            (Focus::Contacts, KeyCode::Char('d')) => self.update_contact(ContactAction::Remove),
+            (Focus::Contacts, KeyCode::Char('e')) => {
+                if let Some(contact) = self.selected_contact() {
+                    self.start_prompt(Prompt::Nickname(contact.permission.pubkey.clone()));
+                }
+            }
+            (Focus::Contacts, KeyCode::Enter | KeyCode::Char('m')) => self.open_contact_conversation(),
+            _ => {}
+        }
+        false
+    }
+
+    fn start_prompt(&mut self, prompt: Prompt) {
+        self.prompt = Some(prompt);
+        self.input.clear();
+    }
+
+    fn handle_prompt_key(&mut self, prompt: Prompt, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => {
+                self.prompt = None;
+                self.input.clear();
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Enter => {
+                let value = std::mem::take(&mut self.input).trim().to_string();
+                self.prompt = None;
+                match prompt {
+                    Prompt::NewConversation if !value.is_empty() => self.open_conversation(&value),
+                    Prompt::NewConversation => {}
+                    Prompt::Nickname(pubkey) => self.set_nickname(&pubkey, value),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Select the conversation with a recipient, or list a new one for them
+    fn open_conversation(&mut self, recipient: &str) {
+        let pubkey = if recipient.starts_with("pubkey:") {
+            Some(recipient)
+        } else {
+            self.session.contact_manager.get_pubkey_for_username(recipient)
+        };
+        let existing = pubkey.map(|pubkey| direct_conversation_id(&self.session.identity, pubkey));
+
+        if let Some(index) = existing.and_then(|id| self.conversations.iter().position(|entry| entry.id == id)) {
+            self.list_state.select(Some(index));
+        } else {
+            self.conversations.retain(|entry| !entry.id.is_empty());
+            self.conversations.insert(
+                0,
+                ConversationEntry {
+                    id: String::new(),
+                    title: recipient.to_string(),
+                    target: Target::Direct(recipient.to_string()),
+                    unread: 0,
+                    status: None,
+                    receipt: None,
+                },
+            );
+            self.list_state.select(Some(0));
+        }
+
+        self.scroll = 0;
+        self.focus = Focus::Compose;
+        self.mark_selected_read();
+    }
+
+    fn open_contact_conversation(&mut self) {
+        if let Some(pubkey) = self.selected_contact().map(|contact| contact.permission.pubkey.clone()) {
+            self.open_conversation(&pubkey);
+        }
+    }
+
+    fn queue_send(&mut self) {
+        let text = self.input.trim().to_string();
+        if text.is_empty() {
+            return;
+        }
+        let Some(target) = self.selected_conversation().map(|entry| entry.target.clone()) else {
+            self.status = "Select a conversation or press n to start one".to_string();
+            return;
+        };
+
+        self.input.clear();
+        self.status = "Sending...".to_string();
+        self.typing_sent = None; // The message ends our typing indicator
+        self.outgoing = Some((target, text));
+    }
+
+    async fn send(&mut self, target: Target, text: String) {
+        let changed = match self.set_timer(&target, &text).await {
+            Some(result) => Some(result),
+            None => self.change_latest(&target, &text).await,
+        };
+        if let Some(changed) = changed {
+            match changed.and_then(|warnings| self.session.save().map(|()| warnings)) {
+                Ok(warnings) => {
+                    self.status = match warnings.last() {
+                        Some(warning) => format!("Updated · Warning: {}", warning),
+                        None => "Updated".to_string(),
+                    };
+                    self.refresh_conversations(None);
+                }
+                Err(e) => {
+                    self.input = text;
+                    self.status = format!("Failed to update: {}", e);
+                }
+            }
+            return;
+        }
+
+        let message = match self.compose(&target, &text) {
+            Ok(message) => message,
+            Err(e) => {
+                self.input = text;
+                self.status = format!("Failed to send: {}", e);
+                return;
+            }
+        };
+        let sent = match &target {
+            Target::Direct(recipient) => deliver_message(&mut self.session, recipient, message, &[])
+                .await
+                .map(|sent| {
+                    let mut warnings = sent.warnings;
+                    if sent.status == DeliveryStatus::Pending {
+                        warnings.push("Relays unreachable, queued in the outbox".to_string());
+                    }
+                    (direct_conversation_id(&self.session.identity, &sent.recipient_pubkey), warnings)
+                }),
+            Target::Group(group_id) => match self.session.group_manager.get_mut(group_id) {
+                Some(group) => deliver_group_message(
+                    &mut self.session.relays,
+                    &self.session.keypair,
+                    self.session.crypto.padding,
+                    group,
+                    &mut self.session.message_store,
+                    message,
+                )
+                .await
+                .map(|()| (group_conversation_id(group_id), Vec::new())),
+                None => Err(anyhow::anyhow!("Unknown group: {}", group_id)),
+            },
+        };
+
+        match sent.and_then(|sent| self.session.save().map(|()| sent)) {
+            Ok((conversation_id, warnings)) => {
+                self.status = match warnings.last() {
+                    Some(warning) => format!("Sent · Warning: {}", warning),
+                    None => "Sent".to_string(),
+                };
+                self.scroll = 0;
+                self.refresh_conversations(Some(conversation_id));
+            }
+            Err(e) => {
+                // Give the text back so it can be retried
+                self.input = text;
+                self.status = format!("Failed to send: {}", e);
+            }
+        }
+    }
+
+    /// Run `/edit <text>` or `/delete` on our latest message here, or `/react [emoji]` on the latest one we got; `None` for plain messages
+    async fn change_latest(&mut self, target: &Target, text: &str) -> Option<Result<Vec<String>>> {
+        let (command, argument) = text.split_once(' ').unwrap_or((text, ""));
+        let argument = argument.trim().to_string();
+        let ours = match command {
+            "/edit" | "/delete" => true,
+            "/react" => false,
+            _ => return None,
+        };
+
+        let message_id = match self.latest_message(target, ours) {
+            Ok(Some(message_id)) => message_id,
+            Ok(None) => return Some(Err(anyhow::anyhow!("No message to change"))),
+            Err(e) => return Some(Err(e)),
+        };
+
+        let session = &mut self.session;
+        Some(match command {
+            "/edit" if argument.is_empty() => Err(anyhow::anyhow!("Usage: /edit <new text>")),
+            "/edit" => change_message(session, &message_id, |target| MessageContent::Edit { target, body: argument }).await,
+            "/delete" => change_message(session, &message_id, |target| MessageContent::Delete { target }).await,
+            _ => match (!argument.is_empty()).then(|| argument.parse()).transpose() {
+                Ok(reaction) => change_message(session, &message_id, |target| MessageContent::React { target, reaction }).await,
+                Err(e) => Err(anyhow::Error::from(e)),
+            },
+        })
+    }
+
+    /// Message for the compose text; `/reply <text>` replies to the latest message we got here
+    fn compose(&self, target: &Target, text: &str) -> Result<Outgoing> {
+        let (text, reply_to) = match text.strip_prefix("/reply ") {
+            Some(text) => {
+                let latest = self.latest_message(target, false)?.ok_or_else(|| anyhow::anyhow!("No message to reply to"))?;
+                (text.trim(), Some(latest))
+            }
+            None => (text, None),
+        };
+        compose_message(&self.session.message_store, &self.session.contact_manager, text, reply_to.as_deref()).map(Outgoing::from)
+    }
+
+    /// ID of the latest message here that we sent, or that we got
+    fn latest_message(&self, target: &Target, ours: bool) -> Result<Option<String>> {
+        let conversation_id = match target {
+            Target::Direct(pubkey) => direct_conversation_id(&self.session.identity, pubkey),
+            Target::Group(group_id) => group_conversation_id(group_id),
+        };
+        Ok(self.session
+            .message_store
+            .get_conversation_messages(&conversation_id, None)?
+            .into_iter()
+            .rev()
+            .find(|message| message.is_outgoing == ours && !message.deleted)
+            .map(|message| message.id))
+    }
+
+    /// Run `/disappear <duration|off> [read]`; `None` for anything else
+    async fn set_timer(&mut self, target: &Target, text: &str) -> Option<Result<Vec<String>>> {
+        let mut words = text.split_whitespace();
+        if words.next() != Some("/disappear") {
+            return None;
+        }
+        let timer = match (words.next(), words.next()) {
+            (Some(duration), after_read) => DisappearingTimer::parse(duration, after_read == Some("read")),
+            (None, _) => return Some(Err(anyhow::anyhow!("Usage: /disappear <30s|10m|1h|1d|1w|off> [read]"))),
+        };
+        let timer = match timer {
+            Ok(timer) => timer,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        let session = &mut self.session;
+        Some(match target {
+            Target::Direct(recipient) => deliver_timer(session, recipient, timer).await.map(|sent| sent.warnings),
+            Target::Group(group_id) => match session.group_manager.get_mut(group_id) {
+                Some(group) => deliver_group_timer(&mut session.relays, &session.keypair, session.crypto.padding, group, &mut session.message_store, timer)
+                    .await
+                    .map(|()| Vec::new()),
+                None => Err(anyhow::anyhow!("Unknown group: {}", group_id)),
+            },
+        })
+    }
+
+    /// Purge expired disappearing messages, redrawing if any went
+    fn sweep(&mut self) {
+        match self.session.message_store.purge_expired(chrono::Utc::now()) {
+            Ok(purged) if !purged.is_empty() => self.refresh_conversations(None),
+            Ok(_) => {}
+            Err(e) => self.status = format!("Error: Failed to purge expired messages: {}", e),
+        }
+    }
+
+    async fn handle_fetched(&mut self, result: FetchResult) {
+        // Retry queued sends on every poll
+        let flushed = flush_outbox(&mut self.session).await;
+        if flushed.delivered > 0 {
+            self.refresh_conversations(None);
+        }
+
+        let fetched = match result {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                self.status = format!("Relays unreachable: {}", e);
+                return;
+            }
+        };
+
+        let received = receive_envelopes(&mut self.session, fetched).await;
+        let receipt_warnings = send_delivery_receipts(&mut self.session, &received).await;
+        if let Some(warning) = flushed.warnings.iter().chain(&received.warnings).chain(&receipt_warnings).last() {
+            self.status = format!("Warning: {}", warning);
+        }
+        if received.messages.is_empty() {
+            if !received.receipts.is_empty() || !received.typing.is_empty() || !received.updated.is_empty() {
+                self.refresh_conversations(None);
+            }
+            if !received.requests.is_empty() {
+                self.status = match self.session.save() {
+                    Ok(()) => format!("{} new message request(s) · review with `nano-client requests list`", received.requests.len()),
+                    Err(e) => format!("Error: {}", e),
+                };
+            } else if received.warnings.is_empty() && flushed.warnings.is_empty() && receipt_warnings.is_empty() {
+                self.status = format!("Up to date · {}", chrono::Local::now().format("%H:%M:%S"));
+            }
+            return;
+        }
+
+        if let Err(e) = self.session.save() {
+            self.status = format!("Error: {}", e);
+            return;
+        }
+
+        let (_, latest) = &received.messages[received.messages.len() - 1];
+        self.status = format!(
+            "{} new message(s) · latest from {}",
+            received.messages.len(),
+            self.session.display_name(&latest.from_pubkey)
+        );
+        self.refresh_conversations(None);
+    }
+
+    fn update_contact(&mut self, action: ContactAction) {
+        let Some(pubkey) = self.selected_contact().map(|contact| contact.permission.pubkey.clone()) else {
+            return;
+        };
+        let manager = &mut self.session.contact_manager;
+        let result = match action {
+            ContactAction::Allow => manager.allow_contact(pubkey.clone()).map(|()| "allowed"),
+            ContactAction::Block => manager.block_contact(pubkey.clone()).map(|()| "blocked"),
+            ContactAction::Remove => {
+                manager.remove_contact(&pubkey);
+                Ok("removed")
+            }
+        };
+        self.finish_contact_update(result.map_err(Into::into), &pubkey);
+    }
+
+    fn set_nickname(&mut self, pubkey: &str, nickname: String) {
+        let result = self
+            .session
+            .contact_manager
+            .update_metadata(pubkey, Some(nickname), None)
+            .map(|()| "renamed");
+        self.finish_contact_update(result.map_err(Into::into), pubkey);
+    }
+
+    fn finish_contact_update(&mut self, result: Result<&str>, pubkey: &str) {
+        let result = result.and_then(|done| {
+            save_contact_manager(&self.session.config_dir, &self.session.contact_manager)?;
+            Ok(done)
+        });
+        self.status = match result {
+            Ok(done) => format!("Contact {} {}", self.session.display_name(pubkey), done),
+            Err(e) => format!("Error: {}", e),
+        };
+        self.refresh_contacts();
+        self.refresh_conversations(None);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, compose, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3), Constraint::Length(1)]).areas(frame.area());
+        let [sidebar, content] = Layout::horizontal([Constraint::Percentage(30), Constraint::Min(20)]).areas(main);
+
+        self.draw_conversations(frame, sidebar);
+        if self.focus == Focus::Contacts {
+            self.draw_contacts(frame, content);
+        } else {
+            self.draw_chat(frame, content);
+        }
+        self.draw_compose(frame, compose);
+        frame.render_widget(Paragraph::new(self.status.as_str()).reversed(), status);
+    }
+
+    fn pane_block(&self, title: String, focused: bool) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if focused {
+            block.border_style(Style::new().fg(Color::Cyan))
+        } else {
+            block
+        }
+    }
+
+    fn draw_conversations(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .conversations
+            .iter()
+            .map(|entry| {
+                let mut spans = vec![Span::raw(format!("{} ", status_marker(entry.status.clone()))), Span::raw(entry.title.clone())];
+                if entry.unread > 0 {
+                    spans.push(Span::styled(format!(" ({})", entry.unread), Style::new().fg(Color::Magenta).bold()));
+                } else if entry.receipt.is_some() {
+                    spans.push(Span::styled(format!(" {}", delivery_marker(None, entry.receipt)), Style::new().dim()));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+
+        let unread: usize = self.conversations.iter().map(|entry| entry.unread).sum();
+        let title = match unread {
+            0 => " Conversations ".to_string(),
+            unread => format!(" Conversations ({} unread) ", unread),
+        };
+        let list = List::new(items)
+            .block(self.pane_block(title, self.focus == Focus::Conversations))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.list_state);
+    }
+
+    fn message_line(&self, message: &StoredMessage) -> Line<'static> {
+        let sender = if message.is_outgoing {
+            Span::styled("me".to_string(), Style::new().fg(Color::Blue).bold())
+        } else {
+            Span::styled(self.session.display_name(&message.from_pubkey), Style::new().bold())
+        };
+        let mut line = Line::from(vec![
+            Span::styled(message.timestamp.with_timezone(&chrono::Local).format("%H:%M ").to_string(), Style::new().dim()),
+            mode_badge(message.crypto_mode),
+            Span::raw(" "),
+            sender,
+            Span::raw(": "),
+            if message.deleted {
+                Span::styled(decorated_text(message), Style::new().dim().italic())
+            } else {
+                Span::raw(decorated_text(message))
+            },
+        ]);
+        if message.is_outgoing {
+            let status = self.session.message_store.delivery_status(&message.id);
+            let style = match status {
+                Some(DeliveryStatus::Failed) => Style::new().fg(Color::Red),
+                _ => Style::new().dim(),
+            };
+            let style = match message.receipt {
+                Some(ReceiptStatus::Read) => Style::new().fg(Color::Cyan),
+                _ => style,
+            };
+            line.push_span(Span::styled(format!(" {}", delivery_marker(status, message.receipt)), style));
+        }
+        line
+    }
+
+    fn draw_chat(&mut self, frame: &mut Frame, area: Rect) {
+        let Some(entry) = self.selected_conversation() else {
+            let block = self.pane_block(" Chat ".to_string(), false);
+            frame.render_widget(Paragraph::new("No conversations yet. Press n to start one.").block(block), area);
+            return;
+        };
+
+        let mut title = match &entry.target {
+            Target::Direct(pubkey) if *pubkey != entry.title => format!(" {} · {} ", entry.title, pubkey),
+            _ => format!(" {} ", entry.title),
+        };
+        let timer = self.session.message_store.timer(&entry.id);
+        if !timer.is_off() {
+            title.push_str(&format!("· ⏱ {} ", timer));
+        }
+        if let Target::Direct(pubkey) = &entry.target {
+            if self.session.typing.is_typing(pubkey, chrono::Utc::now()) {
+                title.push_str("· typing… ");
+            }
+        }
+        let messages = self.session.message_store.get_conversation_messages(&entry.id, None).unwrap_or_else(|e| {
+            self.status = format!("Error: {}", e);
+            Vec::new()
+        });
+        let lines: Vec<Line> = messages.iter().map(|message| self.message_line(message)).collect();
+
+        // Keep the newest message at the bottom unless scrolled back
+        let inner_height = area.height.saturating_sub(2);
+        let total = wrapped_height(&lines, area.width.saturating_sub(2));
+        let max_scroll = total.saturating_sub(usize::from(inner_height));
+        self.scroll = self.scroll.min(u16::try_from(max_scroll).unwrap_or(u16::MAX));
+        let top = u16::try_from(max_scroll).unwrap_or(u16::MAX) - self.scroll;
+
+        let chat = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .scroll((top, 0))
+            .block(self.pane_block(title, false));
+        frame.render_widget(chat, area);
+    }
+
+    fn draw_contacts(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .contacts
+            .iter()
+            .map(|contact| {
+                let mut lines = vec![Line::from(vec![
+                    Span::raw(format!("{} ", status_marker(Some(contact.permission.status.clone())))),
+                    Span::raw(if contact.is_favorite() { "★ " } else { "" }),
+                    Span::styled(contact.display_name().to_string(), Style::new().bold()),
+                    Span::styled(format!("  {}", contact.permission.pubkey), Style::new().dim()),
+                ])];
+                if let Some(memo) = contact.metadata.as_ref().map(|metadata| &metadata.memo).filter(|memo| !memo.is_empty()) {
+                    lines.push(Line::from(format!("    {}", memo)).dim());
+                }
+                if !contact.labels().is_empty() {
+                    lines.push(Line::from(format!("    {}", contact.labels().join(", "))).dim());
+                }
+                ListItem::new(lines)
+            })
+            .collect();
+
+        let title = format!(" Contacts ({}) · a: allow · b: block · d: remove · e: nickname · m: message ", self.contacts.len());
+        let list = List::new(items)
+            .block(self.pane_block(title, true))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.contact_state);
+    }
+
+    fn draw_compose(&self, frame: &mut Frame, area: Rect) {
+        let (title, focused) = match &self.prompt {
+            Some(prompt) => (format!(" {} (Enter to confirm, Esc to cancel) ", prompt.label()), true),
+            None => (
+                " Message (Enter to send, Esc to leave · /reply <text>, /edit <text>, /delete, /react [emoji], /disappear <time|off> [read]) ".to_string(),
+                self.focus == Focus::Compose,
+            ),
+        };
+
+        // Show the end of long input
+        let width = usize::from(area.width.saturating_sub(2).max(1));
+        let chars: Vec<char> = self.input.chars().collect();
+        let visible: String = chars[chars.len().saturating_sub(width - 1)..].iter().collect();
+        frame.render_widget(Paragraph::new(visible.as_str()).block(self.pane_block(title, focused)), area);
+
+        if focused {
+            let x = area.x + 1 + visible.chars().count() as u16;
+            frame.set_cursor_position(Position::new(x, area.y + 1));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ContactAction {
+    Allow,
+    Block,
+    Remove,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{init_user, load_contact_manager};
+    use nano_messenger::{
+        crypto::UserKeyPair,
+        group::{GroupMember, GroupState},
+        protocol::MessagePayload,
+    };
+
+    fn open_app() -> (tempfile::TempDir, App) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_path_buf();
+        init_user(&config_dir, CryptoMode::Classical).unwrap();
+        let session = ClientSession::load(&config_dir, &["127.0.0.1:7733".to_string()]).unwrap();
+        (dir, App::new(session))
+    }
+
+    fn press(app: &mut App, code: KeyCode) -> bool {
+        app.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_text(app: &mut App, text: &str) {
+        for c in text.chars() {
+            press(app, KeyCode::Char(c));
+        }
+    }
+
+    /// Store a message from `sender` so the conversation with them is listed
+    fn receive_from(app: &mut App, sender: &UserKeyPair) {
+        let mut payload = MessagePayload::new(sender.public_key_string(), "hello".to_string(), 1, None);
+        payload.sign(&sender.signing_key).unwrap();
+        let message = StoredMessage::from_payload(payload, app.session.identity.clone(), chrono::Utc::now(), false);
+        app.session.message_store.store_message(message).unwrap();
+        app.refresh_conversations(None);
+    }
+
+    #[test]
+    fn test_focus_and_keys() {
+        let (_dir, mut app) = open_app();
+        assert_eq!(app.focus, Focus::Conversations);
+
+        press(&mut app, KeyCode::Char('c'));
+        assert_eq!(app.focus, Focus::Contacts);
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.focus, Focus::Conversations);
+        assert!(press(&mut app, KeyCode::Char('r')));
+
+        // Letters typed into the compose box are text, not commands
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.focus, Focus::Compose);
+        type_text(&mut app, "qr");
+        press(&mut app, KeyCode::Backspace);
+        assert_eq!(app.input, "q");
+        assert!(!app.quit);
+        press(&mut app, KeyCode::PageUp);
+        assert_eq!(app.scroll, 5);
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.focus, Focus::Contacts);
+        press(&mut app, KeyCode::Esc);
+        assert_eq!(app.focus, Focus::Conversations);
+
+        // Prompts take every key until answered or cancelled
+        press(&mut app, KeyCode::Char('n'));
+        assert_eq!(app.prompt, Some(Prompt::NewConversation));
+        assert!(app.input.is_empty());
+        type_text(&mut app, "q");
+        assert!(!app.quit);
+        press(&mut app, KeyCode::Esc);
+        assert_eq!(app.prompt, None);
+        assert!(app.input.is_empty());
+
+        // Ctrl+C quits from anywhere
+        press(&mut app, KeyCode::Char('n'));
+        app.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL));
+        assert!(app.quit);
+
+        let (_dir, mut app) = open_app();
+        press(&mut app, KeyCode::Char('q'));
+        assert!(app.quit);
+    }
+
+    #[test]
+    fn test_compose_target_resolution() {
+        let (_dir, mut app) = open_app();
+
+        // Nothing to send to yet
+        press(&mut app, KeyCode::Enter);
+        type_text(&mut app, "hi");
+        press(&mut app, KeyCode::Enter);
+        assert!(app.outgoing.is_none());
+        assert_eq!(app.input, "hi");
+        press(&mut app, KeyCode::Esc);
+
+        // A username with a stored conversation opens that conversation
+        let bob = UserKeyPair::generate();
+        let bob_pubkey = bob.public_key_string();
+        app.session.contact_manager.set_username(bob_pubkey.clone(), "bob".to_string());
+        receive_from(&mut app, &bob);
+        press(&mut app, KeyCode::Char('n'));
+        type_text(&mut app, " bob ");
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.focus, Focus::Compose);
+        assert_eq!(app.selected_conversation().unwrap().target, Target::Direct(bob_pubkey.clone()));
+        assert_eq!(app.conversations.len(), 1);
+        type_text(&mut app, "hi bob");
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.outgoing.take(), Some((Target::Direct(bob_pubkey), "hi bob".to_string())));
+        assert!(app.input.is_empty());
+
+        // Unknown recipients get a pending entry, resolved when sending
+        press(&mut app, KeyCode::Esc);
+        press(&mut app, KeyCode::Char('n'));
+        type_text(&mut app, "carol");
+        press(&mut app, KeyCode::Enter);
+        let pending = app.selected_conversation().unwrap();
+        assert!(pending.id.is_empty());
+        assert_eq!(pending.target, Target::Direct("carol".to_string()));
+
+        // Groups are listed before their first message
+        let group = GroupState::create("team".to_string(), GroupMember::new(app.session.keypair.public_keys()), Vec::new());
+        let group_id = group.group_id.clone();
+        app.session.group_manager.insert(group);
+        app.refresh_conversations(Some(group_conversation_id(&group_id)));
+        assert_eq!(app.selected_conversation().unwrap().title, "team");
+        type_text(&mut app, "hi team");
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.outgoing, Some((Target::Group(group_id), "hi team".to_string())));
+    }
+
+    #[test]
+    fn test_update_contact() {
+        let (dir, mut app) = open_app();
+        let bob = UserKeyPair::generate().public_key_string();
+
+        // Nothing is selected without contacts
+        press(&mut app, KeyCode::Char('c'));
+        press(&mut app, KeyCode::Char('b'));
+        assert!(app.contacts.is_empty());
+
+        app.session.contact_manager.allow_contact(bob.clone()).unwrap();
+        app.refresh_contacts();
+        press(&mut app, KeyCode::Char('b'));
+        assert_eq!(app.session.contact_manager.get_status(&bob), ContactStatus::Blocked);
+        assert!(app.status.ends_with("blocked"));
+
+        // Changes are saved straight away
+        let saved = load_contact_manager(&dir.path().to_path_buf()).unwrap();
+        assert_eq!(saved.get_status(&bob), ContactStatus::Blocked);
+
+        press(&mut app, KeyCode::Char('a'));
+        assert_eq!(app.session.contact_manager.get_status(&bob), ContactStatus::Allowed);
+
+        press(&mut app, KeyCode::Char('e'));
+        assert_eq!(app.prompt, Some(Prompt::Nickname(bob.clone())));
+        type_text(&mut app, "Bobby");
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.contacts[0].display_name(), "Bobby");
+        assert_eq!(app.status, "Contact Bobby renamed");
+
+        press(&mut app, KeyCode::Char('d'));
+        assert!(app.contacts.is_empty());
+        assert_eq!(app.contact_state.selected(), None);
+        assert!(load_contact_manager(&dir.path().to_path_buf()).unwrap().list_contacts().is_empty());
+    }
+}
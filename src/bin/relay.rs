@@ -1,13 +1,30 @@
 use nano_messenger::{
-    protocol::{MessageEnvelope, QuantumSafeEnvelope, ProtocolMessage, UsernameClaim},
+    protocol::{MessageEnvelope, QuantumSafeEnvelope, ProtocolMessage, UsernameClaim, REPLAY_REJECTION},
     username::UsernameRegistry,
-    crypto::{CryptoMode},
+    crypto::{CryptoMode, X25519PrivateKey},
+    devices::{DeviceDirectory, DeviceList},
+    error::NanoError,
+    network::RelayClient,
+    blobs::{BlobStore, BlobStoreConfig},
+    media::storage::LocalFileStorage,
+    onion::{OnionLayer, OnionPacket},
+    replay::{payload_digest, NonceCache, NonceCheck, ReplayConfig, RotatingBloomFilter},
+    traffic::{MixConfig, MixPool},
 };
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use rand::rngs::OsRng;
+use tempfile::TempDir;
 use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use clap::Parser;
 use anyhow::Result as AnyhowResult;
 use serde::{Serialize, Deserialize};
@@ -51,6 +68,118 @@ pub struct Cli {
     /// Log all crypto policy decisions for compliance
     #[arg(long)]
     log_crypto_policy: bool,
+    
+    /// How long envelope nonces are remembered for replay protection (seconds)
+    #[arg(long, default_value = "86400")]
+    replay_window: u64,
+    
+    /// Expected envelope nonces per inbox within one replay window
+    #[arg(long, default_value = "1000")]
+    replay_capacity: usize,
+    
+    /// Most envelopes remembered per inbox within one replay window; past it the oldest are forgotten early
+    #[arg(long, default_value = "16000")]
+    replay_limit: usize,
+    
+    /// Minimum mixing delay before a message becomes fetchable (milliseconds)
+    #[arg(long, default_value = "0")]
+    mix_min_delay_ms: u64,
+    
+    /// Maximum mixing delay before a message becomes fetchable (milliseconds, 0 disables mixing)
+    #[arg(long, default_value = "0")]
+    mix_max_delay_ms: u64,
+    
+    /// How often delayed messages are released in a shuffled batch (milliseconds)
+    #[arg(long, default_value = "500")]
+    mix_flush_interval_ms: u64,
+    
+    /// File holding the relay's onion routing key (created if missing; a fresh key is used if unset)
+    #[arg(long)]
+    onion_key_file: Option<PathBuf>,
+    
+    /// Relay that onion packets may be forwarded to (repeatable); packets for any other next hop are refused
+    #[arg(long = "onion-peer")]
+    onion_peers: Vec<String>,
+    
+    /// Expected onion packets within one replay window
+    #[arg(long, default_value = "100000")]
+    onion_replay_capacity: usize,
+    
+    /// Most onion packets remembered within one replay window; past it the oldest are forgotten early
+    #[arg(long, default_value = "1600000")]
+    onion_replay_limit: usize,
+    
+    /// Directory for attachment blobs (a temporary directory removed on exit if unset)
+    #[arg(long)]
+    blob_dir: Option<PathBuf>,
+    
+    /// Total size of all stored attachment blobs (megabytes)
+    #[arg(long, default_value = "1024")]
+    blob_quota_mb: u64,
+    
+    /// Total size of the attachment blobs one client address may hold (megabytes)
+    #[arg(long, default_value = "256")]
+    blob_client_quota_mb: u64,
+    
+    /// Largest attachment blob accepted (megabytes)
+    #[arg(long, default_value = "128")]
+    max_blob_mb: u64,
+    
+    /// How long attachment blobs are kept after they are created (seconds)
+    #[arg(long, default_value = "604800")] // 7 days
+    blob_ttl: u64,
+    
+    /// How long an unfinished upload is kept without receiving a chunk (seconds)
+    #[arg(long, default_value = "600")]
+    blob_upload_idle: u64,
+}
+
+/// Largest protocol message accepted on one line
+const MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+
+/// Load the onion key from `path`, creating it if needed
+fn load_onion_key(path: Option<&Path>) -> AnyhowResult<X25519PrivateKey> {
+    let Some(path) = path else {
+        return Ok(X25519PrivateKey::random_from_rng(OsRng));
+    };
+    
+    if path.exists() {
+        let bytes: [u8; 32] = hex::decode(std::fs::read_to_string(path)?.trim())?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid onion key length in {}", path.display()))?;
+        Ok(X25519PrivateKey::from(bytes))
+    } else {
+        let key = X25519PrivateKey::random_from_rng(OsRng);
+        // Readable by the relay's user only
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(hex::encode(key.to_bytes()).as_bytes())?;
+        Ok(key)
+    }
+}
+
+impl Cli {
+    fn mix_config(&self) -> MixConfig {
+        MixConfig {
+            min_delay: Duration::from_millis(self.mix_min_delay_ms),
+            max_delay: Duration::from_millis(self.mix_max_delay_ms),
+            flush_interval: Duration::from_millis(self.mix_flush_interval_ms.max(1)),
+        }
+    }
+    
+    fn blob_config(&self) -> BlobStoreConfig {
+        BlobStoreConfig {
+            max_blob_bytes: self.max_blob_mb * 1024 * 1024,
+            quota_bytes: self.blob_quota_mb * 1024 * 1024,
+            client_quota_bytes: self.blob_client_quota_mb * 1024 * 1024,
+            ttl: Duration::from_secs(self.blob_ttl),
+            upload_idle: Duration::from_secs(self.blob_upload_idle),
+        }
+    }
 }
 
 /// Crypto policy configuration for the relay
@@ -178,7 +307,7 @@ impl StoredMessage {
         }
     }
     
-    fn _inbox_id(&self) -> &str {
+    fn inbox_id(&self) -> &str {
         match self {
             StoredMessage::Legacy(envelope) => &envelope.inbox_id,
             StoredMessage::QuantumSafe(envelope) => &envelope.inbox_id,
@@ -193,28 +322,37 @@ impl StoredMessage {
     }
 }
 
+/// A stored message together with the time the relay accepted it
+struct InboxEntry {
+    message: StoredMessage,
+    stored_at: Instant,
+}
+
 /// Enhanced inbox storage supporting mixed message types
 struct InboxStorage {
-    messages: Vec<StoredMessage>,
-    last_cleanup: std::time::Instant,
+    messages: Vec<InboxEntry>,
+    last_cleanup: Instant,
+    ttl: Duration,
 }
 
-impl Default for InboxStorage {
-    fn default() -> Self {
+impl InboxStorage {
+    fn new(ttl: Duration) -> Self {
         Self {
             messages: Vec::new(),
-            last_cleanup: std::time::Instant::now(),
+            last_cleanup: Instant::now(),
+            ttl,
         }
     }
-}
-
-impl InboxStorage {
+    
     fn add_message(&mut self, message: StoredMessage, max_size: usize) {
         // Remove expired messages
         self.cleanup_expired();
         
         // Add new message
-        self.messages.push(message);
+        self.messages.push(InboxEntry {
+            message,
+            stored_at: Instant::now(),
+        });
         
         // Limit cache size (remove oldest if needed)
         if self.messages.len() > max_size {
@@ -225,7 +363,7 @@ impl InboxStorage {
     fn _get_legacy_messages(&mut self) -> Vec<MessageEnvelope> {
         self.cleanup_expired();
         self.messages.iter()
-            .filter_map(|msg| match msg {
+            .filter_map(|entry| match &entry.message {
                 StoredMessage::Legacy(envelope) => Some(envelope.clone()),
                 StoredMessage::QuantumSafe(envelope) => {
                     // Convert quantum-safe to legacy if possible
@@ -242,7 +380,7 @@ impl InboxStorage {
     fn get_quantum_safe_messages(&mut self) -> Vec<QuantumSafeEnvelope> {
         self.cleanup_expired();
         self.messages.iter()
-            .map(|msg| match msg {
+            .map(|entry| match &entry.message {
                 StoredMessage::Legacy(envelope) => QuantumSafeEnvelope::from_legacy(envelope.clone()),
                 StoredMessage::QuantumSafe(envelope) => envelope.clone(),
             })
@@ -250,37 +388,97 @@ impl InboxStorage {
     }
     
     fn cleanup_expired(&mut self) {
-        let now = std::time::Instant::now();
+        let now = Instant::now();
         
         // Only cleanup once per minute to avoid overhead
         if now.duration_since(self.last_cleanup).as_secs() < 60 {
             return;
         }
         
-        self.messages.retain(|msg| !msg.is_expired());
+        self.purge(now);
+    }
+    
+    /// Drop messages past their envelope expiry or the relay TTL, returning how many were removed
+    fn purge(&mut self, now: Instant) -> usize {
+        let before = self.messages.len();
+        let ttl = self.ttl;
+        
+        self.messages.retain(|entry| {
+            !entry.message.is_expired() && now.duration_since(entry.stored_at) < ttl
+        });
         self.last_cleanup = now;
+        
+        before - self.messages.len()
     }
+    
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// Purge every inbox and forget inboxes left empty (e.g. those only ever hit by cover traffic)
+fn purge_inboxes(inboxes: &mut HashMap<String, InboxStorage>, now: Instant) -> usize {
+    let removed = inboxes.values_mut().map(|inbox| inbox.purge(now)).sum();
+    inboxes.retain(|_, inbox| !inbox.is_empty());
+    removed
 }
 
 /// Enhanced relay server with crypto policy enforcement
 struct RelayServer {
     inboxes: Arc<RwLock<HashMap<String, InboxStorage>>>,
+    blobs: Arc<BlobStore>,
+    _blob_dir: Option<Arc<TempDir>>, // Removed with the last server handle
     usernames: Arc<RwLock<UsernameRegistry>>,
+    device_lists: Arc<RwLock<DeviceDirectory>>,
     config: Cli,
     crypto_policy: CryptoPolicyConfig,
     policy_stats: Arc<RwLock<PolicyStats>>,
+    nonce_cache: Arc<RwLock<NonceCache>>,
+    onion_replay: Arc<RwLock<RotatingBloomFilter>>, // Kept apart so inbox traffic never evicts or fills it
+    mix_pool: Arc<RwLock<MixPool<StoredMessage>>>,
+    onion_key: Arc<X25519PrivateKey>,
 }
 
 impl RelayServer {
-    fn new(config: Cli) -> AnyhowResult<Self> {
+    async fn new(config: Cli) -> AnyhowResult<Self> {
         let crypto_policy = CryptoPolicyConfig::from_cli(&config)?;
+        let replay_config = ReplayConfig {
+            expected_nonces: config.replay_capacity,
+            max_nonces: config.replay_limit,
+            window: std::time::Duration::from_secs(config.replay_window),
+            ..ReplayConfig::default()
+        };
+        
+        let onion_key = load_onion_key(config.onion_key_file.as_deref())?;
+        let onion_replay = RotatingBloomFilter::new(
+            config.onion_replay_capacity,
+            config.onion_replay_limit,
+            replay_config.false_positive_rate,
+            replay_config.window,
+        );
+        
+        let blob_dir = match &config.blob_dir {
+            Some(_) => None,
+            None => Some(Arc::new(TempDir::with_prefix("nano-relay-blobs-")?)),
+        };
+        let blob_path = config.blob_dir.clone()
+            .unwrap_or_else(|| blob_dir.as_ref().map(|dir| dir.path().to_path_buf()).unwrap_or_default());
+        let blob_storage = LocalFileStorage::new(blob_path.clone()).await?;
+        let blob_store = BlobStore::open(Arc::new(blob_storage), config.blob_config(), blob_path.join("index")).await?;
         
         Ok(Self {
             inboxes: Arc::new(RwLock::new(HashMap::new())),
+            blobs: Arc::new(blob_store),
+            _blob_dir: blob_dir,
             usernames: Arc::new(RwLock::new(UsernameRegistry::new())),
-            config,
+            device_lists: Arc::new(RwLock::new(DeviceDirectory::new())),
             crypto_policy,
             policy_stats: Arc::new(RwLock::new(PolicyStats::default())),
+            nonce_cache: Arc::new(RwLock::new(NonceCache::new(replay_config))),
+            onion_replay: Arc::new(RwLock::new(onion_replay)),
+            mix_pool: Arc::new(RwLock::new(MixPool::new(config.mix_config()))),
+            onion_key: Arc::new(onion_key),
+            config,
         })
     }
     
@@ -288,6 +486,12 @@ impl RelayServer {
         let addr = format!("{}:{}", self.config.address, self.config.port);
         let listener = TcpListener::bind(&addr).await?;
         
+        self.serve(listener).await
+    }
+    
+    async fn serve(&self, listener: TcpListener) -> AnyhowResult<()> {
+        let addr = listener.local_addr()?;
+        
         println!("🚀 Nano-relay server listening on {}", addr);
         println!("📬 Ready to relay encrypted messages");
         println!("🛡️  Crypto Policy Configuration:");
@@ -296,9 +500,57 @@ impl RelayServer {
         println!("   Reject classical: {}", self.crypto_policy.reject_classical);
         println!("   Adaptive recommendations: {}", self.crypto_policy.adaptive_recommendations);
         println!("   Policy logging: {}", self.crypto_policy.log_policy_decisions);
+        println!("🔁 Replay protection: {}s window, {} nonces expected and at most {} per inbox", 
+                 self.config.replay_window, self.config.replay_capacity, self.config.replay_limit);
+        println!("⏳ Message TTL: {}s", self.config.message_ttl);
+        println!("🧅 Onion key: {}", hex::encode(nano_messenger::crypto::X25519PublicKey::from(&*self.onion_key).as_bytes()));
+        println!("🧅 Onion peers: {}", if self.config.onion_peers.is_empty() { "none (final hop only)".to_string() } else { self.config.onion_peers.join(", ") });
+        
+        let mix_config = self.config.mix_config();
+        if mix_config.is_enabled() {
+            println!("🔀 Mixing: {}-{}ms delay, flushed every {}ms", 
+                     self.config.mix_min_delay_ms, self.config.mix_max_delay_ms,
+                     mix_config.flush_interval.as_millis());
+            
+            // Release delayed messages into their inboxes in shuffled batches
+            let server = self.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(mix_config.flush_interval);
+                loop {
+                    interval.tick().await;
+                    let batch = server.mix_pool.write().await.drain_ready();
+                    for message in batch {
+                        server.store_message(message).await;
+                    }
+                }
+            });
+        }
+        
+        // Purge messages and attachments past the relay TTL, including undelivered cover traffic
+        let inboxes_clone = Arc::clone(&self.inboxes);
+        let blobs_clone = Arc::clone(&self.blobs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let mut inboxes = inboxes_clone.write().await;
+                let removed = purge_inboxes(&mut inboxes, Instant::now());
+                if removed > 0 {
+                    println!("🧹 Purged {} expired messages, {} inboxes active", removed, inboxes.len());
+                }
+                
+                drop(inboxes);
+                
+                let removed = blobs_clone.purge(Utc::now()).await;
+                if removed > 0 {
+                    println!("🧹 Purged {} expired attachment blobs, {} active", removed, blobs_clone.len().await);
+                }
+            }
+        });
         
         // Start stats monitoring task
         let stats_clone = Arc::clone(&self.policy_stats);
+        let nonce_cache_clone = Arc::clone(&self.nonce_cache);
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300)); // 5 minutes
             loop {
@@ -307,6 +559,13 @@ impl RelayServer {
                 println!("📊 Policy Stats: {} total, {} accepted, {} rejected, {} violations", 
                          stats.total_messages, stats.accepted_messages, 
                          stats.rejected_messages, stats.policy_violations);
+                
+                // Drop nonce filters for idle inboxes
+                let mut nonce_cache = nonce_cache_clone.write().await;
+                nonce_cache.prune();
+                println!("🔁 Replay Stats: {} inboxes tracked, {} replays rejected, ~{} KB", 
+                         nonce_cache.tracked_inboxes(), nonce_cache.replays_rejected(),
+                         nonce_cache.size_bytes() / 1024);
             }
         });
         
@@ -316,7 +575,7 @@ impl RelayServer {
                     println!("📡 New connection from {}", addr);
                     let server = self.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = server.handle_connection(stream).await {
+                        if let Err(e) = server.handle_connection(stream, addr).await {
                             eprintln!("❌ Error handling connection from {}: {}", addr, e);
                         }
                     });
@@ -328,36 +587,39 @@ impl RelayServer {
         }
     }
     
-    async fn handle_connection(&self, mut stream: TcpStream) -> AnyhowResult<()> {
-        let mut buffer = vec![0; 8192]; // Increased buffer for quantum-safe messages
+    async fn handle_connection(&self, mut stream: TcpStream, peer: SocketAddr) -> AnyhowResult<()> {
+        // Blob quotas are charged to the client's address, not the connection
+        let client = peer.ip().to_string();
+        let (read_half, mut write_half) = stream.split();
+        let mut reader = BufReader::new(read_half);
         
+        // One newline-terminated JSON message per line, so large onion packets are read whole
         loop {
-            match stream.read(&mut buffer).await {
+            let mut line = String::new();
+            let n = (&mut reader).take(MAX_MESSAGE_BYTES).read_line(&mut line).await;
+            
+            match n {
                 Ok(0) => {
                     // Connection closed
                     break;
                 }
-                Ok(n) => {
-                    let data = String::from_utf8_lossy(&buffer[..n]);
+                Ok(_) => {
+                    let data = line.trim();
+                    if data.is_empty() {
+                        continue;
+                    }
                     
                     // Try to parse as JSON protocol message
-                    match serde_json::from_str::<ProtocolMessage>(&data) {
-                        Ok(message) => {
-                            let response = self.handle_protocol_message(message).await;
-                            let response_json = serde_json::to_string(&response)?;
-                            
-                            stream.write_all(response_json.as_bytes()).await?;
-                            stream.write_all(b"\n").await?;
-                        }
-                        Err(e) => {
-                            let error_response = ProtocolMessage::Error {
-                                message: format!("Invalid JSON: {}", e),
-                            };
-                            let response_json = serde_json::to_string(&error_response)?;
-                            stream.write_all(response_json.as_bytes()).await?;
-                            stream.write_all(b"\n").await?;
-                        }
-                    }
+                    let response = match serde_json::from_str::<ProtocolMessage>(data) {
+                        Ok(message) => self.handle_protocol_message(message, &client).await,
+                        Err(e) => ProtocolMessage::Error {
+                            message: format!("Invalid JSON: {}", e),
+                        },
+                    };
+                    
+                    let response_json = serde_json::to_string(&response)?;
+                    write_half.write_all(response_json.as_bytes()).await?;
+                    write_half.write_all(b"\n").await?;
                 }
                 Err(e) => {
                     eprintln!("❌ Error reading from connection: {}", e);
@@ -369,7 +631,7 @@ impl RelayServer {
         Ok(())
     }
     
-    async fn handle_protocol_message(&self, message: ProtocolMessage) -> ProtocolMessage {
+    async fn handle_protocol_message(&self, message: ProtocolMessage, client: &str) -> ProtocolMessage {
         match message {
             ProtocolMessage::SendMessage { envelope } => {
                 self.handle_send_legacy_message(envelope).await
@@ -386,6 +648,59 @@ impl RelayServer {
             ProtocolMessage::LookupUsername { username } => {
                 self.handle_lookup_username(username).await
             }
+            ProtocolMessage::LookupClaim { username } => {
+                let claim = self.usernames.read().await.find_claim(&username).cloned();
+                ProtocolMessage::ClaimResult { username, claim }
+            }
+            ProtocolMessage::PublishDeviceList { list } => {
+                self.handle_publish_device_list(list).await
+            }
+            ProtocolMessage::LookupDevices { identity } => {
+                let list = self.device_lists.read().await.lookup(&identity).cloned();
+                ProtocolMessage::DeviceListResult { identity, list }
+            }
+            ProtocolMessage::RelayOnion { packet } => {
+                self.handle_onion_packet(packet).await
+            }
+            ProtocolMessage::SendMlsMessage { inbox_id, ciphersuite, message } => {
+                match message.to_envelope(inbox_id, ciphersuite) {
+                    Ok(envelope) => self.handle_send_quantum_safe_message(envelope).await,
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Invalid MLS message: {}", e),
+                    },
+                }
+            }
+            ProtocolMessage::UploadBlob { size } => {
+                match self.blobs.create(size, client).await {
+                    Ok(grant) => ProtocolMessage::BlobCreated { grant },
+                    Err(e) => ProtocolMessage::Error { message: e.to_string() },
+                }
+            }
+            ProtocolMessage::UploadChunk { blob_id, token, offset, data } => {
+                self.handle_upload_chunk(blob_id, token, offset, data).await
+            }
+            ProtocolMessage::DownloadBlob { blob_id, token, offset, length } => {
+                match self.blobs.read(&blob_id, &token, offset, length).await {
+                    Ok(data) => ProtocolMessage::BlobData {
+                        blob_id,
+                        offset,
+                        data: general_purpose::STANDARD.encode(data),
+                    },
+                    Err(e) => ProtocolMessage::Error { message: e.to_string() },
+                }
+            }
+            ProtocolMessage::DeleteBlob { blob_id, token } => {
+                match self.blobs.delete(&blob_id, &token).await {
+                    Ok(()) => ProtocolMessage::Success { message: format!("Blob {} deleted", blob_id) },
+                    Err(e) => ProtocolMessage::Error { message: e.to_string() },
+                }
+            }
+            ProtocolMessage::GetRelayInfo => {
+                let public_key = nano_messenger::crypto::X25519PublicKey::from(&*self.onion_key);
+                ProtocolMessage::RelayInfo {
+                    onion_key: hex::encode(public_key.as_bytes()),
+                }
+            }
             _ => ProtocolMessage::Error {
                 message: "Unsupported message type".to_string(),
             },
@@ -428,10 +743,13 @@ impl RelayServer {
             };
         }
         
+        // Reject replayed envelopes
+        if let Err(reason) = self.check_envelope_replay(&envelope.inbox_id, &envelope.payload).await {
+            return reason;
+        }
+        
         // Store message in the target inbox
-        let mut inboxes = self.inboxes.write().await;
-        let inbox = inboxes.entry(envelope.inbox_id.clone()).or_default();
-        inbox.add_message(StoredMessage::Legacy(envelope.clone()), self.config.max_cache_size);
+        self.accept_message(StoredMessage::Legacy(envelope.clone())).await;
         
         // Log policy acceptance
         if self.crypto_policy.log_policy_decisions {
@@ -484,10 +802,13 @@ impl RelayServer {
             };
         }
         
+        // Reject replayed envelopes
+        if let Err(reason) = self.check_envelope_replay(&envelope.inbox_id, &envelope.payload).await {
+            return reason;
+        }
+        
         // Store message in the target inbox
-        let mut inboxes = self.inboxes.write().await;
-        let inbox = inboxes.entry(envelope.inbox_id.clone()).or_default();
-        inbox.add_message(StoredMessage::QuantumSafe(envelope.clone()), self.config.max_cache_size);
+        self.accept_message(StoredMessage::QuantumSafe(envelope.clone())).await;
         
         // Log policy acceptance with crypto mode details
         if self.crypto_policy.log_policy_decisions {
@@ -514,6 +835,94 @@ impl RelayServer {
         }
     }
     
+    /// Peel one onion layer, then forward the inner packet or deliver the envelope
+    async fn handle_onion_packet(&self, packet: OnionPacket) -> ProtocolMessage {
+        // Reject replayed packets before spending a key agreement on them
+        if self.onion_replay.write().await.check_and_insert(packet.replay_id().as_bytes()) == NonceCheck::Replayed {
+            println!("🔁 Onion packet rejected: already seen");
+            return ProtocolMessage::Error {
+                message: format!("{} Onion packet already seen", REPLAY_REJECTION),
+            };
+        }
+        
+        match packet.peel(&self.onion_key) {
+            Ok(OnionLayer::Deliver { envelope }) => {
+                println!("🧅 Onion packet reached its final hop");
+                self.handle_send_quantum_safe_message(envelope).await
+            }
+            Ok(OnionLayer::Forward { next_hop, .. }) if !self.config.onion_peers.contains(&next_hop) => {
+                println!("🧅 Onion packet refused: next hop is not a known relay");
+                ProtocolMessage::Error {
+                    message: format!("Onion next hop {} is not a known relay", next_hop),
+                }
+            }
+            Ok(OnionLayer::Forward { next_hop, packet }) => {
+                // Peeling already padded the packet back to full size; hold it for a mixing delay
+                let delay = self.config.mix_config().sample_delay(&mut rand::thread_rng());
+                
+                println!("🧅 Forwarding onion packet to next hop");
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    if let Err(e) = RelayClient::new(next_hop).send_onion(packet).await {
+                        eprintln!("❌ Failed to forward onion packet: {}", e);
+                    }
+                });
+                
+                ProtocolMessage::Success {
+                    message: "Onion packet accepted".to_string(),
+                }
+            }
+            Err(e) => ProtocolMessage::Error {
+                message: format!("Invalid onion packet: {}", e),
+            },
+        }
+    }
+    
+    /// Store an accepted message, holding it in the mix pool first when mixing is enabled
+    async fn accept_message(&self, message: StoredMessage) {
+        if self.config.mix_config().is_enabled() {
+            self.mix_pool.write().await.push(message);
+        } else {
+            self.store_message(message).await;
+        }
+    }
+    
+    /// Make a message fetchable from its inbox
+    async fn store_message(&self, message: StoredMessage) {
+        let ttl = Duration::from_secs(self.config.message_ttl);
+        let mut inboxes = self.inboxes.write().await;
+        let inbox = inboxes.entry(message.inbox_id().to_string())
+            .or_insert_with(|| InboxStorage::new(ttl));
+        inbox.add_message(message, self.config.max_cache_size);
+    }
+    
+    /// Check an envelope against its inbox's replay cache, keyed on the ciphertext
+    ///
+    /// The nonce an envelope carries is unsigned and could be swapped for a
+    /// fresh one, so only the payload counts.
+    async fn check_envelope_replay(&self, inbox_id: &str, payload: &str) -> Result<(), ProtocolMessage> {
+        match general_purpose::STANDARD.decode(payload) {
+            Ok(payload) => self.check_replay(inbox_id, &payload_digest(&payload)).await,
+            Err(e) => Err(ProtocolMessage::Error {
+                message: format!("Message rejected: invalid payload: {}", e),
+            }),
+        }
+    }
+    
+    /// Check a replay key against the per-inbox seen-nonce cache
+    async fn check_replay(&self, inbox_id: &str, nonce: &str) -> Result<(), ProtocolMessage> {
+        let mut nonce_cache = self.nonce_cache.write().await;
+        
+        nonce_cache.verify(inbox_id, nonce).map_err(|e| {
+            println!("🔁 Envelope rejected for inbox {}: {}", &inbox_id[..inbox_id.len().min(8)], e);
+            let message = match e {
+                NanoError::ReplayDetected(reason) => format!("{} {}", REPLAY_REJECTION, reason),
+                e => format!("Message rejected: {}", e),
+            };
+            ProtocolMessage::Error { message }
+        })
+    }
+    
     async fn handle_fetch_inbox(&self, inbox_id: String) -> ProtocolMessage {
         let mut inboxes = self.inboxes.write().await;
         
@@ -532,6 +941,22 @@ impl RelayServer {
         }
     }
     
+    async fn handle_upload_chunk(&self, blob_id: String, token: String, offset: u64, data: String) -> ProtocolMessage {
+        let chunk = match general_purpose::STANDARD.decode(data) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return ProtocolMessage::Error {
+                    message: format!("Invalid chunk data: {}", e),
+                };
+            }
+        };
+        
+        match self.blobs.append(&blob_id, &token, offset, &chunk).await {
+            Ok(received) => ProtocolMessage::BlobProgress { blob_id, received },
+            Err(e) => ProtocolMessage::Error { message: e.to_string() },
+        }
+    }
+    
     async fn handle_publish_claim(&self, claim: UsernameClaim) -> ProtocolMessage {
         // Verify claim signature
         if let Err(e) = claim.verify_signature() {
@@ -574,6 +999,23 @@ impl RelayServer {
         }
     }
     
+    async fn handle_publish_device_list(&self, list: DeviceList) -> ProtocolMessage {
+        let mut directory = self.device_lists.write().await;
+        let (version, devices) = (list.version, list.devices.len());
+        
+        match directory.publish(list) {
+            Ok(()) => {
+                println!("📱 Device list v{} published ({} devices)", version, devices);
+                ProtocolMessage::Success {
+                    message: format!("Device list v{} published", version),
+                }
+            }
+            Err(e) => ProtocolMessage::Error {
+                message: format!("Failed to publish device list: {}", e),
+            },
+        }
+    }
+    
     /// Get current policy statistics (for monitoring/admin interface)
     pub async fn get_policy_stats(&self) -> PolicyStats {
         self.policy_stats.read().await.clone()
@@ -584,7 +1026,10 @@ impl Clone for RelayServer {
     fn clone(&self) -> Self {
         Self {
             inboxes: Arc::clone(&self.inboxes),
+            blobs: Arc::clone(&self.blobs),
+            _blob_dir: self._blob_dir.clone(),
             usernames: Arc::clone(&self.usernames),
+            device_lists: Arc::clone(&self.device_lists),
             config: Cli {
                 port: self.config.port,
                 address: self.config.address.clone(),
@@ -595,9 +1040,29 @@ impl Clone for RelayServer {
                 adaptive_recommendations: self.config.adaptive_recommendations,
                 reject_classical: self.config.reject_classical,
                 log_crypto_policy: self.config.log_crypto_policy,
+                replay_window: self.config.replay_window,
+                replay_capacity: self.config.replay_capacity,
+                replay_limit: self.config.replay_limit,
+                mix_min_delay_ms: self.config.mix_min_delay_ms,
+                mix_max_delay_ms: self.config.mix_max_delay_ms,
+                mix_flush_interval_ms: self.config.mix_flush_interval_ms,
+                onion_key_file: self.config.onion_key_file.clone(),
+                onion_peers: self.config.onion_peers.clone(),
+                onion_replay_capacity: self.config.onion_replay_capacity,
+                onion_replay_limit: self.config.onion_replay_limit,
+                blob_dir: self.config.blob_dir.clone(),
+                blob_quota_mb: self.config.blob_quota_mb,
+                blob_client_quota_mb: self.config.blob_client_quota_mb,
+                max_blob_mb: self.config.max_blob_mb,
+                blob_ttl: self.config.blob_ttl,
+                blob_upload_idle: self.config.blob_upload_idle,
             },
             crypto_policy: self.crypto_policy.clone(),
             policy_stats: Arc::clone(&self.policy_stats),
+            nonce_cache: Arc::clone(&self.nonce_cache),
+            onion_replay: Arc::clone(&self.onion_replay),
+            mix_pool: Arc::clone(&self.mix_pool),
+            onion_key: Arc::clone(&self.onion_key),
         }
     }
 }
@@ -612,7 +1077,7 @@ async fn main() -> AnyhowResult<()> {
         eprintln!("   Consider setting --minimum-crypto-mode hybrid or quantum");
     }
     
-    let server = RelayServer::new(config)?;
+    let server = RelayServer::new(config).await?;
     
     println!("🛡️  Session 5: Relay with Crypto Policy Enforcement");
     println!("=====================================================");
@@ -647,3 +1112,203 @@ async fn main() -> AnyhowResult<()> {
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_messenger::{
+        blobs::MAX_CHUNK_BYTES,
+        crypto::PaddingScheme,
+        mls::{ApplicationMessage, MlsCiphersuite, MlsMessage},
+        onion::OnionHop,
+        traffic::{CoverTrafficConfig, CoverTrafficGenerator},
+    };
+    use tokio::net::TcpListener;
+    
+    fn dummy_message() -> StoredMessage {
+        let generator = CoverTrafficGenerator::new(CoverTrafficConfig::default(), PaddingScheme::Padme);
+        StoredMessage::Legacy(generator.dummy_envelope().unwrap())
+    }
+    
+    #[test]
+    fn test_cover_traffic_purged_after_ttl() {
+        let ttl = Duration::from_secs(60);
+        let mut inboxes: HashMap<String, InboxStorage> = HashMap::new();
+        
+        for _ in 0..5 {
+            let message = dummy_message();
+            inboxes.entry(message.inbox_id().to_string())
+                .or_insert_with(|| InboxStorage::new(ttl))
+                .add_message(message, 100);
+        }
+        assert_eq!(inboxes.len(), 5);
+        
+        // Nothing is dropped before the TTL
+        assert_eq!(purge_inboxes(&mut inboxes, Instant::now()), 0);
+        
+        // Unfetched dummies and their inboxes are gone once the TTL passes
+        let later = Instant::now() + ttl;
+        assert_eq!(purge_inboxes(&mut inboxes, later), 5);
+        assert!(inboxes.is_empty());
+    }
+    
+    async fn spawn_relay() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = RelayServer::new(Cli::parse_from(["nano-relay"])).await.unwrap();
+        tokio::spawn(async move { server.serve(listener).await });
+        address
+    }
+    
+    async fn fetch_quantum_inbox(address: &str, inbox_id: &str) -> Vec<QuantumSafeEnvelope> {
+        let request = ProtocolMessage::FetchInbox { inbox_id: inbox_id.to_string() };
+        match RelayClient::new(address.to_string()).send_message(request).await.unwrap() {
+            ProtocolMessage::QuantumInboxMessages { messages } => messages,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_onion_delivery_across_local_relays() {
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addresses: Vec<String> = listeners.iter().map(|l| l.local_addr().unwrap().to_string()).collect();
+        
+        // Each relay only forwards to the others
+        let mut hops = Vec::new();
+        for listener in listeners {
+            let mut args = vec!["nano-relay".to_string()];
+            for address in &addresses {
+                args.extend(["--onion-peer".to_string(), address.clone()]);
+            }
+            let server = RelayServer::new(Cli::parse_from(args)).await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            tokio::spawn(async move { server.serve(listener).await });
+            hops.push(RelayClient::new(address).get_onion_hop().await.unwrap());
+        }
+        
+        let inbox_id = "ab".repeat(32);
+        let envelope = QuantumSafeEnvelope::new(CryptoMode::Classical, inbox_id.clone(), b"sealed".to_vec());
+        let packet = OnionPacket::build(&hops, envelope.clone()).unwrap();
+        RelayClient::new(hops[0].address.clone()).send_onion(packet.clone()).await.unwrap();
+        
+        // Forwarding is asynchronous, so poll the final relay
+        let mut delivered = Vec::new();
+        for _ in 0..50 {
+            delivered = fetch_quantum_inbox(&hops[2].address, &inbox_id).await;
+            if !delivered.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].payload, envelope.payload);
+        
+        // Earlier hops never store the envelope
+        assert!(fetch_quantum_inbox(&hops[0].address, &inbox_id).await.is_empty());
+        assert!(fetch_quantum_inbox(&hops[1].address, &inbox_id).await.is_empty());
+        
+        // A replayed packet is refused by the entry relay
+        assert!(matches!(
+            RelayClient::new(hops[0].address.clone()).send_onion(packet).await,
+            Err(NanoError::ReplayDetected(_))
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_onion_forward_to_unknown_relay_is_refused() {
+        let entry = RelayClient::new(spawn_relay().await).get_onion_hop().await.unwrap();
+        let key = X25519PrivateKey::random_from_rng(OsRng);
+        let elsewhere = OnionHop::new("10.0.0.1:80".to_string(), nano_messenger::crypto::X25519PublicKey::from(&key));
+        
+        let envelope = QuantumSafeEnvelope::new(CryptoMode::Classical, "ab".repeat(32), b"sealed".to_vec());
+        let packet = OnionPacket::build(&[entry.clone(), elsewhere], envelope).unwrap();
+        assert!(RelayClient::new(entry.address).send_onion(packet).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_resent_mls_message_is_refused() {
+        let address = spawn_relay().await;
+        let client = RelayClient::new(address.clone());
+        let message = MlsMessage::Application(ApplicationMessage {
+            group_id: "group".to_string(),
+            epoch: 3,
+            sender: 1,
+            generation: 7,
+            ciphertext: vec![9; 48],
+        });
+        
+        client.send_mls_message("mls_inbox_0000".to_string(), MlsCiphersuite::Classical, message.clone()).await.unwrap();
+        assert!(client.send_mls_message("mls_inbox_0000".to_string(), MlsCiphersuite::Classical, message).await.is_err());
+        assert_eq!(fetch_quantum_inbox(&address, "mls_inbox_0000").await.len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_reposted_ciphertext_with_fresh_nonce_is_refused() {
+        let address = spawn_relay().await;
+        let client = RelayClient::new(address.clone());
+        let envelope = QuantumSafeEnvelope::new(CryptoMode::Classical, "ab".repeat(32), b"captured".to_vec());
+        client.send_quantum_envelope(envelope.clone()).await.unwrap();
+        
+        // The nonce is unsigned, so swapping it must not get the copy stored
+        let mut reposted = envelope;
+        reposted.nonce = general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+        assert!(matches!(client.send_quantum_envelope(reposted).await, Err(NanoError::ReplayDetected(_))));
+        assert_eq!(fetch_quantum_inbox(&address, &"ab".repeat(32)).await.len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_blob_upload_resume_and_download() {
+        let client = RelayClient::new(spawn_relay().await);
+        let content: Vec<u8> = (0..MAX_CHUNK_BYTES as u32 + 100).map(|i| (i % 253) as u8).collect();
+        let grant = client.create_blob(content.len() as u64).await.unwrap();
+        let (id, upload, download) = (&grant.blob_id, &grant.upload_token, &grant.download_token);
+        
+        // The first chunk lands, then an empty chunk reports where to resume
+        assert_eq!(client.upload_chunk(id.clone(), upload.clone(), 0, &content[..1000]).await.unwrap(), 1000);
+        assert_eq!(client.upload_chunk(id.clone(), upload.clone(), 0, &[]).await.unwrap(), 1000);
+        assert!(client.download_blob(id.clone(), download.clone(), 0, 10).await.is_err());
+        let received = client.upload_chunk(id.clone(), upload.clone(), 1000, &content[1000..MAX_CHUNK_BYTES]).await.unwrap();
+        assert_eq!(client.upload_chunk(id.clone(), upload.clone(), received, &content[MAX_CHUNK_BYTES..]).await.unwrap(), content.len() as u64);
+        
+        let head = client.download_blob(id.clone(), download.clone(), 0, u64::MAX).await.unwrap();
+        assert_eq!(head, &content[..MAX_CHUNK_BYTES]);
+        assert_eq!(client.download_blob(id.clone(), download.clone(), MAX_CHUNK_BYTES as u64, 1000).await.unwrap(), &content[MAX_CHUNK_BYTES..]);
+        
+        // Only the download token reads, only the upload token deletes
+        assert!(client.download_blob(id.clone(), upload.clone(), 0, 10).await.is_err());
+        assert!(client.delete_blob(id.clone(), download.clone()).await.is_err());
+        client.delete_blob(id.clone(), upload.clone()).await.unwrap();
+        assert!(client.download_blob(id.clone(), download.clone(), 0, 10).await.is_err());
+        
+        // Blobs over the size limit are refused up front
+        assert!(client.create_blob(129 * 1024 * 1024).await.is_err());
+    }
+    
+    #[test]
+    fn test_mix_config_from_cli() {
+        let cli = Cli::parse_from(["nano-relay", "--mix-min-delay-ms", "100", "--mix-max-delay-ms", "2000"]);
+        let mix = cli.mix_config();
+        
+        assert!(mix.is_enabled());
+        assert_eq!(mix.min_delay, Duration::from_millis(100));
+        assert_eq!(mix.max_delay, Duration::from_millis(2000));
+        
+        let default_cli = Cli::parse_from(["nano-relay"]);
+        assert!(!default_cli.mix_config().is_enabled());
+    }
+    
+    #[test]
+    fn test_onion_key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("onion.key");
+        let key = load_onion_key(Some(&path)).unwrap();
+        
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(load_onion_key(Some(&path)).unwrap().to_bytes(), key.to_bytes());
+    }
+}
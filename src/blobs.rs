@@ -0,0 +1,542 @@
+//! Relay-hosted store for encrypted attachment blobs
+//!
+//! A blob is created with its final size and filled by appending chunks at
+//! byte offsets, so an interrupted upload resumes where the relay left off.
+//! Creating a blob hands out two capability tokens: the upload token appends
+//! and deletes, the download token reads the finished blob. The relay keeps
+//! only their hashes, and the content is ciphertext it cannot open.
+//!
+//! Reservations count against a quota for each client as well as the
+//! relay's total, and an upload that stops sending chunks is dropped long
+//! before the blob's lifetime runs out. With an index directory the blob
+//! records survive a restart, so stored content is still served and purged.
+
+use crate::crypto::hash_sha256;
+use crate::error::{NanoError, Result};
+use crate::media::storage::{FileId, FileStorage, StorageLocation};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Largest chunk appended or read in one request, in bytes
+pub const MAX_CHUNK_BYTES: usize = 512 * 1024;
+
+/// Limits of a relay's blob store
+#[derive(Debug, Clone, Copy)]
+pub struct BlobStoreConfig {
+    /// Largest blob that can be created
+    pub max_blob_bytes: u64,
+    /// Total size of all live blobs, counted from creation
+    pub quota_bytes: u64,
+    /// Total size of the live blobs one client may hold
+    pub client_quota_bytes: u64,
+    /// How long a blob lives after it is created
+    pub ttl: Duration,
+    /// How long an unfinished upload is kept without a new chunk
+    pub upload_idle: Duration,
+}
+
+impl Default for BlobStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_blob_bytes: 128 * 1024 * 1024,
+            quota_bytes: 1024 * 1024 * 1024,
+            client_quota_bytes: 256 * 1024 * 1024,
+            ttl: Duration::from_secs(7 * 86400),
+            upload_idle: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Capability tokens returned to the creator of a blob
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobGrant {
+    pub blob_id: String,
+    pub upload_token: String,
+    pub download_token: String,
+    /// Seconds until the relay drops the blob
+    pub expires_in: u64,
+}
+
+/// Where a finished blob lives, and the token that reads it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobLink {
+    pub relay: String,
+    pub blob_id: String,
+    pub size: u64,
+    pub token: String,
+}
+
+/// Bytes of a blob kept as one stored file
+#[derive(Clone, Serialize, Deserialize)]
+struct Piece {
+    offset: u64,
+    location: StorageLocation,
+    len: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Blob {
+    size: u64,
+    received: u64,
+    pieces: Vec<Piece>,
+    upload_token: [u8; 32],
+    download_token: [u8; 32],
+    owner: String, // Client that created it, for its quota
+    expires_at: DateTime<Utc>,
+    idle_deadline: DateTime<Utc>, // Dropped unfinished after this unless a chunk arrives
+    #[serde(skip)]
+    busy: bool, // A chunk is being stored or the record written
+}
+
+impl Blob {
+    fn is_finished(&self) -> bool {
+        self.received >= self.size
+    }
+
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        now < self.expires_at && (self.is_finished() || now < self.idle_deadline)
+    }
+}
+
+/// Blobs of one relay, with their content in a `FileStorage` backend
+///
+/// The blob records sit behind a lock that is never held while content is
+/// stored or read, so transfers of different blobs run side by side.
+pub struct BlobStore {
+    storage: Arc<dyn FileStorage>,
+    config: BlobStoreConfig,
+    index_dir: Option<PathBuf>, // One JSON record per blob when persistent
+    blobs: Mutex<HashMap<String, Blob>>,
+}
+
+impl BlobStore {
+    /// A store whose blob records live in memory only
+    pub fn new(storage: Arc<dyn FileStorage>, config: BlobStoreConfig) -> Self {
+        Self {
+            storage,
+            config,
+            index_dir: None,
+            blobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A store keeping its blob records in `index_dir`, loading those left by an earlier run
+    ///
+    /// Unfinished uploads get a fresh idle deadline so their clients can resume.
+    pub async fn open(storage: Arc<dyn FileStorage>, config: BlobStoreConfig, index_dir: PathBuf) -> Result<Self> {
+        tokio::fs::create_dir_all(&index_dir).await?;
+        let mut blobs = HashMap::new();
+        let mut entries = tokio::fs::read_dir(&index_dir).await?;
+        let now = Utc::now();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(blob_id) = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string) else {
+                continue;
+            };
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            match serde_json::from_slice::<Blob>(&tokio::fs::read(&path).await?) {
+                Ok(mut blob) => {
+                    blob.idle_deadline = blob.idle_deadline.max(now + config.upload_idle);
+                    blobs.insert(blob_id, blob);
+                }
+                Err(e) => eprintln!("⚠️  Ignoring unreadable blob record {}: {}", path.display(), e),
+            }
+        }
+
+        let store = Self {
+            storage,
+            config,
+            index_dir: Some(index_dir),
+            blobs: Mutex::new(blobs),
+        };
+        store.purge(now).await;
+        Ok(store)
+    }
+
+    /// Bytes reserved by live blobs, whether or not they are uploaded yet
+    pub async fn reserved_bytes(&self) -> u64 {
+        let now = Utc::now();
+        self.blobs.lock().await.values().filter(|blob| blob.is_live(now)).map(|blob| blob.size).sum()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.blobs.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.blobs.lock().await.is_empty()
+    }
+
+    /// Reserve a blob of `size` bytes for `owner` and hand out its tokens
+    pub async fn create(&self, size: u64, owner: &str) -> Result<BlobGrant> {
+        if size > self.config.max_blob_bytes {
+            return Err(NanoError::Storage(format!(
+                "Blob of {} bytes is larger than the {} byte limit",
+                size, self.config.max_blob_bytes
+            )));
+        }
+
+        let blob_id = FileId::new_v4().to_string();
+        let upload_token = new_token();
+        let download_token = new_token();
+        let now = Utc::now();
+        let blob = Blob {
+            size,
+            received: 0,
+            pieces: Vec::new(),
+            upload_token: hash_sha256(upload_token.as_bytes()),
+            download_token: hash_sha256(download_token.as_bytes()),
+            owner: owner.to_string(),
+            expires_at: now + self.config.ttl,
+            idle_deadline: now + self.config.upload_idle,
+            busy: true,
+        };
+        {
+            let mut blobs = self.blobs.lock().await;
+            let live = || blobs.values().filter(|blob| blob.is_live(now));
+            if live().map(|blob| blob.size).sum::<u64>() + size > self.config.quota_bytes {
+                return Err(NanoError::Storage("Relay blob storage is full".to_string()));
+            }
+            if live().filter(|blob| blob.owner == owner).map(|blob| blob.size).sum::<u64>() + size > self.config.client_quota_bytes {
+                return Err(NanoError::Storage("Blob storage quota for this client is used up".to_string()));
+            }
+            blobs.insert(blob_id.clone(), blob.clone());
+        }
+
+        if let Err(e) = self.save_record(&blob_id, &blob).await {
+            self.blobs.lock().await.remove(&blob_id);
+            return Err(e);
+        }
+        self.release(&blob_id).await;
+
+        Ok(BlobGrant {
+            blob_id,
+            upload_token,
+            download_token,
+            expires_in: self.config.ttl.as_secs(),
+        })
+    }
+
+    /// Append `data` at `offset` and return how many bytes the blob now holds
+    ///
+    /// Bytes before the received count are skipped, so a resent chunk is harmless,
+    /// and empty data at offset 0 just reports progress. Each stored chunk pushes
+    /// back the deadline of an unfinished upload.
+    pub async fn append(&self, blob_id: &str, upload_token: &str, offset: u64, data: &[u8]) -> Result<u64> {
+        if data.len() > MAX_CHUNK_BYTES {
+            return Err(NanoError::Storage(format!("Chunk larger than {} bytes", MAX_CHUNK_BYTES)));
+        }
+        let received = {
+            let mut blobs = self.blobs.lock().await;
+            let blob = authorize(&mut blobs, blob_id, upload_token, |blob| &blob.upload_token)?;
+            if offset > blob.received {
+                return Err(NanoError::Storage(format!(
+                    "Chunk at offset {} leaves a gap after byte {}",
+                    offset, blob.received
+                )));
+            }
+            if offset + data.len() as u64 > blob.size {
+                return Err(NanoError::Storage(format!("Chunk runs past the {} byte blob", blob.size)));
+            }
+            if offset + (data.len() as u64) <= blob.received {
+                return Ok(blob.received);
+            }
+            if blob.busy {
+                return Err(NanoError::Storage(format!("Blob {} is busy with another chunk", blob_id)));
+            }
+            blob.busy = true;
+            blob.received
+        };
+
+        let fresh = &data[usize::try_from(received - offset).unwrap_or(usize::MAX).min(data.len())..];
+        let stored = self.storage.store_file(FileId::new_v4(), fresh).await;
+        let result = self.record_piece(blob_id, received, fresh.len() as u64, stored).await;
+        self.release(blob_id).await;
+        result
+    }
+
+    /// Add a stored piece to its blob and write the record, unless the blob went away meanwhile
+    async fn record_piece(&self, blob_id: &str, offset: u64, len: u64, stored: Result<StorageLocation>) -> Result<u64> {
+        let location = stored?;
+        let record = {
+            let mut blobs = self.blobs.lock().await;
+            match blobs.get_mut(blob_id) {
+                Some(blob) => {
+                    blob.pieces.push(Piece { offset, location: location.clone(), len });
+                    blob.received += len;
+                    blob.idle_deadline = Utc::now() + self.config.upload_idle;
+                    Some(blob.clone())
+                }
+                None => None,
+            }
+        };
+
+        match record {
+            Some(blob) => {
+                self.save_record(blob_id, &blob).await?;
+                Ok(blob.received)
+            }
+            None => {
+                self.delete_piece(&location).await;
+                Err(NanoError::Storage(format!("Unknown blob {} or invalid token", blob_id)))
+            }
+        }
+    }
+
+    /// Read up to `length` bytes of a finished blob from `offset`
+    pub async fn read(&self, blob_id: &str, download_token: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let (size, pieces) = {
+            let mut blobs = self.blobs.lock().await;
+            let blob = authorize(&mut blobs, blob_id, download_token, |blob| &blob.download_token)?;
+            if !blob.is_finished() {
+                return Err(NanoError::Storage(format!("Blob {} is still being uploaded", blob_id)));
+            }
+            (blob.size, blob.pieces.clone())
+        };
+
+        let end = offset.saturating_add(length.min(MAX_CHUNK_BYTES as u64)).min(size);
+        let mut data = Vec::new();
+        for piece in pieces.iter().filter(|piece| piece.offset < end && offset < piece.offset + piece.len) {
+            let content = self.storage.retrieve_file(&piece.location).await?;
+            let from = offset.saturating_sub(piece.offset) as usize;
+            let to = (end - piece.offset).min(piece.len) as usize;
+            data.extend_from_slice(content.get(from..to).ok_or_else(|| {
+                NanoError::Storage(format!("Stored piece of blob {} is truncated", blob_id))
+            })?);
+        }
+        Ok(data)
+    }
+
+    /// Drop a blob and its stored content
+    pub async fn delete(&self, blob_id: &str, upload_token: &str) -> Result<()> {
+        let blob = {
+            let mut blobs = self.blobs.lock().await;
+            if authorize(&mut blobs, blob_id, upload_token, |blob| &blob.upload_token)?.busy {
+                return Err(NanoError::Storage(format!("Blob {} is busy with another chunk", blob_id)));
+            }
+            blobs.remove(blob_id).expect("blob was authorized")
+        };
+        self.discard(blob_id, blob).await;
+        Ok(())
+    }
+
+    /// Drop blobs past their TTL and uploads left idle, returning how many were removed
+    pub async fn purge(&self, now: DateTime<Utc>) -> usize {
+        let expired: Vec<(String, Blob)> = {
+            let mut blobs = self.blobs.lock().await;
+            let ids: Vec<String> = blobs.iter()
+                .filter(|(_, blob)| !blob.busy && !blob.is_live(now))
+                .map(|(blob_id, _)| blob_id.clone())
+                .collect();
+            ids.into_iter().filter_map(|blob_id| blobs.remove(&blob_id).map(|blob| (blob_id, blob))).collect()
+        };
+        let removed = expired.len();
+        for (blob_id, blob) in expired {
+            self.discard(&blob_id, blob).await;
+        }
+        removed
+    }
+
+    /// Mark a blob free for the next chunk
+    async fn release(&self, blob_id: &str) {
+        if let Some(blob) = self.blobs.lock().await.get_mut(blob_id) {
+            blob.busy = false;
+        }
+    }
+
+    fn record_path(&self, blob_id: &str) -> Option<PathBuf> {
+        self.index_dir.as_ref().map(|dir| dir.join(format!("{}.json", blob_id)))
+    }
+
+    /// Write a blob's record; only the holder of its busy mark writes it
+    async fn save_record(&self, blob_id: &str, blob: &Blob) -> Result<()> {
+        let Some(path) = self.record_path(blob_id) else {
+            return Ok(());
+        };
+        let temp = path.with_extension("json.tmp");
+        tokio::fs::write(&temp, serde_json::to_vec(blob)?).await?;
+        tokio::fs::rename(&temp, &path).await?;
+        Ok(())
+    }
+
+    /// Delete a removed blob's stored content and record
+    async fn discard(&self, blob_id: &str, blob: Blob) {
+        for piece in &blob.pieces {
+            self.delete_piece(&piece.location).await;
+        }
+        if let Some(path) = self.record_path(blob_id) {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                eprintln!("⚠️  Failed to delete blob record {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    async fn delete_piece(&self, location: &StorageLocation) {
+        if let Err(e) = self.storage.delete_file(location).await {
+            eprintln!("⚠️  Failed to delete blob piece {}: {}", location.path, e);
+        }
+    }
+}
+
+/// Live blob whose token hash matches; unknown blobs and wrong tokens look the same
+fn authorize<'a>(
+    blobs: &'a mut HashMap<String, Blob>,
+    blob_id: &str,
+    token: &str,
+    expected: impl Fn(&Blob) -> &[u8; 32],
+) -> Result<&'a mut Blob> {
+    blobs.get_mut(blob_id)
+        .filter(|blob| blob.is_live(Utc::now()) && *expected(blob) == hash_sha256(token.as_bytes()))
+        .ok_or_else(|| NanoError::Storage(format!("Unknown blob {} or invalid token", blob_id)))
+}
+
+/// Random capability token
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::storage::LocalFileStorage;
+    use tempfile::TempDir;
+
+    const CLIENT: &str = "192.0.2.1";
+
+    async fn store(config: BlobStoreConfig) -> (BlobStore, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let storage = LocalFileStorage::new(dir.path().to_path_buf()).await.unwrap();
+        (BlobStore::new(Arc::new(storage), config), dir)
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload_and_ranged_read() {
+        let (store, _dir) = store(BlobStoreConfig::default()).await;
+        let content: Vec<u8> = (0..1000u32).map(|i| (i % 256) as u8).collect();
+        let grant = store.create(content.len() as u64, CLIENT).await.unwrap();
+        let id = &grant.blob_id;
+
+        assert_eq!(store.append(id, &grant.upload_token, 0, &content[..400]).await.unwrap(), 400);
+        // A resent chunk overlapping what arrived is trimmed, a gap is refused
+        assert_eq!(store.append(id, &grant.upload_token, 300, &content[300..700]).await.unwrap(), 700);
+        assert!(store.append(id, &grant.upload_token, 800, &content[800..]).await.is_err());
+        assert_eq!(store.append(id, &grant.upload_token, 0, &[]).await.unwrap(), 700);
+
+        // Unfinished blobs cannot be read
+        assert!(store.read(id, &grant.download_token, 0, 10).await.is_err());
+        assert_eq!(store.append(id, &grant.upload_token, 700, &content[700..]).await.unwrap(), 1000);
+        assert!(store.append(id, &grant.upload_token, 1000, b"x").await.is_err());
+
+        assert_eq!(store.read(id, &grant.download_token, 0, 2000).await.unwrap(), content);
+        assert_eq!(store.read(id, &grant.download_token, 350, 400).await.unwrap(), &content[350..750]);
+        assert!(store.read(id, &grant.download_token, 1000, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_capabilities() {
+        let (store, _dir) = store(BlobStoreConfig::default()).await;
+        let grant = store.create(4, CLIENT).await.unwrap();
+        let id = &grant.blob_id;
+
+        // Each token only does its own job
+        assert!(store.append(id, &grant.download_token, 0, b"data").await.is_err());
+        store.append(id, &grant.upload_token, 0, b"data").await.unwrap();
+        assert!(store.read(id, &grant.upload_token, 0, 4).await.is_err());
+        assert!(store.read(id, "guess", 0, 4).await.is_err());
+        assert!(store.delete(id, &grant.download_token).await.is_err());
+
+        store.delete(id, &grant.upload_token).await.unwrap();
+        assert!(store.read(id, &grant.download_token, 0, 4).await.is_err());
+        assert!(store.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn test_quota_and_ttl() {
+        let config = BlobStoreConfig {
+            max_blob_bytes: 100,
+            quota_bytes: 150,
+            client_quota_bytes: 150,
+            ttl: Duration::from_secs(60),
+            upload_idle: Duration::from_secs(60),
+        };
+        let (store, _dir) = store(config).await;
+
+        assert!(store.create(101, CLIENT).await.is_err());
+        let grant = store.create(100, CLIENT).await.unwrap();
+        store.append(&grant.blob_id, &grant.upload_token, 0, &[7; 100]).await.unwrap();
+        assert!(store.create(60, CLIENT).await.is_err());
+        store.create(50, CLIENT).await.unwrap();
+        assert_eq!(store.reserved_bytes().await, 150);
+
+        // Expired blobs free their quota and stored pieces
+        assert_eq!(store.purge(Utc::now()).await, 0);
+        assert_eq!(store.purge(Utc::now() + config.ttl).await, 2);
+        assert_eq!(store.storage.get_stats().await.unwrap().total_files, 0);
+        store.create(100, CLIENT).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_reservations_and_client_quota() {
+        let config = BlobStoreConfig {
+            max_blob_bytes: 100,
+            quota_bytes: 300,
+            client_quota_bytes: 150,
+            ttl: Duration::from_secs(3600),
+            upload_idle: Duration::from_secs(60),
+        };
+        let (store, _dir) = store(config).await;
+
+        // One client cannot take the whole store
+        let idle = store.create(100, CLIENT).await.unwrap();
+        let active = store.create(50, CLIENT).await.unwrap();
+        assert!(store.create(10, CLIENT).await.is_err());
+        store.create(100, "192.0.2.2").await.unwrap();
+
+        // A reservation nobody uploads to lapses long before its TTL; chunks keep one alive
+        let lapsed = Utc::now() + config.upload_idle;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        store.append(&active.blob_id, &active.upload_token, 0, &[1; 10]).await.unwrap();
+        assert_eq!(store.purge(lapsed - chrono::Duration::seconds(1)).await, 0);
+        assert_eq!(store.purge(lapsed).await, 2);
+        assert!(store.append(&idle.blob_id, &idle.upload_token, 0, &[1; 10]).await.is_err());
+        store.append(&active.blob_id, &active.upload_token, 10, &[1; 10]).await.unwrap();
+        store.create(100, CLIENT).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_records_survive_restart() {
+        let dir = TempDir::new().unwrap();
+        let storage: Arc<dyn FileStorage> = Arc::new(LocalFileStorage::new(dir.path().join("pieces")).await.unwrap());
+        let index = dir.path().join("index");
+        let config = BlobStoreConfig { ttl: Duration::from_secs(60), ..BlobStoreConfig::default() };
+
+        let (finished, deleted) = {
+            let store = BlobStore::open(Arc::clone(&storage), config, index.clone()).await.unwrap();
+            let finished = store.create(4, CLIENT).await.unwrap();
+            store.append(&finished.blob_id, &finished.upload_token, 0, b"kept").await.unwrap();
+            let deleted = store.create(4, CLIENT).await.unwrap();
+            store.append(&deleted.blob_id, &deleted.upload_token, 0, b"gone").await.unwrap();
+            store.delete(&deleted.blob_id, &deleted.upload_token).await.unwrap();
+            (finished, deleted)
+        };
+
+        // Stored blobs are still served after a restart, and purged when they expire
+        let store = BlobStore::open(Arc::clone(&storage), config, index.clone()).await.unwrap();
+        assert_eq!(store.read(&finished.blob_id, &finished.download_token, 0, 4).await.unwrap(), b"kept");
+        assert!(store.read(&deleted.blob_id, &deleted.download_token, 0, 4).await.is_err());
+        assert_eq!(store.purge(Utc::now() + config.ttl).await, 1);
+        assert_eq!(storage.get_stats().await.unwrap().total_files, 0);
+        assert_eq!(std::fs::read_dir(&index).unwrap().count(), 0);
+    }
+}
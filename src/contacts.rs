@@ -1,3 +1,5 @@
+use crate::crypto::UserPublicKeys;
+use crate::devices::DeviceList;
 use crate::error::{NanoError, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -11,11 +13,18 @@ pub enum ContactStatus {
     Blocked,  // Blocked contact, messages ignored
 }
 
+/// Most labels one contact can carry
+pub const MAX_LABELS: usize = 20;
+
 /// Contact metadata (stored locally only)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContactMetadata {
     pub nickname: String,    // Max 50 chars
     pub memo: String,        // Max 200 chars  
+    #[serde(default)]
+    pub labels: Vec<String>, // Max 20, each max 32 chars
+    #[serde(default)]
+    pub favorite: bool,
     pub added_at: DateTime<Utc>,
     pub last_modified: DateTime<Utc>,
 }
@@ -29,11 +38,44 @@ impl ContactMetadata {
         Ok(Self {
             nickname,
             memo,
+            labels: Vec::new(),
+            favorite: false,
             added_at: now,
             last_modified: now,
         })
     }
 
+    /// Whether the contact carries `label`, ignoring case
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|existing| existing.eq_ignore_ascii_case(label))
+    }
+
+    /// Add a label unless it is already there, returning whether it was added
+    pub fn add_label(&mut self, label: &str) -> Result<bool> {
+        let label = label.trim();
+        Self::validate_label(label)?;
+        if self.has_label(label) {
+            return Ok(false);
+        }
+        if self.labels.len() >= MAX_LABELS {
+            return Err(NanoError::Protocol(format!("A contact cannot have more than {} labels", MAX_LABELS)));
+        }
+        self.labels.push(label.to_string());
+        self.last_modified = Utc::now();
+        Ok(true)
+    }
+
+    /// Remove a label, returning whether it was there
+    pub fn remove_label(&mut self, label: &str) -> bool {
+        let before = self.labels.len();
+        self.labels.retain(|existing| !existing.eq_ignore_ascii_case(label.trim()));
+        if self.labels.len() == before {
+            return false;
+        }
+        self.last_modified = Utc::now();
+        true
+    }
+
     pub fn update(&mut self, nickname: Option<String>, memo: Option<String>) -> Result<()> {
         if let Some(nickname) = nickname {
             Self::validate_nickname(&nickname)?;
@@ -63,6 +105,45 @@ impl ContactMetadata {
         }
         Ok(())
     }
+
+    fn validate_label(label: &str) -> Result<()> {
+        if label.is_empty() || label.len() > 32 {
+            return Err(NanoError::Protocol("Labels must be 1 to 32 characters".to_string()));
+        }
+        if label.contains(',') || label.chars().any(char::is_control) {
+            return Err(NanoError::Protocol("Labels cannot contain commas or control characters".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// What we tell a contact about our activity (stored locally only)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PrivacySettings {
+    pub delivery_receipts: bool,
+    pub read_receipts: bool,
+    pub typing_indicators: bool,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            delivery_receipts: true,
+            read_receipts: true,
+            typing_indicators: true,
+        }
+    }
+}
+
+impl PrivacySettings {
+    /// These settings with any given toggles changed
+    pub fn updated(self, delivery_receipts: Option<bool>, read_receipts: Option<bool>, typing_indicators: Option<bool>) -> Self {
+        Self {
+            delivery_receipts: delivery_receipts.unwrap_or(self.delivery_receipts),
+            read_receipts: read_receipts.unwrap_or(self.read_receipts),
+            typing_indicators: typing_indicators.unwrap_or(self.typing_indicators),
+        }
+    }
 }
 
 /// Contact permission entry (synced across devices)
@@ -100,6 +181,14 @@ pub struct Contact {
 }
 
 impl Contact {
+    pub fn labels(&self) -> &[String] {
+        self.metadata.as_ref().map(|metadata| metadata.labels.as_slice()).unwrap_or_default()
+    }
+
+    pub fn is_favorite(&self) -> bool {
+        self.metadata.as_ref().is_some_and(|metadata| metadata.favorite)
+    }
+
     pub fn display_name(&self) -> &str {
         if let Some(metadata) = &self.metadata {
             if !metadata.nickname.is_empty() {
@@ -120,6 +209,10 @@ pub struct ContactManager {
     permissions: HashMap<String, ContactPermission>, // pubkey -> permission (synced)
     metadata: HashMap<String, ContactMetadata>,      // pubkey -> metadata (local only)
     username_to_pubkey: HashMap<String, String>,     // username -> pubkey mapping
+    device_lists: HashMap<String, DeviceList>,       // pubkey -> linked devices
+    public_keys: HashMap<String, UserPublicKeys>,    // pubkey -> last looked-up keys
+    relays: HashMap<String, Vec<String>>,            // pubkey -> relays they accept mail on
+    privacy: HashMap<String, PrivacySettings>,       // pubkey -> non-default privacy settings
 }
 
 impl ContactManager {
@@ -128,35 +221,46 @@ impl ContactManager {
             permissions: HashMap::new(),
             metadata: HashMap::new(),
             username_to_pubkey: HashMap::new(),
+            device_lists: HashMap::new(),
+            public_keys: HashMap::new(),
+            relays: HashMap::new(),
+            privacy: HashMap::new(),
         }
     }
 
     /// Allow a contact to send messages
     pub fn allow_contact(&mut self, pubkey: String) -> Result<()> {
-        match self.permissions.get_mut(&pubkey) {
-            Some(permission) => {
-                permission.update_status(ContactStatus::Allowed);
-            }
-            None => {
-                let permission = ContactPermission::new(pubkey.clone(), ContactStatus::Allowed);
-                self.permissions.insert(pubkey, permission);
-            }
-        }
+        self.set_status(pubkey, ContactStatus::Allowed);
         Ok(())
     }
 
     /// Block a contact from sending messages
     pub fn block_contact(&mut self, pubkey: String) -> Result<()> {
+        self.set_status(pubkey, ContactStatus::Blocked);
+        Ok(())
+    }
+
+    /// Set a contact's status, adding them if they are new
+    pub fn set_status(&mut self, pubkey: String, status: ContactStatus) {
         match self.permissions.get_mut(&pubkey) {
-            Some(permission) => {
-                permission.update_status(ContactStatus::Blocked);
-            }
+            Some(permission) => permission.update_status(status),
             None => {
-                let permission = ContactPermission::new(pubkey.clone(), ContactStatus::Blocked);
+                let permission = ContactPermission::new(pubkey.clone(), status);
                 self.permissions.insert(pubkey, permission);
             }
         }
-        Ok(())
+    }
+
+    /// Set the status of every contact with `label`, returning their pubkeys
+    pub fn set_label_status(&mut self, label: &str, status: ContactStatus) -> Vec<String> {
+        let pubkeys: Vec<String> = self.contacts_with_label(label)
+            .into_iter()
+            .map(|contact| contact.permission.pubkey)
+            .collect();
+        for pubkey in &pubkeys {
+            self.set_status(pubkey.clone(), status.clone());
+        }
+        pubkeys
     }
 
     /// Check if a contact is allowed to send messages
@@ -210,6 +314,56 @@ impl ContactManager {
         Ok(())
     }
 
+    /// Metadata of a contact, created empty if they have none yet
+    fn metadata_mut(&mut self, pubkey: &str) -> &mut ContactMetadata {
+        self.metadata.entry(pubkey.to_string()).or_insert_with(|| {
+            ContactMetadata::new(String::new(), String::new()).expect("empty metadata is valid")
+        })
+    }
+
+    /// Label a contact, returning whether the label is new to them
+    pub fn add_label(&mut self, pubkey: &str, label: &str) -> Result<bool> {
+        self.metadata_mut(pubkey).add_label(label)
+    }
+
+    /// Take a label off a contact, returning whether they had it
+    pub fn remove_label(&mut self, pubkey: &str, label: &str) -> bool {
+        self.metadata.get_mut(pubkey).is_some_and(|metadata| metadata.remove_label(label))
+    }
+
+    pub fn set_favorite(&mut self, pubkey: &str, favorite: bool) {
+        let metadata = self.metadata_mut(pubkey);
+        metadata.favorite = favorite;
+        metadata.last_modified = Utc::now();
+    }
+
+    /// Every label in use with how many contacts carry it, sorted by name
+    pub fn list_labels(&self) -> Vec<(String, usize)> {
+        let mut contacts = self.list_contacts();
+        contacts.sort_by(|a, b| a.permission.pubkey.cmp(&b.permission.pubkey));
+        
+        // The first spelling of a label, in pubkey order, names it
+        let mut labels: Vec<(String, usize)> = Vec::new();
+        for contact in contacts {
+            for label in contact.labels() {
+                match labels.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(label)) {
+                    Some((_, count)) => *count += 1,
+                    None => labels.push((label.clone(), 1)),
+                }
+            }
+        }
+        labels.sort_by_key(|(label, _)| label.to_lowercase());
+        labels
+    }
+
+    /// Contacts carrying `label`, ignoring case
+    pub fn contacts_with_label(&self, label: &str) -> Vec<Contact> {
+        self.list_contacts()
+            .into_iter()
+            .filter(|contact| contact.metadata.as_ref().is_some_and(|metadata| metadata.has_label(label)))
+            .collect()
+    }
+
     /// Set username for a contact
     pub fn set_username(&mut self, pubkey: String, username: String) {
         self.username_to_pubkey.insert(username, pubkey);
@@ -241,7 +395,7 @@ impl ContactManager {
         contacts
     }
 
-    /// Search contacts by nickname or memo
+    /// Search contacts by nickname, memo, label or username
     pub fn search_contacts(&self, query: &str) -> Vec<Contact> {
         let query_lower = query.to_lowercase();
         let mut results = Vec::new();
@@ -259,6 +413,11 @@ impl ContactManager {
                 if metadata.memo.to_lowercase().contains(&query_lower) {
                     matches = true;
                 }
+                
+                // Search in labels
+                if metadata.labels.iter().any(|label| label.to_lowercase().contains(&query_lower)) {
+                    matches = true;
+                }
             }
             
             // Search in username
@@ -296,6 +455,10 @@ impl ContactManager {
     pub fn remove_contact(&mut self, pubkey: &str) {
         self.permissions.remove(pubkey);
         self.metadata.remove(pubkey);
+        self.device_lists.remove(pubkey);
+        self.public_keys.remove(pubkey);
+        self.relays.remove(pubkey);
+        self.privacy.remove(pubkey);
         
         // Remove username mapping
         self.username_to_pubkey.retain(|_, pk| pk != pubkey);
@@ -320,6 +483,114 @@ impl ContactManager {
     pub fn import_metadata(&mut self, metadata: HashMap<String, ContactMetadata>) {
         self.metadata = metadata;
     }
+
+    /// Record a contact's device list if it verifies and is newer than the known one
+    pub fn update_device_list(&mut self, list: DeviceList) -> Result<bool> {
+        list.verify()?;
+        if let Some(existing) = self.device_lists.get(&list.identity) {
+            if list.version <= existing.version {
+                return Ok(false);
+            }
+        }
+        self.device_lists.insert(list.identity.clone(), list);
+        Ok(true)
+    }
+
+    pub fn get_device_list(&self, pubkey: &str) -> Option<&DeviceList> {
+        self.device_lists.get(pubkey)
+    }
+
+    /// Whether a contact has revoked the given device
+    pub fn is_device_revoked(&self, pubkey: &str, device_id: &str) -> bool {
+        self.device_lists
+            .get(pubkey)
+            .is_some_and(|list| list.is_revoked(device_id))
+    }
+
+    /// Export known device lists (for backup)
+    pub fn export_device_lists(&self) -> &HashMap<String, DeviceList> {
+        &self.device_lists
+    }
+
+    /// Import device lists (from backup)
+    pub fn import_device_lists(&mut self, device_lists: HashMap<String, DeviceList>) {
+        self.device_lists = device_lists;
+    }
+
+    /// Export username mappings (for backup)
+    pub fn export_usernames(&self) -> &HashMap<String, String> {
+        &self.username_to_pubkey
+    }
+
+    /// Import username mappings (from backup)
+    pub fn import_usernames(&mut self, usernames: HashMap<String, String>) {
+        self.username_to_pubkey = usernames;
+    }
+
+    /// Remember a contact's keys so messages can be sealed while the relay is unreachable
+    pub fn remember_public_keys(&mut self, public_keys: UserPublicKeys) {
+        self.public_keys.insert(public_keys.public_key_string(), public_keys);
+    }
+
+    pub fn get_public_keys(&self, pubkey: &str) -> Option<&UserPublicKeys> {
+        self.public_keys.get(pubkey)
+    }
+
+    /// Export cached public keys (for backup)
+    pub fn export_public_keys(&self) -> &HashMap<String, UserPublicKeys> {
+        &self.public_keys
+    }
+
+    /// Import cached public keys (from backup)
+    pub fn import_public_keys(&mut self, public_keys: HashMap<String, UserPublicKeys>) {
+        self.public_keys = public_keys;
+    }
+
+    /// Record the relays a contact advertises in their username claim
+    pub fn set_relays(&mut self, pubkey: String, relays: Vec<String>) {
+        if relays.is_empty() {
+            self.relays.remove(&pubkey);
+        } else {
+            self.relays.insert(pubkey, relays);
+        }
+    }
+
+    pub fn get_relays(&self, pubkey: &str) -> Option<&[String]> {
+        self.relays.get(pubkey).map(Vec::as_slice)
+    }
+
+    /// Export advertised relays (for backup)
+    pub fn export_relays(&self) -> &HashMap<String, Vec<String>> {
+        &self.relays
+    }
+
+    /// Import advertised relays (from backup)
+    pub fn import_relays(&mut self, relays: HashMap<String, Vec<String>>) {
+        self.relays = relays;
+    }
+
+    /// Receipts and typing indicators we send a contact; everything is on by default
+    pub fn privacy(&self, pubkey: &str) -> PrivacySettings {
+        self.privacy.get(pubkey).copied().unwrap_or_default()
+    }
+
+    pub fn set_privacy(&mut self, pubkey: String, settings: PrivacySettings) {
+        if settings == PrivacySettings::default() {
+            self.privacy.remove(&pubkey);
+        } else {
+            self.privacy.insert(pubkey, settings);
+        }
+    }
+
+    /// Export privacy settings (for backup)
+    pub fn export_privacy(&self) -> &HashMap<String, PrivacySettings> {
+        &self.privacy
+    }
+
+    /// Import privacy settings (from backup)
+    pub fn import_privacy(&mut self, privacy: HashMap<String, PrivacySettings>) {
+        self.privacy = privacy;
+    }
 }
 
 impl Default for ContactManager {
@@ -464,4 +735,98 @@ mod tests {
         };
         assert_eq!(contact.display_name(), "Alice K.");
     }
+
+    #[test]
+    fn test_contact_device_lists() {
+        use crate::crypto::UserKeyPair;
+        use crate::devices::DeviceCertificate;
+
+        let mut manager = ContactManager::new();
+        let alice = UserKeyPair::generate();
+        let pubkey = alice.public_key_string();
+        let mut list = DeviceList::new(&alice).unwrap();
+        assert!(manager.update_device_list(list.clone()).unwrap());
+
+        let phone = UserKeyPair::generate();
+        let certificate = DeviceCertificate::issue(&alice, "phone".to_string(), phone.public_keys()).unwrap();
+        let phone_id = certificate.device_id.clone();
+        list.add(&alice, certificate).unwrap();
+        let linked = list.clone();
+        list.revoke(&alice, &phone_id).unwrap();
+
+        // Newer lists replace older ones but never the other way round
+        assert!(manager.update_device_list(list).unwrap());
+        assert!(!manager.update_device_list(linked).unwrap());
+        assert!(manager.is_device_revoked(&pubkey, &phone_id));
+        assert_eq!(manager.get_device_list(&pubkey).unwrap().devices.len(), 1);
+
+        manager.remove_contact(&pubkey);
+        assert!(manager.get_device_list(&pubkey).is_none());
+    }
+
+    #[test]
+    fn test_cached_public_keys() {
+        let mut manager = ContactManager::new();
+        let bob = crate::crypto::UserKeyPair::generate().public_keys();
+        let pubkey = bob.public_key_string();
+
+        manager.remember_public_keys(bob);
+        manager.set_username(pubkey.clone(), "bob".to_string());
+        manager.set_relays(pubkey.clone(), vec!["relay-b:7733".to_string()]);
+        assert!(manager.get_public_keys(&pubkey).is_some());
+        assert_eq!(manager.export_usernames()["bob"], pubkey);
+        assert_eq!(manager.get_relays(&pubkey).unwrap(), ["relay-b:7733"]);
+
+        manager.remove_contact(&pubkey);
+        assert!(manager.get_public_keys(&pubkey).is_none());
+        assert!(manager.get_relays(&pubkey).is_none());
+    }
+
+    #[test]
+    fn test_privacy_settings() {
+        let mut manager = ContactManager::new();
+        let pubkey = "pubkey:abc123".to_string();
+        assert_eq!(manager.privacy(&pubkey), PrivacySettings::default());
+
+        let quiet = PrivacySettings { read_receipts: false, typing_indicators: false, ..Default::default() };
+        manager.set_privacy(pubkey.clone(), quiet);
+        assert!(manager.privacy(&pubkey).delivery_receipts);
+        assert!(!manager.privacy(&pubkey).read_receipts);
+        assert_eq!(manager.export_privacy().len(), 1);
+
+        // Defaults are not stored
+        manager.set_privacy(pubkey.clone(), PrivacySettings::default());
+        assert!(manager.export_privacy().is_empty());
+    }
+
+    #[test]
+    fn test_labels_and_favorites() {
+        let mut manager = ContactManager::new();
+        let alice = "pubkey:alice".to_string();
+        let bob = "pubkey:bob".to_string();
+        manager.allow_contact(alice.clone()).unwrap();
+        manager.allow_contact(bob.clone()).unwrap();
+
+        assert!(manager.add_label(&alice, "Work").unwrap());
+        assert!(!manager.add_label(&alice, " work ").unwrap());
+        manager.add_label(&alice, "climbing").unwrap();
+        manager.add_label(&bob, "work").unwrap();
+        assert!(manager.add_label(&bob, "a,b").is_err());
+        assert!(manager.add_label(&bob, "").is_err());
+        manager.set_favorite(&bob, true);
+
+        assert_eq!(manager.list_labels(), vec![("climbing".to_string(), 1), ("Work".to_string(), 2)]);
+        assert_eq!(manager.contacts_with_label("WORK").len(), 2);
+        assert!(manager.get_contact(&bob).unwrap().is_favorite());
+        assert_eq!(manager.search_contacts("climb")[0].permission.pubkey, alice);
+
+        // Bulk status changes only touch the labelled contacts
+        assert_eq!(manager.set_label_status("climbing", ContactStatus::Blocked), vec![alice.clone()]);
+        assert!(manager.is_blocked(&alice));
+        assert!(manager.is_allowed(&bob));
+
+        assert!(manager.remove_label(&alice, "WORK"));
+        assert!(!manager.remove_label(&alice, "work"));
+        assert_eq!(manager.get_contact(&alice).unwrap().labels(), ["climbing"]);
+    }
 }
@@ -0,0 +1,152 @@
+//! Edits, deletions and reactions
+//!
+//! A payload whose `content` is set changes an earlier message instead of
+//! adding one. Messages are named by a `MessageRef`: the author and the
+//! timestamp they signed, which every copy of a message shares.
+
+use crate::error::{NanoError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Message ID that is the same for the sender, every recipient and their devices
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MessageRef {
+    pub author: String, // Pubkey that signed the message
+    pub timestamp: i64, // Payload timestamp they signed
+}
+
+impl MessageRef {
+    pub fn new(author: String, timestamp: i64) -> Self {
+        Self { author, timestamp }
+    }
+}
+
+/// Emoji reaction to a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Reaction {
+    Like,
+    Dislike,
+    Love,
+    Laugh,
+    Wow,
+    Sad,
+    Angry,
+    Fire,
+}
+
+impl Reaction {
+    /// Get emoji representation
+    pub fn emoji(&self) -> &'static str {
+        match self {
+            Reaction::Like => "👍",
+            Reaction::Dislike => "👎",
+            Reaction::Love => "❤️",
+            Reaction::Laugh => "😂",
+            Reaction::Wow => "😮",
+            Reaction::Sad => "😢",
+            Reaction::Angry => "😠",
+            Reaction::Fire => "🔥",
+        }
+    }
+
+    /// Get all available reactions
+    pub fn all() -> Vec<Reaction> {
+        vec![
+            Reaction::Like,
+            Reaction::Dislike,
+            Reaction::Love,
+            Reaction::Laugh,
+            Reaction::Wow,
+            Reaction::Sad,
+            Reaction::Angry,
+            Reaction::Fire,
+        ]
+    }
+}
+
+impl fmt::Display for Reaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.emoji())
+    }
+}
+
+impl FromStr for Reaction {
+    type Err = NanoError;
+
+    /// Parse a reaction from its name ("like") or its emoji
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        Reaction::all()
+            .into_iter()
+            .find(|reaction| {
+                format!("{:?}", reaction).eq_ignore_ascii_case(s)
+                    || reaction.emoji() == s
+                    || reaction.emoji().trim_end_matches('\u{fe0f}') == s
+            })
+            .ok_or_else(|| NanoError::Protocol(format!("Unknown reaction '{}'", s)))
+    }
+}
+
+/// Change to an earlier message, carried in `MessagePayload::content`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageContent {
+    /// The author replaced the message text
+    Edit { target: MessageRef, body: String },
+
+    /// The author deleted the message for everyone
+    Delete { target: MessageRef },
+
+    /// The sender reacted to the message; `None` withdraws their reaction
+    React { target: MessageRef, reaction: Option<Reaction> },
+}
+
+impl MessageContent {
+    pub fn target(&self) -> &MessageRef {
+        match self {
+            MessageContent::Edit { target, .. }
+            | MessageContent::Delete { target }
+            | MessageContent::React { target, .. } => target,
+        }
+    }
+
+    /// Text for the payload body, shown by clients that do not understand `content`
+    pub fn fallback_text(&self) -> String {
+        match self {
+            MessageContent::Edit { body, .. } => format!("✏️ {}", body),
+            MessageContent::Delete { .. } => "🗑 Deleted a message".to_string(),
+            MessageContent::React { reaction: Some(reaction), .. } => format!("Reacted {} to a message", reaction),
+            MessageContent::React { reaction: None, .. } => "Removed a reaction".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reaction_parsing() {
+        assert_eq!("like".parse::<Reaction>().unwrap(), Reaction::Like);
+        assert_eq!("Fire".parse::<Reaction>().unwrap(), Reaction::Fire);
+        assert_eq!("👍".parse::<Reaction>().unwrap(), Reaction::Like);
+        // With or without the emoji variation selector
+        assert_eq!("❤️".parse::<Reaction>().unwrap(), Reaction::Love);
+        assert_eq!("❤".parse::<Reaction>().unwrap(), Reaction::Love);
+        assert!("shrug".parse::<Reaction>().is_err());
+    }
+
+    #[test]
+    fn test_content_round_trip() {
+        let react = MessageContent::React {
+            target: MessageRef::new("pubkey:alice".to_string(), 1_700_000_000),
+            reaction: Some(Reaction::Laugh),
+        };
+        let json = serde_json::to_string(&react).unwrap();
+        assert!(json.contains("\"type\":\"react\""));
+        assert_eq!(serde_json::from_str::<MessageContent>(&json).unwrap(), react);
+        assert_eq!(react.target().timestamp, 1_700_000_000);
+        assert_eq!(react.fallback_text(), "Reacted 😂 to a message");
+    }
+}
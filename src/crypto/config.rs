@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use crate::error::{NanoError, Result};
+use super::padding::PaddingScheme;
 
 /// Available cryptographic modes
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -159,6 +160,8 @@ pub struct CryptoConfig {
     pub adaptive_mode: bool,
     /// Minimum acceptable crypto mode for incoming messages
     pub minimum_mode: CryptoMode,
+    /// Plaintext padding applied before encryption
+    pub padding: PaddingScheme,
 }
 
 impl Default for CryptoConfig {
@@ -168,6 +171,7 @@ impl Default for CryptoConfig {
             allow_auto_upgrade: true,
             adaptive_mode: false,
             minimum_mode: CryptoMode::Classical,
+            padding: PaddingScheme::Padme,
         }
     }
 }
@@ -188,6 +192,7 @@ impl CryptoConfig {
             allow_auto_upgrade: true,
             adaptive_mode: false,
             minimum_mode: CryptoMode::Hybrid,
+            padding: PaddingScheme::Buckets,
         }
     }
 
@@ -198,6 +203,7 @@ impl CryptoConfig {
             allow_auto_upgrade: false,
             adaptive_mode: true,
             minimum_mode: CryptoMode::Classical,
+            padding: PaddingScheme::Padme,
         }
     }
 
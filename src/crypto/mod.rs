@@ -5,6 +5,7 @@ pub mod classical;
 pub mod post_quantum;
 pub mod hybrid;
 pub mod quantum_safe; // Session 3: Quantum-safe messaging functions
+pub mod padding; // Plaintext length hiding
 
 // Session 6: Performance optimization modules
 pub mod benchmarks;
@@ -12,6 +13,7 @@ pub mod optimizations;
 
 // Re-export the main types and traits for easy access
 pub use config::{CryptoConfig, CryptoMode};
+pub use padding::PaddingScheme;
 pub use traits::{
     AsymmetricEncryption, CryptoProvider, DigitalSignature, KeyExchange, SymmetricEncryption,
 };
@@ -74,6 +76,7 @@ pub fn get_crypto_config() -> &'static CryptoConfig {
         allow_auto_upgrade: true,
         adaptive_mode: false,
         minimum_mode: CryptoMode::Classical,
+        padding: PaddingScheme::Padme,
     };
     CRYPTO_CONFIG.get().unwrap_or(&DEFAULT_CONFIG)
 }
@@ -296,6 +299,17 @@ pub fn derive_shared_secret(
         .to_bytes()
 }
 
+/// Derive a key for encrypting local client state at rest
+/// Uses: SHA256("local_storage:" + label + ":" + our X25519 private key)
+pub fn derive_storage_key(our_private: &X25519PrivateKey, label: &str) -> [u8; 32] {
+    let mut data = Vec::new();
+    data.extend_from_slice(b"local_storage:");
+    data.extend_from_slice(label.as_bytes());
+    data.push(b':');
+    data.extend_from_slice(&our_private.to_bytes());
+    hash_sha256(&data)
+}
+
 // Re-export types for backwards compatibility
 pub type UserKeyPair = ClassicalUserKeyPair;
 pub type UserPublicKeys = ClassicalUserPublicKeys;
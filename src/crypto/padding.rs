@@ -0,0 +1,180 @@
+use crate::error::{NanoError, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Marker byte identifying a padded plaintext (unpadded JSON payloads start with `{`)
+const PADDING_FORMAT_V1: u8 = 0x01;
+
+/// Marker byte plus big-endian u32 plaintext length
+const PADDING_HEADER_LEN: usize = 5;
+
+/// Fixed bucket sizes; larger messages are rounded up to a multiple of the last bucket
+const BUCKET_SIZES: [usize; 8] = [256, 512, 1024, 2048, 4096, 8192, 16384, 32768];
+
+/// Plaintext padding applied before encryption to hide message lengths
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaddingScheme {
+    /// No padding (ciphertext size reveals plaintext size)
+    None,
+    /// Padmé padding: at most ~12% overhead, leaks O(log log L) bits of length
+    #[default]
+    Padme,
+    /// Round up to fixed power-of-two buckets from 256 bytes to 32 KiB
+    Buckets,
+}
+
+impl FromStr for PaddingScheme {
+    type Err = NanoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" | "off" => Ok(PaddingScheme::None),
+            "padme" => Ok(PaddingScheme::Padme),
+            "buckets" | "bucket" | "fixed" => Ok(PaddingScheme::Buckets),
+            _ => Err(NanoError::Crypto(format!(
+                "Invalid padding scheme: {}. Valid options: none, padme, buckets",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for PaddingScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaddingScheme::None => write!(f, "none"),
+            PaddingScheme::Padme => write!(f, "padme"),
+            PaddingScheme::Buckets => write!(f, "buckets"),
+        }
+    }
+}
+
+impl PaddingScheme {
+    /// Size of the padded plaintext for a payload of `payload_len` bytes
+    pub fn padded_len(&self, payload_len: usize) -> usize {
+        let framed = payload_len + PADDING_HEADER_LEN;
+        match self {
+            PaddingScheme::None => payload_len,
+            PaddingScheme::Padme => padme_len(framed),
+            PaddingScheme::Buckets => bucket_len(framed),
+        }
+    }
+
+    /// Pad a plaintext payload according to this scheme
+    pub fn pad(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        if *self == PaddingScheme::None {
+            return Ok(payload.to_vec());
+        }
+
+        let length = u32::try_from(payload.len())
+            .map_err(|_| NanoError::Crypto("Payload too large to pad".to_string()))?;
+
+        let mut padded = Vec::with_capacity(self.padded_len(payload.len()));
+        padded.push(PADDING_FORMAT_V1);
+        padded.extend_from_slice(&length.to_be_bytes());
+        padded.extend_from_slice(payload);
+        padded.resize(self.padded_len(payload.len()), 0);
+
+        Ok(padded)
+    }
+}
+
+/// Remove padding from a decrypted plaintext
+///
+/// Plaintexts without the padding marker are returned unchanged, so messages
+/// from peers that do not pad still decrypt.
+pub fn unpad(plaintext: &[u8]) -> Result<Vec<u8>> {
+    if plaintext.first() != Some(&PADDING_FORMAT_V1) {
+        return Ok(plaintext.to_vec());
+    }
+
+    if plaintext.len() < PADDING_HEADER_LEN {
+        return Err(NanoError::Crypto("Truncated padding header".to_string()));
+    }
+
+    let length = u32::from_be_bytes(plaintext[1..PADDING_HEADER_LEN].try_into().unwrap()) as usize;
+    let end = PADDING_HEADER_LEN
+        .checked_add(length)
+        .filter(|end| *end <= plaintext.len())
+        .ok_or_else(|| NanoError::Crypto("Padding length exceeds plaintext".to_string()))?;
+
+    if plaintext[end..].iter().any(|b| *b != 0) {
+        return Err(NanoError::Crypto("Invalid padding bytes".to_string()));
+    }
+
+    Ok(plaintext[PADDING_HEADER_LEN..end].to_vec())
+}
+
+/// Padmé: round up so only the top O(log log L) bits of the length remain
+fn padme_len(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+
+    let e = usize::BITS - 1 - len.leading_zeros(); // floor(log2(len))
+    let s = u32::BITS - e.leading_zeros(); // floor(log2(e)) + 1
+    let last_bits = e - s;
+    let mask = (1usize << last_bits) - 1;
+
+    (len + mask) & !mask
+}
+
+fn bucket_len(len: usize) -> usize {
+    let largest = BUCKET_SIZES[BUCKET_SIZES.len() - 1];
+    match BUCKET_SIZES.iter().find(|size| **size >= len) {
+        Some(size) => *size,
+        None => len.div_ceil(largest) * largest,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding_round_trip() {
+        let payload = br#"{"body":"hello"}"#;
+
+        for scheme in [PaddingScheme::None, PaddingScheme::Padme, PaddingScheme::Buckets] {
+            let padded = scheme.pad(payload).unwrap();
+            assert_eq!(padded.len(), scheme.padded_len(payload.len()));
+            assert_eq!(unpad(&padded).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn test_padme_lengths() {
+        // Known Padmé outputs
+        assert_eq!(padme_len(100), 104);
+        assert_eq!(padme_len(1000), 1024);
+        assert_eq!(padme_len(1025), 1088);
+
+        // Overhead stays below 12%
+        for len in 2..20_000 {
+            let padded = padme_len(len);
+            assert!(padded >= len);
+            assert!((padded - len) as f64 / len as f64 <= 0.12);
+        }
+    }
+
+    #[test]
+    fn test_bucket_lengths() {
+        assert_eq!(bucket_len(1), 256);
+        assert_eq!(bucket_len(257), 512);
+        assert_eq!(bucket_len(32768), 32768);
+        assert_eq!(bucket_len(40000), 65536);
+    }
+
+    #[test]
+    fn test_unpad_rejects_corruption() {
+        let mut padded = PaddingScheme::Buckets.pad(b"{}").unwrap();
+        let last = padded.len() - 1;
+        padded[last] = 1;
+        assert!(unpad(&padded).is_err());
+
+        let mut oversized = PaddingScheme::Buckets.pad(b"{}").unwrap();
+        oversized[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(unpad(&oversized).is_err());
+    }
+}
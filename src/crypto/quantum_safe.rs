@@ -1,7 +1,8 @@
 use crate::crypto::{
     CryptoMode, UnifiedKeyPair, UnifiedPublicKeys, CryptoInterface,
     ClassicalAsymmetricEncryption, HybridAsymmetricEncryption, PostQuantumAsymmetricEncryption,
-    traits::AsymmetricEncryption,
+    traits::AsymmetricEncryption, get_crypto_config,
+    padding::{self, PaddingScheme},
 };
 use crate::error::{NanoError, Result};
 use crate::protocol::{MessagePayload, QuantumSafeEnvelope};
@@ -98,11 +99,30 @@ impl QuantumSafeMessaging {
     }
 
     /// Encrypt payload data using the specified crypto mode
+    /// The payload is padded first according to the configured padding scheme
     fn encrypt_payload_with_mode(
         to_public_keys: &UnifiedPublicKeys,
         payload_bytes: &[u8],
         mode: CryptoMode,
     ) -> Result<Vec<u8>> {
+        Self::encrypt_payload_with_padding(
+            to_public_keys,
+            payload_bytes,
+            mode,
+            get_crypto_config().padding,
+        )
+    }
+
+    /// Pad and encrypt payload data using an explicit padding scheme
+    fn encrypt_payload_with_padding(
+        to_public_keys: &UnifiedPublicKeys,
+        payload_bytes: &[u8],
+        mode: CryptoMode,
+        padding: PaddingScheme,
+    ) -> Result<Vec<u8>> {
+        let padded = padding.pad(payload_bytes)?;
+        let payload_bytes = padded.as_slice();
+
         match (mode, to_public_keys) {
             (CryptoMode::Classical, UnifiedPublicKeys::Classical(keys)) => {
                 ClassicalAsymmetricEncryption::encrypt(&keys.x25519_key, payload_bytes)
@@ -161,11 +181,20 @@ impl QuantumSafeMessaging {
         }
     }
 
-    /// Decrypt payload data using the specified crypto mode
+    /// Decrypt payload data using the specified crypto mode and strip any padding
     fn decrypt_payload_with_mode(
         our_keypair: &UnifiedKeyPair,
         encrypted_payload: &[u8],
         mode: CryptoMode,
+    ) -> Result<Vec<u8>> {
+        let padded = Self::decrypt_padded_payload(our_keypair, encrypted_payload, mode)?;
+        padding::unpad(&padded)
+    }
+
+    fn decrypt_padded_payload(
+        our_keypair: &UnifiedKeyPair,
+        encrypted_payload: &[u8],
+        mode: CryptoMode,
     ) -> Result<Vec<u8>> {
         match (mode, our_keypair) {
             (CryptoMode::Classical, UnifiedKeyPair::Classical(kp)) => {
@@ -311,6 +340,50 @@ mod tests {
         assert_eq!(decrypted_payload.crypto_mode, Some(CryptoMode::Classical));
     }
 
+    #[test]
+    fn test_padded_ciphertext_sizes() {
+        let bob_keypair = UnifiedKeyPair::Classical(crate::crypto::ClassicalUserKeyPair::generate());
+        let bob_public = bob_keypair.public_keys();
+
+        // Classical ciphertext overhead: ephemeral key + nonce + tag
+        let overhead = {
+            let ct = QuantumSafeMessaging::encrypt_payload_with_padding(
+                &bob_public, b"", CryptoMode::Classical, PaddingScheme::None,
+            ).unwrap();
+            ct.len()
+        };
+
+        let buckets = [256, 512, 1024, 2048, 4096];
+        for len in [1, 100, 250, 251, 600, 1500, 3000] {
+            let body = vec![b'x'; len];
+            let ct = QuantumSafeMessaging::encrypt_payload_with_padding(
+                &bob_public, &body, CryptoMode::Classical, PaddingScheme::Buckets,
+            ).unwrap();
+            assert!(buckets.contains(&(ct.len() - overhead)), "{} -> {}", len, ct.len());
+
+            // Padding is removed on decrypt
+            let decrypted = QuantumSafeMessaging::decrypt_payload_with_mode(
+                &bob_keypair, &ct, CryptoMode::Classical,
+            ).unwrap();
+            assert_eq!(decrypted, body);
+        }
+
+        // Messages of similar length are indistinguishable by size
+        let short = QuantumSafeMessaging::encrypt_payload_with_padding(
+            &bob_public, b"hi", CryptoMode::Classical, PaddingScheme::Buckets,
+        ).unwrap();
+        let longer = QuantumSafeMessaging::encrypt_payload_with_padding(
+            &bob_public, &[b'y'; 200], CryptoMode::Classical, PaddingScheme::Buckets,
+        ).unwrap();
+        assert_eq!(short.len(), longer.len());
+
+        // Padmé sizes match the scheme's padded length
+        let ct = QuantumSafeMessaging::encrypt_payload_with_padding(
+            &bob_public, &[b'z'; 1000], CryptoMode::Classical, PaddingScheme::Padme,
+        ).unwrap();
+        assert_eq!(ct.len() - overhead, PaddingScheme::Padme.padded_len(1000));
+    }
+
     #[test]
     fn test_mode_compatibility() {
         assert!(QuantumSafeMessaging::modes_compatible(CryptoMode::Classical, CryptoMode::Classical));
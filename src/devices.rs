@@ -0,0 +1,360 @@
+use crate::crypto::{hash_sha256, sign_data, verify_signature, UserKeyPair, UserPublicKeys};
+use crate::content::MessageContent;
+use crate::disappearing::DisappearingTimer;
+use crate::rich::RichContent;
+use crate::error::{NanoError, Result};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Room marking payloads that sync state between one identity's devices
+pub const DEVICE_SYNC_ROOM: &str = "device-sync";
+
+const PAIRING_REQUEST_PREFIX: &str = "nano-link:";
+const PAIRING_RESPONSE_PREFIX: &str = "nano-linked:";
+
+/// Short identifier for a device, derived from its verifying key
+pub fn device_id(public_keys: &UserPublicKeys) -> String {
+    hex::encode(&hash_sha256(public_keys.verifying_key.as_bytes())[..8])
+}
+
+fn sign(identity: &UserKeyPair, data: &[u8]) -> String {
+    general_purpose::STANDARD.encode(sign_data(&identity.signing_key, data).to_bytes())
+}
+
+fn verify(identity: &str, data: &[u8], sig: &str) -> Result<()> {
+    let sig_bytes: [u8; 64] = general_purpose::STANDARD
+        .decode(sig)?
+        .try_into()
+        .map_err(|_| NanoError::Crypto("Invalid signature length".to_string()))?;
+    let verifying_key = UserPublicKeys::from_public_key_string(identity)?;
+    verify_signature(&verifying_key, data, &Signature::from_bytes(&sig_bytes))
+}
+
+/// A device's keys, signed by the identity they act for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceCertificate {
+    pub identity: String, // Identity public key string
+    pub device_id: String,
+    pub name: String,
+    pub public_keys: UserPublicKeys,
+    pub created_at: i64,
+    pub sig: String, // Base64 signature by the identity key
+}
+
+impl DeviceCertificate {
+    /// Sign a device's public keys with the identity key
+    pub fn issue(identity: &UserKeyPair, name: String, public_keys: UserPublicKeys) -> Result<Self> {
+        let mut certificate = Self {
+            identity: identity.public_key_string(),
+            device_id: device_id(&public_keys),
+            name,
+            public_keys,
+            created_at: Utc::now().timestamp(),
+            sig: String::new(),
+        };
+        certificate.sig = sign(identity, &certificate.signable_data()?);
+        Ok(certificate)
+    }
+
+    fn signable_data(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&(
+            &self.identity,
+            &self.device_id,
+            &self.name,
+            &self.public_keys,
+            self.created_at,
+        ))
+        .map_err(Into::into)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        if self.device_id != device_id(&self.public_keys) {
+            return Err(NanoError::Crypto("Device ID does not match device keys".to_string()));
+        }
+        verify(&self.identity, &self.signable_data()?, &self.sig)
+    }
+}
+
+/// Signed, versioned list of an identity's devices
+///
+/// The identity's own keys are always the first device. Revoked device IDs
+/// stay listed so a stale certificate cannot be reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceList {
+    pub identity: String,
+    pub version: u64,
+    pub devices: Vec<DeviceCertificate>,
+    pub revoked: Vec<String>,
+    pub sig: String,
+}
+
+impl DeviceList {
+    /// List holding only the primary device
+    pub fn new(identity: &UserKeyPair) -> Result<Self> {
+        let primary = DeviceCertificate::issue(identity, "primary".to_string(), identity.public_keys())?;
+        let mut list = Self {
+            identity: identity.public_key_string(),
+            version: 0,
+            devices: vec![primary],
+            revoked: Vec::new(),
+            sig: String::new(),
+        };
+        list.resign(identity)?;
+        Ok(list)
+    }
+
+    fn signable_data(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&(&self.identity, self.version, &self.devices, &self.revoked)).map_err(Into::into)
+    }
+
+    /// Only the identity may change its list; checked before any change is made
+    fn check_identity(&self, identity: &UserKeyPair) -> Result<()> {
+        if identity.public_key_string() != self.identity {
+            return Err(NanoError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    fn resign(&mut self, identity: &UserKeyPair) -> Result<()> {
+        self.check_identity(identity)?;
+        self.version += 1;
+        self.sig = sign(identity, &self.signable_data()?);
+        Ok(())
+    }
+
+    /// Add or replace a linked device
+    pub fn add(&mut self, identity: &UserKeyPair, certificate: DeviceCertificate) -> Result<()> {
+        self.check_identity(identity)?;
+        certificate.verify()?;
+        if certificate.identity != self.identity {
+            return Err(NanoError::Protocol("Certificate is for another identity".to_string()));
+        }
+        if self.is_revoked(&certificate.device_id) {
+            return Err(NanoError::Protocol(format!("Device {} was revoked", certificate.device_id)));
+        }
+
+        self.devices.retain(|device| device.device_id != certificate.device_id);
+        self.devices.push(certificate);
+        self.resign(identity)
+    }
+
+    /// Revoke a linked device; the primary device cannot be revoked
+    pub fn revoke(&mut self, identity: &UserKeyPair, device_id: &str) -> Result<()> {
+        self.check_identity(identity)?;
+        if self.devices.first().map(|device| device.device_id.as_str()) == Some(device_id) {
+            return Err(NanoError::Protocol("The primary device cannot be revoked".to_string()));
+        }
+        if self.device(device_id).is_none() {
+            return Err(NanoError::Protocol(format!("Unknown device: {}", device_id)));
+        }
+
+        self.devices.retain(|device| device.device_id != device_id);
+        self.revoked.push(device_id.to_string());
+        self.resign(identity)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        verify(&self.identity, &self.signable_data()?, &self.sig)?;
+        for device in &self.devices {
+            device.verify()?;
+            if device.identity != self.identity {
+                return Err(NanoError::Protocol("Device list contains a foreign device".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<&DeviceCertificate> {
+        self.devices.iter().find(|device| device.device_id == device_id)
+    }
+
+    pub fn is_revoked(&self, device_id: &str) -> bool {
+        self.revoked.iter().any(|revoked| revoked == device_id)
+    }
+}
+
+/// Relay-side store of the latest device list per identity
+#[derive(Default, Clone)]
+pub struct DeviceDirectory {
+    lists: HashMap<String, DeviceList>,
+}
+
+impl DeviceDirectory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a verified list unless a newer version is already known
+    pub fn publish(&mut self, list: DeviceList) -> Result<()> {
+        list.verify()?;
+        if let Some(existing) = self.lists.get(&list.identity) {
+            if list.version <= existing.version {
+                return Err(NanoError::Protocol(format!(
+                    "Device list version {} is not newer than {}",
+                    list.version, existing.version
+                )));
+            }
+        }
+        self.lists.insert(list.identity.clone(), list);
+        Ok(())
+    }
+
+    pub fn lookup(&self, identity: &str) -> Option<&DeviceList> {
+        self.lists.get(identity)
+    }
+}
+
+fn encode_code<T: Serialize>(prefix: &str, value: &T) -> Result<String> {
+    Ok(format!("{}{}", prefix, general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(value)?)))
+}
+
+fn decode_code<T: for<'de> Deserialize<'de>>(prefix: &str, code: &str) -> Result<T> {
+    let encoded = code
+        .trim()
+        .strip_prefix(prefix)
+        .ok_or_else(|| NanoError::Protocol(format!("Pairing code must start with '{}'", prefix)))?;
+    let json = general_purpose::URL_SAFE_NO_PAD.decode(encoded)?;
+    serde_json::from_slice(&json).map_err(Into::into)
+}
+
+/// Shown by a new device so the primary can certify its keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingRequest {
+    pub name: String,
+    pub public_keys: UserPublicKeys,
+}
+
+impl PairingRequest {
+    pub fn to_code(&self) -> Result<String> {
+        encode_code(PAIRING_REQUEST_PREFIX, self)
+    }
+
+    pub fn from_code(code: &str) -> Result<Self> {
+        decode_code(PAIRING_REQUEST_PREFIX, code)
+    }
+}
+
+/// Returned by the primary: the new device's certificate and the updated list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingResponse {
+    pub certificate: DeviceCertificate,
+    pub device_list: DeviceList,
+}
+
+impl PairingResponse {
+    pub fn to_code(&self) -> Result<String> {
+        encode_code(PAIRING_RESPONSE_PREFIX, self)
+    }
+
+    pub fn from_code(code: &str) -> Result<Self> {
+        decode_code(PAIRING_RESPONSE_PREFIX, code)
+    }
+}
+
+/// Copy of a sent message, delivered to the sender's other devices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentMessageSync {
+    pub to_pubkey: String,
+    pub body: String,
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<MessageContent>, // Set when the sent message changed an earlier one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disappearing: Option<DisappearingTimer>, // Set when the sent message changed the conversation timer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rich: Option<RichContent>, // Rich content of the sent message
+}
+
+impl SentMessageSync {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(Into::into)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_and_revoke_device() {
+        let identity = UserKeyPair::generate();
+        let laptop = UserKeyPair::generate();
+        let mut list = DeviceList::new(&identity).unwrap();
+        assert_eq!(list.devices.len(), 1);
+
+        // Pairing round trip through codes
+        let request = PairingRequest { name: "laptop".to_string(), public_keys: laptop.public_keys() };
+        let request = PairingRequest::from_code(&request.to_code().unwrap()).unwrap();
+        let certificate = DeviceCertificate::issue(&identity, request.name, request.public_keys).unwrap();
+        list.add(&identity, certificate.clone()).unwrap();
+
+        let response = PairingResponse { certificate, device_list: list.clone() };
+        let response = PairingResponse::from_code(&response.to_code().unwrap()).unwrap();
+        response.device_list.verify().unwrap();
+        assert_eq!(response.certificate.device_id, device_id(&laptop.public_keys()));
+        assert_eq!(response.device_list.devices.len(), 2);
+
+        // Revoked devices cannot be re-added with their old certificate
+        let laptop_id = response.certificate.device_id.clone();
+        list.revoke(&identity, &laptop_id).unwrap();
+        assert!(list.is_revoked(&laptop_id));
+        assert!(list.add(&identity, response.certificate).is_err());
+        let primary_id = list.devices[0].device_id.clone();
+        assert!(list.revoke(&identity, &primary_id).is_err());
+        list.verify().unwrap();
+    }
+
+    #[test]
+    fn test_only_identity_can_sign_devices() {
+        let identity = UserKeyPair::generate();
+        let mallory = UserKeyPair::generate();
+        let mut list = DeviceList::new(&identity).unwrap();
+
+        // A certificate from another identity is rejected
+        let foreign = DeviceCertificate::issue(&mallory, "evil".to_string(), mallory.public_keys()).unwrap();
+        assert!(list.add(&identity, foreign).is_err());
+
+        // Tampering with a signed list breaks verification
+        let mut tampered = list.clone();
+        tampered.devices[0].name = "renamed".to_string();
+        assert!(tampered.verify().is_err());
+
+        // Only the identity can re-sign its list
+        let laptop = UserKeyPair::generate();
+        let certificate = DeviceCertificate::issue(&identity, "laptop".to_string(), laptop.public_keys()).unwrap();
+        assert!(list.add(&mallory, certificate.clone()).is_err());
+        list.verify().unwrap();
+        assert_eq!(list.devices.len(), 1);
+
+        // A rejected revocation leaves the list as signed
+        list.add(&identity, certificate.clone()).unwrap();
+        assert!(list.revoke(&mallory, &certificate.device_id).is_err());
+        list.verify().unwrap();
+        assert!(list.device(&certificate.device_id).is_some() && !list.is_revoked(&certificate.device_id));
+    }
+
+    #[test]
+    fn test_directory_keeps_newest_list() {
+        let identity = UserKeyPair::generate();
+        let mut directory = DeviceDirectory::new();
+        let mut list = DeviceList::new(&identity).unwrap();
+        directory.publish(list.clone()).unwrap();
+
+        let old = list.clone();
+        let laptop = UserKeyPair::generate();
+        let certificate = DeviceCertificate::issue(&identity, "laptop".to_string(), laptop.public_keys()).unwrap();
+        list.add(&identity, certificate).unwrap();
+        directory.publish(list).unwrap();
+
+        // Rolling back to an older list is refused
+        assert!(directory.publish(old).is_err());
+        assert_eq!(directory.lookup(&identity.public_key_string()).unwrap().devices.len(), 2);
+    }
+}
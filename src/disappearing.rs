@@ -0,0 +1,152 @@
+//! Disappearing-message timers
+//!
+//! Each conversation has a timer that either participant can change. The
+//! change travels in-band as an ordinary signed message whose payload carries
+//! the new `DisappearingTimer`, so every device converges on the most recent
+//! setting. Messages stored while a timer is on get an expiry, and the
+//! client's sweeper purges them from the message store and search index.
+
+use crate::error::{NanoError, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How long a conversation's messages are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "start", rename_all = "snake_case")]
+pub enum DisappearingTimer {
+    /// Messages are kept until deleted
+    #[default]
+    Off,
+
+    /// Messages disappear this long after they were sent
+    AfterSent { seconds: u64 },
+
+    /// Messages disappear this long after we read them; our own after sending
+    AfterRead { seconds: u64 },
+}
+
+impl DisappearingTimer {
+    /// Parse a duration such as "30s", "10m", "1h", "2d" or "1w", or "off"
+    pub fn parse(duration: &str, after_read: bool) -> Result<Self> {
+        let duration = duration.trim().to_ascii_lowercase();
+        if duration == "off" {
+            return Ok(DisappearingTimer::Off);
+        }
+
+        let split = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+        let (number, unit) = duration.split_at(split);
+        let invalid = || NanoError::Protocol(format!("Invalid timer '{}': expected e.g. 30s, 10m, 1h, 2d, 1w or off", duration));
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit_secs = match unit {
+            "s" | "" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 7 * 86400,
+            _ => return Err(invalid()),
+        };
+        let seconds = number.checked_mul(unit_secs).filter(|secs| *secs > 0).ok_or_else(invalid)?;
+
+        Ok(if after_read {
+            DisappearingTimer::AfterRead { seconds }
+        } else {
+            DisappearingTimer::AfterSent { seconds }
+        })
+    }
+
+    pub fn is_off(&self) -> bool {
+        *self == DisappearingTimer::Off
+    }
+
+    /// When a message stored under this timer expires, if it is known yet
+    ///
+    /// Incoming messages under an after-read timer expire once read, so they
+    /// get no expiry here; see `seconds_after_read`.
+    pub fn expires_at(&self, sent_at: DateTime<Utc>, is_outgoing: bool) -> Option<DateTime<Utc>> {
+        match *self {
+            DisappearingTimer::Off => None,
+            DisappearingTimer::AfterSent { seconds } => Some(sent_at + duration(seconds)),
+            DisappearingTimer::AfterRead { seconds } if is_outgoing => Some(sent_at + duration(seconds)),
+            DisappearingTimer::AfterRead { .. } => None,
+        }
+    }
+
+    /// Countdown that starts when an incoming message is read
+    pub fn seconds_after_read(&self, is_outgoing: bool) -> Option<u64> {
+        match *self {
+            DisappearingTimer::AfterRead { seconds } if !is_outgoing => Some(seconds),
+            _ => None,
+        }
+    }
+
+    /// Text of the message announcing the change, also shown by clients without timers
+    pub fn notice_text(&self) -> String {
+        match self {
+            DisappearingTimer::Off => "⏱ Disappearing messages turned off".to_string(),
+            timer => format!("⏱ Disappearing messages set to {}", timer),
+        }
+    }
+}
+
+impl fmt::Display for DisappearingTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DisappearingTimer::Off => write!(f, "off"),
+            DisappearingTimer::AfterSent { seconds } => write!(f, "{} after sending", format_duration(seconds)),
+            DisappearingTimer::AfterRead { seconds } => write!(f, "{} after reading", format_duration(seconds)),
+        }
+    }
+}
+
+/// Timer in force for a conversation and when it was set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationTimer {
+    pub timer: DisappearingTimer,
+    pub set_at: DateTime<Utc>, // Signed timestamp of the message that set it
+}
+
+fn duration(seconds: u64) -> Duration {
+    Duration::seconds(i64::try_from(seconds).unwrap_or(i64::MAX / 1000))
+}
+
+/// Largest whole unit that fits, e.g. "90m" or "2d"
+fn format_duration(seconds: u64) -> String {
+    [(7 * 86400, "w"), (86400, "d"), (3600, "h"), (60, "m")]
+        .into_iter()
+        .find(|(unit, _)| seconds.is_multiple_of(*unit))
+        .map_or_else(|| format!("{}s", seconds), |(unit, suffix)| format!("{}{}", seconds / unit, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(DisappearingTimer::parse("off", false).unwrap(), DisappearingTimer::Off);
+        assert_eq!(DisappearingTimer::parse("1h", false).unwrap(), DisappearingTimer::AfterSent { seconds: 3600 });
+        assert_eq!(DisappearingTimer::parse("90M", true).unwrap(), DisappearingTimer::AfterRead { seconds: 5400 });
+        assert_eq!(DisappearingTimer::parse("45", false).unwrap(), DisappearingTimer::AfterSent { seconds: 45 });
+        assert!(DisappearingTimer::parse("0s", false).is_err());
+        assert!(DisappearingTimer::parse("1y", false).is_err());
+        assert!(DisappearingTimer::parse("h", false).is_err());
+
+        assert_eq!(DisappearingTimer::AfterSent { seconds: 5400 }.to_string(), "90m after sending");
+        assert_eq!(DisappearingTimer::AfterRead { seconds: 14 * 86400 }.notice_text(), "⏱ Disappearing messages set to 2w after reading");
+    }
+
+    #[test]
+    fn test_expiry_starts() {
+        let sent_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let after_read = DisappearingTimer::AfterRead { seconds: 60 };
+        assert_eq!(after_read.expires_at(sent_at, true), Some(sent_at + Duration::seconds(60)));
+        assert_eq!(after_read.expires_at(sent_at, false), None);
+        assert_eq!(after_read.seconds_after_read(false), Some(60));
+
+        let after_sent = DisappearingTimer::AfterSent { seconds: 60 };
+        assert_eq!(after_sent.expires_at(sent_at, false), Some(sent_at + Duration::seconds(60)));
+        assert_eq!(after_sent.seconds_after_read(false), None);
+        assert_eq!(DisappearingTimer::Off.expires_at(sent_at, true), None);
+    }
+}
@@ -29,6 +29,9 @@ pub enum NanoError {
     #[error("Message expired")]
     MessageExpired,
     
+    #[error("Replay detected: {0}")]
+    ReplayDetected(String),
+    
     // Session 9: Media and file attachment errors
     #[error("Media error: {0}")]
     Media(String),
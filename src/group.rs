@@ -0,0 +1,690 @@
+use crate::crypto::{decrypt_symmetric, encrypt_symmetric, hash_sha256, UserPublicKeys};
+use crate::error::{NanoError, Result};
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// How far ahead of the last seen iteration a sender chain may be advanced
+const MAX_SKIPPED_KEYS: u64 = 256;
+
+/// Version of the encrypted group store
+const GROUP_STORE_VERSION: u32 = 1;
+
+/// Derives the shared inbox ID for a group epoch
+/// Uses: SHA256("group_inbox:" + group_secret + epoch)
+pub fn derive_group_inbox(group_secret: &[u8; 32], epoch: u64) -> String {
+    hex::encode(derive_group_key(b"group_inbox:", group_secret, epoch))
+}
+
+fn derive_group_key(label: &[u8], group_secret: &[u8; 32], epoch: u64) -> [u8; 32] {
+    let mut data = Vec::new();
+    data.extend_from_slice(label);
+    data.extend_from_slice(group_secret);
+    data.extend_from_slice(&epoch.to_be_bytes());
+    hash_sha256(&data)
+}
+
+fn ratchet_step(label: &[u8], chain_key: &[u8; 32]) -> [u8; 32] {
+    let mut data = Vec::new();
+    data.extend_from_slice(label);
+    data.extend_from_slice(chain_key);
+    hash_sha256(&data)
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// Symmetric hash ratchet for one member's outgoing group messages
+///
+/// Each message uses a fresh key derived from the chain, and the chain only
+/// moves forward, so a member who learns the key later cannot read earlier
+/// messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    pub key_id: String,
+    chain_key: [u8; 32],
+    iteration: u64,
+    #[serde(default)]
+    skipped: HashMap<u64, [u8; 32]>,
+}
+
+/// Sender key material sent pairwise to other group members
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SenderKeyDistribution {
+    pub key_id: String,
+    pub chain_key: String, // Hex encoded
+    pub iteration: u64,
+}
+
+impl SenderKey {
+    pub fn generate() -> Self {
+        Self {
+            key_id: hex::encode(&random_secret()[..8]),
+            chain_key: random_secret(),
+            iteration: 0,
+            skipped: HashMap::new(),
+        }
+    }
+
+    /// Current chain state, for handing to another member
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            key_id: self.key_id.clone(),
+            chain_key: hex::encode(self.chain_key),
+            iteration: self.iteration,
+        }
+    }
+
+    pub fn from_distribution(distribution: &SenderKeyDistribution) -> Result<Self> {
+        let chain_key: [u8; 32] = hex::decode(&distribution.chain_key)
+            .map_err(|e| NanoError::Crypto(format!("Invalid sender key: {}", e)))?
+            .try_into()
+            .map_err(|_| NanoError::Crypto("Invalid sender key length".to_string()))?;
+
+        Ok(Self {
+            key_id: distribution.key_id.clone(),
+            chain_key,
+            iteration: distribution.iteration,
+            skipped: HashMap::new(),
+        })
+    }
+
+    /// Take the next message key for sending
+    fn next_message_key(&mut self) -> (u64, [u8; 32]) {
+        let iteration = self.iteration;
+        let message_key = ratchet_step(b"sender_key_message:", &self.chain_key);
+        self.chain_key = ratchet_step(b"sender_key_chain:", &self.chain_key);
+        self.iteration += 1;
+        (iteration, message_key)
+    }
+
+    /// Recover the message key for a received iteration, allowing out-of-order delivery
+    fn message_key_for(&mut self, iteration: u64) -> Result<[u8; 32]> {
+        if iteration < self.iteration {
+            return self.skipped.remove(&iteration).ok_or_else(|| {
+                NanoError::ReplayDetected(format!("sender key iteration {} already used", iteration))
+            });
+        }
+
+        if iteration - self.iteration > MAX_SKIPPED_KEYS {
+            return Err(NanoError::Crypto("Sender key iteration too far ahead".to_string()));
+        }
+
+        while self.iteration < iteration {
+            let (skipped_iteration, key) = self.next_message_key();
+            self.skipped.insert(skipped_iteration, key);
+        }
+
+        // Keep only the most recent skipped keys
+        let oldest = self.iteration.saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.retain(|i, _| *i >= oldest);
+
+        Ok(self.next_message_key().1)
+    }
+}
+
+/// A group member as known to every other member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub pubkey: String,
+    pub public_keys: UserPublicKeys,
+}
+
+impl GroupMember {
+    pub fn new(public_keys: UserPublicKeys) -> Self {
+        Self {
+            pubkey: public_keys.public_key_string(),
+            public_keys,
+        }
+    }
+}
+
+/// Group control messages, sent pairwise in a `MessagePayload` whose `room` is the group ID
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GroupControl {
+    /// Full group state from the admin: creation, membership change, or rekey
+    Welcome {
+        group_id: String,
+        name: String,
+        admin: String,
+        epoch: u64,
+        group_secret: String, // Hex encoded
+        members: Vec<GroupMember>,
+    },
+
+    /// A member's sender key for the current epoch
+    SenderKey {
+        group_id: String,
+        epoch: u64,
+        distribution: SenderKeyDistribution,
+    },
+}
+
+impl GroupControl {
+    pub fn group_id(&self) -> &str {
+        match self {
+            GroupControl::Welcome { group_id, .. } => group_id,
+            GroupControl::SenderKey { group_id, .. } => group_id,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(Into::into)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(Into::into)
+    }
+}
+
+/// Header identifying the sender chain, encrypted under the epoch's header key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupHeader {
+    sender: String,
+    key_id: String,
+    iteration: u64,
+}
+
+/// Wire format of a group message stored in the group inbox
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupCiphertext {
+    header: String, // Base64 encoded encrypted GroupHeader
+    body: String,   // Base64 encoded body encrypted with the sender's message key
+}
+
+/// One group as seen by the local member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupState {
+    pub group_id: String,
+    pub name: String,
+    pub admin: String,
+    pub me: String,
+    pub epoch: u64,
+    group_secret: [u8; 32],
+    pub members: Vec<GroupMember>,
+    own_sender_key: SenderKey,
+    member_sender_keys: HashMap<String, SenderKey>,
+    distributed_to: HashSet<String>,
+}
+
+impl GroupState {
+    /// Create a new group administered by `me`
+    pub fn create(name: String, me: GroupMember, others: Vec<GroupMember>) -> Self {
+        let mut state = Self {
+            group_id: hex::encode(&random_secret()[..16]),
+            name,
+            admin: me.pubkey.clone(),
+            me: me.pubkey.clone(),
+            epoch: 0,
+            group_secret: random_secret(),
+            members: vec![me],
+            own_sender_key: SenderKey::generate(),
+            member_sender_keys: HashMap::new(),
+            distributed_to: HashSet::new(),
+        };
+
+        for member in others {
+            if !state.is_member(&member.pubkey) {
+                state.members.push(member);
+            }
+        }
+
+        state
+    }
+
+    /// Relay inbox shared by all members for the current epoch
+    pub fn inbox_id(&self) -> String {
+        derive_group_inbox(&self.group_secret, self.epoch)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin == self.me
+    }
+
+    pub fn is_member(&self, pubkey: &str) -> bool {
+        self.members.iter().any(|m| m.pubkey == pubkey)
+    }
+
+    /// Members other than ourselves
+    pub fn other_members(&self) -> impl Iterator<Item = &GroupMember> {
+        self.members.iter().filter(move |m| m.pubkey != self.me)
+    }
+
+    /// Add a member (admin only); they can read messages sent from now on
+    pub fn add_member(&mut self, member: GroupMember) -> Result<()> {
+        self.require_admin()?;
+        if self.is_member(&member.pubkey) {
+            return Err(NanoError::Protocol(format!("{} is already a member", member.pubkey)));
+        }
+        self.members.push(member);
+        Ok(())
+    }
+
+    /// Remove a member (admin only) and rotate the group secret and sender keys
+    pub fn remove_member(&mut self, pubkey: &str) -> Result<()> {
+        self.require_admin()?;
+        if pubkey == self.me {
+            return Err(NanoError::Protocol("The admin cannot remove themselves".to_string()));
+        }
+        if !self.is_member(pubkey) {
+            return Err(NanoError::Protocol(format!("{} is not a member", pubkey)));
+        }
+
+        self.members.retain(|m| m.pubkey != pubkey);
+        self.rekey(self.epoch + 1, random_secret());
+        Ok(())
+    }
+
+    fn require_admin(&self) -> Result<()> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(NanoError::Protocol("Only the group admin can change membership".to_string()))
+        }
+    }
+
+    fn rekey(&mut self, epoch: u64, group_secret: [u8; 32]) {
+        self.epoch = epoch;
+        self.group_secret = group_secret;
+        self.own_sender_key = SenderKey::generate();
+        self.member_sender_keys.clear();
+        self.distributed_to.clear();
+    }
+
+    /// Welcome message carrying the current group state
+    pub fn welcome(&self) -> GroupControl {
+        GroupControl::Welcome {
+            group_id: self.group_id.clone(),
+            name: self.name.clone(),
+            admin: self.admin.clone(),
+            epoch: self.epoch,
+            group_secret: hex::encode(self.group_secret),
+            members: self.members.clone(),
+        }
+    }
+
+    /// Members who have not yet received our sender key for this epoch
+    pub fn pending_distribution(&self) -> Vec<GroupMember> {
+        self.other_members()
+            .filter(|m| !self.distributed_to.contains(&m.pubkey))
+            .cloned()
+            .collect()
+    }
+
+    /// Our sender key message for this epoch
+    pub fn sender_key_message(&self) -> GroupControl {
+        GroupControl::SenderKey {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            distribution: self.own_sender_key.distribution(),
+        }
+    }
+
+    pub fn mark_distributed(&mut self, pubkey: &str) {
+        self.distributed_to.insert(pubkey.to_string());
+    }
+
+    /// Encrypt a plaintext once for all members
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let (iteration, message_key) = self.own_sender_key.next_message_key();
+        let header = GroupHeader {
+            sender: self.me.clone(),
+            key_id: self.own_sender_key.key_id.clone(),
+            iteration,
+        };
+
+        let header_key = derive_group_key(b"group_header:", &self.group_secret, self.epoch);
+        let ciphertext = GroupCiphertext {
+            header: general_purpose::STANDARD.encode(encrypt_symmetric(&header_key, &serde_json::to_vec(&header)?)?),
+            body: general_purpose::STANDARD.encode(encrypt_symmetric(&message_key, plaintext)?),
+        };
+
+        serde_json::to_vec(&ciphertext).map_err(Into::into)
+    }
+
+    /// Decrypt a group message, returning the sender and plaintext
+    ///
+    /// Returns `None` for our own messages, which we see when fetching the group inbox.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Option<(String, Vec<u8>)>> {
+        let ciphertext: GroupCiphertext = serde_json::from_slice(data)?;
+
+        let header_key = derive_group_key(b"group_header:", &self.group_secret, self.epoch);
+        let header_bytes = decrypt_symmetric(&header_key, &general_purpose::STANDARD.decode(&ciphertext.header)?)?;
+        let header: GroupHeader = serde_json::from_slice(&header_bytes)?;
+
+        if header.sender == self.me {
+            return Ok(None);
+        }
+        if !self.is_member(&header.sender) {
+            return Err(NanoError::Protocol("Group message from a non-member".to_string()));
+        }
+
+        let sender_key = self
+            .member_sender_keys
+            .get_mut(&header.sender)
+            .filter(|key| key.key_id == header.key_id)
+            .ok_or_else(|| NanoError::Crypto("No sender key for this group member".to_string()))?;
+
+        // Only commit the ratchet once the body authenticates
+        let mut advanced = sender_key.clone();
+        let message_key = advanced.message_key_for(header.iteration)?;
+        let plaintext = decrypt_symmetric(&message_key, &general_purpose::STANDARD.decode(&ciphertext.body)?)?;
+        *sender_key = advanced;
+
+        Ok(Some((header.sender, plaintext)))
+    }
+
+    /// Apply a control message from `from` to an existing group
+    pub fn apply_control(&mut self, from: &str, control: GroupControl) -> Result<()> {
+        match control {
+            GroupControl::Welcome { epoch, group_secret, members, name, admin, .. } => {
+                if from != self.admin || admin != self.admin {
+                    return Err(NanoError::Protocol("Group update not sent by the admin".to_string()));
+                }
+                if epoch < self.epoch {
+                    return Err(NanoError::ReplayDetected(format!("stale group epoch {}", epoch)));
+                }
+
+                if epoch > self.epoch {
+                    self.rekey(epoch, decode_group_secret(&group_secret)?);
+                }
+                self.name = name;
+                self.members = members;
+                self.member_sender_keys.retain(|pubkey, _| self.members.iter().any(|m| &m.pubkey == pubkey));
+                Ok(())
+            }
+            GroupControl::SenderKey { epoch, distribution, .. } => {
+                if !self.is_member(from) {
+                    return Err(NanoError::Protocol("Sender key from a non-member".to_string()));
+                }
+                if epoch < self.epoch {
+                    return Err(NanoError::ReplayDetected(format!("sender key for stale epoch {}", epoch)));
+                }
+                if epoch > self.epoch {
+                    return Err(NanoError::Protocol(format!("Sender key for epoch {}, group is at {}", epoch, self.epoch)));
+                }
+
+                // Re-delivered distributions must not rewind a chain we have already advanced
+                if self.member_sender_keys.get(from).is_some_and(|key| key.key_id == distribution.key_id) {
+                    return Ok(());
+                }
+
+                self.member_sender_keys.insert(from.to_string(), SenderKey::from_distribution(&distribution)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Build our view of a group from the admin's welcome
+    pub fn from_welcome(me: &str, from: &str, control: GroupControl) -> Result<Self> {
+        let GroupControl::Welcome { group_id, name, admin, epoch, group_secret, members } = control else {
+            return Err(NanoError::Protocol("Unknown group".to_string()));
+        };
+
+        if from != admin {
+            return Err(NanoError::Protocol("Group welcome not sent by the admin".to_string()));
+        }
+        if !members.iter().any(|m| m.pubkey == me) || !members.iter().any(|m| m.pubkey == admin) {
+            return Err(NanoError::Protocol("Group welcome does not list us and the admin".to_string()));
+        }
+
+        Ok(Self {
+            group_id,
+            name,
+            admin,
+            me: me.to_string(),
+            epoch,
+            group_secret: decode_group_secret(&group_secret)?,
+            members,
+            own_sender_key: SenderKey::generate(),
+            member_sender_keys: HashMap::new(),
+            distributed_to: HashSet::new(),
+        })
+    }
+}
+
+fn decode_group_secret(encoded: &str) -> Result<[u8; 32]> {
+    hex::decode(encoded)
+        .map_err(|e| NanoError::Crypto(format!("Invalid group secret: {}", e)))?
+        .try_into()
+        .map_err(|_| NanoError::Crypto("Invalid group secret length".to_string()))
+}
+
+/// All groups the local user belongs to
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GroupManager {
+    groups: HashMap<String, GroupState>,
+}
+
+impl GroupManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, group: GroupState) {
+        self.groups.insert(group.group_id.clone(), group);
+    }
+
+    /// Find a group by ID or, failing that, by name
+    pub fn find_mut(&mut self, id_or_name: &str) -> Option<&mut GroupState> {
+        if self.groups.contains_key(id_or_name) {
+            return self.groups.get_mut(id_or_name);
+        }
+        self.groups.values_mut().find(|g| g.name == id_or_name)
+    }
+
+    pub fn get_mut(&mut self, group_id: &str) -> Option<&mut GroupState> {
+        self.groups.get_mut(group_id)
+    }
+
+    pub fn list(&self) -> Vec<&GroupState> {
+        let mut groups: Vec<&GroupState> = self.groups.values().collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    /// Apply a control message received from `from`, creating the group on first welcome
+    pub fn handle_control(&mut self, me: &str, from: &str, control: GroupControl) -> Result<()> {
+        match self.groups.get_mut(control.group_id()) {
+            Some(group) => group.apply_control(from, control),
+            None => {
+                let group = GroupState::from_welcome(me, from, control)?;
+                self.insert(group);
+                Ok(())
+            }
+        }
+    }
+
+    /// Forget a group, e.g. after being removed from it
+    pub fn remove(&mut self, group_id: &str) -> Option<GroupState> {
+        self.groups.remove(group_id)
+    }
+
+    /// Serialize and encrypt every group, secrets and sender keys included, for storage at rest
+    pub fn to_encrypted(&self, storage_key: &[u8; 32]) -> Result<Vec<u8>> {
+        #[derive(Serialize)]
+        struct GroupStoreRef<'a> {
+            version: u32,
+            manager: &'a GroupManager,
+        }
+
+        let store = GroupStoreRef {
+            version: GROUP_STORE_VERSION,
+            manager: self,
+        };
+        encrypt_symmetric(storage_key, &serde_json::to_vec(&store)?)
+    }
+
+    /// Decrypt groups written by `to_encrypted`
+    pub fn from_encrypted(storage_key: &[u8; 32], data: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct GroupStore {
+            version: u32,
+            manager: GroupManager,
+        }
+
+        let store: GroupStore = serde_json::from_slice(&decrypt_symmetric(storage_key, data)?)?;
+        if store.version != GROUP_STORE_VERSION {
+            return Err(NanoError::Storage(format!("Unsupported group store version {}", store.version)));
+        }
+        Ok(store.manager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::UserKeyPair;
+
+    fn member() -> GroupMember {
+        GroupMember::new(UserKeyPair::generate().public_keys())
+    }
+
+    /// Deliver a control message to another member's manager
+    fn deliver(to: &mut GroupManager, to_me: &str, from: &str, control: &GroupControl) -> Result<()> {
+        to.handle_control(to_me, from, control.clone())
+    }
+
+    fn exchange_sender_keys(states: &mut [&mut GroupManager], ids: &[&str], group_id: &str) {
+        for i in 0..states.len() {
+            let message = states[i].get_mut(group_id).unwrap().sender_key_message();
+            for j in 0..states.len() {
+                if i != j {
+                    deliver(states[j], ids[j], ids[i], &message).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sender_key_ratchet_out_of_order() {
+        let mut sending = SenderKey::generate();
+        let mut receiving = SenderKey::from_distribution(&sending.distribution()).unwrap();
+
+        let keys: Vec<_> = (0..4).map(|_| sending.next_message_key()).collect();
+
+        assert_eq!(receiving.message_key_for(2).unwrap(), keys[2].1);
+        assert_eq!(receiving.message_key_for(0).unwrap(), keys[0].1);
+        assert_eq!(receiving.message_key_for(3).unwrap(), keys[3].1);
+
+        // Each key can be used only once
+        assert!(matches!(receiving.message_key_for(2), Err(NanoError::ReplayDetected(_))));
+        assert!(receiving.message_key_for(1000).is_err());
+    }
+
+    #[test]
+    fn test_group_message_reaches_all_members() {
+        let (alice, bob, carol) = (member(), member(), member());
+        let (a, b, c) = (alice.pubkey.clone(), bob.pubkey.clone(), carol.pubkey.clone());
+
+        let group = GroupState::create("friends".to_string(), alice, vec![bob, carol]);
+        let group_id = group.group_id.clone();
+        let welcome = group.welcome();
+
+        let mut alice_groups = GroupManager::new();
+        alice_groups.insert(group);
+        let mut bob_groups = GroupManager::new();
+        let mut carol_groups = GroupManager::new();
+        deliver(&mut bob_groups, &b, &a, &welcome).unwrap();
+        deliver(&mut carol_groups, &c, &a, &welcome).unwrap();
+
+        exchange_sender_keys(&mut [&mut alice_groups, &mut bob_groups, &mut carol_groups], &[&a, &b, &c], &group_id);
+
+        let inbox = alice_groups.get_mut(&group_id).unwrap().inbox_id();
+        assert_eq!(bob_groups.get_mut(&group_id).unwrap().inbox_id(), inbox);
+
+        let ciphertext = alice_groups.get_mut(&group_id).unwrap().encrypt(b"hello group").unwrap();
+
+        for groups in [&mut bob_groups, &mut carol_groups] {
+            let (sender, plaintext) = groups.get_mut(&group_id).unwrap().decrypt(&ciphertext).unwrap().unwrap();
+            assert_eq!(sender, a);
+            assert_eq!(plaintext, b"hello group");
+        }
+
+        // The sender skips its own message
+        assert!(alice_groups.get_mut(&group_id).unwrap().decrypt(&ciphertext).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_removal_rekeys_group() {
+        let (alice, bob, carol) = (member(), member(), member());
+        let (a, b, c) = (alice.pubkey.clone(), bob.pubkey.clone(), carol.pubkey.clone());
+
+        let group = GroupState::create("team".to_string(), alice, vec![bob, carol]);
+        let group_id = group.group_id.clone();
+        let welcome = group.welcome();
+
+        let mut alice_groups = GroupManager::new();
+        alice_groups.insert(group);
+        let mut bob_groups = GroupManager::new();
+        let mut carol_groups = GroupManager::new();
+        deliver(&mut bob_groups, &b, &a, &welcome).unwrap();
+        deliver(&mut carol_groups, &c, &a, &welcome).unwrap();
+
+        let old_inbox = alice_groups.get_mut(&group_id).unwrap().inbox_id();
+
+        // Alice removes Carol; only Bob receives the new epoch
+        let alice_group = alice_groups.get_mut(&group_id).unwrap();
+        alice_group.remove_member(&c).unwrap();
+        assert_eq!(alice_group.epoch, 1);
+        assert_ne!(alice_group.inbox_id(), old_inbox);
+        assert_eq!(alice_group.pending_distribution().len(), 1);
+        let rekey = alice_group.welcome();
+        deliver(&mut bob_groups, &b, &a, &rekey).unwrap();
+
+        exchange_sender_keys(&mut [&mut alice_groups, &mut bob_groups], &[&a, &b], &group_id);
+
+        let ciphertext = alice_groups.get_mut(&group_id).unwrap().encrypt(b"after removal").unwrap();
+        let (_, plaintext) = bob_groups.get_mut(&group_id).unwrap().decrypt(&ciphertext).unwrap().unwrap();
+        assert_eq!(plaintext, b"after removal");
+
+        // Carol still holds the old epoch and cannot read it
+        assert!(carol_groups.get_mut(&group_id).unwrap().decrypt(&ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_only_admin_changes_membership() {
+        let (alice, bob) = (member(), member());
+        let (a, b) = (alice.pubkey.clone(), bob.pubkey.clone());
+
+        let group = GroupState::create("club".to_string(), alice, vec![bob]);
+        let group_id = group.group_id.clone();
+
+        let mut bob_groups = GroupManager::new();
+        deliver(&mut bob_groups, &b, &a, &group.welcome()).unwrap();
+        let bob_group = bob_groups.get_mut(&group_id).unwrap();
+
+        assert!(bob_group.add_member(member()).is_err());
+        assert!(bob_group.remove_member(&a).is_err());
+
+        // Welcomes forged by a non-admin are rejected
+        let mut forged = group.welcome();
+        if let GroupControl::Welcome { epoch, .. } = &mut forged {
+            *epoch += 1;
+        }
+        assert!(bob_group.apply_control(&b, forged).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_store_round_trip() {
+        let group = GroupState::create("vault".to_string(), member(), vec![member()]);
+        let group_id = group.group_id.clone();
+        let secret = hex::encode(group.group_secret);
+        let mut groups = GroupManager::new();
+        groups.insert(group);
+        let inbox = groups.get_mut(&group_id).unwrap().inbox_id();
+
+        let key = [7u8; 32];
+        let sealed = groups.to_encrypted(&key).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains(&secret));
+
+        let mut restored = GroupManager::from_encrypted(&key, &sealed).unwrap();
+        assert_eq!(restored.get_mut(&group_id).unwrap().inbox_id(), inbox);
+        assert!(GroupManager::from_encrypted(&[8u8; 32], &sealed).is_err());
+    }
+}
@@ -1,5 +1,12 @@
-use std::collections::HashMap;
-use crate::crypto::{hash_sha256, derive_shared_secret, X25519PrivateKey, X25519PublicKey};
+use crate::crypto::{
+    decrypt_symmetric, derive_shared_secret, encrypt_symmetric, hash_sha256, X25519PrivateKey, X25519PublicKey,
+};
+use crate::error::{NanoError, Result};
+use crate::replay::CounterWindow;
+use serde::{Deserialize, Serialize};
+
+/// Format version of the encrypted conversation store
+const CONVERSATION_STORE_VERSION: u32 = 1;
 
 /// Derives inbox ID for first contact messages
 /// Uses: SHA256("first_contact:" + recipient_public_key)
@@ -47,12 +54,15 @@ pub fn compute_shared_secret(
 }
 
 /// Inbox manager to track conversation state
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConversationState {
+    pub our_public_key: X25519PublicKey,
     pub their_public_key: X25519PublicKey,
+    #[serde(with = "hex_secret")]
     pub shared_secret: [u8; 32],
     pub our_counter: u64,        // Counter for messages we send
     pub their_last_counter: u64, // Last counter we saw from them
+    pub replay_window: CounterWindow, // Counters already accepted from them
 }
 
 impl ConversationState {
@@ -63,16 +73,18 @@ impl ConversationState {
         let shared_secret = compute_shared_secret(our_private, &their_public);
         
         Self {
+            our_public_key: X25519PublicKey::from(our_private),
             their_public_key: their_public,
             shared_secret,
             our_counter: 1, // Start at 1 (0 was the first contact)
             their_last_counter: 0,
+            replay_window: CounterWindow::new(),
         }
     }
 
     /// Get the inbox ID for our next outgoing message
     pub fn get_outgoing_inbox(&mut self) -> String {
-        let inbox = derive_conversation_inbox(&self.shared_secret, self.our_counter);
+        let inbox = derive_conversation_inbox(&self.direction_secret(&self.our_public_key), self.our_counter);
         self.our_counter += 1;
         inbox
     }
@@ -80,16 +92,24 @@ impl ConversationState {
     /// Get inbox IDs we should check for their incoming messages
     pub fn get_incoming_inboxes(&self, check_count: usize) -> Vec<String> {
         // Check from their_last_counter + 1 forward
+        let secret = self.direction_secret(&self.their_public_key);
         let mut inboxes = Vec::new();
         
         for i in 1..=check_count {
             let counter = self.their_last_counter + i as u64;
-            inboxes.push(derive_conversation_inbox(&self.shared_secret, counter));
+            inboxes.push(derive_conversation_inbox(&secret, counter));
         }
         
         inboxes
     }
 
+    /// Inbox secret for messages from `sender`, so the two directions never share an inbox
+    fn direction_secret(&self, sender: &X25519PublicKey) -> [u8; 32] {
+        let mut data = self.shared_secret.to_vec();
+        data.extend_from_slice(sender.as_bytes());
+        hash_sha256(&data)
+    }
+
     /// Update the last seen counter from them
     pub fn update_their_counter(&mut self, counter: u64) {
         if counter > self.their_last_counter {
@@ -97,6 +117,15 @@ impl ConversationState {
         }
     }
 
+    /// Record an incoming message counter, returning `false` if it is a replay
+    pub fn accept_their_counter(&mut self, counter: u64) -> bool {
+        if !self.replay_window.accept(counter) {
+            return false;
+        }
+        self.update_their_counter(counter);
+        true
+    }
+
     /// Get the first contact inbox for this conversation
     pub fn get_first_contact_inbox(&self) -> String {
         derive_first_contact_inbox(&self.their_public_key)
@@ -104,11 +133,18 @@ impl ConversationState {
 }
 
 /// Utility to manage multiple conversations
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct ConversationManager {
     conversations: std::collections::HashMap<String, ConversationState>, // pubkey -> state
 }
 
+/// Versioned plaintext of the encrypted conversation store
+#[derive(Serialize, Deserialize)]
+struct ConversationStore {
+    version: u32,
+    manager: ConversationManager,
+}
+
 impl ConversationManager {
     pub fn new() -> Self {
         Self::default()
@@ -131,10 +167,59 @@ impl ConversationManager {
         self.conversations.get_mut(their_public_key_str)
     }
 
+    /// Drop a conversation, e.g. when the contact's keys changed
+    pub fn remove_conversation(&mut self, their_public_key_str: &str) -> Option<ConversationState> {
+        self.conversations.remove(their_public_key_str)
+    }
+
     /// List all active conversations
     pub fn list_conversations(&self) -> Vec<&str> {
         self.conversations.keys().map(|s| s.as_str()).collect()
     }
+
+    /// Serialize and encrypt all conversation state for storage at rest
+    pub fn to_encrypted(&self, storage_key: &[u8; 32]) -> Result<Vec<u8>> {
+        #[derive(Serialize)]
+        struct ConversationStoreRef<'a> {
+            version: u32,
+            manager: &'a ConversationManager,
+        }
+
+        let store = ConversationStoreRef {
+            version: CONVERSATION_STORE_VERSION,
+            manager: self,
+        };
+        encrypt_symmetric(storage_key, &serde_json::to_vec(&store)?)
+    }
+
+    /// Decrypt conversation state written by `to_encrypted`
+    pub fn from_encrypted(storage_key: &[u8; 32], data: &[u8]) -> Result<Self> {
+        let store: ConversationStore = serde_json::from_slice(&decrypt_symmetric(storage_key, data)?)?;
+        if store.version != CONVERSATION_STORE_VERSION {
+            return Err(NanoError::Storage(format!(
+                "Unsupported conversation store version {}",
+                store.version
+            )));
+        }
+        Ok(store.manager)
+    }
+}
+
+/// Serde helper keeping shared secrets hex encoded inside the encrypted store
+mod hex_secret {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(secret: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(secret))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(encoded)
+            .map_err(serde::de::Error::custom)?
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("shared secret must be 32 bytes"))
+    }
 }
 
 #[cfg(test)]
@@ -196,11 +281,58 @@ mod tests {
         let bob_inboxes = bob_conv.get_incoming_inboxes(5);
         assert!(bob_inboxes.contains(&alice_outbox));
         
+        // Each direction has its own inboxes, so neither side fetches its own messages
+        assert!(!alice_conv.get_incoming_inboxes(5).contains(&alice_outbox));
+        assert_ne!(bob_conv.get_outgoing_inbox(), alice_outbox);
+        
         // Update Bob's counter
         bob_conv.update_their_counter(1);
         assert_eq!(bob_conv.their_last_counter, 1);
     }
 
+    #[test]
+    fn test_conversation_replay_window() {
+        let alice = UserKeyPair::generate();
+        let bob = UserKeyPair::generate();
+
+        let mut conv = ConversationState::new(&alice.x25519_key, bob.public_keys().x25519_key);
+
+        assert!(conv.accept_their_counter(1));
+        assert!(conv.accept_their_counter(3));
+        assert!(!conv.accept_their_counter(1));
+        assert!(conv.accept_their_counter(2));
+        assert_eq!(conv.their_last_counter, 3);
+    }
+
+    #[test]
+    fn test_conversation_manager_encrypted_round_trip() {
+        let alice = UserKeyPair::generate();
+        let bob = UserKeyPair::generate();
+        let bob_public = bob.public_keys();
+        let bob_pubkey_str = bob_public.public_key_string();
+
+        let mut manager = ConversationManager::new();
+        let conv = manager.get_or_create_conversation(&alice.x25519_key, &bob_pubkey_str, bob_public.x25519_key);
+        conv.get_outgoing_inbox();
+        assert!(conv.accept_their_counter(4));
+        let secret = conv.shared_secret;
+
+        let key = crate::crypto::derive_storage_key(&alice.x25519_key, "conversations");
+        let sealed = manager.to_encrypted(&key).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains(&hex::encode(secret)));
+
+        // Counters and replay state survive a restart
+        let mut restored = ConversationManager::from_encrypted(&key, &sealed).unwrap();
+        let conv = restored.get_conversation(&bob_pubkey_str).unwrap();
+        assert_eq!(conv.our_counter, 2);
+        assert_eq!(conv.their_last_counter, 4);
+        assert!(!conv.accept_their_counter(4));
+
+        // Another user's key cannot open the store
+        let wrong_key = crate::crypto::derive_storage_key(&bob.x25519_key, "conversations");
+        assert!(ConversationManager::from_encrypted(&wrong_key, &sealed).is_err());
+    }
+
     #[test]
     fn test_recent_inboxes() {
         let shared_secret = [123u8; 32];
@@ -6,6 +6,26 @@ pub mod username;
 pub mod contacts;
 pub mod network;
 pub mod messages;
+pub mod replay; // Relay and client replay protection
+pub mod traffic; // Cover traffic and relay mixing
+pub mod onion; // Onion-routed delivery through relay chains
+pub mod group; // Group messaging with sender keys
+pub mod mls; // MLS-style tree group key agreement
+pub mod devices; // Linked devices per identity
+pub mod message_db; // Encrypted embedded message database
+pub mod search; // Full-text message search
+pub mod outbox; // Queued outgoing envelopes with retry
+pub mod receipts; // Delivery and read receipts, typing indicators
+pub mod content; // Message edits, deletions and reactions
+pub mod disappearing; // Per-conversation disappearing-message timers
+pub mod rich; // Replies, mentions, formatting and attachment pointers
+pub mod blobs; // Relay-hosted encrypted blob store with capability tokens
+pub mod attachments; // Encrypted file attachments through relay blob stores
+pub mod backup; // Encrypted account export/import archives
+pub mod profiles; // Named identity profiles in one config directory
+pub mod vcard; // Contact import/export as vCard
+pub mod trust; // Trust-on-first-use key pinning and key change history
+pub mod requests; // Message requests from unknown senders
 pub mod config; // Session 6: Adaptive configuration
 pub mod production; // Session 8: Production hardening
 pub mod media; // Session 9: Media and file attachments
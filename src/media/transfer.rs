@@ -3,11 +3,11 @@
 /// Orchestrates file uploads, downloads, and transfers with quantum-resistant
 /// encryption, chunking for large files, and comprehensive progress tracking
 
-use crate::crypto::{CryptoMode, UnifiedPublicKeys, UnifiedKeyPair};
+use crate::crypto::{hash_sha256, CryptoMode, UnifiedPublicKeys, UnifiedKeyPair};
 use crate::error::{NanoError, Result};
 use crate::media::{
     storage::{FileStorage, FileId},
-    encryption::{FileEncryption, EncryptedFile},
+    encryption::{FileEncryption, EncryptedFile, EncryptionMetadata, FileKey},
     metadata::{FileMetadata, MetadataStore, FilePermissions, FileReference, UserId},
 };
 use mime_guess::from_path;
@@ -20,6 +20,9 @@ use std::time::{Duration, SystemTime};
 use tokio::sync::{RwLock, Semaphore};
 use uuid::Uuid;
 
+/// `EncryptionMetadata::custom_params` entry holding a keyed upload's chunk count
+pub const CHUNKS_PARAM: &str = "chunks";
+
 /// File upload data structure
 #[derive(Debug, Clone)]
 pub struct FileUpload {
@@ -270,6 +273,12 @@ impl FileTransferManager {
         }
     }
 
+    /// Set plaintext bytes per encrypted chunk
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
     /// Upload a file
     pub async fn upload_file(
         &self,
@@ -493,6 +502,184 @@ impl FileTransferManager {
         })
     }
 
+    /// Upload a file in chunks encrypted under `file_key`, which the caller shares itself
+    ///
+    /// Every `chunk_size` bytes are sealed on their own and stored as one
+    /// object of big-endian `u32` length-prefixed chunks. The chunk count and
+    /// plaintext hash go into the returned metadata for `download_with_key`.
+    pub async fn upload_with_key(
+        &self,
+        file_upload: FileUpload,
+        uploader: &str,
+        file_key: &FileKey,
+    ) -> Result<FileMetadata> {
+        let _permit = self.concurrent_limit.acquire().await.map_err(|e| {
+            NanoError::Media(format!("Failed to acquire transfer permit: {}", e))
+        })?;
+
+        let file_id = file_upload.file_id;
+        let mut progress = TransferProgress::new(file_id, TransferOperation::Upload, file_upload.size());
+
+        {
+            let mut transfers = self.active_transfers.write().await;
+            transfers.insert(file_id, progress.clone());
+        }
+
+        let result = self.upload_with_key_internal(file_upload, uploader, file_key, &mut progress).await;
+
+        match &result {
+            Ok(_) => progress.complete(),
+            Err(e) => progress.fail(e.to_string()),
+        }
+
+        {
+            let mut transfers = self.active_transfers.write().await;
+            transfers.insert(file_id, progress);
+        }
+
+        result
+    }
+
+    async fn upload_with_key_internal(
+        &self,
+        file_upload: FileUpload,
+        uploader: &str,
+        file_key: &FileKey,
+        progress: &mut TransferProgress,
+    ) -> Result<FileMetadata> {
+        let file_id = file_upload.file_id;
+        let file_size = file_upload.size();
+
+        progress.status = TransferStatus::InProgress;
+        let mut chunks = file_upload.content
+            .chunks(self.chunk_size)
+            .map(|chunk| self.encryption.encrypt_content_symmetric(file_key, chunk))
+            .collect::<Result<Vec<_>>>()?;
+        if chunks.is_empty() {
+            chunks.push(self.encryption.encrypt_content_symmetric(file_key, &[])?);
+        }
+
+        let mut framed = Vec::with_capacity(chunks.iter().map(|chunk| chunk.len() + 4).sum());
+        for chunk in &chunks {
+            framed.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            framed.extend_from_slice(chunk);
+        }
+
+        progress.update(file_size / 2); // 50% after encryption
+
+        let storage_location = self.storage.store_file(file_id, &framed).await?;
+
+        progress.update(file_size); // 100% after storage
+
+        let mut encryption_info = EncryptionMetadata {
+            original_size: file_size,
+            encrypted_size: framed.len() as u64,
+            ..EncryptionMetadata::default()
+        };
+        encryption_info.custom_params.insert(CHUNKS_PARAM.to_string(), chunks.len().to_string());
+
+        let mut metadata = FileMetadata::new(
+            file_id,
+            file_upload.original_name.clone(),
+            file_upload.get_mime_type(),
+            file_size,
+            uploader.to_string(),
+            encryption_info,
+            storage_location,
+            hex::encode(hash_sha256(&file_upload.content)),
+        );
+        metadata.tags = file_upload.tags;
+        metadata.description = file_upload.description;
+        metadata.access_permissions = file_upload.permissions;
+        metadata.expiry_timestamp = file_upload.expires_at;
+        metadata.custom_metadata = file_upload.custom_metadata;
+
+        self.metadata_store.store_metadata(metadata.clone()).await?;
+        Ok(metadata)
+    }
+
+    /// Download a file stored by `upload_with_key`, checking it against its metadata
+    pub async fn download_with_key(&self, metadata: &FileMetadata, file_key: &FileKey) -> Result<DecryptedFile> {
+        let _permit = self.concurrent_limit.acquire().await.map_err(|e| {
+            NanoError::Media(format!("Failed to acquire transfer permit: {}", e))
+        })?;
+
+        let file_id = metadata.file_id;
+        let mut progress = TransferProgress::new(file_id, TransferOperation::Download, metadata.file_size);
+
+        {
+            let mut transfers = self.active_transfers.write().await;
+            transfers.insert(file_id, progress.clone());
+        }
+
+        let result = self.download_with_key_internal(metadata, file_key, &mut progress).await;
+
+        match &result {
+            Ok(_) => progress.complete(),
+            Err(e) => progress.fail(e.to_string()),
+        }
+
+        {
+            let mut transfers = self.active_transfers.write().await;
+            transfers.insert(file_id, progress);
+        }
+
+        result
+    }
+
+    async fn download_with_key_internal(
+        &self,
+        metadata: &FileMetadata,
+        file_key: &FileKey,
+        progress: &mut TransferProgress,
+    ) -> Result<DecryptedFile> {
+        progress.status = TransferStatus::InProgress;
+        let name = &metadata.original_name;
+
+        let framed = self.storage.retrieve_file(&metadata.storage_location).await?;
+
+        progress.update(metadata.file_size / 2); // 50% after retrieval
+
+        let truncated = || NanoError::Media(format!("Stored copy of '{}' is truncated", name));
+        let mut chunks = Vec::new();
+        let mut rest = framed.as_slice();
+        while !rest.is_empty() {
+            let (length, tail) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+            let length = u32::from_be_bytes(*length) as usize;
+            if tail.len() < length {
+                return Err(truncated());
+            }
+            let (chunk, tail) = tail.split_at(length);
+            chunks.push(chunk);
+            rest = tail;
+        }
+
+        let expected = metadata.encryption_info.custom_params.get(CHUNKS_PARAM)
+            .and_then(|count| count.parse::<usize>().ok())
+            .ok_or_else(|| NanoError::Media(format!("No chunk count recorded for '{}'", name)))?;
+        if chunks.len() != expected {
+            return Err(NanoError::Media(format!("Expected {} chunks of '{}', got {}", expected, name, chunks.len())));
+        }
+
+        let mut content = Vec::with_capacity(usize::try_from(metadata.file_size).unwrap_or_default());
+        for chunk in chunks {
+            content.extend(self.encryption.decrypt_content_symmetric(file_key, chunk)?);
+        }
+        if content.len() as u64 != metadata.file_size || hex::encode(hash_sha256(&content)) != metadata.checksum {
+            return Err(NanoError::Media(format!("'{}' does not match its hash", name)));
+        }
+
+        progress.update(metadata.file_size); // 100% after decryption
+
+        Ok(DecryptedFile {
+            file_id: metadata.file_id,
+            original_name: metadata.original_name.clone(),
+            content,
+            mime_type: metadata.mime_type.clone(),
+            metadata: metadata.clone(),
+        })
+    }
+
     /// Get transfer progress
     pub async fn get_transfer_progress(&self, file_id: &FileId) -> Option<TransferProgress> {
         let transfers = self.active_transfers.read().await;
@@ -739,6 +926,45 @@ mod tests {
         assert_eq!(progress.status as u8, TransferStatus::Completed as u8);
     }
 
+    #[tokio::test]
+    async fn test_upload_and_download_with_key() {
+        let (manager, _temp_dir) = create_test_transfer_manager().await;
+        let manager = manager.with_chunk_size(1000);
+        let content: Vec<u8> = (0..2100).map(|i| (i % 251) as u8).collect();
+        let key = FileEncryption::generate_file_key();
+        let upload = FileUpload::new("notes.txt".to_string(), content.clone());
+        let metadata = manager.upload_with_key(upload, "pubkey:alice", &key).await.unwrap();
+
+        assert_eq!(metadata.encryption_info.custom_params[CHUNKS_PARAM], "3");
+        assert_eq!(metadata.mime_type, "text/plain");
+        assert_eq!(manager.download_with_key(&metadata, &key).await.unwrap().content, content);
+
+        // Another key, reordered or missing chunks are all caught
+        let other_key = FileEncryption::generate_file_key();
+        assert!(manager.download_with_key(&metadata, &other_key).await.is_err());
+        let path = std::path::PathBuf::from(&metadata.storage_location.path);
+        let stored = std::fs::read(&path).unwrap();
+        let first = 4 + 1000 + 28;
+        let mut swapped = stored[first..2 * first].to_vec();
+        swapped.extend_from_slice(&stored[..first]);
+        swapped.extend_from_slice(&stored[2 * first..]);
+        for tampered in [swapped, stored[..2 * first].to_vec(), stored[..first + 10].to_vec()] {
+            std::fs::write(&path, tampered).unwrap();
+            assert!(manager.download_with_key(&metadata, &key).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_empty_file_with_key() {
+        let (manager, _temp_dir) = create_test_transfer_manager().await;
+        let key = FileEncryption::generate_file_key();
+        let upload = FileUpload::new("empty.bin".to_string(), Vec::new());
+        let metadata = manager.upload_with_key(upload, "pubkey:alice", &key).await.unwrap();
+
+        assert_eq!(metadata.encryption_info.custom_params[CHUNKS_PARAM], "1");
+        assert!(manager.download_with_key(&metadata, &key).await.unwrap().content.is_empty());
+    }
+
     #[tokio::test]
     async fn test_file_upload_structure() {
         let content = b"test file content";
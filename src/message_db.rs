@@ -0,0 +1,716 @@
+//! Encrypted embedded database behind the client `MessageStore`
+//!
+//! Messages live in a redb file, one row per message, so storing a message
+//! is a single small transaction instead of a rewrite of every message.
+//! Rows are sealed with a key derived from the user's keys. Index keys use
+//! keyed tags for conversation and message IDs; only timestamps stay in the
+//! clear so the indexes can be range-scanned in order. Queries read only the
+//! rows an index range names, so opening the store does not read every message.
+//! The full-text search index is kept in the same file, under keyed token tags,
+//! and changes in the same transaction as the messages it covers.
+
+use crate::crypto::{decrypt_symmetric, encrypt_symmetric};
+use crate::disappearing::ConversationTimer;
+use crate::error::{NanoError, Result};
+use crate::messages::StoredMessage;
+use crate::outbox::OutboxEntry;
+use crate::search::{token_positions, SearchIndex};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use redb::backends::InMemoryBackend;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// message tag -> encrypted `StoredMessage`
+const MESSAGES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("messages");
+/// conversation tag ++ timestamp ++ message tag -> ()
+const BY_CONVERSATION: TableDefinition<&[u8], ()> = TableDefinition::new("by_conversation");
+/// timestamp ++ message tag -> ()
+const BY_TIME: TableDefinition<&[u8], ()> = TableDefinition::new("by_time");
+/// conversation tag -> encrypted (conversation_id, last read time)
+const LAST_READ: TableDefinition<&[u8], &[u8]> = TableDefinition::new("last_read");
+/// outbox entry tag -> encrypted `OutboxEntry`
+const OUTBOX: TableDefinition<&[u8], &[u8]> = TableDefinition::new("outbox");
+/// conversation tag -> encrypted (conversation_id, `ConversationTimer`)
+const TIMERS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("timers");
+/// message tag -> timestamp of a message purged by its disappearing timer
+const PURGED: TableDefinition<&[u8], i64> = TableDefinition::new("purged");
+/// conversation tag -> encrypted conversation ID
+const CONVERSATIONS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("conversations");
+/// expiry timestamp ++ message tag -> ()
+const EXPIRIES: TableDefinition<&[u8], ()> = TableDefinition::new("expiries");
+/// setting name -> value
+const META: TableDefinition<&str, u32> = TableDefinition::new("meta");
+/// token tag ++ message tag -> encrypted (message ID, token positions)
+const SEARCH_POSTINGS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("search_postings");
+/// message tag -> token count of a message in the search index
+const SEARCH_LENGTHS: TableDefinition<&[u8], u32> = TableDefinition::new("search_lengths");
+/// total name -> value, e.g. tokens across the search index
+const SEARCH_TOTALS: TableDefinition<&str, u64> = TableDefinition::new("search_totals");
+
+/// Version of the index tables; older databases are reindexed once on open
+const INDEX_VERSION: u32 = 3;
+
+const TAG_LEN: usize = 32;
+
+/// conversation_id -> when it was last read
+pub type ReadMarkers = HashMap<String, DateTime<Utc>>;
+
+fn db_err(e: impl Into<redb::Error>) -> NanoError {
+    NanoError::Storage(e.into().to_string())
+}
+
+/// Big-endian timestamp whose byte order matches numeric order
+fn sortable_timestamp(timestamp: &DateTime<Utc>) -> [u8; 8] {
+    ((timestamp.timestamp() as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Index key range covering every key under `prefix` whose timestamp falls in `from..=to` seconds
+fn timestamp_range(prefix: &[u8], from: &DateTime<Utc>, to: &DateTime<Utc>) -> (Vec<u8>, Vec<u8>) {
+    (
+        [prefix, &sortable_timestamp(from), &[0x00; TAG_LEN]].concat(),
+        [prefix, &sortable_timestamp(to), &[0xff; TAG_LEN]].concat(),
+    )
+}
+
+pub struct MessageDb {
+    db: Database,
+    key: [u8; 32],
+}
+
+impl MessageDb {
+    /// Open or create the database at `path`, sealing rows with `key`
+    pub fn open(path: &Path, key: [u8; 32]) -> Result<Self> {
+        Self::init(Database::create(path).map_err(db_err)?, key)
+    }
+
+    /// A database that lives only as long as the value, for stores that are not persisted
+    pub fn in_memory(key: [u8; 32]) -> Result<Self> {
+        Self::init(Database::builder().create_with_backend(InMemoryBackend::new()).map_err(db_err)?, key)
+    }
+
+    fn init(db: Database, key: [u8; 32]) -> Result<Self> {
+        // Create every table up front so read transactions can open them
+        let txn = db.begin_write().map_err(db_err)?;
+        txn.open_table(MESSAGES).map_err(db_err)?;
+        txn.open_table(BY_CONVERSATION).map_err(db_err)?;
+        txn.open_table(BY_TIME).map_err(db_err)?;
+        txn.open_table(LAST_READ).map_err(db_err)?;
+        txn.open_table(OUTBOX).map_err(db_err)?;
+        txn.open_table(TIMERS).map_err(db_err)?;
+        txn.open_table(PURGED).map_err(db_err)?;
+        txn.open_table(CONVERSATIONS).map_err(db_err)?;
+        txn.open_table(EXPIRIES).map_err(db_err)?;
+        txn.open_table(META).map_err(db_err)?;
+        txn.open_table(SEARCH_POSTINGS).map_err(db_err)?;
+        txn.open_table(SEARCH_LENGTHS).map_err(db_err)?;
+        txn.open_table(SEARCH_TOTALS).map_err(db_err)?;
+        txn.commit().map_err(db_err)?;
+
+        let db = Self { db, key };
+        db.reindex_if_needed()?;
+        Ok(db)
+    }
+
+    /// Fill the conversation, expiry and search indexes of a database written before they existed
+    fn reindex_if_needed(&self) -> Result<()> {
+        {
+            let txn = self.db.begin_read().map_err(db_err)?;
+            let meta = txn.open_table(META).map_err(db_err)?;
+            if meta.get("index_version").map_err(db_err)?.is_some_and(|version| version.value() >= INDEX_VERSION) {
+                return Ok(());
+            }
+        }
+
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let rows = txn.open_table(MESSAGES).map_err(db_err)?;
+            let mut conversations = txn.open_table(CONVERSATIONS).map_err(db_err)?;
+            let mut expiries = txn.open_table(EXPIRIES).map_err(db_err)?;
+            for row in rows.iter().map_err(db_err)? {
+                let message = self.open_row(row.map_err(db_err)?.1.value())?;
+                self.index_conversation(&mut conversations, &message.conversation_id)?;
+                if let Some(key) = self.expiry_key(&message) {
+                    expiries.insert(key.as_slice(), ()).map_err(db_err)?;
+                }
+                self.index_text(&txn, &message)?;
+            }
+            txn.open_table(META).map_err(db_err)?.insert("index_version", INDEX_VERSION).map_err(db_err)?;
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    fn tag(&self, label: &[u8], value: &str) -> [u8; TAG_LEN] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(label);
+        mac.update(value.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    fn message_tag(&self, id: &str) -> [u8; TAG_LEN] {
+        self.tag(b"message:", id)
+    }
+
+    fn conversation_tag(&self, conversation_id: &str) -> [u8; TAG_LEN] {
+        self.tag(b"conversation:", conversation_id)
+    }
+
+    /// Record a conversation's ID the first time one of its messages is stored
+    fn index_conversation(&self, conversations: &mut redb::Table<&[u8], &[u8]>, conversation_id: &str) -> Result<()> {
+        let tag = self.conversation_tag(conversation_id);
+        if conversations.get(tag.as_slice()).map_err(db_err)?.is_none() {
+            let sealed = encrypt_symmetric(&self.key, conversation_id.as_bytes())?;
+            conversations.insert(tag.as_slice(), sealed.as_slice()).map_err(db_err)?;
+        }
+        Ok(())
+    }
+
+    /// Index key range covering every message of a conversation
+    fn conversation_bounds(&self, conversation_id: &str) -> (Vec<u8>, Vec<u8>) {
+        // Index keys are the conversation tag followed by 40 bytes of timestamp and message tag
+        let prefix = self.conversation_tag(conversation_id);
+        ([prefix.as_slice(), &[0x00; 8 + TAG_LEN]].concat(), [prefix.as_slice(), &[0xff; 8 + TAG_LEN]].concat())
+    }
+
+    fn token_tag(&self, token: &str) -> [u8; TAG_LEN] {
+        self.tag(b"token:", token)
+    }
+
+    /// Add a message's text to the search index, unless it is deleted or already indexed
+    fn index_text(&self, txn: &WriteTransaction, message: &StoredMessage) -> Result<()> {
+        let message_tag = self.message_tag(&message.id);
+        let mut lengths = txn.open_table(SEARCH_LENGTHS).map_err(db_err)?;
+        if message.deleted || lengths.get(message_tag.as_slice()).map_err(db_err)?.is_some() {
+            return Ok(());
+        }
+
+        let (positions, length) = token_positions(&message.content);
+        let mut postings = txn.open_table(SEARCH_POSTINGS).map_err(db_err)?;
+        for (token, positions) in positions {
+            let sealed = encrypt_symmetric(&self.key, &serde_json::to_vec(&(&message.id, positions))?)?;
+            let key = [self.token_tag(&token), message_tag].concat();
+            postings.insert(key.as_slice(), sealed.as_slice()).map_err(db_err)?;
+        }
+        lengths.insert(message_tag.as_slice(), length).map_err(db_err)?;
+
+        let mut totals = txn.open_table(SEARCH_TOTALS).map_err(db_err)?;
+        let tokens = totals.get("tokens").map_err(db_err)?.map_or(0, |total| total.value());
+        totals.insert("tokens", tokens + length as u64).map_err(db_err)?;
+        Ok(())
+    }
+
+    /// Drop a message's text, as `index_text` added it, from the search index
+    fn unindex_text(&self, txn: &WriteTransaction, message: &StoredMessage) -> Result<()> {
+        let message_tag = self.message_tag(&message.id);
+        let mut lengths = txn.open_table(SEARCH_LENGTHS).map_err(db_err)?;
+        let Some(length) = lengths.remove(message_tag.as_slice()).map_err(db_err)?.map(|length| length.value()) else {
+            return Ok(());
+        };
+
+        let mut postings = txn.open_table(SEARCH_POSTINGS).map_err(db_err)?;
+        for token in token_positions(&message.content).0.keys() {
+            postings.remove([self.token_tag(token), message_tag].concat().as_slice()).map_err(db_err)?;
+        }
+
+        let mut totals = txn.open_table(SEARCH_TOTALS).map_err(db_err)?;
+        let tokens = totals.get("tokens").map_err(db_err)?.map_or(0, |total| total.value());
+        totals.insert("tokens", tokens.saturating_sub(length as u64)).map_err(db_err)?;
+        Ok(())
+    }
+
+    fn expiry_key(&self, message: &StoredMessage) -> Option<Vec<u8>> {
+        let expires_at = message.expires_at.as_ref()?;
+        Some([sortable_timestamp(expires_at).as_slice(), &self.message_tag(&message.id)].concat())
+    }
+
+    /// Insert messages in one transaction, skipping IDs already stored
+    pub fn insert_messages<'a>(&self, messages: impl IntoIterator<Item = &'a StoredMessage>) -> Result<()> {
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut rows = txn.open_table(MESSAGES).map_err(db_err)?;
+            let mut by_conversation = txn.open_table(BY_CONVERSATION).map_err(db_err)?;
+            let mut by_time = txn.open_table(BY_TIME).map_err(db_err)?;
+            let mut conversations = txn.open_table(CONVERSATIONS).map_err(db_err)?;
+            let mut expiries = txn.open_table(EXPIRIES).map_err(db_err)?;
+
+            for message in messages {
+                let message_tag = self.message_tag(&message.id);
+                if rows.get(message_tag.as_slice()).map_err(db_err)?.is_some() {
+                    continue;
+                }
+
+                let sealed = encrypt_symmetric(&self.key, &serde_json::to_vec(message)?)?;
+                rows.insert(message_tag.as_slice(), sealed.as_slice()).map_err(db_err)?;
+
+                let timestamp = sortable_timestamp(&message.timestamp);
+                let conversation_key = [
+                    self.conversation_tag(&message.conversation_id).as_slice(),
+                    &timestamp,
+                    &message_tag,
+                ]
+                .concat();
+                by_conversation.insert(conversation_key.as_slice(), ()).map_err(db_err)?;
+                by_time.insert([timestamp.as_slice(), &message_tag].concat().as_slice(), ()).map_err(db_err)?;
+                self.index_conversation(&mut conversations, &message.conversation_id)?;
+                if let Some(key) = self.expiry_key(message) {
+                    expiries.insert(key.as_slice(), ()).map_err(db_err)?;
+                }
+                self.index_text(&txn, message)?;
+            }
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    pub fn insert_message(&self, message: &StoredMessage) -> Result<()> {
+        self.insert_messages(std::iter::once(message))
+    }
+
+    /// Replace a stored message's row; its ID, conversation and timestamp must not change
+    pub fn update_message(&self, message: &StoredMessage) -> Result<()> {
+        let sealed = encrypt_symmetric(&self.key, &serde_json::to_vec(message)?)?;
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut rows = txn.open_table(MESSAGES).map_err(db_err)?;
+            let mut expiries = txn.open_table(EXPIRIES).map_err(db_err)?;
+            let message_tag = self.message_tag(&message.id);
+            let previous = match rows.get(message_tag.as_slice()).map_err(db_err)? {
+                Some(row) => Some(self.open_row(row.value())?),
+                None => None,
+            };
+            if let Some(key) = previous.as_ref().and_then(|previous| self.expiry_key(previous)) {
+                expiries.remove(key.as_slice()).map_err(db_err)?;
+            }
+            if let Some(key) = self.expiry_key(message) {
+                expiries.insert(key.as_slice(), ()).map_err(db_err)?;
+            }
+            rows.insert(message_tag.as_slice(), sealed.as_slice()).map_err(db_err)?;
+
+            // Edits and deletes change what search finds
+            if let Some(previous) = previous.filter(|previous| previous.content != message.content || previous.deleted != message.deleted) {
+                self.unindex_text(&txn, &previous)?;
+                self.index_text(&txn, message)?;
+            }
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    /// Delete every message whose timer ran out by `now`, leaving a tombstone for each, then compact the file
+    ///
+    /// Rows are sealed, so pages freed but not yet reused hold only ciphertext.
+    /// Returns the purged messages.
+    pub fn purge_expired(&mut self, now: DateTime<Utc>) -> Result<Vec<StoredMessage>> {
+        let txn = self.db.begin_write().map_err(db_err)?;
+        let mut messages = Vec::new();
+        {
+            let mut rows = txn.open_table(MESSAGES).map_err(db_err)?;
+            let mut by_conversation = txn.open_table(BY_CONVERSATION).map_err(db_err)?;
+            let mut by_time = txn.open_table(BY_TIME).map_err(db_err)?;
+            let mut purged = txn.open_table(PURGED).map_err(db_err)?;
+            let mut expiries = txn.open_table(EXPIRIES).map_err(db_err)?;
+
+            let end = [sortable_timestamp(&now).as_slice(), &[0xff; TAG_LEN]].concat();
+            let mut due = Vec::new();
+            for entry in expiries.range(..=end.as_slice()).map_err(db_err)? {
+                due.push(entry.map_err(db_err)?.0.value().to_vec());
+            }
+            for key in due {
+                let Some(row) = rows.get(&key[key.len() - TAG_LEN..]).map_err(db_err)? else {
+                    expiries.remove(key.as_slice()).map_err(db_err)?;
+                    continue;
+                };
+                let message = self.open_row(row.value())?;
+                drop(row);
+                // Expiry keys only hold whole seconds
+                if message.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                    expiries.remove(key.as_slice()).map_err(db_err)?;
+                    messages.push(message);
+                }
+            }
+
+            for message in &messages {
+                let message_tag = self.message_tag(&message.id);
+                let timestamp = sortable_timestamp(&message.timestamp);
+                let conversation_key = [
+                    self.conversation_tag(&message.conversation_id).as_slice(),
+                    &timestamp,
+                    &message_tag,
+                ]
+                .concat();
+
+                rows.remove(message_tag.as_slice()).map_err(db_err)?;
+                by_conversation.remove(conversation_key.as_slice()).map_err(db_err)?;
+                by_time.remove([timestamp.as_slice(), &message_tag].concat().as_slice()).map_err(db_err)?;
+                purged.insert(message_tag.as_slice(), message.timestamp.timestamp()).map_err(db_err)?;
+                self.unindex_text(&txn, message)?;
+            }
+        }
+        txn.commit().map_err(db_err)?;
+
+        if !messages.is_empty() {
+            self.db.compact().map_err(db_err)?;
+        }
+        Ok(messages)
+    }
+
+    /// Whether a message with this ID was purged
+    pub fn is_purged(&self, message_id: &str) -> Result<bool> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let purged = txn.open_table(PURGED).map_err(db_err)?;
+        Ok(purged.get(self.message_tag(message_id).as_slice()).map_err(db_err)?.is_some())
+    }
+
+    /// Forget tombstones of messages sent before `before`
+    pub fn prune_purged(&self, before: DateTime<Utc>) -> Result<()> {
+        let txn = self.db.begin_write().map_err(db_err)?;
+        txn.open_table(PURGED)
+            .map_err(db_err)?
+            .retain(|_, timestamp| timestamp >= before.timestamp())
+            .map_err(db_err)?;
+        txn.commit().map_err(db_err)
+    }
+
+    fn open_row(&self, sealed: &[u8]) -> Result<StoredMessage> {
+        serde_json::from_slice(&decrypt_symmetric(&self.key, sealed)?).map_err(Into::into)
+    }
+
+    /// Load the messages whose tags appear at the end of the given index keys
+    fn load_tagged(&self, index_keys: Vec<Vec<u8>>) -> Result<Vec<StoredMessage>> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let rows = txn.open_table(MESSAGES).map_err(db_err)?;
+
+        index_keys
+            .iter()
+            .filter_map(|key| {
+                let message_tag = &key[key.len() - TAG_LEN..];
+                rows.get(message_tag).transpose()
+            })
+            .map(|row| self.open_row(row.map_err(db_err)?.value()))
+            .collect()
+    }
+
+    /// The message with this ID, if stored
+    pub fn message(&self, message_id: &str) -> Result<Option<StoredMessage>> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let rows = txn.open_table(MESSAGES).map_err(db_err)?;
+        let row = rows.get(self.message_tag(message_id).as_slice()).map_err(db_err)?;
+        row.map(|row| self.open_row(row.value())).transpose()
+    }
+
+    /// Messages of a conversation sent from `from` to `to`, to the second, oldest first
+    pub fn conversation_between(&self, conversation_id: &str, from: &DateTime<Utc>, to: &DateTime<Utc>) -> Result<Vec<StoredMessage>> {
+        let (start, end) = timestamp_range(&self.conversation_tag(conversation_id), from, to);
+        let keys = {
+            let txn = self.db.begin_read().map_err(db_err)?;
+            let index = txn.open_table(BY_CONVERSATION).map_err(db_err)?;
+            let mut keys = Vec::new();
+            for entry in index.range(start.as_slice()..=end.as_slice()).map_err(db_err)? {
+                keys.push(entry.map_err(db_err)?.0.value().to_vec());
+            }
+            keys
+        };
+        self.load_tagged(keys)
+    }
+
+    /// Number of messages in a conversation, counted from the index alone
+    pub fn conversation_len(&self, conversation_id: &str) -> Result<usize> {
+        let (start, end) = self.conversation_bounds(conversation_id);
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let index = txn.open_table(BY_CONVERSATION).map_err(db_err)?;
+        Ok(index.range(start.as_slice()..=end.as_slice()).map_err(db_err)?.count())
+    }
+
+    /// ID of every conversation that ever had a message
+    pub fn conversation_ids(&self) -> Result<Vec<String>> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let mut ids = Vec::new();
+        for row in txn.open_table(CONVERSATIONS).map_err(db_err)?.iter().map_err(db_err)? {
+            let sealed = decrypt_symmetric(&self.key, row.map_err(db_err)?.1.value())?;
+            ids.push(String::from_utf8(sealed).map_err(|e| NanoError::Storage(e.to_string()))?);
+        }
+        Ok(ids)
+    }
+
+    pub fn message_count(&self) -> Result<usize> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        Ok(txn.open_table(MESSAGES).map_err(db_err)?.len().map_err(db_err)? as usize)
+    }
+
+    /// Latest `limit` messages of a conversation, oldest first
+    pub fn conversation_messages(&self, conversation_id: &str, limit: Option<usize>) -> Result<Vec<StoredMessage>> {
+        let (start, end) = self.conversation_bounds(conversation_id);
+        let keys = {
+            let txn = self.db.begin_read().map_err(db_err)?;
+            let index = txn.open_table(BY_CONVERSATION).map_err(db_err)?;
+            let range = index.range(start.as_slice()..=end.as_slice()).map_err(db_err)?;
+            let mut keys = Vec::new();
+            for entry in range.rev().take(limit.unwrap_or(usize::MAX)) {
+                keys.push(entry.map_err(db_err)?.0.value().to_vec());
+            }
+            keys.reverse();
+            keys
+        };
+        self.load_tagged(keys)
+    }
+
+    /// Latest `limit` messages across all conversations, newest first
+    pub fn recent_messages(&self, limit: Option<usize>) -> Result<Vec<StoredMessage>> {
+        let keys = {
+            let txn = self.db.begin_read().map_err(db_err)?;
+            let index = txn.open_table(BY_TIME).map_err(db_err)?;
+            let mut keys = Vec::new();
+            for entry in index.iter().map_err(db_err)?.rev().take(limit.unwrap_or(usize::MAX)) {
+                keys.push(entry.map_err(db_err)?.0.value().to_vec());
+            }
+            keys
+        };
+        self.load_tagged(keys)
+    }
+
+    pub fn set_last_read(&self, conversation_id: &str, read_at: DateTime<Utc>) -> Result<()> {
+        let sealed = encrypt_symmetric(&self.key, &serde_json::to_vec(&(conversation_id, read_at))?)?;
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut table = txn.open_table(LAST_READ).map_err(db_err)?;
+            let tag = self.conversation_tag(conversation_id);
+            table.insert(tag.as_slice(), sealed.as_slice()).map_err(db_err)?;
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    pub fn set_timer(&self, conversation_id: &str, timer: &ConversationTimer) -> Result<()> {
+        let sealed = encrypt_symmetric(&self.key, &serde_json::to_vec(&(conversation_id, timer))?)?;
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut table = txn.open_table(TIMERS).map_err(db_err)?;
+            let tag = self.conversation_tag(conversation_id);
+            table.insert(tag.as_slice(), sealed.as_slice()).map_err(db_err)?;
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    /// Disappearing-message timer of every conversation that has one
+    pub fn load_timers(&self) -> Result<HashMap<String, ConversationTimer>> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let mut timers = HashMap::new();
+        for row in txn.open_table(TIMERS).map_err(db_err)?.iter().map_err(db_err)? {
+            let sealed = decrypt_symmetric(&self.key, row.map_err(db_err)?.1.value())?;
+            let (conversation_id, timer): (String, ConversationTimer) = serde_json::from_slice(&sealed)?;
+            timers.insert(conversation_id, timer);
+        }
+        Ok(timers)
+    }
+
+    /// Insert or replace an outbox entry
+    pub fn save_outbox_entry(&self, entry: &OutboxEntry) -> Result<()> {
+        let sealed = encrypt_symmetric(&self.key, &serde_json::to_vec(entry)?)?;
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut table = txn.open_table(OUTBOX).map_err(db_err)?;
+            let tag = self.tag(b"outbox:", &entry.id);
+            table.insert(tag.as_slice(), sealed.as_slice()).map_err(db_err)?;
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    pub fn remove_outbox_entries(&self, ids: &[String]) -> Result<()> {
+        let txn = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut table = txn.open_table(OUTBOX).map_err(db_err)?;
+            for id in ids {
+                table.remove(self.tag(b"outbox:", id).as_slice()).map_err(db_err)?;
+            }
+        }
+        txn.commit().map_err(db_err)
+    }
+
+    pub fn load_outbox(&self) -> Result<Vec<OutboxEntry>> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let mut entries = Vec::new();
+        for row in txn.open_table(OUTBOX).map_err(db_err)?.iter().map_err(db_err)? {
+            let sealed = decrypt_symmetric(&self.key, row.map_err(db_err)?.1.value())?;
+            entries.push(serde_json::from_slice(&sealed)?);
+        }
+        Ok(entries)
+    }
+
+    /// When each conversation was last read
+    pub fn load_read_markers(&self) -> Result<ReadMarkers> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let mut last_read = ReadMarkers::new();
+        for row in txn.open_table(LAST_READ).map_err(db_err)?.iter().map_err(db_err)? {
+            let sealed = decrypt_symmetric(&self.key, row.map_err(db_err)?.1.value())?;
+            let (conversation_id, read_at): (String, DateTime<Utc>) = serde_json::from_slice(&sealed)?;
+            last_read.insert(conversation_id, read_at);
+        }
+        Ok(last_read)
+    }
+
+    /// The search index's postings for `tokens` and the lengths of the messages they name,
+    /// with totals over the whole index, so ranking matches a full in-memory index
+    pub fn search_postings(&self, tokens: &[&String]) -> Result<SearchIndex> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let table = txn.open_table(SEARCH_POSTINGS).map_err(db_err)?;
+        let lengths_table = txn.open_table(SEARCH_LENGTHS).map_err(db_err)?;
+
+        let mut postings = HashMap::new();
+        let mut lengths = HashMap::new();
+        for token in tokens {
+            let prefix = self.token_tag(token);
+            let (start, end) = ([prefix.as_slice(), &[0x00; TAG_LEN]].concat(), [prefix.as_slice(), &[0xff; TAG_LEN]].concat());
+            let mut messages = HashMap::new();
+            for entry in table.range(start.as_slice()..=end.as_slice()).map_err(db_err)? {
+                let (key, value) = entry.map_err(db_err)?;
+                let (id, positions): (String, Vec<u32>) = serde_json::from_slice(&decrypt_symmetric(&self.key, value.value())?)?;
+                let length = lengths_table.get(&key.value()[TAG_LEN..]).map_err(db_err)?.map_or(0, |length| length.value());
+                lengths.insert(id.clone(), length);
+                messages.insert(id, positions);
+            }
+            if !messages.is_empty() {
+                postings.insert(token.to_string(), messages);
+            }
+        }
+
+        let documents = lengths_table.len().map_err(db_err)?;
+        let total_length = txn.open_table(SEARCH_TOTALS).map_err(db_err)?
+            .get("tokens").map_err(db_err)?
+            .map_or(0, |total| total.value());
+        Ok(SearchIndex::partial(postings, lengths, documents, total_length))
+    }
+
+    /// Every stored message, for export and filter-only search
+    pub fn all_messages(&self) -> Result<Vec<StoredMessage>> {
+        let txn = self.db.begin_read().map_err(db_err)?;
+        let mut messages = Vec::new();
+        for row in txn.open_table(MESSAGES).map_err(db_err)?.iter().map_err(db_err)? {
+            messages.push(self.open_row(row.map_err(db_err)?.1.value())?);
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(conversation_id: &str, id: &str, timestamp: i64) -> StoredMessage {
+        let timestamp = DateTime::from_timestamp(timestamp, 0).unwrap();
+        StoredMessage {
+            id: id.to_string(),
+            from_pubkey: "pubkey:alice".to_string(),
+            to_pubkey: "pubkey:bob".to_string(),
+            content: format!("secret body {}", id),
+            timestamp,
+            received_at: timestamp,
+            is_outgoing: false,
+            conversation_id: conversation_id.to_string(),
+            counter: 0,
+            crypto_mode: None,
+            receipt: None,
+            edits: Vec::new(),
+            deleted: false,
+            reactions: Default::default(),
+            timer_change: None,
+            expires_at: None,
+            expires_after_read: None,
+            rich: None,
+            key_change: None,
+        }
+    }
+
+    #[test]
+    fn test_indexed_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = MessageDb::open(&dir.path().join("messages.redb"), [7u8; 32]).unwrap();
+
+        db.insert_messages(&[message("a|b", "1", 30), message("a|c", "2", 10), message("a|b", "3", 20)])
+            .unwrap();
+        db.insert_message(&message("a|b", "1", 30)).unwrap(); // Duplicate is ignored
+
+        let conversation = db.conversation_messages("a|b", None).unwrap();
+        assert_eq!(conversation.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), ["3", "1"]);
+        assert_eq!(db.conversation_messages("a|b", Some(1)).unwrap()[0].id, "1");
+
+        let recent = db.recent_messages(Some(2)).unwrap();
+        assert_eq!(recent.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), ["1", "3"]);
+
+        let at = |seconds| DateTime::from_timestamp(seconds, 0).unwrap();
+        assert_eq!(db.conversation_between("a|b", &at(20), &at(25)).unwrap()[0].id, "3");
+        assert_eq!(db.conversation_len("a|b").unwrap(), 2);
+        assert_eq!(db.message("2").unwrap().unwrap().conversation_id, "a|c");
+        assert!(db.message("4").unwrap().is_none());
+
+        let mut conversations = db.conversation_ids().unwrap();
+        conversations.sort();
+        assert_eq!(conversations, ["a|b", "a|c"]);
+        assert_eq!(db.message_count().unwrap(), 3);
+        assert_eq!(db.all_messages().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_encrypted_and_durable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        {
+            let db = MessageDb::open(&path, [7u8; 32]).unwrap();
+            db.insert_message(&message("a|b", "1", 30)).unwrap();
+            db.set_last_read("a|b", DateTime::from_timestamp(40, 0).unwrap()).unwrap();
+        }
+
+        // Nothing readable on disk
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(11).any(|w| w == b"secret body"));
+        assert!(!raw.windows(3).any(|w| w == b"a|b"));
+
+        // Reopening keeps committed data; the wrong key cannot read it
+        let db = MessageDb::open(&path, [7u8; 32]).unwrap();
+        assert_eq!(db.all_messages().unwrap()[0].content, "secret body 1");
+        assert_eq!(db.load_read_markers().unwrap()["a|b"].timestamp(), 40);
+        drop(db);
+        assert!(MessageDb::open(&path, [8u8; 32]).unwrap().all_messages().is_err());
+    }
+
+    #[test]
+    fn test_outbox_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = MessageDb::open(&dir.path().join("messages.redb"), [7u8; 32]).unwrap();
+        let envelope = crate::protocol::MessageEnvelope::new("inbox".to_string(), vec![1, 2, 3]);
+        let mut entry = OutboxEntry::new("m1".to_string(), envelope, Vec::new(), false);
+
+        db.save_outbox_entry(&entry).unwrap();
+        entry.record_sent();
+        db.save_outbox_entry(&entry).unwrap(); // Replaces the pending row
+        let loaded = db.load_outbox().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].status, crate::outbox::DeliveryStatus::Sent);
+
+        db.remove_outbox_entries(&[entry.id.clone()]).unwrap();
+        assert!(db.load_outbox().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_purged_messages_leave_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let mut db = MessageDb::open(&path, [7u8; 32]).unwrap();
+        let mut expiring = message("a|b", "1", 30);
+        expiring.expires_at = DateTime::from_timestamp(50, 0);
+        db.insert_messages(&[expiring.clone(), message("a|b", "2", 40)]).unwrap();
+
+        // Starting a countdown moves the message in the expiry index
+        expiring.expires_at = DateTime::from_timestamp(35, 0);
+        db.update_message(&expiring).unwrap();
+        assert!(db.purge_expired(DateTime::from_timestamp(34, 0).unwrap()).unwrap().is_empty());
+        let purged = db.purge_expired(DateTime::from_timestamp(35, 0).unwrap()).unwrap();
+        assert_eq!(purged.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), ["1"]);
+        assert_eq!(db.conversation_messages("a|b", None).unwrap().len(), 1);
+        assert_eq!(db.recent_messages(None).unwrap().len(), 1);
+        assert!(db.is_purged("1").unwrap());
+        assert!(!db.is_purged("2").unwrap());
+
+        // Tombstones are dropped once copies would be too old to accept
+        db.prune_purged(DateTime::from_timestamp(31, 0).unwrap()).unwrap();
+        assert!(!db.is_purged("1").unwrap());
+    }
+}
@@ -1,8 +1,24 @@
-use crate::error::Result;
+use crate::content::{MessageContent, MessageRef, Reaction};
+use crate::crypto::CryptoMode;
+use crate::disappearing::{ConversationTimer, DisappearingTimer};
+use crate::error::{NanoError, Result};
+use crate::message_db::MessageDb;
+use crate::outbox::{DeliveryStatus, Outbox, OutboxEntry, MAX_OUTBOX_AGE_SECS};
 use crate::protocol::MessagePayload;
+use crate::receipts::ReceiptStatus;
+use crate::rich::RichContent;
+use crate::search::SearchQuery;
+use crate::trust::KeyChange;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Shown in place of a message its author deleted
+pub const DELETED_TEXT: &str = "🗑 This message was deleted";
+
+/// Sender of notices the client adds to a conversation itself
+pub const SYSTEM_SENDER: &str = "system";
 
 /// A stored message in the local database
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,11 +32,45 @@ pub struct StoredMessage {
     pub is_outgoing: bool,             // True if we sent this message
     pub conversation_id: String,       // Conversation identifier
     pub counter: u64,                  // Message counter in conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crypto_mode: Option<CryptoMode>, // Signature mode, if the sender recorded one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<ReceiptStatus>, // Latest receipt for an outgoing message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<MessageEdit>,       // Earlier versions, oldest first
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,                 // Deleted for everyone by its author
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, ReactionMark>, // pubkey -> their latest reaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timer_change: Option<DisappearingTimer>, // Timer this message set for its conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>, // When the sweeper purges it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_after_read: Option<u64>, // Seconds it is kept once read, while still unread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rich: Option<RichContent>, // Reply, mentions, formatting and attachments of `content`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_change: Option<KeyChange>, // Key change this system notice reports
+}
+
+/// Text a message had before its author edited it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub content: String,            // Text before the edit
+    pub replaced_at: DateTime<Utc>, // When the author replaced it
+}
+
+/// One participant's latest reaction; withdrawn ones are kept so an older reaction cannot return
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReactionMark {
+    pub reaction: Option<Reaction>,
+    pub reacted_at: DateTime<Utc>,
 }
 
 impl StoredMessage {
     pub fn from_payload(
-        payload: MessagePayload,
+        mut payload: MessagePayload,
         our_pubkey: String,
         received_at: DateTime<Utc>,
         is_outgoing: bool,
@@ -32,6 +82,7 @@ impl StoredMessage {
         };
 
         let id = format!("{}:{}:{}", conversation_id, payload.counter, payload.timestamp);
+        let rich = payload.rich.take();
 
         Self {
             id,
@@ -44,7 +95,103 @@ impl StoredMessage {
             is_outgoing,
             conversation_id,
             counter: payload.counter,
+            crypto_mode: payload.crypto_mode,
+            receipt: None,
+            edits: Vec::new(),
+            deleted: false,
+            reactions: BTreeMap::new(),
+            timer_change: payload.disappearing,
+            expires_at: None,
+            expires_after_read: None,
+            rich: None,
+            key_change: None,
+        }
+        .with_rich(rich)
+    }
+
+    /// System notice in a conversation that the contact's keys changed
+    pub fn key_change_notice(conversation_id: String, our_pubkey: String, change: KeyChange) -> Self {
+        let content = if change.accepted {
+            format!(
+                "🔑 The keys of {} changed ({} → {}). Compare fingerprints with them to make sure.",
+                change.contact, change.old_fingerprint, change.new_fingerprint
+            )
+        } else {
+            format!(
+                "🔑 The keys of {} changed ({} → {}); messages are blocked until you accept the new keys.",
+                change.contact, change.old_fingerprint, change.new_fingerprint
+            )
+        };
+        Self {
+            id: format!("key-change:{}:{}", conversation_id, change.seen_at.timestamp_micros()),
+            from_pubkey: SYSTEM_SENDER.to_string(),
+            to_pubkey: our_pubkey,
+            content,
+            timestamp: change.seen_at,
+            received_at: change.seen_at,
+            is_outgoing: false,
+            conversation_id,
+            counter: 0,
+            crypto_mode: None,
+            receipt: None,
+            edits: Vec::new(),
+            deleted: false,
+            reactions: BTreeMap::new(),
+            timer_change: None,
+            expires_at: None,
+            expires_after_read: None,
+            rich: None,
+            key_change: Some(change),
+        }
+    }
+
+    /// Attach rich content, whose text replaces the fallback body; content this client cannot show is dropped
+    pub fn with_rich(mut self, rich: Option<RichContent>) -> Self {
+        if let Some(rich) = rich.filter(|rich| rich.validate().is_ok()) {
+            self.content = rich.text.clone();
+            self.rich = Some(rich);
+        }
+        self
+    }
+
+    /// How other participants refer to this message
+    pub fn message_ref(&self) -> MessageRef {
+        MessageRef::new(self.from_pubkey.clone(), self.timestamp.timestamp())
+    }
+
+    /// When the current text replaced an earlier one, if it was edited
+    pub fn edited_at(&self) -> Option<DateTime<Utc>> {
+        self.edits.last().map(|edit| edit.replaced_at)
+    }
+
+    /// Content to show, with a placeholder for deleted messages
+    pub fn display_text(&self) -> &str {
+        if self.deleted {
+            DELETED_TEXT
+        } else {
+            &self.content
+        }
+    }
+
+    /// Content to show with its reply quote, formatting and attachments
+    pub fn rendered_text(&self) -> String {
+        match &self.rich {
+            Some(rich) if !self.deleted => rich.render(),
+            _ => self.display_text().to_string(),
+        }
+    }
+
+    /// Current reactions with how many participants chose each, most popular first
+    pub fn reaction_counts(&self) -> Vec<(Reaction, usize)> {
+        let mut counts: Vec<(Reaction, usize)> = Vec::new();
+        for reaction in self.reactions.values().filter_map(|mark| mark.reaction) {
+            match counts.iter_mut().find(|(counted, _)| *counted == reaction) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((reaction, 1)),
+            }
         }
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts
     }
 }
 
@@ -57,14 +204,27 @@ pub struct ConversationSummary {
     pub last_timestamp: DateTime<Utc>,
     pub unread_count: usize,
     pub message_count: usize,
+    pub last_receipt: Option<ReceiptStatus>, // Receipt for the last message, if we sent it
 }
 
 /// Message storage and retrieval
-#[derive(Default)]
+///
+/// Messages stay in the database; conversation and time queries read only
+/// the rows their index range names. The full-text index lives in the database
+/// too, updated as messages are stored, edited, deleted and purged, so a text
+/// search reads only the postings of its words.
 pub struct MessageStore {
-    messages: HashMap<String, StoredMessage>, // message_id -> message
-    conversations: HashMap<String, Vec<String>>, // conversation_id -> [message_ids]
+    db: MessageDb,
     last_read: HashMap<String, DateTime<Utc>>, // conversation_id -> last_read_time
+    outbox: Outbox,                            // Outgoing envelopes and their delivery state
+    timers: HashMap<String, ConversationTimer>, // conversation_id -> disappearing-message timer
+}
+
+impl Default for MessageStore {
+    /// A store kept in memory only
+    fn default() -> Self {
+        Self::with_db(MessageDb::in_memory(rand::random()).expect("in-memory database opens"))
+    }
 }
 
 impl MessageStore {
@@ -72,216 +232,339 @@ impl MessageStore {
         Self::default()
     }
 
-    /// Store a new message
-    pub fn store_message(&mut self, message: StoredMessage) -> Result<()> {
-        // Check for duplicate
-        if self.messages.contains_key(&message.id) {
-            return Ok(()); // Already stored
+    fn with_db(db: MessageDb) -> Self {
+        Self {
+            db,
+            last_read: HashMap::new(),
+            outbox: Outbox::default(),
+            timers: HashMap::new(),
         }
+    }
 
-        let conversation_id = message.conversation_id.clone();
-        let message_id = message.id.clone();
-
-        // Store the message
-        self.messages.insert(message_id.clone(), message);
-
-        // Add to conversation
-        let conversation_id_clone = conversation_id.clone();
-        self.conversations
-            .entry(conversation_id_clone)
-            .or_default()
-            .push(message_id);
-
-        // Sort conversation messages by timestamp
-        if let Some(msg_ids) = self.conversations.get_mut(&conversation_id) {
-            msg_ids.sort_by(|a, b| {
-                let msg_a = &self.messages[a];
-                let msg_b = &self.messages[b];
-                msg_a.timestamp.cmp(&msg_b.timestamp)
-            });
+    /// Open a persistent store backed by an encrypted database file
+    pub fn open(path: &Path, storage_key: [u8; 32]) -> Result<Self> {
+        let db = MessageDb::open(path, storage_key)?;
+        let last_read = db.load_read_markers()?;
+        let timers = db.load_timers()?;
+
+        // Sent entries only matter while the message is recent
+        let mut outbox = Outbox::default();
+        for entry in db.load_outbox()? {
+            outbox.insert(entry);
+        }
+        let stale = outbox.stale_sent(Utc::now() - chrono::Duration::seconds(MAX_OUTBOX_AGE_SECS));
+        if !stale.is_empty() {
+            db.remove_outbox_entries(&stale)?;
+            for id in &stale {
+                outbox.remove(id);
+            }
         }
 
-        Ok(())
+        // Tombstones only need to outlive copies receivers would still accept
+        db.prune_purged(Utc::now() - chrono::Duration::seconds(MAX_OUTBOX_AGE_SECS))?;
+
+        let mut store = Self { last_read, timers, outbox, ..Self::with_db(db) };
+        store.purge_expired(Utc::now())?;
+        Ok(store)
+    }
+
+    /// Underlying database, for indexed queries
+    pub fn database(&self) -> &MessageDb {
+        &self.db
+    }
+
+    /// Store a new message, returning whether it was new
+    ///
+    /// A message that changes its conversation's disappearing timer applies
+    /// the change first; the message then expires under the conversation's timer.
+    pub fn store_message(&mut self, mut message: StoredMessage) -> Result<bool> {
+        // Check for duplicate, or a copy of a message that already expired
+        if self.db.message(&message.id)?.is_some() || self.db.is_purged(&message.id)? {
+            return Ok(false);
+        }
+
+        if let Some(timer) = message.timer_change {
+            self.set_timer(&message.conversation_id, timer, message.timestamp)?;
+        }
+        if message.expires_at.is_none() && message.expires_after_read.is_none() {
+            let timer = self.timer(&message.conversation_id);
+            message.expires_at = timer.expires_at(message.timestamp, message.is_outgoing);
+            message.expires_after_read = timer.seconds_after_read(message.is_outgoing);
+            // Arriving in an already-read stretch of the conversation counts as read
+            if let Some(seconds) = message.expires_after_read {
+                if self.last_read.get(&message.conversation_id).is_some_and(|read_at| message.timestamp <= *read_at) {
+                    message.expires_at = Some(Utc::now() + chrono::Duration::seconds(seconds as i64));
+                }
+            }
+        }
+
+        self.db.insert_message(&message)?;
+        Ok(true)
+    }
+
+    pub fn get_message(&self, message_id: &str) -> Result<Option<StoredMessage>> {
+        self.db.message(message_id)
     }
 
-    /// Get messages for a conversation
+    /// Get the latest `limit` messages of a conversation, oldest first
     pub fn get_conversation_messages(
         &self,
         conversation_id: &str,
         limit: Option<usize>,
-    ) -> Vec<&StoredMessage> {
-        let msg_ids = match self.conversations.get(conversation_id) {
-            Some(ids) => ids,
-            None => return vec![],
-        };
+    ) -> Result<Vec<StoredMessage>> {
+        self.db.conversation_messages(conversation_id, limit)
+    }
+
+    /// Get all messages (latest first)
+    pub fn get_all_messages(&self, limit: Option<usize>) -> Result<Vec<StoredMessage>> {
+        self.db.recent_messages(limit)
+    }
 
-        let mut messages: Vec<&StoredMessage> = msg_ids
-            .iter()
-            .filter_map(|id| self.messages.get(id))
-            .collect();
+    fn last_read(&self, conversation_id: &str) -> DateTime<Utc> {
+        self.last_read.get(conversation_id).copied()
+            .unwrap_or_else(|| DateTime::from_timestamp(0, 0).unwrap())
+    }
 
-        // Sort by timestamp (most recent last)
-        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    /// Get conversation summaries
+    pub fn get_conversation_summaries(&self) -> Result<Vec<ConversationSummary>> {
+        let mut summaries = Vec::new();
 
-        // Apply limit
-        if let Some(limit) = limit {
-            if messages.len() > limit {
-                let start_index = messages.len() - limit;
-                messages = messages[start_index..].iter().copied().collect();
-            }
+        for conversation_id in self.db.conversation_ids()? {
+            let Some(last_message) = self.db.conversation_messages(&conversation_id, Some(1))?.pop() else {
+                continue;
+            };
+
+            // The other party is whoever we last sent to or heard from
+            let other_pubkey = if last_message.is_outgoing {
+                last_message.to_pubkey.clone()
+            } else {
+                last_message.from_pubkey.clone()
+            };
+
+            summaries.push(ConversationSummary {
+                unread_count: self.unread_messages(&conversation_id)?.len(),
+                message_count: self.db.conversation_len(&conversation_id)?,
+                id: conversation_id,
+                other_pubkey,
+                last_message: last_message.display_text().to_string(),
+                last_timestamp: last_message.timestamp,
+                last_receipt: last_message.receipt.filter(|_| last_message.is_outgoing),
+            });
         }
 
-        messages
+        // Sort by last message timestamp (newest first)
+        summaries.sort_by(|a, b| b.last_timestamp.cmp(&a.last_timestamp));
+        Ok(summaries)
     }
 
-    /// Get messages from a specific sender
-    pub fn get_messages_from(
-        &self,
-        from_pubkey: &str,
-        limit: Option<usize>,
-    ) -> Vec<&StoredMessage> {
-        let mut messages: Vec<&StoredMessage> = self
-            .messages
-            .values()
-            .filter(|msg| msg.from_pubkey == from_pubkey)
-            .collect();
+    /// Messages of a conversation sent after `after`, oldest first
+    fn messages_after(&self, conversation_id: &str, after: DateTime<Utc>) -> Result<Vec<StoredMessage>> {
+        let mut messages = self.db.conversation_between(conversation_id, &after, &DateTime::<Utc>::MAX_UTC)?;
+        messages.retain(|msg| msg.timestamp > after);
+        Ok(messages)
+    }
 
-        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    /// Incoming messages of a conversation that arrived since it was last read
+    pub fn unread_messages(&self, conversation_id: &str) -> Result<Vec<StoredMessage>> {
+        let mut messages = self.messages_after(conversation_id, self.last_read(conversation_id))?;
+        messages.retain(|msg| !msg.is_outgoing);
+        Ok(messages)
+    }
 
-        if let Some(limit) = limit {
-            if messages.len() > limit {
-                let start_index = messages.len() - limit;
-                messages = messages[start_index..].iter().copied().collect();
+    /// Record a receipt on our outgoing messages, never moving one back from read to delivered
+    ///
+    /// Returns the IDs of the messages that changed.
+    pub fn record_receipt(&mut self, message_ids: &[String], status: ReceiptStatus) -> Result<Vec<String>> {
+        let mut updated = Vec::new();
+        for id in message_ids {
+            let Some(mut message) = self.db.message(id)? else {
+                continue;
+            };
+            if !message.is_outgoing || message.receipt.is_some_and(|receipt| receipt >= status) {
+                continue;
             }
+            message.receipt = Some(status);
+            self.db.update_message(&message)?;
+            updated.push(id.clone());
         }
+        Ok(updated)
+    }
 
-        messages
+    /// Message of a conversation that `target` names
+    pub fn find_message(&self, conversation_id: &str, target: &MessageRef) -> Result<Option<StoredMessage>> {
+        let Some(sent_at) = DateTime::from_timestamp(target.timestamp, 0) else {
+            return Ok(None);
+        };
+        Ok(self.db.conversation_between(conversation_id, &sent_at, &sent_at)?
+            .into_iter()
+            .find(|msg| msg.from_pubkey == target.author && msg.timestamp.timestamp() == target.timestamp))
     }
 
-    /// Get all messages (latest first)
-    pub fn get_all_messages(&self, limit: Option<usize>) -> Vec<&StoredMessage> {
-        let mut messages: Vec<&StoredMessage> = self.messages.values().collect();
-        messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp)); // Newest first
+    /// Apply an edit, deletion or reaction that `from_pubkey` sent at `sent_at` to a message of a conversation
+    ///
+    /// Only a message's author may edit or delete it. Returns the changed message,
+    /// or `None` if the target is unknown or deleted, or the change is not newer
+    /// than what is stored, so refetched changes are ignored.
+    pub fn apply_content(
+        &mut self,
+        conversation_id: &str,
+        from_pubkey: &str,
+        sent_at: DateTime<Utc>,
+        content: &MessageContent,
+    ) -> Result<Option<StoredMessage>> {
+        let target = content.target();
+        let Some(mut message) = self.find_message(conversation_id, target)? else {
+            return Ok(None);
+        };
+        if !matches!(content, MessageContent::React { .. }) && from_pubkey != target.author {
+            return Err(NanoError::Protocol("Only the author of a message can edit or delete it".to_string()));
+        }
 
-        if let Some(limit) = limit {
-            messages.truncate(limit);
+        if message.deleted {
+            return Ok(None);
+        }
+        match content {
+            MessageContent::Edit { body, .. } => {
+                if sent_at <= message.edited_at().unwrap_or(message.timestamp) {
+                    return Ok(None);
+                }
+                let previous = std::mem::replace(&mut message.content, body.clone());
+                if let Some(rich) = &mut message.rich {
+                    rich.replace_text(body.clone());
+                }
+                message.edits.push(MessageEdit { content: previous, replaced_at: sent_at });
+            }
+            MessageContent::Delete { .. } => {
+                // Nothing of the message survives, including its history
+                message.content.clear();
+                message.edits.clear();
+                message.reactions.clear();
+                message.rich = None;
+                message.deleted = true;
+            }
+            MessageContent::React { reaction, .. } => {
+                if message.reactions.get(from_pubkey).is_some_and(|mark| mark.reacted_at >= sent_at) {
+                    return Ok(None);
+                }
+                let mark = ReactionMark { reaction: *reaction, reacted_at: sent_at };
+                message.reactions.insert(from_pubkey.to_string(), mark);
+            }
         }
 
-        messages
+        self.db.update_message(&message)?;
+        Ok(Some(message))
     }
 
-    /// Get conversation summaries
-    pub fn get_conversation_summaries(&self) -> Vec<ConversationSummary> {
-        let mut summaries = Vec::new();
+    /// Mark conversation as read, starting the countdown of messages that disappear once read
+    pub fn mark_conversation_read(&mut self, conversation_id: &str) -> Result<()> {
+        let now = Utc::now();
+        // Messages up to the previous read already have their countdown
+        let unread = self.messages_after(conversation_id, self.last_read(conversation_id))?;
+        self.db.set_last_read(conversation_id, now)?;
+        self.last_read.insert(conversation_id.to_string(), now);
 
-        for (conversation_id, msg_ids) in &self.conversations {
-            if msg_ids.is_empty() {
+        for mut message in unread {
+            let Some(seconds) = message.expires_after_read.filter(|_| message.expires_at.is_none()) else {
                 continue;
-            }
+            };
+            message.expires_at = Some(now + chrono::Duration::seconds(seconds as i64));
+            self.db.update_message(&message)?;
+        }
+        Ok(())
+    }
 
-            let messages: Vec<&StoredMessage> = msg_ids
-                .iter()
-                .filter_map(|id| self.messages.get(id))
-                .collect();
+    /// Disappearing-message timer in force for a conversation
+    pub fn timer(&self, conversation_id: &str) -> DisappearingTimer {
+        self.timers.get(conversation_id).map(|current| current.timer).unwrap_or_default()
+    }
 
-            if let Some(last_message) = messages.last() {
-                let last_read = self.last_read.get(conversation_id).copied()
-                    .unwrap_or_else(|| DateTime::from_timestamp(0, 0).unwrap());
-
-                let unread_count = messages
-                    .iter()
-                    .filter(|msg| msg.timestamp > last_read && !msg.is_outgoing)
-                    .count();
-
-                // Extract other party's pubkey from conversation ID
-                let parts: Vec<&str> = conversation_id.split('|').collect();
-                let other_pubkey = if parts.len() >= 2 {
-                    if parts[0] != last_message.to_pubkey {
-                        parts[0].to_string()
-                    } else {
-                        parts[1].to_string()
-                    }
-                } else {
-                    "unknown".to_string()
-                };
-
-                summaries.push(ConversationSummary {
-                    id: conversation_id.clone(),
-                    other_pubkey,
-                    last_message: last_message.content.clone(),
-                    last_timestamp: last_message.timestamp,
-                    unread_count,
-                    message_count: messages.len(),
-                });
-            }
+    /// Adopt a timer set at `set_at`, unless a later change is already in force
+    fn set_timer(&mut self, conversation_id: &str, timer: DisappearingTimer, set_at: DateTime<Utc>) -> Result<()> {
+        if self.timers.get(conversation_id).is_some_and(|current| current.set_at >= set_at) {
+            return Ok(());
         }
+        let current = ConversationTimer { timer, set_at };
+        self.db.set_timer(conversation_id, &current)?;
+        self.timers.insert(conversation_id.to_string(), current);
+        Ok(())
+    }
 
-        // Sort by last message timestamp (newest first)
-        summaries.sort_by(|a, b| b.last_timestamp.cmp(&a.last_timestamp));
-        summaries
+    /// Remove every message whose timer ran out by `now`, returning their IDs
+    ///
+    /// Expired messages leave the search index and the database, which is
+    /// compacted so their sealed rows do not linger in the file. A tombstone
+    /// keeps copies refetched from the relay from coming back.
+    pub fn purge_expired(&mut self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        Ok(self.db.purge_expired(now)?.into_iter().map(|msg| msg.id).collect())
+    }
+
+    /// Queue a new outbox entry or record an attempt on an existing one
+    pub fn save_outbox_entry(&mut self, entry: OutboxEntry) -> Result<()> {
+        self.db.save_outbox_entry(&entry)?;
+        self.outbox.insert(entry);
+        Ok(())
     }
 
-    /// Mark conversation as read
-    pub fn mark_conversation_read(&mut self, conversation_id: &str) {
-        self.last_read.insert(conversation_id.to_string(), Utc::now());
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    /// Delivery state of an outgoing message; `None` if it never went through the outbox
+    pub fn delivery_status(&self, message_id: &str) -> Option<DeliveryStatus> {
+        self.outbox.delivery_status(message_id)
     }
 
     /// Get message count
-    pub fn message_count(&self) -> usize {
-        self.messages.len()
+    pub fn message_count(&self) -> Result<usize> {
+        self.db.message_count()
     }
 
     /// Get conversation count
-    pub fn conversation_count(&self) -> usize {
-        self.conversations.len()
+    pub fn conversation_count(&self) -> Result<usize> {
+        Ok(self.db.conversation_ids()?.len())
     }
 
     /// Export messages for backup/sync
-    pub fn export_messages(&self) -> HashMap<String, StoredMessage> {
-        self.messages.clone()
+    pub fn export_messages(&self) -> Result<HashMap<String, StoredMessage>> {
+        Ok(self.db.all_messages()?.into_iter().map(|msg| (msg.id.clone(), msg)).collect())
     }
 
     /// Import messages from backup/sync
     pub fn import_messages(&mut self, messages: HashMap<String, StoredMessage>) -> Result<()> {
-        for (id, message) in messages {
-            self.messages.insert(id.clone(), message.clone());
-            
-            // Rebuild conversation index
-            let conversation_id = message.conversation_id.clone();
-            self.conversations
-                .entry(conversation_id)
-                .or_default()
-                .push(id);
-        }
-
-        // Sort all conversations
-        for (_, msg_ids) in self.conversations.iter_mut() {
-            msg_ids.sort_by(|a, b| {
-                let msg_a = &self.messages[a];
-                let msg_b = &self.messages[b];
-                msg_a.timestamp.cmp(&msg_b.timestamp)
-            });
-        }
+        self.db.insert_messages(messages.values())
+    }
 
-        Ok(())
+    /// Search messages with the query syntax of `SearchQuery`
+    pub fn search_messages(&self, query: &str, limit: Option<usize>) -> Result<Vec<StoredMessage>> {
+        self.search(&SearchQuery::parse(query)?, limit)
     }
 
-    /// Search messages by content
-    pub fn search_messages(&self, query: &str, limit: Option<usize>) -> Vec<&StoredMessage> {
-        let query_lower = query.to_lowercase();
-        let mut matches: Vec<&StoredMessage> = self
-            .messages
-            .values()
-            .filter(|msg| msg.content.to_lowercase().contains(&query_lower))
-            .collect();
+    /// Best matches first, or newest first when the query only has filters
+    pub fn search(&self, query: &SearchQuery, limit: Option<usize>) -> Result<Vec<StoredMessage>> {
+        let mut matches: Vec<(StoredMessage, f64)> = Vec::new();
+        if query.has_text() {
+            for (id, score) in self.db.search_postings(&query.tokens())?.score(query) {
+                if let Some(msg) = self.db.message(&id)?.filter(|msg| query.matches_filters(msg)) {
+                    matches.push((msg, score));
+                }
+            }
+        } else {
+            matches = self.db.all_messages()?
+                .into_iter()
+                .filter(|msg| query.matches_filters(msg))
+                .map(|msg| (msg, 0.0))
+                .collect();
+        }
 
-        matches.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        matches.sort_by(|(a, a_score), (b, b_score)| {
+            b_score.total_cmp(a_score).then_with(|| b.timestamp.cmp(&a.timestamp))
+        });
 
         if let Some(limit) = limit {
             matches.truncate(limit);
         }
 
-        matches
+        Ok(matches.into_iter().map(|(msg, _)| msg).collect())
     }
 }
 
@@ -299,6 +582,11 @@ mod tests {
             counter,
             sig: "test_sig".to_string(),
             crypto_mode: Some(crate::crypto::CryptoMode::Classical), // Add the missing field
+            device: None,
+            content: None,
+            disappearing: None,
+            rich: None,
+            conversation_key: None,
         }
     }
 
@@ -331,15 +619,15 @@ mod tests {
         store.store_message(msg2.clone()).unwrap();
 
         // Test retrieval
-        assert_eq!(store.message_count(), 2);
-        assert_eq!(store.conversation_count(), 1);
+        assert_eq!(store.message_count().unwrap(), 2);
+        assert_eq!(store.conversation_count().unwrap(), 1);
 
         let conversation_id = &msg1.conversation_id;
-        let messages = store.get_conversation_messages(conversation_id, None);
+        let messages = store.get_conversation_messages(conversation_id, None).unwrap();
         assert_eq!(messages.len(), 2);
 
         // Test conversation summaries
-        let summaries = store.get_conversation_summaries();
+        let summaries = store.get_conversation_summaries().unwrap();
         assert_eq!(summaries.len(), 1);
         assert_eq!(summaries[0].other_pubkey, bob_pubkey);
         assert_eq!(summaries[0].message_count, 2);
@@ -355,11 +643,287 @@ mod tests {
 
         store.store_message(msg).unwrap();
 
-        let results = store.search_messages("world", None);
+        let results = store.search_messages("world", None).unwrap();
         assert_eq!(results.len(), 1);
         assert!(results[0].content.contains("world"));
 
-        let no_results = store.search_messages("xyz", None);
+        let no_results = store.search_messages("xyz", None).unwrap();
         assert_eq!(no_results.len(), 0);
     }
+
+    #[test]
+    fn test_persistent_store_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let key = [9u8; 32];
+        let conversation_id = {
+            let mut store = MessageStore::open(&path, key).unwrap();
+            let msg = StoredMessage::from_payload(
+                create_test_payload("pubkey:alice", "Kept across restarts", 1),
+                "pubkey:bob".to_string(),
+                Utc::now(),
+                false,
+            );
+            let conversation_id = msg.conversation_id.clone();
+            store.store_message(msg).unwrap();
+            store.mark_conversation_read(&conversation_id).unwrap();
+            conversation_id
+        };
+
+        let store = MessageStore::open(&path, key).unwrap();
+        assert_eq!(store.message_count().unwrap(), 1);
+        assert_eq!(store.get_conversation_messages(&conversation_id, None).unwrap()[0].content, "Kept across restarts");
+        assert_eq!(store.get_conversation_summaries().unwrap()[0].unread_count, 0);
+        assert_eq!(store.database().conversation_messages(&conversation_id, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_outbox_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let key = [9u8; 32];
+        {
+            let mut store = MessageStore::open(&path, key).unwrap();
+            let envelope = crate::protocol::MessageEnvelope::new("inbox".to_string(), vec![1]);
+            let mut entry = OutboxEntry::new("m1".to_string(), envelope, Vec::new(), false);
+            store.save_outbox_entry(entry.clone()).unwrap();
+            assert_eq!(store.delivery_status("m1"), Some(DeliveryStatus::Pending));
+
+            entry.record_failure("connection refused".to_string(), true, Utc::now());
+            store.save_outbox_entry(entry).unwrap();
+        }
+
+        let store = MessageStore::open(&path, key).unwrap();
+        assert_eq!(store.outbox().len(), 1);
+        assert_eq!(store.outbox().entries()[0].attempts, 1);
+        assert_eq!(store.delivery_status("m1"), Some(DeliveryStatus::Pending));
+    }
+
+    #[test]
+    fn test_receipts_only_move_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let key = [9u8; 32];
+        let mut store = MessageStore::open(&path, key).unwrap();
+
+        let incoming = StoredMessage::from_payload(
+            create_test_payload("pubkey:bob", "Hi Alice", 1),
+            "pubkey:alice".to_string(),
+            Utc::now(),
+            false,
+        );
+        let mut outgoing = incoming.clone();
+        outgoing.id = "pubkey:alice:pubkey:bob:1".to_string();
+        outgoing.is_outgoing = true;
+        outgoing.timestamp = incoming.timestamp + chrono::Duration::seconds(1);
+        let conversation_id = incoming.conversation_id.clone();
+        store.store_message(incoming.clone()).unwrap();
+        store.store_message(outgoing.clone()).unwrap();
+        assert_eq!(store.unread_messages(&conversation_id).unwrap().len(), 1);
+
+        // Incoming messages never carry receipts, and read is not downgraded
+        let ids = vec![incoming.id.clone(), outgoing.id.clone()];
+        assert_eq!(store.record_receipt(&ids, ReceiptStatus::Read).unwrap(), vec![outgoing.id.clone()]);
+        assert!(store.record_receipt(&ids, ReceiptStatus::Delivered).unwrap().is_empty());
+        assert_eq!(store.get_conversation_summaries().unwrap()[0].last_receipt, Some(ReceiptStatus::Read));
+
+        store.mark_conversation_read(&conversation_id).unwrap();
+        assert!(store.unread_messages(&conversation_id).unwrap().is_empty());
+        drop(store);
+
+        let store = MessageStore::open(&path, key).unwrap();
+        assert_eq!(store.get_message(&outgoing.id).unwrap().unwrap().receipt, Some(ReceiptStatus::Read));
+    }
+
+    #[test]
+    fn test_edits_deletes_and_reactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let key = [9u8; 32];
+        let mut store = MessageStore::open(&path, key).unwrap();
+
+        let msg = StoredMessage::from_payload(
+            create_test_payload("pubkey:bob", "Lunch at noon", 1),
+            "pubkey:alice".to_string(),
+            Utc::now(),
+            false,
+        );
+        let conversation_id = msg.conversation_id.clone();
+        let target = msg.message_ref();
+        let later = |seconds| msg.timestamp + chrono::Duration::seconds(seconds);
+        store.store_message(msg.clone()).unwrap();
+
+        // Only the author may edit, and a refetched edit is not applied twice
+        let edit = MessageContent::Edit { target: target.clone(), body: "Lunch at one".to_string() };
+        assert!(store.apply_content(&conversation_id, "pubkey:alice", later(5), &edit).is_err());
+        let edited = store.apply_content(&conversation_id, "pubkey:bob", later(5), &edit).unwrap().unwrap();
+        assert_eq!(edited.content, "Lunch at one");
+        assert_eq!(edited.edits[0].content, "Lunch at noon");
+        assert!(store.apply_content(&conversation_id, "pubkey:bob", later(5), &edit).unwrap().is_none());
+        assert_eq!(store.search_messages("one", None).unwrap().len(), 1);
+        assert!(store.search_messages("noon", None).unwrap().is_empty());
+
+        // A withdrawn reaction stays withdrawn when the older reaction is refetched
+        let like = MessageContent::React { target: target.clone(), reaction: Some(Reaction::Like) };
+        let unlike = MessageContent::React { target: target.clone(), reaction: None };
+        store.apply_content(&conversation_id, "pubkey:alice", later(6), &like).unwrap().unwrap();
+        store.apply_content(&conversation_id, "pubkey:alice", later(7), &unlike).unwrap().unwrap();
+        assert!(store.apply_content(&conversation_id, "pubkey:alice", later(6), &like).unwrap().is_none());
+        let reacted = store.apply_content(&conversation_id, "pubkey:bob", later(8), &like).unwrap().unwrap();
+        assert_eq!(reacted.reaction_counts(), vec![(Reaction::Like, 1)]);
+
+        // Deleting removes the text, its history and its reactions for good
+        let delete = MessageContent::Delete { target: target.clone() };
+        store.apply_content(&conversation_id, "pubkey:bob", later(9), &delete).unwrap().unwrap();
+        assert!(store.apply_content(&conversation_id, "pubkey:bob", later(10), &edit).unwrap().is_none());
+        assert!(store.search_messages("lunch", None).unwrap().is_empty());
+        assert_eq!(store.get_conversation_summaries().unwrap()[0].last_message, DELETED_TEXT);
+        drop(store);
+
+        let store = MessageStore::open(&path, key).unwrap();
+        let deleted = store.find_message(&conversation_id, &target).unwrap().unwrap();
+        assert!(deleted.deleted && deleted.content.is_empty() && deleted.edits.is_empty() && deleted.reactions.is_empty());
+    }
+
+    #[test]
+    fn test_rich_messages() {
+        let mut store = MessageStore::new();
+        let rich = RichContent::from_markup("**Lunch** with @alice?", |name| (name == "alice").then(|| "pubkey:alice".to_string()))
+            .with_reply(MessageRef::new("pubkey:alice".to_string(), 1_700_000_000), "Hungry?");
+        let mut payload = create_test_payload("pubkey:bob", &rich.fallback_text(), 1);
+        payload.rich = Some(rich);
+
+        // The store keeps the unmarked text, which is also what search sees
+        let msg = StoredMessage::from_payload(payload.clone(), "pubkey:alice".to_string(), Utc::now(), false);
+        assert_eq!(msg.content, "Lunch with @alice?");
+        assert_eq!(msg.rendered_text(), "↩ \"Hungry?\" · **Lunch** with @alice?");
+        let conversation_id = msg.conversation_id.clone();
+        let target = msg.message_ref();
+        store.store_message(msg.clone()).unwrap();
+        assert_eq!(store.search_messages("lunch", None).unwrap().len(), 1);
+
+        // Content from a newer schema falls back to the body
+        payload.rich.as_mut().unwrap().version += 1;
+        let newer = StoredMessage::from_payload(payload.clone(), "pubkey:alice".to_string(), Utc::now(), false);
+        assert!(newer.rich.is_none());
+        assert_eq!(newer.content, payload.body);
+
+        // An edit keeps the reply but drops formatting meant for the old text
+        let edit = MessageContent::Edit { target: target.clone(), body: "Dinner?".to_string() };
+        let later = msg.timestamp + chrono::Duration::seconds(5);
+        let edited = store.apply_content(&conversation_id, "pubkey:bob", later, &edit).unwrap().unwrap();
+        assert_eq!(edited.rendered_text(), "↩ \"Hungry?\" · Dinner?");
+
+        let delete = MessageContent::Delete { target };
+        let deleted = store.apply_content(&conversation_id, "pubkey:bob", later + chrono::Duration::seconds(1), &delete).unwrap().unwrap();
+        assert!(deleted.rich.is_none());
+        assert_eq!(deleted.rendered_text(), DELETED_TEXT);
+    }
+
+    #[test]
+    fn test_disappearing_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let key = [9u8; 32];
+        let mut store = MessageStore::open(&path, key).unwrap();
+
+        // Bob turns on a timer counted from reading; an older change does not undo it
+        let mut set_timer = create_test_payload("pubkey:bob", "⏱ Disappearing messages set to 1m after reading", 1);
+        set_timer.disappearing = Some(DisappearingTimer::AfterRead { seconds: 60 });
+        let notice = StoredMessage::from_payload(set_timer, "pubkey:alice".to_string(), Utc::now(), false);
+        let conversation_id = notice.conversation_id.clone();
+        store.store_message(notice.clone()).unwrap();
+        let mut stale = create_test_payload("pubkey:bob", "⏱ Disappearing messages turned off", 2);
+        stale.timestamp -= 3600;
+        stale.disappearing = Some(DisappearingTimer::Off);
+        store.store_message(StoredMessage::from_payload(stale, "pubkey:alice".to_string(), Utc::now(), false)).unwrap();
+        assert_eq!(store.timer(&conversation_id), DisappearingTimer::AfterRead { seconds: 60 });
+
+        let msg = StoredMessage::from_payload(
+            create_test_payload("pubkey:bob", "Self-destructing secret", 3),
+            "pubkey:alice".to_string(),
+            Utc::now(),
+            false,
+        );
+        store.store_message(msg.clone()).unwrap();
+        assert_eq!(store.get_message(&msg.id).unwrap().unwrap().expires_at, None);
+
+        // Reading starts the countdown; the sweep then removes it everywhere
+        store.mark_conversation_read(&conversation_id).unwrap();
+        let expires_at = store.get_message(&msg.id).unwrap().unwrap().expires_at.unwrap();
+        assert!(store.purge_expired(expires_at - chrono::Duration::seconds(1)).unwrap().is_empty());
+        let purged = store.purge_expired(expires_at).unwrap();
+        assert!(purged.contains(&msg.id));
+        assert!(store.get_message(&msg.id).unwrap().is_none());
+        assert!(store.search_messages("secret", None).unwrap().is_empty());
+
+        // A copy refetched from the relay stays gone, and the timer survives reopening
+        assert!(!store.store_message(msg.clone()).unwrap());
+        drop(store);
+        let mut store = MessageStore::open(&path, key).unwrap();
+        assert!(store.get_message(&msg.id).unwrap().is_none());
+        assert!(!store.store_message(msg).unwrap());
+        assert_eq!(store.timer(&conversation_id), DisappearingTimer::AfterRead { seconds: 60 });
+    }
+
+    #[test]
+    fn test_search_filters_and_ranking() {
+        let mut store = MessageStore::new();
+        let ours = "pubkey:alice".to_string();
+
+        for (from, body, counter) in [
+            ("pubkey:bob", "Lunch at noon? Lunch is on me", 1),
+            ("pubkey:carol", "Lunch tomorrow maybe", 2),
+            ("pubkey:bob", "See you at the café", 3),
+        ] {
+            let msg = StoredMessage::from_payload(create_test_payload(from, body, counter), ours.clone(), Utc::now(), false);
+            store.store_message(msg).unwrap();
+        }
+
+        // Ranked by relevance, then filtered by sender
+        let results = store.search_messages("lunch", None).unwrap();
+        assert_eq!(results[0].counter, 1);
+        assert_eq!(store.search_messages("lunch from:pubkey:carol", None).unwrap()[0].counter, 2);
+
+        // Phrases and normalized text
+        assert_eq!(store.search_messages(r#""you at the cafe""#, None).unwrap().len(), 1);
+        assert!(store.search_messages(r#""the you""#, None).unwrap().is_empty());
+
+        // Filter-only queries list matches newest first
+        assert_eq!(store.search_messages("mode:classical in:pubkey:bob", None).unwrap().len(), 2);
+        assert!(store.search_messages("mode:hybrid", None).unwrap().is_empty());
+        assert!(store.search_messages("after:2999-01-01", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_index_is_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.redb");
+        let key = [9u8; 32];
+        {
+            let mut store = MessageStore::open(&path, key).unwrap();
+            let lunch = StoredMessage::from_payload(create_test_payload("pubkey:bob", "Lunch at noon", 1), "pubkey:alice".to_string(), Utc::now(), false);
+            // Sent a second earlier, so the edit below cannot match it by reference
+            let mut earlier = create_test_payload("pubkey:bob", "Lunch secret", 2);
+            earlier.timestamp -= 1;
+            let mut expiring = StoredMessage::from_payload(earlier, "pubkey:alice".to_string(), Utc::now(), false);
+            expiring.expires_at = Some(Utc::now() + chrono::Duration::seconds(60));
+            store.store_message(lunch.clone()).unwrap();
+            store.store_message(expiring).unwrap();
+
+            let edit = MessageContent::Edit { target: lunch.message_ref(), body: "Lunch at one".to_string() };
+            let edited_at = lunch.timestamp + chrono::Duration::seconds(5);
+            store.apply_content(&lunch.conversation_id, "pubkey:bob", edited_at, &edit).unwrap().unwrap();
+            store.purge_expired(Utc::now() + chrono::Duration::seconds(61)).unwrap();
+        }
+
+        // A later process reads the stored postings rather than indexing every message again
+        let store = MessageStore::open(&path, key).unwrap();
+        let lunch = "lunch".to_string();
+        let postings = store.database().search_postings(&[&lunch]).unwrap();
+        assert_eq!(postings.score(&SearchQuery::parse("lunch").unwrap()).len(), 1);
+        assert_eq!(store.search_messages("one", None).unwrap().len(), 1);
+        assert!(store.search_messages("noon", None).unwrap().is_empty());
+        assert!(store.search_messages("secret", None).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,1000 @@
+//! Group state machine: proposals, commits and welcomes
+
+use crate::crypto::{decrypt_symmetric, encrypt_symmetric, sign_data, verify_signature, UserKeyPair, UserPublicKeys};
+use crate::error::{NanoError, Result};
+use crate::mls::hpke::{seal, HpkeCiphertext, NodeKeyPair};
+use crate::mls::key_schedule::{derive_secret, expand_with_label, hash, EpochSecrets, GroupContext};
+use crate::mls::tree::{LeafNode, RatchetTree};
+use crate::mls::{tree_math, MlsCiphersuite};
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::Signature;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+const WELCOME_INFO: &[u8] = b"nano mls welcome";
+const INBOX_LABEL: &str = "nano inbox";
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+fn sign(identity: &UserKeyPair, data: &[u8]) -> String {
+    general_purpose::STANDARD.encode(sign_data(&identity.signing_key, data).to_bytes())
+}
+
+fn verify(identity: &str, data: &[u8], signature: &str) -> Result<()> {
+    let bytes: [u8; 64] = general_purpose::STANDARD
+        .decode(signature)?
+        .try_into()
+        .map_err(|_| NanoError::Crypto("Invalid signature length".to_string()))?;
+    let verifying_key = UserPublicKeys::from_public_key_string(identity)?;
+    verify_signature(&verifying_key, data, &Signature::from_bytes(&bytes))
+}
+
+fn decode_hash(value: &str) -> Result<[u8; 32]> {
+    hex::decode(value)
+        .map_err(|e| NanoError::Crypto(format!("Invalid hash: {}", e)))?
+        .try_into()
+        .map_err(|_| NanoError::Crypto("Invalid hash length".to_string()))
+}
+
+fn to_secret(bytes: Vec<u8>) -> Result<[u8; 32]> {
+    bytes
+        .try_into()
+        .map_err(|_| NanoError::Crypto("Invalid secret length".to_string()))
+}
+
+/// HPKE info binding path secrets to the epoch they create
+fn path_info(group_id: &str, epoch: u64) -> Vec<u8> {
+    let mut info = group_id.as_bytes().to_vec();
+    info.extend_from_slice(&epoch.to_be_bytes());
+    info
+}
+
+/// A prospective member's published keys, signed with their identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyPackage {
+    pub ciphersuite: MlsCiphersuite,
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub init_key: Vec<u8>,
+    pub leaf_node: LeafNode,
+    pub signature: String,
+}
+
+impl KeyPackage {
+    fn signable_data(&self) -> Result<Vec<u8>> {
+        let unsigned = Self {
+            signature: String::new(),
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned).map_err(Into::into)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        verify(&self.leaf_node.identity, &self.signable_data()?, &self.signature)
+    }
+
+    /// Hash identifying this key package inside a Welcome
+    pub fn reference(&self) -> Result<String> {
+        Ok(hex::encode(hash(&[&serde_json::to_vec(self)?])))
+    }
+
+    /// Inbox where the owner waits for a Welcome
+    pub fn welcome_inbox_id(&self) -> String {
+        hex::encode(hash(&[b"mls_welcome:", &self.init_key]))
+    }
+}
+
+/// A key package together with its private keys
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyPackageBundle {
+    pub key_package: KeyPackage,
+    init_key: NodeKeyPair,
+    leaf_key: NodeKeyPair,
+}
+
+impl KeyPackageBundle {
+    pub fn generate(ciphersuite: MlsCiphersuite, identity: &UserKeyPair) -> Result<Self> {
+        let init_key = NodeKeyPair::generate(ciphersuite);
+        let leaf_key = NodeKeyPair::generate(ciphersuite);
+
+        let mut key_package = KeyPackage {
+            ciphersuite,
+            init_key: init_key.public_key(),
+            leaf_node: LeafNode {
+                identity: identity.public_key_string(),
+                encryption_key: leaf_key.public_key(),
+            },
+            signature: String::new(),
+        };
+        key_package.signature = sign(identity, &key_package.signable_data()?);
+
+        Ok(Self {
+            key_package,
+            init_key,
+            leaf_key,
+        })
+    }
+}
+
+/// A change to group membership
+///
+/// Members refresh their own keys with an empty commit, so there is no
+/// separate Update proposal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Proposal {
+    Add { key_package: KeyPackage },
+    Remove { removed: u32 }, // Leaf index
+}
+
+/// A proposal sent ahead of the commit that applies it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProposalMessage {
+    pub group_id: String,
+    pub epoch: u64,
+    pub sender: u32,
+    pub proposal: Proposal,
+    pub signature: String,
+}
+
+impl ProposalMessage {
+    fn signable_data(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&(&self.group_id, self.epoch, self.sender, &self.proposal)).map_err(Into::into)
+    }
+}
+
+/// New public key for one node on the committer's filtered direct path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdatePathNode {
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub encryption_key: Vec<u8>,
+    /// Path secret sealed to each node in the copath child's resolution
+    pub encrypted_path_secret: Vec<HpkeCiphertext>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdatePath {
+    pub leaf_node: LeafNode,
+    pub nodes: Vec<UpdatePathNode>,
+}
+
+/// Applies proposals and moves the group to the next epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Commit {
+    pub group_id: String,
+    pub epoch: u64, // Epoch the commit was created in
+    pub sender: u32,
+    pub proposals: Vec<Proposal>,
+    pub path: UpdatePath,
+    pub signature: String,
+    pub confirmation_tag: String, // Hex encoded
+}
+
+impl Commit {
+    fn signable_data(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&(&self.group_id, self.epoch, self.sender, &self.proposals, &self.path)).map_err(Into::into)
+    }
+}
+
+/// Secrets for one new member, sealed to their key package's init key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedGroupSecrets {
+    pub key_package_ref: String,
+    pub encrypted: HpkeCiphertext,
+}
+
+/// Everything a new member needs to join at the commit's epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Welcome {
+    pub ciphersuite: MlsCiphersuite,
+    pub secrets: Vec<EncryptedGroupSecrets>,
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub encrypted_group_info: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GroupSecrets {
+    joiner_secret: [u8; 32],
+    path_secret: Option<[u8; 32]>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct GroupInfo {
+    context: GroupContext,
+    tree: RatchetTree,
+    confirmation_tag: String,
+    signer: u32,
+    signature: String,
+}
+
+impl GroupInfo {
+    fn signable_data(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&(&self.context, &self.tree, &self.confirmation_tag, self.signer)).map_err(Into::into)
+    }
+}
+
+/// Group message encrypted under the epoch's encryption secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplicationMessage {
+    pub group_id: String,
+    pub epoch: u64,
+    pub sender: u32,
+    pub generation: u32,
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ApplicationContent {
+    #[serde(with = "crate::mls::base64_bytes")]
+    content: Vec<u8>,
+    signature: String,
+}
+
+fn application_signable(group_id: &str, epoch: u64, sender: u32, generation: u32, content: &[u8]) -> Result<Vec<u8>> {
+    serde_json::to_vec(&(group_id, epoch, sender, generation, hex::encode(content))).map_err(Into::into)
+}
+
+/// Result of deriving the next epoch from a commit
+struct NextEpoch {
+    joiner_secret: [u8; 32],
+    secrets: EpochSecrets,
+    confirmed_transcript_hash: [u8; 32],
+    confirmation_tag: [u8; 32],
+}
+
+/// One member's view of an MLS group
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MlsGroup {
+    pub group_id: String,
+    pub ciphersuite: MlsCiphersuite,
+    pub epoch: u64,
+    pub own_leaf: u32,
+    tree: RatchetTree,
+    private_keys: BTreeMap<u32, NodeKeyPair>, // Node index -> key pair
+    secrets: EpochSecrets,
+    confirmed_transcript_hash: [u8; 32],
+    interim_transcript_hash: [u8; 32],
+    pending_proposals: Vec<Proposal>,
+    next_generation: u32,
+    seen_generations: HashSet<(u32, u32)>,
+}
+
+impl MlsGroup {
+    /// Start a one-member group at epoch 0
+    pub fn create(ciphersuite: MlsCiphersuite, identity: &UserKeyPair) -> Self {
+        let leaf_key = NodeKeyPair::generate(ciphersuite);
+        let tree = RatchetTree::new(LeafNode {
+            identity: identity.public_key_string(),
+            encryption_key: leaf_key.public_key(),
+        });
+
+        let mut private_keys = BTreeMap::new();
+        private_keys.insert(0, leaf_key);
+
+        Self {
+            group_id: hex::encode(&random_secret()[..16]),
+            ciphersuite,
+            epoch: 0,
+            own_leaf: 0,
+            tree,
+            private_keys,
+            secrets: EpochSecrets::from_epoch_secret(&random_secret()),
+            confirmed_transcript_hash: [0u8; 32],
+            interim_transcript_hash: [0u8; 32],
+            pending_proposals: Vec::new(),
+            next_generation: 0,
+            seen_generations: HashSet::new(),
+        }
+    }
+
+    /// Join a group from a Welcome addressed to one of our key packages
+    pub fn join(welcome: &Welcome, bundle: &KeyPackageBundle) -> Result<Self> {
+        if welcome.ciphersuite != bundle.key_package.ciphersuite {
+            return Err(NanoError::Protocol("Welcome uses a different ciphersuite".to_string()));
+        }
+
+        let reference = bundle.key_package.reference()?;
+        let entry = welcome
+            .secrets
+            .iter()
+            .find(|entry| entry.key_package_ref == reference)
+            .ok_or_else(|| NanoError::Protocol("Welcome is not addressed to this key package".to_string()))?;
+
+        let group_secrets: GroupSecrets =
+            serde_json::from_slice(&bundle.init_key.open(WELCOME_INFO, &entry.encrypted)?)?;
+        let welcome_key = EpochSecrets::welcome_key(&group_secrets.joiner_secret);
+        let group_info: GroupInfo =
+            serde_json::from_slice(&decrypt_symmetric(&welcome_key, &welcome.encrypted_group_info)?)?;
+
+        let tree = group_info.tree.clone();
+        let context = group_info.context.clone();
+        let signer = tree
+            .leaf(group_info.signer)
+            .ok_or_else(|| NanoError::Protocol("Welcome signer is not a member".to_string()))?;
+        verify(&signer.identity, &group_info.signable_data()?, &group_info.signature)?;
+
+        if hex::encode(tree.tree_hash()) != context.tree_hash {
+            return Err(NanoError::Crypto("Welcome tree does not match the group context".to_string()));
+        }
+
+        let own_leaf = tree
+            .leaves()
+            .find(|(_, leaf)| **leaf == bundle.key_package.leaf_node)
+            .map(|(index, _)| index)
+            .ok_or_else(|| NanoError::Protocol("Welcome tree does not contain our leaf".to_string()))?;
+
+        let secrets = EpochSecrets::from_joiner_secret(&group_secrets.joiner_secret, &context);
+        let confirmed_transcript_hash = decode_hash(&context.confirmed_transcript_hash)?;
+        let confirmation_tag = secrets.confirmation_tag(&confirmed_transcript_hash);
+        if hex::encode(confirmation_tag) != group_info.confirmation_tag {
+            return Err(NanoError::Crypto("Welcome confirmation tag mismatch".to_string()));
+        }
+
+        let own_node = tree_math::leaf_to_node(own_leaf);
+        let mut private_keys = BTreeMap::new();
+        private_keys.insert(own_node, bundle.leaf_key.clone());
+
+        // Derive keys from our common ancestor with the committer up to the root
+        if let Some(mut path_secret) = group_secrets.path_secret {
+            let signer_node = tree_math::leaf_to_node(group_info.signer);
+            let mut first = true;
+
+            for parent in tree_math::direct_path(signer_node, tree.leaf_count()) {
+                let Some(node) = tree.node(parent) else { continue };
+                if !tree_math::is_in_subtree(own_node, parent) {
+                    continue;
+                }
+                if !first {
+                    path_secret = derive_secret(&path_secret, "path");
+                }
+                first = false;
+
+                let key = NodeKeyPair::from_seed(welcome.ciphersuite, derive_secret(&path_secret, "node"));
+                if key.public_key() != node.encryption_key() {
+                    return Err(NanoError::Crypto("Welcome path secret does not match the tree".to_string()));
+                }
+                private_keys.insert(parent, key);
+            }
+        }
+
+        Ok(Self {
+            group_id: context.group_id,
+            ciphersuite: welcome.ciphersuite,
+            epoch: context.epoch,
+            own_leaf,
+            tree,
+            private_keys,
+            secrets,
+            confirmed_transcript_hash,
+            interim_transcript_hash: hash(&[&confirmed_transcript_hash, &confirmation_tag]),
+            pending_proposals: Vec::new(),
+            next_generation: 0,
+            seen_generations: HashSet::new(),
+        })
+    }
+
+    pub fn tree(&self) -> &RatchetTree {
+        &self.tree
+    }
+
+    /// Identities of all current members, in leaf order
+    pub fn members(&self) -> Vec<String> {
+        self.tree.leaves().map(|(_, leaf)| leaf.identity.clone()).collect()
+    }
+
+    pub fn context(&self) -> GroupContext {
+        GroupContext {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            ciphersuite: self.ciphersuite,
+            tree_hash: hex::encode(self.tree.tree_hash()),
+            confirmed_transcript_hash: hex::encode(self.confirmed_transcript_hash),
+        }
+    }
+
+    /// Shared inbox for the current epoch, derived from the exporter secret
+    pub fn inbox_id(&self) -> String {
+        hex::encode(self.secrets.export(INBOX_LABEL, self.group_id.as_bytes(), 32))
+    }
+
+    /// Value all members agree on for the epoch, suitable for out-of-band comparison
+    pub fn epoch_authenticator(&self) -> String {
+        hex::encode(self.secrets.epoch_authenticator)
+    }
+
+    pub fn export_secret(&self, label: &str, context: &[u8], length: u16) -> Vec<u8> {
+        self.secrets.export(label, context, length)
+    }
+
+    fn own_identity(&self) -> &str {
+        &self.tree.leaf(self.own_leaf).expect("own leaf is occupied").identity
+    }
+
+    fn check_signer(&self, identity: &UserKeyPair) -> Result<()> {
+        if identity.public_key_string() != self.own_identity() {
+            return Err(NanoError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Apply removes then adds, returning the leaves given to new members
+    fn apply_proposals(&self, tree: &mut RatchetTree, proposals: &[Proposal], committer: u32) -> Result<Vec<(u32, KeyPackage)>> {
+        for proposal in proposals {
+            if let Proposal::Remove { removed } = proposal {
+                if *removed == committer {
+                    return Err(NanoError::Protocol("A committer cannot remove itself".to_string()));
+                }
+                if tree.leaf(*removed).is_none() {
+                    return Err(NanoError::Protocol(format!("Leaf {} is not a member", removed)));
+                }
+                tree.remove_leaf(*removed);
+            }
+        }
+
+        let mut added = Vec::new();
+        for proposal in proposals {
+            if let Proposal::Add { key_package } = proposal {
+                key_package.verify()?;
+                if key_package.ciphersuite != self.ciphersuite {
+                    return Err(NanoError::Protocol("Key package uses a different ciphersuite".to_string()));
+                }
+                if tree.find_leaf(&key_package.leaf_node.identity).is_some() {
+                    return Err(NanoError::Protocol("Member is already in the group".to_string()));
+                }
+                added.push((tree.add_leaf(key_package.leaf_node.clone()), key_package.clone()));
+            }
+        }
+        Ok(added)
+    }
+
+    /// Send a proposal for a later commit, keeping it pending locally too
+    pub fn propose(&mut self, identity: &UserKeyPair, proposal: Proposal) -> Result<ProposalMessage> {
+        self.check_signer(identity)?;
+        self.apply_proposals(&mut self.tree.clone(), std::slice::from_ref(&proposal), self.own_leaf)?;
+
+        let mut message = ProposalMessage {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            proposal,
+            signature: String::new(),
+        };
+        message.signature = sign(identity, &message.signable_data()?);
+
+        self.pending_proposals.push(message.proposal.clone());
+        Ok(message)
+    }
+
+    /// Verify and queue another member's proposal
+    pub fn process_proposal(&mut self, message: &ProposalMessage) -> Result<()> {
+        self.check_epoch(&message.group_id, message.epoch)?;
+        let sender = self
+            .tree
+            .leaf(message.sender)
+            .ok_or_else(|| NanoError::Protocol("Proposal sender is not a member".to_string()))?;
+        verify(&sender.identity, &message.signable_data()?, &message.signature)?;
+        self.apply_proposals(&mut self.tree.clone(), std::slice::from_ref(&message.proposal), message.sender)?;
+
+        self.pending_proposals.push(message.proposal.clone());
+        Ok(())
+    }
+
+    fn check_epoch(&self, group_id: &str, epoch: u64) -> Result<()> {
+        if group_id != self.group_id {
+            return Err(NanoError::Protocol("Message is for another group".to_string()));
+        }
+        if epoch < self.epoch {
+            return Err(NanoError::ReplayDetected(format!("message from past epoch {}", epoch)));
+        }
+        if epoch > self.epoch {
+            return Err(NanoError::Protocol(format!(
+                "Message from epoch {} but group is at epoch {}",
+                epoch, self.epoch
+            )));
+        }
+        Ok(())
+    }
+
+    /// Commit adding the given key packages, plus any pending proposals
+    pub fn add_members(&mut self, identity: &UserKeyPair, key_packages: Vec<KeyPackage>) -> Result<(Commit, Option<Welcome>)> {
+        let proposals = key_packages
+            .into_iter()
+            .map(|key_package| Proposal::Add { key_package })
+            .collect();
+        self.commit(identity, proposals)
+    }
+
+    /// Commit removing the given identities, plus any pending proposals
+    pub fn remove_members(&mut self, identity: &UserKeyPair, identities: &[String]) -> Result<(Commit, Option<Welcome>)> {
+        let proposals = identities
+            .iter()
+            .map(|member| {
+                self.tree
+                    .find_leaf(member)
+                    .map(|removed| Proposal::Remove { removed })
+                    .ok_or_else(|| NanoError::Protocol(format!("{} is not a member", member)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.commit(identity, proposals)
+    }
+
+    /// Commit pending proposals and `proposals`, refreshing our path keys
+    ///
+    /// The new epoch takes effect locally straight away; other members move
+    /// to it when they process the returned commit.
+    pub fn commit(&mut self, identity: &UserKeyPair, proposals: Vec<Proposal>) -> Result<(Commit, Option<Welcome>)> {
+        self.check_signer(identity)?;
+
+        let mut all_proposals = self.pending_proposals.clone();
+        all_proposals.extend(proposals);
+
+        let mut tree = self.tree.clone();
+        let added = self.apply_proposals(&mut tree, &all_proposals, self.own_leaf)?;
+        let added_nodes: Vec<u32> = added.iter().map(|(leaf, _)| tree_math::leaf_to_node(*leaf)).collect();
+
+        let leaf_secret = random_secret();
+        let leaf_key = NodeKeyPair::from_seed(self.ciphersuite, derive_secret(&leaf_secret, "node"));
+        let filtered_path = tree.filtered_direct_path(self.own_leaf);
+        let info = path_info(&self.group_id, self.epoch + 1);
+
+        let mut path_secret = leaf_secret;
+        let mut path_secrets = Vec::new();
+        let mut path_nodes = Vec::new();
+        let mut path_keys = Vec::new();
+        let mut private_keys = BTreeMap::new();
+
+        for (parent, copath_node) in &filtered_path {
+            path_secret = derive_secret(&path_secret, "path");
+            let key = NodeKeyPair::from_seed(self.ciphersuite, derive_secret(&path_secret, "node"));
+
+            // New members get their path secret from the Welcome instead
+            let encrypted_path_secret = tree
+                .resolution(*copath_node)
+                .into_iter()
+                .filter(|node| !added_nodes.contains(node))
+                .map(|node| {
+                    let public_key = tree.node(node).expect("resolution holds non-blank nodes").encryption_key();
+                    seal(self.ciphersuite, public_key, &info, &path_secret)
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            path_nodes.push(UpdatePathNode {
+                encryption_key: key.public_key(),
+                encrypted_path_secret,
+            });
+            path_keys.push((*parent, key.public_key()));
+            private_keys.insert(*parent, key);
+            path_secrets.push(path_secret);
+        }
+        let commit_secret = derive_secret(&path_secret, "path");
+
+        let leaf_node = LeafNode {
+            identity: self.own_identity().to_string(),
+            encryption_key: leaf_key.public_key(),
+        };
+        tree.merge_path(self.own_leaf, leaf_node.clone(), &path_keys);
+        private_keys.insert(tree_math::leaf_to_node(self.own_leaf), leaf_key);
+
+        let mut commit = Commit {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            proposals: all_proposals,
+            path: UpdatePath {
+                leaf_node,
+                nodes: path_nodes,
+            },
+            signature: String::new(),
+            confirmation_tag: String::new(),
+        };
+        commit.signature = sign(identity, &commit.signable_data()?);
+
+        let next = self.next_epoch(&tree, &commit, &commit_secret)?;
+        commit.confirmation_tag = hex::encode(next.confirmation_tag);
+
+        let welcome = if added.is_empty() {
+            None
+        } else {
+            let mut group_info = GroupInfo {
+                context: self.next_context(&tree, &next.confirmed_transcript_hash),
+                tree: tree.clone(),
+                confirmation_tag: commit.confirmation_tag.clone(),
+                signer: self.own_leaf,
+                signature: String::new(),
+            };
+            group_info.signature = sign(identity, &group_info.signable_data()?);
+
+            let welcome_key = EpochSecrets::welcome_key(&next.joiner_secret);
+            let encrypted_group_info = encrypt_symmetric(&welcome_key, &serde_json::to_vec(&group_info)?)?;
+
+            let secrets = added
+                .iter()
+                .map(|(leaf, key_package)| {
+                    let node = tree_math::leaf_to_node(*leaf);
+                    let path_secret = filtered_path
+                        .iter()
+                        .position(|(parent, _)| tree_math::is_in_subtree(node, *parent))
+                        .map(|index| path_secrets[index]);
+                    let group_secrets = GroupSecrets {
+                        joiner_secret: next.joiner_secret,
+                        path_secret,
+                    };
+
+                    Ok(EncryptedGroupSecrets {
+                        key_package_ref: key_package.reference()?,
+                        encrypted: seal(
+                            self.ciphersuite,
+                            &key_package.init_key,
+                            WELCOME_INFO,
+                            &serde_json::to_vec(&group_secrets)?,
+                        )?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Some(Welcome {
+                ciphersuite: self.ciphersuite,
+                secrets,
+                encrypted_group_info,
+            })
+        };
+
+        self.private_keys = private_keys;
+        self.install_epoch(tree, next);
+        Ok((commit, welcome))
+    }
+
+    /// Apply another member's commit
+    ///
+    /// Returns `false` when the commit removes us; the group should then be dropped.
+    pub fn process_commit(&mut self, commit: &Commit) -> Result<bool> {
+        self.check_epoch(&commit.group_id, commit.epoch)?;
+        if commit.sender == self.own_leaf {
+            return Err(NanoError::Protocol("Cannot process our own commit".to_string()));
+        }
+
+        let sender = self
+            .tree
+            .leaf(commit.sender)
+            .ok_or_else(|| NanoError::Protocol("Commit sender is not a member".to_string()))?;
+        verify(&sender.identity, &commit.signable_data()?, &commit.signature)?;
+        if commit.path.leaf_node.identity != sender.identity {
+            return Err(NanoError::Protocol("Commit changes the sender's identity".to_string()));
+        }
+
+        let mut tree = self.tree.clone();
+        let added = self.apply_proposals(&mut tree, &commit.proposals, commit.sender)?;
+        if tree.leaf(self.own_leaf).map(|leaf| leaf.identity.as_str()) != Some(self.own_identity()) {
+            return Ok(false);
+        }
+        let added_nodes: Vec<u32> = added.iter().map(|(leaf, _)| tree_math::leaf_to_node(*leaf)).collect();
+
+        let filtered_path = tree.filtered_direct_path(commit.sender);
+        if filtered_path.len() != commit.path.nodes.len() {
+            return Err(NanoError::Protocol("Update path does not match the tree".to_string()));
+        }
+
+        // Find the lowest node on the committer's path that is also above us
+        let own_node = tree_math::leaf_to_node(self.own_leaf);
+        let position = filtered_path
+            .iter()
+            .position(|(parent, _)| tree_math::is_in_subtree(own_node, *parent))
+            .ok_or_else(|| NanoError::Protocol("Update path does not cover our leaf".to_string()))?;
+
+        let (slot, key) = tree
+            .resolution(filtered_path[position].1)
+            .into_iter()
+            .filter(|node| !added_nodes.contains(node))
+            .enumerate()
+            .find_map(|(slot, node)| self.private_keys.get(&node).map(|key| (slot, key)))
+            .ok_or_else(|| NanoError::Crypto("No key to decrypt the update path".to_string()))?;
+        let sealed = commit.path.nodes[position]
+            .encrypted_path_secret
+            .get(slot)
+            .ok_or_else(|| NanoError::Protocol("Update path is missing a path secret".to_string()))?;
+
+        let info = path_info(&self.group_id, self.epoch + 1);
+        let mut path_secret = to_secret(key.open(&info, sealed)?)?;
+        let mut new_keys = Vec::new();
+
+        for (index, (parent, _)) in filtered_path.iter().enumerate().skip(position) {
+            if index > position {
+                path_secret = derive_secret(&path_secret, "path");
+            }
+            let key = NodeKeyPair::from_seed(self.ciphersuite, derive_secret(&path_secret, "node"));
+            if key.public_key() != commit.path.nodes[index].encryption_key {
+                return Err(NanoError::Crypto("Update path key does not match its secret".to_string()));
+            }
+            new_keys.push((*parent, key));
+        }
+        let commit_secret = derive_secret(&path_secret, "path");
+
+        let path_keys: Vec<(u32, Vec<u8>)> = filtered_path
+            .iter()
+            .zip(&commit.path.nodes)
+            .map(|((parent, _), node)| (*parent, node.encryption_key.clone()))
+            .collect();
+        tree.merge_path(commit.sender, commit.path.leaf_node.clone(), &path_keys);
+
+        let next = self.next_epoch(&tree, commit, &commit_secret)?;
+        if hex::encode(next.confirmation_tag) != commit.confirmation_tag {
+            return Err(NanoError::Crypto("Commit confirmation tag mismatch".to_string()));
+        }
+
+        self.private_keys.extend(new_keys);
+        self.install_epoch(tree, next);
+        Ok(true)
+    }
+
+    fn next_context(&self, tree: &RatchetTree, confirmed_transcript_hash: &[u8; 32]) -> GroupContext {
+        GroupContext {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch + 1,
+            ciphersuite: self.ciphersuite,
+            tree_hash: hex::encode(tree.tree_hash()),
+            confirmed_transcript_hash: hex::encode(confirmed_transcript_hash),
+        }
+    }
+
+    fn next_epoch(&self, tree: &RatchetTree, commit: &Commit, commit_secret: &[u8; 32]) -> Result<NextEpoch> {
+        let confirmed_transcript_hash = hash(&[
+            &self.interim_transcript_hash,
+            &commit.signable_data()?,
+            commit.signature.as_bytes(),
+        ]);
+
+        let context = self.next_context(tree, &confirmed_transcript_hash);
+        let (joiner_secret, secrets) = EpochSecrets::derive(&self.secrets.init_secret, commit_secret, &context);
+
+        Ok(NextEpoch {
+            joiner_secret,
+            confirmation_tag: secrets.confirmation_tag(&confirmed_transcript_hash),
+            secrets,
+            confirmed_transcript_hash,
+        })
+    }
+
+    fn install_epoch(&mut self, tree: RatchetTree, next: NextEpoch) {
+        self.tree = tree;
+        self.epoch += 1;
+        self.secrets = next.secrets;
+        self.confirmed_transcript_hash = next.confirmed_transcript_hash;
+        self.interim_transcript_hash = hash(&[&next.confirmed_transcript_hash, &next.confirmation_tag]);
+        self.pending_proposals.clear();
+        self.next_generation = 0;
+        self.seen_generations.clear();
+
+        // Drop keys for nodes that were blanked or replaced
+        let tree = &self.tree;
+        self.private_keys.retain(|node, key| {
+            tree.node(*node)
+                .is_some_and(|current| current.encryption_key() == key.public_key())
+        });
+    }
+
+    fn application_key(&self, sender: u32, generation: u32) -> [u8; 32] {
+        let mut context = sender.to_be_bytes().to_vec();
+        context.extend_from_slice(&generation.to_be_bytes());
+        expand_with_label(&self.secrets.encryption_secret, "application", &context, 32)
+            .try_into()
+            .expect("expand returns the requested length")
+    }
+
+    /// Encrypt and sign an application message for the current epoch
+    pub fn encrypt(&mut self, identity: &UserKeyPair, plaintext: &[u8]) -> Result<ApplicationMessage> {
+        self.check_signer(identity)?;
+
+        let generation = self.next_generation;
+        self.next_generation += 1;
+
+        let content = ApplicationContent {
+            content: plaintext.to_vec(),
+            signature: sign(
+                identity,
+                &application_signable(&self.group_id, self.epoch, self.own_leaf, generation, plaintext)?,
+            ),
+        };
+        let key = self.application_key(self.own_leaf, generation);
+
+        Ok(ApplicationMessage {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            generation,
+            ciphertext: encrypt_symmetric(&key, &serde_json::to_vec(&content)?)?,
+        })
+    }
+
+    /// Decrypt an application message, returning the sender's identity
+    ///
+    /// Returns `None` for our own messages.
+    pub fn decrypt(&mut self, message: &ApplicationMessage) -> Result<Option<(String, Vec<u8>)>> {
+        self.check_epoch(&message.group_id, message.epoch)?;
+        if message.sender == self.own_leaf {
+            return Ok(None);
+        }
+        if self.seen_generations.contains(&(message.sender, message.generation)) {
+            return Err(NanoError::ReplayDetected(format!(
+                "generation {} from leaf {}",
+                message.generation, message.sender
+            )));
+        }
+
+        let sender = self
+            .tree
+            .leaf(message.sender)
+            .ok_or_else(|| NanoError::Protocol("Message sender is not a member".to_string()))?
+            .identity
+            .clone();
+
+        let key = self.application_key(message.sender, message.generation);
+        let content: ApplicationContent = serde_json::from_slice(&decrypt_symmetric(&key, &message.ciphertext)?)?;
+        verify(
+            &sender,
+            &application_signable(&self.group_id, message.epoch, message.sender, message.generation, &content.content)?,
+            &content.signature,
+        )?;
+
+        self.seen_generations.insert((message.sender, message.generation));
+        Ok(Some((sender, content.content)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(ciphersuite: MlsCiphersuite) -> (UserKeyPair, KeyPackageBundle) {
+        let identity = UserKeyPair::generate();
+        let bundle = KeyPackageBundle::generate(ciphersuite, &identity).unwrap();
+        (identity, bundle)
+    }
+
+    fn assert_same_epoch(groups: &[&MlsGroup]) {
+        for group in &groups[1..] {
+            assert_eq!(group.epoch, groups[0].epoch);
+            assert_eq!(group.inbox_id(), groups[0].inbox_id());
+            assert_eq!(group.epoch_authenticator(), groups[0].epoch_authenticator());
+            assert_eq!(group.tree(), groups[0].tree());
+        }
+    }
+
+    #[test]
+    fn test_welcome_and_application_messages() {
+        let alice = UserKeyPair::generate();
+        let (bob, bob_package) = member(MlsCiphersuite::Classical);
+        let (_, carol_package) = member(MlsCiphersuite::Classical);
+
+        let mut alice_group = MlsGroup::create(MlsCiphersuite::Classical, &alice);
+        let first_inbox = alice_group.inbox_id();
+        let (_, welcome) = alice_group
+            .add_members(&alice, vec![bob_package.key_package.clone(), carol_package.key_package.clone()])
+            .unwrap();
+        let welcome = welcome.unwrap();
+
+        let mut bob_group = MlsGroup::join(&welcome, &bob_package).unwrap();
+        let mut carol_group = MlsGroup::join(&welcome, &carol_package).unwrap();
+        assert_same_epoch(&[&alice_group, &bob_group, &carol_group]);
+        assert_ne!(alice_group.inbox_id(), first_inbox);
+        assert_eq!(bob_group.members().len(), 3);
+
+        let message = bob_group.encrypt(&bob, b"hello group").unwrap();
+        let (sender, plaintext) = alice_group.decrypt(&message).unwrap().unwrap();
+        assert_eq!(sender, bob.public_key_string());
+        assert_eq!(plaintext, b"hello group");
+        assert!(carol_group.decrypt(&message).unwrap().is_some());
+
+        // Replays and our own messages yield nothing new
+        assert!(matches!(alice_group.decrypt(&message), Err(NanoError::ReplayDetected(_))));
+        assert!(bob_group.decrypt(&message).unwrap().is_none());
+
+        // Members cannot sign as someone else
+        assert!(carol_group.encrypt(&bob, b"spoof").is_err());
+    }
+
+    #[test]
+    fn test_removed_member_cannot_follow_new_epoch() {
+        let alice = UserKeyPair::generate();
+        let (bob, bob_package) = member(MlsCiphersuite::Classical);
+        let (_, carol_package) = member(MlsCiphersuite::Classical);
+
+        let mut alice_group = MlsGroup::create(MlsCiphersuite::Classical, &alice);
+        let (_, welcome) = alice_group
+            .add_members(&alice, vec![bob_package.key_package.clone(), carol_package.key_package.clone()])
+            .unwrap();
+        let welcome = welcome.unwrap();
+        let mut bob_group = MlsGroup::join(&welcome, &bob_package).unwrap();
+        let mut carol_group = MlsGroup::join(&welcome, &carol_package).unwrap();
+
+        let carol_identity = carol_package.key_package.leaf_node.identity.clone();
+        let (commit, welcome) = alice_group.remove_members(&alice, &[carol_identity]).unwrap();
+        assert!(welcome.is_none());
+
+        assert!(bob_group.process_commit(&commit).unwrap());
+        assert!(!carol_group.process_commit(&commit).unwrap());
+        assert_same_epoch(&[&alice_group, &bob_group]);
+        assert_eq!(alice_group.members().len(), 2);
+
+        // Carol is stuck in the old epoch, with the old inbox and keys
+        assert_ne!(carol_group.inbox_id(), alice_group.inbox_id());
+        let message = bob_group.encrypt(&bob, b"after carol left").unwrap();
+        assert!(carol_group.decrypt(&message).is_err());
+        assert!(alice_group.decrypt(&message).unwrap().is_some());
+
+        // Processing the same commit again is a replay
+        assert!(matches!(bob_group.process_commit(&commit), Err(NanoError::ReplayDetected(_))));
+    }
+
+    #[test]
+    fn test_proposals_and_commits_from_any_member() {
+        let alice = UserKeyPair::generate();
+        let (bob, bob_package) = member(MlsCiphersuite::Classical);
+        let (dave, dave_package) = member(MlsCiphersuite::Classical);
+
+        let mut alice_group = MlsGroup::create(MlsCiphersuite::Classical, &alice);
+        let (_, welcome) = alice_group.add_members(&alice, vec![bob_package.key_package.clone()]).unwrap();
+        let mut bob_group = MlsGroup::join(&welcome.unwrap(), &bob_package).unwrap();
+
+        // Bob proposes Dave; Alice commits the pending proposal
+        let proposal = bob_group
+            .propose(&bob, Proposal::Add { key_package: dave_package.key_package.clone() })
+            .unwrap();
+        alice_group.process_proposal(&proposal).unwrap();
+        let (commit, welcome) = alice_group.commit(&alice, Vec::new()).unwrap();
+        assert!(bob_group.process_commit(&commit).unwrap());
+        let mut dave_group = MlsGroup::join(&welcome.unwrap(), &dave_package).unwrap();
+        assert_same_epoch(&[&alice_group, &bob_group, &dave_group]);
+
+        // The newest member refreshes its keys with an empty commit
+        let (commit, welcome) = dave_group.commit(&dave, Vec::new()).unwrap();
+        assert!(welcome.is_none());
+        assert!(alice_group.process_commit(&commit).unwrap());
+        assert!(bob_group.process_commit(&commit).unwrap());
+        assert_same_epoch(&[&alice_group, &bob_group, &dave_group]);
+
+        // A tampered commit fails signature verification
+        let (mut commit, _) = bob_group.commit(&bob, Vec::new()).unwrap();
+        commit.sender = 0;
+        assert!(dave_group.process_commit(&commit).is_err());
+    }
+
+    #[test]
+    fn test_hybrid_suite_scales_logarithmically() {
+        let suite = MlsCiphersuite::HybridPq;
+        let alice = UserKeyPair::generate();
+        let mut alice_group = MlsGroup::create(suite, &alice);
+
+        let members: Vec<(UserKeyPair, KeyPackageBundle)> = (0..20).map(|_| member(suite)).collect();
+        let packages = members.iter().map(|(_, bundle)| bundle.key_package.clone()).collect();
+        let (_, welcome) = alice_group.add_members(&alice, packages).unwrap();
+        let welcome = welcome.unwrap();
+
+        let mut groups: Vec<MlsGroup> = members
+            .iter()
+            .map(|(_, bundle)| MlsGroup::join(&welcome, bundle).unwrap())
+            .collect();
+
+        // A later update touches one node per tree level, not one per member
+        let (identity, _) = &members[7];
+        let (commit, _) = groups[7].commit(identity, Vec::new()).unwrap();
+        assert!(commit.path.nodes.len() <= 5);
+
+        assert!(alice_group.process_commit(&commit).unwrap());
+        for (index, group) in groups.iter_mut().enumerate() {
+            if index != 7 {
+                assert!(group.process_commit(&commit).unwrap());
+            }
+        }
+        assert_eq!(groups[0].inbox_id(), alice_group.inbox_id());
+        assert_eq!(groups[19].epoch_authenticator(), groups[7].epoch_authenticator());
+
+        // Key packages from another suite are refused
+        let (_, classical_package) = member(MlsCiphersuite::Classical);
+        assert!(alice_group.add_members(&alice, vec![classical_package.key_package]).is_err());
+    }
+}
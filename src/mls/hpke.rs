@@ -0,0 +1,177 @@
+//! Public-key encryption to tree nodes for each MLS ciphersuite
+//!
+//! This is HPKE in spirit rather than RFC 9180 byte-for-byte: the KEM output
+//! and shared secret go through HKDF into a ChaCha20-Poly1305 key. The hybrid
+//! suite runs X25519 alongside the post-quantum KEM and combines both secrets
+//! the same way `HybridSharedSecret` does.
+
+use crate::crypto::hybrid::{HybridPrivateKey, HybridSharedSecret};
+use crate::crypto::post_quantum::PostQuantumPrivateKey;
+use crate::crypto::{
+    decrypt_symmetric, encrypt_symmetric, ClassicalKeyExchange, HybridKeyExchange, KeyExchange,
+    PostQuantumKeyExchange, X25519PrivateKey, X25519PublicKey,
+};
+use crate::error::{NanoError, Result};
+use crate::mls::key_schedule::{expand_with_label, extract};
+use crate::mls::MlsCiphersuite;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+const X25519_LEN: usize = 32;
+const PQ_CIPHERTEXT_LEN: usize = 64;
+
+/// Encrypted payload addressed to one node's public key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HpkeCiphertext {
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub kem_output: Vec<u8>,
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// Node key pair, stored as the seed it is derived from
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeKeyPair {
+    pub ciphersuite: MlsCiphersuite,
+    seed: [u8; 32],
+}
+
+impl NodeKeyPair {
+    pub fn generate(ciphersuite: MlsCiphersuite) -> Self {
+        let mut seed = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Self::from_seed(ciphersuite, seed)
+    }
+
+    /// Deterministic key pair, so every member holding `seed` derives the same keys
+    pub fn from_seed(ciphersuite: MlsCiphersuite, seed: [u8; 32]) -> Self {
+        Self { ciphersuite, seed }
+    }
+
+    fn classical_key(&self) -> X25519PrivateKey {
+        X25519PrivateKey::from(derive_bytes(&self.seed, "dkp x25519"))
+    }
+
+    fn hybrid_key(&self) -> HybridPrivateKey {
+        HybridPrivateKey {
+            classical: self.classical_key(),
+            post_quantum: PostQuantumPrivateKey {
+                kem_key: derive_bytes(&self.seed, "dkp pq kem"),
+                sign_key: derive_bytes(&self.seed, "dkp pq sign"),
+            },
+        }
+    }
+
+    /// Encoded public key, as carried in the ratchet tree
+    pub fn public_key(&self) -> Vec<u8> {
+        match self.ciphersuite {
+            MlsCiphersuite::Classical => X25519PublicKey::from(&self.classical_key()).as_bytes().to_vec(),
+            MlsCiphersuite::HybridPq => {
+                let public_key = HybridKeyExchange::derive_public_key(&self.hybrid_key());
+                HybridKeyExchange::public_key_to_bytes(&public_key)
+            }
+        }
+    }
+
+    pub fn open(&self, info: &[u8], sealed: &HpkeCiphertext) -> Result<Vec<u8>> {
+        let shared_secret = match self.ciphersuite {
+            MlsCiphersuite::Classical => {
+                let ephemeral = x25519_public_from_bytes(&sealed.kem_output)?;
+                self.classical_key().diffie_hellman(&ephemeral).as_bytes().to_vec()
+            }
+            MlsCiphersuite::HybridPq => {
+                if sealed.kem_output.len() != X25519_LEN + PQ_CIPHERTEXT_LEN {
+                    return Err(NanoError::Crypto("Invalid hybrid KEM output length".to_string()));
+                }
+                let (ephemeral, pq_ciphertext) = sealed.kem_output.split_at(X25519_LEN);
+                let private_key = self.hybrid_key();
+
+                let classical = ClassicalKeyExchange::key_exchange(
+                    &private_key.classical,
+                    &x25519_public_from_bytes(ephemeral)?,
+                )?;
+                let post_quantum = PostQuantumKeyExchange::decapsulate(
+                    &private_key.post_quantum,
+                    &PostQuantumKeyExchange::ciphertext_from_bytes(pq_ciphertext)?,
+                )?;
+                HybridSharedSecret::new(classical, post_quantum).combined.to_vec()
+            }
+        };
+
+        let key = aead_key(&sealed.kem_output, &shared_secret, info);
+        decrypt_symmetric(&key, &sealed.ciphertext)
+    }
+}
+
+/// Encrypt `plaintext` to an encoded node public key
+pub fn seal(ciphersuite: MlsCiphersuite, public_key: &[u8], info: &[u8], plaintext: &[u8]) -> Result<HpkeCiphertext> {
+    let ephemeral = NodeKeyPair::generate(MlsCiphersuite::Classical).classical_key();
+    let mut kem_output = X25519PublicKey::from(&ephemeral).as_bytes().to_vec();
+
+    let shared_secret = match ciphersuite {
+        MlsCiphersuite::Classical => {
+            let recipient = x25519_public_from_bytes(public_key)?;
+            ephemeral.diffie_hellman(&recipient).as_bytes().to_vec()
+        }
+        MlsCiphersuite::HybridPq => {
+            let recipient = HybridKeyExchange::public_key_from_bytes(public_key)?;
+            let classical = ClassicalKeyExchange::key_exchange(&ephemeral, &recipient.classical)?;
+            let (post_quantum, pq_ciphertext) = PostQuantumKeyExchange::encapsulate(&recipient.post_quantum)?;
+            kem_output.extend_from_slice(&PostQuantumKeyExchange::ciphertext_to_bytes(&pq_ciphertext));
+            HybridSharedSecret::new(classical, post_quantum).combined.to_vec()
+        }
+    };
+
+    let key = aead_key(&kem_output, &shared_secret, info);
+    Ok(HpkeCiphertext {
+        ciphertext: encrypt_symmetric(&key, plaintext)?,
+        kem_output,
+    })
+}
+
+fn aead_key(kem_output: &[u8], shared_secret: &[u8], info: &[u8]) -> [u8; 32] {
+    let prk = extract(kem_output, shared_secret);
+    derive_bytes_with_context(&prk, "hpke key", info)
+}
+
+fn derive_bytes(seed: &[u8; 32], label: &str) -> [u8; 32] {
+    derive_bytes_with_context(seed, label, &[])
+}
+
+fn derive_bytes_with_context(secret: &[u8], label: &str, context: &[u8]) -> [u8; 32] {
+    expand_with_label(secret, label, context, 32)
+        .try_into()
+        .expect("expand returns the requested length")
+}
+
+fn x25519_public_from_bytes(bytes: &[u8]) -> Result<X25519PublicKey> {
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| NanoError::Crypto("Invalid X25519 public key length".to_string()))?;
+    Ok(X25519PublicKey::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_both_suites() {
+        for suite in [MlsCiphersuite::Classical, MlsCiphersuite::HybridPq] {
+            let recipient = NodeKeyPair::generate(suite);
+            let sealed = seal(suite, &recipient.public_key(), b"info", b"path secret").unwrap();
+
+            assert_eq!(recipient.open(b"info", &sealed).unwrap(), b"path secret");
+            assert!(recipient.open(b"other info", &sealed).is_err());
+            assert!(NodeKeyPair::generate(suite).open(b"info", &sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_key_pairs_derive_from_seed() {
+        let a = NodeKeyPair::from_seed(MlsCiphersuite::HybridPq, [9u8; 32]);
+        let b = NodeKeyPair::from_seed(MlsCiphersuite::HybridPq, [9u8; 32]);
+        assert_eq!(a.public_key(), b.public_key());
+        assert_ne!(a.public_key(), NodeKeyPair::from_seed(MlsCiphersuite::Classical, [9u8; 32]).public_key());
+    }
+}
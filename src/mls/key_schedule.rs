@@ -0,0 +1,210 @@
+//! HKDF helpers and the per-epoch key schedule (RFC 9420 Section 8)
+
+use crate::mls::MlsCiphersuite;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Output length of the suite hash, `KDF.Nh`
+pub const SECRET_LEN: usize = 32;
+
+const LABEL_PREFIX: &[u8] = b"MLS 1.0 ";
+
+/// HKDF-Extract with SHA-256 (RFC 5869)
+pub fn extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(salt).expect("HMAC accepts any key length");
+    mac.update(ikm);
+    mac.finalize().into_bytes().into()
+}
+
+/// HKDF-Expand with SHA-256 (RFC 5869)
+pub fn expand(prk: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+    assert!(length <= 255 * SECRET_LEN, "HKDF output too long");
+
+    let mut okm = Vec::with_capacity(length);
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+
+    while okm.len() < length {
+        let mut mac = HmacSha256::new_from_slice(prk).expect("HMAC accepts any key length");
+        mac.update(&block);
+        mac.update(info);
+        mac.update(&[counter]);
+        block = mac.finalize().into_bytes().to_vec();
+        okm.extend_from_slice(&block);
+        counter += 1;
+    }
+
+    okm.truncate(length);
+    okm
+}
+
+/// Append an MLS variable-length vector (QUIC-style varint length prefix)
+fn write_opaque(out: &mut Vec<u8>, data: &[u8]) {
+    let len = data.len();
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&((len as u16) | 0x4000).to_be_bytes());
+    } else {
+        out.extend_from_slice(&((len as u32) | 0x8000_0000).to_be_bytes());
+    }
+    out.extend_from_slice(data);
+}
+
+/// ExpandWithLabel(Secret, Label, Context, Length)
+pub fn expand_with_label(secret: &[u8], label: &str, context: &[u8], length: u16) -> Vec<u8> {
+    let mut full_label = LABEL_PREFIX.to_vec();
+    full_label.extend_from_slice(label.as_bytes());
+
+    let mut kdf_label = length.to_be_bytes().to_vec();
+    write_opaque(&mut kdf_label, &full_label);
+    write_opaque(&mut kdf_label, context);
+
+    expand(secret, &kdf_label, length as usize)
+}
+
+/// DeriveSecret(Secret, Label)
+pub fn derive_secret(secret: &[u8], label: &str) -> [u8; 32] {
+    to_secret(&expand_with_label(secret, label, &[], SECRET_LEN as u16))
+}
+
+fn to_secret(bytes: &[u8]) -> [u8; 32] {
+    bytes.try_into().expect("secret has suite length")
+}
+
+/// SHA-256 over the concatenation of `parts`
+pub fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// State every member must agree on for an epoch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupContext {
+    pub group_id: String,
+    pub epoch: u64,
+    pub ciphersuite: MlsCiphersuite,
+    pub tree_hash: String,                 // Hex encoded
+    pub confirmed_transcript_hash: String, // Hex encoded
+}
+
+impl GroupContext {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("group context serializes")
+    }
+}
+
+/// Secrets derived from one epoch secret
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EpochSecrets {
+    pub encryption_secret: [u8; 32],
+    pub exporter_secret: [u8; 32],
+    pub confirmation_key: [u8; 32],
+    pub epoch_authenticator: [u8; 32],
+    pub init_secret: [u8; 32],
+}
+
+impl EpochSecrets {
+    pub fn from_epoch_secret(epoch_secret: &[u8; 32]) -> Self {
+        Self {
+            encryption_secret: derive_secret(epoch_secret, "encryption"),
+            exporter_secret: derive_secret(epoch_secret, "exporter"),
+            confirmation_key: derive_secret(epoch_secret, "confirm"),
+            epoch_authenticator: derive_secret(epoch_secret, "authentication"),
+            init_secret: derive_secret(epoch_secret, "init"),
+        }
+    }
+
+    /// Advance from the previous epoch's init secret, returning the joiner secret too
+    pub fn derive(init_secret: &[u8; 32], commit_secret: &[u8; 32], context: &GroupContext) -> ([u8; 32], Self) {
+        let joiner_secret = to_secret(&expand_with_label(
+            &extract(init_secret, commit_secret),
+            "joiner",
+            &context.to_bytes(),
+            SECRET_LEN as u16,
+        ));
+        (joiner_secret, Self::from_joiner_secret(&joiner_secret, context))
+    }
+
+    /// Epoch secrets as computed by a member joining from a Welcome
+    pub fn from_joiner_secret(joiner_secret: &[u8; 32], context: &GroupContext) -> Self {
+        // No pre-shared keys are used, so psk_secret is all zeroes
+        let epoch_secret = to_secret(&expand_with_label(
+            &extract(joiner_secret, &[0u8; SECRET_LEN]),
+            "epoch",
+            &context.to_bytes(),
+            SECRET_LEN as u16,
+        ));
+        Self::from_epoch_secret(&epoch_secret)
+    }
+
+    /// Key protecting the GroupInfo inside a Welcome
+    pub fn welcome_key(joiner_secret: &[u8; 32]) -> [u8; 32] {
+        let welcome_secret = derive_secret(&extract(joiner_secret, &[0u8; SECRET_LEN]), "welcome");
+        to_secret(&expand_with_label(&welcome_secret, "key", &[], SECRET_LEN as u16))
+    }
+
+    /// MLS-Exporter(Label, Context, Length)
+    pub fn export(&self, label: &str, context: &[u8], length: u16) -> Vec<u8> {
+        let secret = derive_secret(&self.exporter_secret, label);
+        expand_with_label(&secret, "exported", &hash(&[context]), length)
+    }
+
+    /// MAC binding the confirmed transcript hash to this epoch
+    pub fn confirmation_tag(&self, confirmed_transcript_hash: &[u8]) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(&self.confirmation_key).expect("HMAC accepts any key length");
+        mac.update(confirmed_transcript_hash);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hkdf_rfc5869_case_1() {
+        let ikm = [0x0b; 22];
+        let salt = hex::decode("000102030405060708090a0b0c").unwrap();
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+
+        let prk = extract(&salt, &ikm);
+        assert_eq!(
+            hex::encode(prk),
+            "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"
+        );
+
+        let okm = expand(&prk, &info, 42);
+        assert_eq!(
+            hex::encode(okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+    }
+
+    #[test]
+    fn test_joiner_and_members_agree_on_epoch() {
+        let context = GroupContext {
+            group_id: "group".to_string(),
+            epoch: 1,
+            ciphersuite: MlsCiphersuite::Classical,
+            tree_hash: hex::encode([1u8; 32]),
+            confirmed_transcript_hash: hex::encode([2u8; 32]),
+        };
+
+        let (joiner_secret, member) = EpochSecrets::derive(&[3u8; 32], &[4u8; 32], &context);
+        let joiner = EpochSecrets::from_joiner_secret(&joiner_secret, &context);
+        assert_eq!(member.exporter_secret, joiner.exporter_secret);
+        assert_eq!(member.init_secret, joiner.init_secret);
+
+        // A different commit secret gives an unrelated epoch
+        let (_, other) = EpochSecrets::derive(&[3u8; 32], &[5u8; 32], &context);
+        assert_ne!(member.exporter_secret, other.exporter_secret);
+        assert_ne!(member.export("inbox", b"", 32), other.export("inbox", b"", 32));
+    }
+}
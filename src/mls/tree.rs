@@ -0,0 +1,225 @@
+//! Public ratchet tree shared by all group members (TreeKEM)
+
+use crate::mls::key_schedule::hash;
+use crate::mls::tree_math;
+use serde::{Deserialize, Serialize};
+
+/// A member's leaf: their identity and current encryption key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeafNode {
+    pub identity: String, // Nano public key string
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub encryption_key: Vec<u8>,
+}
+
+/// An intermediate node shared by every leaf below it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParentNode {
+    #[serde(with = "crate::mls::base64_bytes")]
+    pub encryption_key: Vec<u8>,
+    /// Leaves added below this node since its key was last set
+    pub unmerged_leaves: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Node {
+    Leaf(LeafNode),
+    Parent(ParentNode),
+}
+
+impl Node {
+    pub fn encryption_key(&self) -> &[u8] {
+        match self {
+            Node::Leaf(leaf) => &leaf.encryption_key,
+            Node::Parent(parent) => &parent.encryption_key,
+        }
+    }
+}
+
+/// Array-backed ratchet tree; `None` marks a blank node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetTree {
+    nodes: Vec<Option<Node>>,
+}
+
+impl RatchetTree {
+    /// One-member tree for a newly created group
+    pub fn new(leaf: LeafNode) -> Self {
+        Self {
+            nodes: vec![Some(Node::Leaf(leaf))],
+        }
+    }
+
+    pub fn leaf_count(&self) -> u32 {
+        (self.nodes.len() as u32).div_ceil(2)
+    }
+
+    pub fn node(&self, x: u32) -> Option<&Node> {
+        self.nodes.get(x as usize).and_then(Option::as_ref)
+    }
+
+    pub fn leaf(&self, leaf: u32) -> Option<&LeafNode> {
+        match self.node(tree_math::leaf_to_node(leaf)) {
+            Some(Node::Leaf(leaf)) => Some(leaf),
+            _ => None,
+        }
+    }
+
+    /// Occupied leaves with their leaf indices
+    pub fn leaves(&self) -> impl Iterator<Item = (u32, &LeafNode)> {
+        (0..self.leaf_count()).filter_map(move |i| self.leaf(i).map(|leaf| (i, leaf)))
+    }
+
+    pub fn find_leaf(&self, identity: &str) -> Option<u32> {
+        self.leaves().find(|(_, leaf)| leaf.identity == identity).map(|(i, _)| i)
+    }
+
+    /// Place a new member in the leftmost blank leaf, growing the tree if full
+    pub fn add_leaf(&mut self, leaf: LeafNode) -> u32 {
+        let index = match (0..self.leaf_count()).find(|&i| self.leaf(i).is_none()) {
+            Some(index) => index,
+            None => {
+                let index = self.leaf_count();
+                let width = tree_math::node_width(index * 2);
+                self.nodes.resize(width as usize, None);
+                index
+            }
+        };
+
+        let node = tree_math::leaf_to_node(index);
+        self.nodes[node as usize] = Some(Node::Leaf(leaf));
+
+        for parent in tree_math::direct_path(node, self.leaf_count()) {
+            if let Some(Node::Parent(parent)) = &mut self.nodes[parent as usize] {
+                parent.unmerged_leaves.push(index);
+            }
+        }
+        index
+    }
+
+    /// Blank a leaf and its direct path, then drop any empty right half
+    pub fn remove_leaf(&mut self, leaf: u32) {
+        let node = tree_math::leaf_to_node(leaf);
+        self.nodes[node as usize] = None;
+        self.blank_direct_path(node);
+
+        while self.leaf_count() > 1 {
+            let half = self.leaf_count() / 2;
+            if (half..self.leaf_count()).any(|i| self.leaf(i).is_some()) {
+                break;
+            }
+            self.nodes.truncate(tree_math::node_width(half) as usize);
+        }
+    }
+
+    fn blank_direct_path(&mut self, node: u32) {
+        for parent in tree_math::direct_path(node, self.leaf_count()) {
+            self.nodes[parent as usize] = None;
+        }
+    }
+
+    /// Minimal set of non-blank nodes covering the subtree under `x`
+    pub fn resolution(&self, x: u32) -> Vec<u32> {
+        match self.node(x) {
+            Some(Node::Leaf(_)) => vec![x],
+            Some(Node::Parent(parent)) => {
+                let mut nodes = vec![x];
+                nodes.extend(parent.unmerged_leaves.iter().map(|&leaf| tree_math::leaf_to_node(leaf)));
+                nodes
+            }
+            None if tree_math::is_leaf(x) => Vec::new(),
+            None => {
+                let mut nodes = self.resolution(tree_math::left(x));
+                nodes.extend(self.resolution(tree_math::right(x)));
+                nodes
+            }
+        }
+    }
+
+    /// Direct path of a leaf paired with copath nodes, skipping parents whose
+    /// copath child has an empty resolution
+    pub fn filtered_direct_path(&self, leaf: u32) -> Vec<(u32, u32)> {
+        let node = tree_math::leaf_to_node(leaf);
+        let n_leaves = self.leaf_count();
+
+        tree_math::direct_path(node, n_leaves)
+            .into_iter()
+            .zip(tree_math::copath(node, n_leaves))
+            .filter(|(_, copath_node)| !self.resolution(*copath_node).is_empty())
+            .collect()
+    }
+
+    /// Install a committer's new leaf and path keys
+    pub fn merge_path(&mut self, leaf: u32, leaf_node: LeafNode, path_keys: &[(u32, Vec<u8>)]) {
+        let node = tree_math::leaf_to_node(leaf);
+        self.nodes[node as usize] = Some(Node::Leaf(leaf_node));
+        self.blank_direct_path(node);
+
+        for (parent, encryption_key) in path_keys {
+            self.nodes[*parent as usize] = Some(Node::Parent(ParentNode {
+                encryption_key: encryption_key.clone(),
+                unmerged_leaves: Vec::new(),
+            }));
+        }
+    }
+
+    /// Hash committing to the whole public tree
+    ///
+    /// A flat hash over the serialized nodes rather than RFC 9420's recursive
+    /// tree hash; members only compare it with each other.
+    pub fn tree_hash(&self) -> [u8; 32] {
+        hash(&[&serde_json::to_vec(&self.nodes).expect("tree serializes")])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(name: &str) -> LeafNode {
+        LeafNode {
+            identity: name.to_string(),
+            encryption_key: name.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_add_and_remove_resize_tree() {
+        let mut tree = RatchetTree::new(leaf("a"));
+        assert_eq!(tree.add_leaf(leaf("b")), 1);
+        assert_eq!(tree.add_leaf(leaf("c")), 2);
+        assert_eq!(tree.leaf_count(), 4);
+
+        // Blank leaves are reused before the tree grows again
+        tree.remove_leaf(1);
+        assert_eq!(tree.add_leaf(leaf("d")), 1);
+        assert_eq!(tree.find_leaf("d"), Some(1));
+
+        // Removing everything on the right shrinks the tree
+        tree.remove_leaf(2);
+        assert_eq!(tree.leaf_count(), 2);
+    }
+
+    #[test]
+    fn test_resolution_and_unmerged_leaves() {
+        let mut tree = RatchetTree::new(leaf("a"));
+        for name in ["b", "c", "d"] {
+            tree.add_leaf(leaf(name));
+        }
+
+        // Blank parents resolve to their leaves
+        assert_eq!(tree.resolution(3), vec![0, 2, 4, 6]);
+        assert_eq!(tree.filtered_direct_path(0), vec![(1, 2), (3, 5)]);
+
+        // With leaf 1 gone, node 1 has nothing to encrypt to and is skipped
+        tree.remove_leaf(1);
+        assert_eq!(tree.filtered_direct_path(0), vec![(3, 5)]);
+
+        // A leaf added under the refreshed root is unmerged there
+        tree.merge_path(0, leaf("a"), &[(3, vec![3])]);
+        assert_eq!(tree.add_leaf(leaf("e")), 1);
+        assert_eq!(tree.resolution(3), vec![3, 2]);
+        assert_eq!(tree.resolution(1), vec![0, 2]);
+    }
+}
@@ -0,0 +1,154 @@
+//! Array-based binary tree arithmetic from RFC 9420 Appendix C
+//!
+//! Leaves sit at even node indices and parents at odd ones. Trees always
+//! hold a power-of-two number of leaves, so every function here works on
+//! full trees only.
+
+/// Floor of log2(x), with log2(0) = 0
+pub fn log2(x: u32) -> u32 {
+    if x == 0 {
+        return 0;
+    }
+    31 - x.leading_zeros()
+}
+
+/// Level of a node: 0 for leaves, increasing towards the root
+pub fn level(x: u32) -> u32 {
+    x.trailing_ones()
+}
+
+/// Number of nodes in a tree with `n_leaves` leaves
+pub fn node_width(n_leaves: u32) -> u32 {
+    if n_leaves == 0 {
+        0
+    } else {
+        2 * (n_leaves - 1) + 1
+    }
+}
+
+/// Index of the root node
+pub fn root(n_leaves: u32) -> u32 {
+    (1 << log2(node_width(n_leaves))) - 1
+}
+
+pub fn leaf_to_node(leaf: u32) -> u32 {
+    2 * leaf
+}
+
+pub fn node_to_leaf(node: u32) -> u32 {
+    node / 2
+}
+
+pub fn is_leaf(x: u32) -> bool {
+    x.is_multiple_of(2)
+}
+
+/// Left child of an intermediate node
+pub fn left(x: u32) -> u32 {
+    let k = level(x);
+    assert!(k > 0, "leaf nodes have no children");
+    x ^ (1 << (k - 1))
+}
+
+/// Right child of an intermediate node
+pub fn right(x: u32) -> u32 {
+    let k = level(x);
+    assert!(k > 0, "leaf nodes have no children");
+    x ^ (3 << (k - 1))
+}
+
+/// Parent of a non-root node
+pub fn parent(x: u32, n_leaves: u32) -> u32 {
+    assert!(x != root(n_leaves), "root node has no parent");
+    let k = level(x);
+    let b = (x >> (k + 1)) & 1;
+    (x | (1 << k)) ^ (b << (k + 1))
+}
+
+/// The other child of a node's parent
+pub fn sibling(x: u32, n_leaves: u32) -> u32 {
+    let p = parent(x, n_leaves);
+    if x < p {
+        right(p)
+    } else {
+        left(p)
+    }
+}
+
+/// Ancestors of a node, from its parent up to the root
+pub fn direct_path(x: u32, n_leaves: u32) -> Vec<u32> {
+    let r = root(n_leaves);
+    let mut path = Vec::new();
+    let mut x = x;
+    while x != r {
+        x = parent(x, n_leaves);
+        path.push(x);
+    }
+    path
+}
+
+/// Siblings of the node and of each of its ancestors below the root
+pub fn copath(x: u32, n_leaves: u32) -> Vec<u32> {
+    if x == root(n_leaves) {
+        return Vec::new();
+    }
+
+    let mut path = vec![x];
+    path.extend(direct_path(x, n_leaves));
+    path.pop();
+    path.into_iter().map(|y| sibling(y, n_leaves)).collect()
+}
+
+/// Whether `x` lies in the subtree rooted at `ancestor`
+pub fn is_in_subtree(x: u32, ancestor: u32) -> bool {
+    let span = (1u32 << level(ancestor)) - 1;
+    x >= ancestor - span && x <= ancestor + span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc9420_eight_leaf_example() {
+        // Figures from RFC 9420 Section 4.1 and Appendix C
+        let n = 8;
+        assert_eq!(node_width(n), 15);
+        assert_eq!(root(n), 7);
+        assert_eq!(level(7), 3);
+        assert_eq!(left(7), 3);
+        assert_eq!(right(7), 11);
+        assert_eq!(parent(1, n), 3);
+        assert_eq!(sibling(3, n), 11);
+
+        // Leaf A
+        assert_eq!(direct_path(0, n), vec![1, 3, 7]);
+        assert_eq!(copath(0, n), vec![2, 5, 11]);
+
+        // Leaf C
+        assert_eq!(direct_path(4, n), vec![5, 3, 7]);
+        assert_eq!(copath(4, n), vec![6, 1, 11]);
+    }
+
+    #[test]
+    fn test_tree_math_is_consistent() {
+        for n_leaves in [1u32, 2, 4, 8, 16, 32] {
+            let r = root(n_leaves);
+            assert_eq!(r, node_width(n_leaves) / 2);
+
+            for x in 0..node_width(n_leaves) {
+                assert!(is_in_subtree(x, r));
+                if x == r {
+                    assert!(direct_path(x, n_leaves).is_empty());
+                    continue;
+                }
+
+                let p = parent(x, n_leaves);
+                assert!(left(p) == x || right(p) == x);
+                assert_eq!(parent(sibling(x, n_leaves), n_leaves), p);
+                assert_eq!(direct_path(x, n_leaves).len(), copath(x, n_leaves).len());
+                assert_eq!(*direct_path(x, n_leaves).last().unwrap(), r);
+            }
+        }
+    }
+}
@@ -1,9 +1,27 @@
-use crate::protocol::{ProtocolMessage, QuantumSafeEnvelope};
+use crate::protocol::{ProtocolMessage, QuantumSafeEnvelope, UsernameClaim, REPLAY_REJECTION};
 use crate::crypto::UnifiedPublicKeys;
+use crate::devices::DeviceList;
+use crate::mls::{MlsCiphersuite, MlsMessage};
+use crate::onion::{OnionHop, OnionPacket};
+use crate::blobs::BlobGrant;
 use crate::error::{NanoError, Result};
+use base64::{engine::general_purpose, Engine as _};
 use tokio::net::TcpStream;
 use tokio::io::{AsyncWriteExt, BufReader, AsyncBufReadExt};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a relay is passed over after its first failure, doubled per consecutive failure
+const RELAY_COOLDOWN_BASE: Duration = Duration::from_secs(5);
+const RELAY_COOLDOWN_MAX: Duration = Duration::from_secs(300);
+
+/// Error for a relay's reply to a sent envelope, keeping replay rejections apart
+fn relay_error(message: String) -> NanoError {
+    match message.strip_prefix(REPLAY_REJECTION) {
+        Some(reason) => NanoError::ReplayDetected(reason.trim().to_string()),
+        None => NanoError::Protocol(format!("Relay error: {}", message)),
+    }
+}
 
 /// TCP client for communicating with nano-relay servers
 pub struct RelayClient {
@@ -15,6 +33,10 @@ impl RelayClient {
         Self { address }
     }
 
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
     /// Send a message to the relay and return the response
     pub async fn send_message(&self, message: ProtocolMessage) -> Result<ProtocolMessage> {
         let mut stream = self.connect().await?;
@@ -27,7 +49,13 @@ impl RelayClient {
         // Read response
         let mut reader = BufReader::new(stream);
         let mut response_line = String::new();
-        reader.read_line(&mut response_line).await?;
+        if reader.read_line(&mut response_line).await? == 0 {
+            // The request may still have been handled, so callers retry it like any network failure
+            return Err(NanoError::Network(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Relay closed the connection without replying",
+            )));
+        }
         
         let response = ProtocolMessage::from_json(&response_line.trim())?;
         Ok(response)
@@ -55,6 +83,17 @@ impl RelayClient {
         
         match response {
             ProtocolMessage::Success { .. } => Ok(()),
+            ProtocolMessage::Error { message } => Err(relay_error(message)),
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Ask the relay for its onion routing key
+    pub async fn get_onion_hop(&self) -> Result<OnionHop> {
+        let response = self.send_message(ProtocolMessage::GetRelayInfo).await?;
+        
+        match response {
+            ProtocolMessage::RelayInfo { onion_key } => OnionHop::from_hex(self.address.clone(), &onion_key),
             ProtocolMessage::Error { message } => {
                 Err(NanoError::Protocol(format!("Relay error: {}", message)))
             }
@@ -62,6 +101,18 @@ impl RelayClient {
         }
     }
 
+    /// Hand an onion packet to this relay, the first hop of its route
+    pub async fn send_onion(&self, packet: OnionPacket) -> Result<()> {
+        let message = ProtocolMessage::RelayOnion { packet };
+        let response = self.send_message(message).await?;
+        
+        match response {
+            ProtocolMessage::Success { .. } => Ok(()),
+            ProtocolMessage::Error { message } => Err(relay_error(message)),
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
     /// Fetch messages from an inbox
     pub async fn fetch_inbox(&self, inbox_id: String) -> Result<Vec<crate::protocol::MessageEnvelope>> {
         let message = ProtocolMessage::FetchInbox { inbox_id };
@@ -69,6 +120,9 @@ impl RelayClient {
         
         match response {
             ProtocolMessage::InboxMessages { messages } => Ok(messages),
+            ProtocolMessage::QuantumInboxMessages { messages } => {
+                Ok(messages.iter().map(QuantumSafeEnvelope::to_legacy).collect())
+            }
             ProtocolMessage::Error { message } => {
                 Err(NanoError::Protocol(format!("Relay error: {}", message)))
             }
@@ -104,6 +158,116 @@ impl RelayClient {
         }
     }
 
+    /// Look up the signed claim for a username or pubkey, checking the relay did not alter it
+    pub async fn lookup_claim(&self, username: String) -> Result<Option<UsernameClaim>> {
+        let message = ProtocolMessage::LookupClaim { username: username.clone() };
+        let response = self.send_message(message).await?;
+
+        match response {
+            ProtocolMessage::ClaimResult { claim: Some(claim), .. } => {
+                claim.verify_signature()?;
+                if claim.username != username && claim.public_keys.public_key_string() != username {
+                    return Err(NanoError::Protocol(format!("Relay returned a claim for {}", claim.username)));
+                }
+                Ok(Some(claim))
+            }
+            ProtocolMessage::ClaimResult { claim: None, .. } => Ok(None),
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Publish our identity's signed device list
+    pub async fn publish_device_list(&self, list: DeviceList) -> Result<()> {
+        let message = ProtocolMessage::PublishDeviceList { list };
+        let response = self.send_message(message).await?;
+
+        match response {
+            ProtocolMessage::Success { .. } => Ok(()),
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Look up the devices linked to an identity
+    pub async fn lookup_devices(&self, identity: String) -> Result<Option<DeviceList>> {
+        let message = ProtocolMessage::LookupDevices { identity };
+        let response = self.send_message(message).await?;
+
+        match response {
+            ProtocolMessage::DeviceListResult { list, .. } => Ok(list),
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Reserve a blob of `size` bytes, returning its capability tokens
+    pub async fn create_blob(&self, size: u64) -> Result<BlobGrant> {
+        let response = self.send_message(ProtocolMessage::UploadBlob { size }).await?;
+
+        match response {
+            ProtocolMessage::BlobCreated { grant } => Ok(grant),
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Append ciphertext to a blob at `offset`, returning how many bytes the relay holds
+    pub async fn upload_chunk(&self, blob_id: String, upload_token: String, offset: u64, data: &[u8]) -> Result<u64> {
+        let message = ProtocolMessage::UploadChunk {
+            blob_id: blob_id.clone(),
+            token: upload_token,
+            offset,
+            data: general_purpose::STANDARD.encode(data),
+        };
+        let response = self.send_message(message).await?;
+
+        match response {
+            ProtocolMessage::BlobProgress { blob_id: returned, received } if returned == blob_id => Ok(received),
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Read up to `length` bytes of a finished blob from `offset`
+    pub async fn download_blob(&self, blob_id: String, download_token: String, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let message = ProtocolMessage::DownloadBlob { blob_id: blob_id.clone(), token: download_token, offset, length };
+        let response = self.send_message(message).await?;
+
+        match response {
+            ProtocolMessage::BlobData { blob_id: returned, offset: returned_offset, data } if returned == blob_id && returned_offset == offset => {
+                general_purpose::STANDARD.decode(data).map_err(|e| NanoError::Protocol(format!("Invalid blob data: {}", e)))
+            }
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Drop a blob we uploaded
+    pub async fn delete_blob(&self, blob_id: String, upload_token: String) -> Result<()> {
+        let response = self.send_message(ProtocolMessage::DeleteBlob { blob_id, token: upload_token }).await?;
+
+        match response {
+            ProtocolMessage::Success { .. } => Ok(()),
+            ProtocolMessage::Error { message } => {
+                Err(NanoError::Protocol(format!("Relay error: {}", message)))
+            }
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
     // Session 5: Quantum-Safe Messaging Support
 
     /// Send quantum-safe message envelope to relay
@@ -113,9 +277,7 @@ impl RelayClient {
         
         match response {
             ProtocolMessage::Success { .. } => Ok(()),
-            ProtocolMessage::Error { message } => {
-                Err(NanoError::Protocol(format!("Relay error: {}", message)))
-            }
+            ProtocolMessage::Error { message } => Err(relay_error(message)),
             _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
         }
     }
@@ -141,6 +303,24 @@ impl RelayClient {
         }
     }
 
+    /// Send an MLS handshake or application message to an inbox
+    pub async fn send_mls_message(&self, inbox_id: String, ciphersuite: MlsCiphersuite, message: MlsMessage) -> Result<()> {
+        let message = ProtocolMessage::SendMlsMessage { inbox_id, ciphersuite, message };
+        let response = self.send_message(message).await?;
+        
+        match response {
+            ProtocolMessage::Success { .. } => Ok(()),
+            ProtocolMessage::Error { message } => Err(relay_error(message)),
+            _ => Err(NanoError::Protocol("Unexpected response type".to_string())),
+        }
+    }
+
+    /// Fetch MLS messages from an inbox, skipping anything else stored there
+    pub async fn fetch_mls_messages(&self, inbox_id: String) -> Result<Vec<MlsMessage>> {
+        let envelopes = self.fetch_quantum_inbox(inbox_id).await?;
+        Ok(envelopes.iter().filter_map(|envelope| MlsMessage::from_envelope(envelope).ok()).collect())
+    }
+
     /// Look up a username with unified public key support
     pub async fn lookup_username_unified(&self, username: String) -> Result<Option<UnifiedPublicKeys>> {
         let message = ProtocolMessage::LookupUsername { username };
@@ -168,6 +348,92 @@ impl RelayClient {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+struct RelayHealth {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Relay addresses in order of preference, with failure tracking for failover
+#[derive(Debug, Clone, Default)]
+pub struct RelayPool {
+    relays: Vec<String>,
+    health: HashMap<String, RelayHealth>, // Also tracks other users' relays we deliver to
+}
+
+impl RelayPool {
+    pub fn new(relays: Vec<String>) -> Self {
+        Self { relays, health: HashMap::new() }
+    }
+
+    /// Our relays in order of preference
+    pub fn relays(&self) -> &[String] {
+        &self.relays
+    }
+
+    /// Whether a relay is not cooling down after a failure
+    pub fn is_healthy(&self, relay: &str) -> bool {
+        self.is_healthy_at(relay, Instant::now())
+    }
+
+    fn is_healthy_at(&self, relay: &str, now: Instant) -> bool {
+        self.health
+            .get(relay)
+            .and_then(|health| health.retry_at)
+            .is_none_or(|retry_at| retry_at <= now)
+    }
+
+    /// `candidates` with healthy relays first in their given order, then the rest by soonest retry
+    pub fn ordered(&self, candidates: &[String]) -> Vec<String> {
+        let now = Instant::now();
+        let (healthy, mut cooling): (Vec<&String>, Vec<&String>) =
+            candidates.iter().partition(|relay| self.is_healthy_at(relay, now));
+        cooling.sort_by_key(|relay| self.health.get(relay.as_str()).and_then(|health| health.retry_at));
+        healthy.into_iter().chain(cooling).cloned().collect()
+    }
+
+    pub fn record_success(&mut self, relay: &str) {
+        self.health.remove(relay);
+    }
+
+    /// Pass a relay over for a while, longer after each consecutive failure
+    pub fn record_failure(&mut self, relay: &str) {
+        self.record_failure_at(relay, Instant::now());
+    }
+
+    fn record_failure_at(&mut self, relay: &str, now: Instant) {
+        let health = self.health.entry(relay.to_string()).or_default();
+        health.failures += 1;
+        let cooldown = RELAY_COOLDOWN_BASE * 2u32.pow(health.failures.saturating_sub(1).min(10));
+        health.retry_at = Some(now + cooldown.min(RELAY_COOLDOWN_MAX));
+    }
+
+    /// Run `request` against each candidate, healthiest first, until one answers
+    ///
+    /// Only network errors fail over; an answer from a relay, even an error, is final.
+    pub async fn request<T>(
+        &mut self,
+        candidates: &[String],
+        mut request: impl AsyncFnMut(&RelayClient) -> Result<T>,
+    ) -> Result<T> {
+        let mut last_error = None;
+        for relay in self.ordered(candidates) {
+            match request(&RelayClient::new(relay.clone())).await {
+                Ok(result) => {
+                    self.record_success(&relay);
+                    return Ok(result);
+                }
+                Err(NanoError::Network(e)) => {
+                    self.record_failure(&relay);
+                    last_error = Some(NanoError::Network(e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| NanoError::Config("No relays configured".to_string())))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,4 +446,55 @@ mod tests {
 
     // Note: Integration tests would require a running relay server
     // These should be in tests/ directory for proper integration testing
+
+    fn relays(addresses: &[&str]) -> Vec<String> {
+        addresses.iter().map(|address| address.to_string()).collect()
+    }
+
+    #[test]
+    fn test_relay_pool_ordering() {
+        let mut pool = RelayPool::new(relays(&["a:1", "b:2", "c:3"]));
+        assert_eq!(pool.ordered(pool.relays()), relays(&["a:1", "b:2", "c:3"]));
+
+        // Failed relays drop to the back, the most recently failed last
+        let now = Instant::now();
+        pool.record_failure_at("a:1", now);
+        pool.record_failure_at("a:1", now);
+        pool.record_failure_at("b:2", now);
+        assert!(!pool.is_healthy("a:1"));
+        assert_eq!(pool.ordered(pool.relays()), relays(&["c:3", "b:2", "a:1"]));
+
+        // Cooldowns double per consecutive failure and end on success
+        assert!(pool.is_healthy_at("b:2", now + RELAY_COOLDOWN_BASE));
+        assert!(!pool.is_healthy_at("a:1", now + RELAY_COOLDOWN_BASE));
+        pool.record_success("a:1");
+        assert_eq!(pool.ordered(pool.relays())[0], "a:1");
+    }
+
+    #[tokio::test]
+    async fn test_relay_pool_failover() {
+        let mut pool = RelayPool::new(relays(&["down:1", "up:2"]));
+        let unreachable = || NanoError::Network(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused"));
+
+        let answered = pool
+            .request(&relays(&["down:1", "up:2"]), async |client: &RelayClient| match client.address() {
+                "down:1" => Err(unreachable()),
+                address => Ok(address.to_string()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(answered, "up:2");
+        assert!(!pool.is_healthy("down:1"));
+
+        // Relay answers are not retried elsewhere
+        let mut tried = Vec::new();
+        let result: Result<()> = pool
+            .request(pool.relays().to_vec().as_slice(), async |client: &RelayClient| {
+                tried.push(client.address().to_string());
+                Err(NanoError::Protocol("Relay error: rejected".to_string()))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(tried, relays(&["up:2"]));
+    }
 }
@@ -0,0 +1,319 @@
+use crate::crypto::{
+    decrypt_asymmetric, decrypt_symmetric, encrypt_asymmetric, encrypt_symmetric, X25519PrivateKey, X25519PublicKey,
+};
+use crate::error::{NanoError, Result};
+use crate::protocol::QuantumSafeEnvelope;
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Fewest relays an onion route may use
+pub const MIN_ONION_HOPS: usize = 2;
+
+/// Most relays an onion route may use
+pub const MAX_ONION_HOPS: usize = 3;
+
+/// Size of every onion layer before base64, whichever hop it is sealed to
+pub const ONION_PACKET_BYTES: usize = 64 * 1024;
+
+const ONION_VERSION: &str = "2.0-onion";
+
+/// Body key and body length, sealed to the hop's onion key
+const HEADER_PLAINTEXT_BYTES: usize = 32 + 4;
+
+/// Sealed header: ephemeral key, nonce, plaintext and tag
+const HEADER_BYTES: usize = 32 + 12 + HEADER_PLAINTEXT_BYTES + 16;
+
+/// A relay on an onion route
+#[derive(Debug, Clone)]
+pub struct OnionHop {
+    pub address: String,
+    pub public_key: X25519PublicKey,
+}
+
+impl OnionHop {
+    pub fn new(address: String, public_key: X25519PublicKey) -> Self {
+        Self { address, public_key }
+    }
+
+    /// Parse a hop from its address and hex-encoded onion key
+    pub fn from_hex(address: String, onion_key: &str) -> Result<Self> {
+        let bytes: [u8; 32] = hex::decode(onion_key)
+            .map_err(|e| NanoError::Crypto(format!("Invalid onion key: {}", e)))?
+            .try_into()
+            .map_err(|_| NanoError::Crypto("Invalid onion key length".to_string()))?;
+        Ok(Self::new(address, X25519PublicKey::from(bytes)))
+    }
+}
+
+/// What a relay finds after removing its layer
+#[derive(Debug, Clone)]
+pub enum OnionLayer {
+    /// Pass the inner packet, already padded back to full size, on to the next relay
+    Forward { next_hop: String, packet: OnionPacket },
+
+    /// Final hop: store the envelope in its inbox
+    Deliver { envelope: QuantumSafeEnvelope },
+}
+
+/// Routing instructions inside a layer, followed by the next hop's sealed layer
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Route {
+    #[serde(rename = "forward")]
+    Forward { next_hop: String },
+
+    #[serde(rename = "deliver")]
+    Deliver { envelope: QuantumSafeEnvelope },
+}
+
+/// Layered-encrypted packet, loosely modelled on Sphinx
+///
+/// Each layer is sealed to one relay's onion key and names only the next
+/// hop. A layer is a fixed-size header holding the body key and length, the
+/// encrypted body, then random filler up to `ONION_PACKET_BYTES`. Relays
+/// pad the inner layer back to that size after peeling, so every hop sees a
+/// packet of the same size whatever its position on the route.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnionPacket {
+    pub version: String,
+    pub layer: String, // Base64 encoded layer sealed to this hop, always ONION_PACKET_BYTES long
+}
+
+impl OnionPacket {
+    /// Wrap an envelope for delivery along `path`, first hop first
+    pub fn build(path: &[OnionHop], envelope: QuantumSafeEnvelope) -> Result<Self> {
+        if !(MIN_ONION_HOPS..=MAX_ONION_HOPS).contains(&path.len()) {
+            return Err(NanoError::Protocol(format!(
+                "Onion routes need {} to {} relays, got {}",
+                MIN_ONION_HOPS,
+                MAX_ONION_HOPS,
+                path.len()
+            )));
+        }
+
+        let (last, earlier) = path.split_last().unwrap();
+        let mut sealed = seal(&last.public_key, &Route::Deliver { envelope }, &[])?;
+
+        for (index, hop) in earlier.iter().enumerate().rev() {
+            let route = Route::Forward {
+                next_hop: path[index + 1].address.clone(),
+            };
+            sealed = seal(&hop.public_key, &route, &sealed)?;
+        }
+
+        Self::padded(sealed)
+    }
+
+    /// Top a sealed layer up to the fixed packet size with random filler
+    fn padded(mut sealed: Vec<u8>) -> Result<Self> {
+        if sealed.len() > ONION_PACKET_BYTES {
+            return Err(NanoError::Protocol(format!(
+                "Envelope too large for an onion packet ({} of {} bytes)",
+                sealed.len(),
+                ONION_PACKET_BYTES
+            )));
+        }
+
+        let sealed_len = sealed.len();
+        sealed.resize(ONION_PACKET_BYTES, 0);
+        rand::thread_rng().fill_bytes(&mut sealed[sealed_len..]);
+
+        Ok(Self {
+            version: ONION_VERSION.to_string(),
+            layer: general_purpose::STANDARD.encode(sealed),
+        })
+    }
+
+    /// Remove this relay's layer
+    pub fn peel(&self, onion_key: &X25519PrivateKey) -> Result<OnionLayer> {
+        if self.version != ONION_VERSION {
+            return Err(NanoError::Protocol(format!("Unsupported onion version: {}", self.version)));
+        }
+
+        let packet = general_purpose::STANDARD.decode(&self.layer)?;
+        if packet.len() != ONION_PACKET_BYTES {
+            return Err(NanoError::Protocol(format!(
+                "Onion packets must be {} bytes, got {}",
+                ONION_PACKET_BYTES,
+                packet.len()
+            )));
+        }
+
+        let (header, rest) = packet.split_at(HEADER_BYTES);
+        let header = decrypt_asymmetric(onion_key, header)?;
+        if header.len() != HEADER_PLAINTEXT_BYTES {
+            return Err(NanoError::Protocol("Invalid onion layer header".to_string()));
+        }
+        let (key, body_len) = header.split_at(32);
+        let key: [u8; 32] = key.try_into().unwrap();
+        let body_len = u32::from_be_bytes(body_len.try_into().unwrap()) as usize;
+
+        let body = rest
+            .get(..body_len)
+            .ok_or_else(|| NanoError::Protocol("Onion layer body overruns the packet".to_string()))?;
+        let plaintext = decrypt_symmetric(&key, body)?;
+
+        if plaintext.len() < 4 {
+            return Err(NanoError::Protocol("Onion layer body too short".to_string()));
+        }
+        let (route_len, plaintext) = plaintext.split_at(4);
+        let route_len = u32::from_be_bytes(route_len.try_into().unwrap()) as usize;
+        if plaintext.len() < route_len {
+            return Err(NanoError::Protocol("Onion layer route overruns the body".to_string()));
+        }
+        let (route, inner) = plaintext.split_at(route_len);
+
+        match serde_json::from_slice(route)? {
+            Route::Forward { next_hop } => Ok(OnionLayer::Forward {
+                next_hop,
+                packet: Self::padded(inner.to_vec())?,
+            }),
+            Route::Deliver { envelope } => Ok(OnionLayer::Deliver { envelope }),
+        }
+    }
+
+    /// Size of the packet as seen on the wire
+    pub fn wire_len(&self) -> usize {
+        self.layer.len()
+    }
+
+    /// Stable identifier used by relays to reject replayed packets
+    ///
+    /// Only the header counts: the filler is never authenticated, so a
+    /// replay with altered filler still peels.
+    pub fn replay_id(&self) -> String {
+        let header = self.layer.get(..HEADER_BYTES / 3 * 4).unwrap_or(&self.layer);
+        hex::encode(Sha256::digest(header.as_bytes()))
+    }
+}
+
+/// Seal a route and the next hop's layer to one relay, without filler
+fn seal(public_key: &X25519PublicKey, route: &Route, inner: &[u8]) -> Result<Vec<u8>> {
+    let route = serde_json::to_vec(route)?;
+    let mut plaintext = Vec::with_capacity(4 + route.len() + inner.len());
+    plaintext.extend_from_slice(&(route.len() as u32).to_be_bytes());
+    plaintext.extend_from_slice(&route);
+    plaintext.extend_from_slice(inner);
+
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let body = encrypt_symmetric(&key, &plaintext)?;
+
+    let mut header = Vec::with_capacity(HEADER_PLAINTEXT_BYTES);
+    header.extend_from_slice(&key);
+    header.extend_from_slice(&(body.len() as u32).to_be_bytes());
+
+    let mut sealed = encrypt_asymmetric(public_key, &header)?;
+    if sealed.len() != HEADER_BYTES {
+        return Err(NanoError::Crypto("Unexpected onion header size".to_string()));
+    }
+    sealed.extend_from_slice(&body);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoMode;
+    use rand::rngs::OsRng;
+
+    fn relay(address: &str) -> (X25519PrivateKey, OnionHop) {
+        let key = X25519PrivateKey::random_from_rng(OsRng);
+        let hop = OnionHop::new(address.to_string(), X25519PublicKey::from(&key));
+        (key, hop)
+    }
+
+    fn test_envelope() -> QuantumSafeEnvelope {
+        QuantumSafeEnvelope::new(CryptoMode::Hybrid, "inbox".to_string(), b"secret".to_vec())
+    }
+
+    #[test]
+    fn test_onion_peels_along_route() {
+        let (key_a, hop_a) = relay("127.0.0.1:7001");
+        let (key_b, hop_b) = relay("127.0.0.1:7002");
+        let (key_c, hop_c) = relay("127.0.0.1:7003");
+
+        let envelope = test_envelope();
+        let packet = OnionPacket::build(&[hop_a, hop_b, hop_c], envelope.clone()).unwrap();
+        let wire_len = packet.wire_len();
+        assert_eq!(wire_len, ONION_PACKET_BYTES.div_ceil(3) * 4);
+
+        let packet = match packet.peel(&key_a).unwrap() {
+            OnionLayer::Forward { next_hop, packet } => {
+                assert_eq!(next_hop, "127.0.0.1:7002");
+                packet
+            }
+            OnionLayer::Deliver { .. } => panic!("first hop must forward"),
+        };
+        assert_eq!(packet.wire_len(), wire_len);
+
+        let packet = match packet.peel(&key_b).unwrap() {
+            OnionLayer::Forward { next_hop, packet } => {
+                assert_eq!(next_hop, "127.0.0.1:7003");
+                packet
+            }
+            OnionLayer::Deliver { .. } => panic!("middle hop must forward"),
+        };
+        assert_eq!(packet.wire_len(), wire_len);
+
+        match packet.peel(&key_c).unwrap() {
+            OnionLayer::Deliver { envelope: delivered } => {
+                assert_eq!(delivered.inbox_id, envelope.inbox_id);
+                assert_eq!(delivered.payload, envelope.payload);
+            }
+            OnionLayer::Forward { .. } => panic!("last hop must deliver"),
+        }
+    }
+
+    #[test]
+    fn test_onion_layers_are_hop_specific() {
+        let (_, hop_a) = relay("127.0.0.1:7001");
+        let (key_b, hop_b) = relay("127.0.0.1:7002");
+
+        let packet = OnionPacket::build(&[hop_a, hop_b], test_envelope()).unwrap();
+
+        // Only the first hop can open the outer layer
+        assert!(packet.peel(&key_b).is_err());
+    }
+
+    #[test]
+    fn test_onion_filler_is_ignored() {
+        let (key_a, hop_a) = relay("127.0.0.1:7001");
+        let (_, hop_b) = relay("127.0.0.1:7002");
+
+        let packet = OnionPacket::build(&[hop_a, hop_b], test_envelope()).unwrap();
+
+        // Rewriting the tail changes neither the layer nor the replay id
+        let mut altered = packet.clone();
+        let tail = altered.layer.len() - 8;
+        altered.layer.replace_range(tail..tail + 4, "AAAA");
+        assert_ne!(altered.layer, packet.layer);
+        assert_eq!(altered.replay_id(), packet.replay_id());
+        assert!(matches!(altered.peel(&key_a).unwrap(), OnionLayer::Forward { .. }));
+
+        // Truncated packets are refused outright
+        let mut truncated = packet;
+        truncated.layer.truncate(truncated.layer.len() - 4);
+        assert!(truncated.peel(&key_a).is_err());
+    }
+
+    #[test]
+    fn test_onion_rejects_oversized_envelopes() {
+        let hops: Vec<OnionHop> = (0..2).map(|i| relay(&format!("127.0.0.1:700{}", i)).1).collect();
+        let envelope = QuantumSafeEnvelope::new(CryptoMode::Hybrid, "inbox".to_string(), vec![0; ONION_PACKET_BYTES]);
+
+        assert!(OnionPacket::build(&hops, envelope).is_err());
+    }
+
+    #[test]
+    fn test_onion_route_length_limits() {
+        let hops: Vec<OnionHop> = (0..4).map(|i| relay(&format!("127.0.0.1:700{}", i)).1).collect();
+
+        assert!(OnionPacket::build(&hops[..1], test_envelope()).is_err());
+        assert!(OnionPacket::build(&hops[..2], test_envelope()).is_ok());
+        assert!(OnionPacket::build(&hops[..3], test_envelope()).is_ok());
+        assert!(OnionPacket::build(&hops, test_envelope()).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Outgoing envelopes waiting for the relay
+//!
+//! Envelopes are sealed and signed when a message is sent, then queued here
+//! until a relay accepts them. Unreachable relays are retried with
+//! exponential backoff; envelopes the relay rejects, or that are too old for
+//! receivers to accept, are marked failed.
+
+use crate::protocol::MessageEnvelope;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// First retry delay, doubled on each failed attempt
+const BASE_BACKOFF_SECS: i64 = 30;
+/// Longest wait between attempts
+const MAX_BACKOFF_SECS: i64 = 3600;
+/// Receivers reject payloads older than their freshness window, so stop retrying by then
+pub const MAX_OUTBOX_AGE_SECS: i64 = 7 * 86400;
+
+/// Where a queued envelope, or the message it carries, stands
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending, // Waiting for a relay to accept it
+    Sent,    // Accepted by the relay
+    Failed,  // Rejected or given up on
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryStatus::Pending => write!(f, "pending"),
+            DeliveryStatus::Sent => write!(f, "sent"),
+            DeliveryStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Delay before the next attempt after `attempts` failures
+pub fn backoff(attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(16);
+    Duration::seconds((BASE_BACKOFF_SECS << exponent).min(MAX_BACKOFF_SECS))
+}
+
+/// One signed envelope queued for delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub message_id: String,   // Stored message the envelope carries
+    pub envelope: MessageEnvelope,
+    #[serde(default)]
+    pub relays: Vec<String>,  // Recipient's relays to deliver through; empty for our own
+    pub via: Vec<String>,     // Onion route ending at the delivering relay
+    pub device_sync: bool,    // Copy for one of our own devices
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    pub fn new(message_id: String, envelope: MessageEnvelope, via: Vec<String>, device_sync: bool) -> Self {
+        let now = Utc::now();
+        Self {
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            message_id,
+            envelope,
+            relays: Vec::new(),
+            via,
+            device_sync,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            created_at: now,
+            next_attempt: now,
+            last_error: None,
+        }
+    }
+
+    /// Deliver through the recipient's relays instead of our own
+    pub fn with_relays(mut self, relays: Vec<String>) -> Self {
+        self.relays = relays;
+        self
+    }
+
+    /// Whether the entry should be attempted at `now`
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.status == DeliveryStatus::Pending && self.next_attempt <= now
+    }
+
+    pub fn record_sent(&mut self) {
+        self.attempts += 1;
+        self.status = DeliveryStatus::Sent;
+        self.last_error = None;
+    }
+
+    /// Schedule a retry, or give up if the error is permanent or the envelope is too old
+    pub fn record_failure(&mut self, error: String, retryable: bool, now: DateTime<Utc>) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+
+        let too_old = now - self.created_at >= Duration::seconds(MAX_OUTBOX_AGE_SECS);
+        if !retryable || too_old || self.envelope.is_expired() {
+            self.status = DeliveryStatus::Failed;
+        } else {
+            self.next_attempt = now + backoff(self.attempts);
+        }
+    }
+
+    /// Queue a failed entry again, keeping its age
+    pub fn retry(&mut self) {
+        self.status = DeliveryStatus::Pending;
+        self.attempts = 0;
+        self.next_attempt = Utc::now();
+    }
+}
+
+/// Queued envelopes by entry ID
+#[derive(Debug, Default)]
+pub struct Outbox {
+    entries: HashMap<String, OutboxEntry>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, entry: OutboxEntry) {
+        self.entries.insert(entry.id.clone(), entry);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&OutboxEntry> {
+        self.entries.get(id)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<OutboxEntry> {
+        self.entries.remove(id)
+    }
+
+    /// Every entry, oldest first
+    pub fn entries(&self) -> Vec<&OutboxEntry> {
+        let mut entries: Vec<&OutboxEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.created_at);
+        entries
+    }
+
+    /// IDs of entries due for an attempt, oldest first
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<String> {
+        self.entries().into_iter().filter(|entry| entry.is_due(now)).map(|entry| entry.id.clone()).collect()
+    }
+
+    /// Combined status of the copies sent to a message's recipients; `None` if nothing was queued
+    pub fn delivery_status(&self, message_id: &str) -> Option<DeliveryStatus> {
+        let statuses: Vec<DeliveryStatus> = self
+            .entries
+            .values()
+            .filter(|entry| entry.message_id == message_id && !entry.device_sync)
+            .map(|entry| entry.status)
+            .collect();
+
+        if statuses.is_empty() {
+            None
+        } else if statuses.contains(&DeliveryStatus::Failed) {
+            Some(DeliveryStatus::Failed)
+        } else if statuses.contains(&DeliveryStatus::Pending) {
+            Some(DeliveryStatus::Pending)
+        } else {
+            Some(DeliveryStatus::Sent)
+        }
+    }
+
+    /// IDs of sent entries created before `before`, which no longer need tracking
+    pub fn stale_sent(&self, before: DateTime<Utc>) -> Vec<String> {
+        self.entries
+            .values()
+            .filter(|entry| entry.status == DeliveryStatus::Sent && entry.created_at < before)
+            .map(|entry| entry.id.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message_id: &str, device_sync: bool) -> OutboxEntry {
+        OutboxEntry::new(message_id.to_string(), MessageEnvelope::new("inbox".to_string(), vec![1, 2, 3]), Vec::new(), device_sync)
+    }
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        assert_eq!(backoff(1).num_seconds(), 30);
+        assert_eq!(backoff(2).num_seconds(), 60);
+        assert_eq!(backoff(4).num_seconds(), 240);
+        assert_eq!(backoff(20).num_seconds(), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn test_failures_retry_then_give_up() {
+        let mut pending = entry("m1", false);
+        let now = pending.created_at;
+        assert!(pending.is_due(now));
+
+        pending.record_failure("connection refused".to_string(), true, now);
+        assert_eq!(pending.status, DeliveryStatus::Pending);
+        assert!(!pending.is_due(now));
+        assert!(pending.is_due(now + Duration::seconds(30)));
+
+        // Too old for receivers to accept
+        pending.record_failure("connection refused".to_string(), true, now + Duration::seconds(MAX_OUTBOX_AGE_SECS));
+        assert_eq!(pending.status, DeliveryStatus::Failed);
+
+        // Relay rejections are permanent
+        let mut rejected = entry("m2", false);
+        rejected.record_failure("Relay error: bad envelope".to_string(), false, now);
+        assert_eq!(rejected.status, DeliveryStatus::Failed);
+        rejected.retry();
+        assert!(rejected.is_due(Utc::now()));
+    }
+
+    #[test]
+    fn test_message_delivery_status() {
+        let mut outbox = Outbox::new();
+        let mut primary = entry("m1", false);
+        let linked = entry("m1", false);
+        let mut sync = entry("m1", true);
+        primary.record_sent();
+        sync.record_failure("unreachable".to_string(), false, Utc::now());
+        let linked_id = linked.id.clone();
+        for e in [primary, linked, sync] {
+            outbox.insert(e);
+        }
+
+        // Our own device copies do not count toward the message's status
+        assert_eq!(outbox.delivery_status("m1"), Some(DeliveryStatus::Pending));
+        assert_eq!(outbox.due(Utc::now()), vec![linked_id.clone()]);
+        assert_eq!(outbox.delivery_status("m2"), None);
+
+        let mut linked = outbox.get(&linked_id).unwrap().clone();
+        linked.record_sent();
+        outbox.insert(linked);
+        assert_eq!(outbox.delivery_status("m1"), Some(DeliveryStatus::Sent));
+        assert_eq!(outbox.stale_sent(Utc::now() + Duration::seconds(1)).len(), 2);
+    }
+}
@@ -0,0 +1,90 @@
+//! Named identity profiles sharing one client config directory
+//!
+//! The config directory itself is the `default` profile, so existing setups
+//! keep working; every other profile lives in `profiles/<name>` below it with
+//! its own keys, stores, relays and security preferences.
+
+use crate::error::{NanoError, Result};
+use std::path::{Path, PathBuf};
+
+/// Profile stored directly in the config directory
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Subdirectory holding the named profiles
+const PROFILES_DIR: &str = "profiles";
+
+/// Longest profile name accepted
+const MAX_NAME_CHARS: usize = 32;
+
+/// Check a profile name is safe to use as a directory name
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_CHARS
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.starts_with('-');
+    if !valid {
+        return Err(NanoError::Protocol(format!(
+            "Invalid profile name '{}': use up to {} letters, digits, '-' or '_'",
+            name, MAX_NAME_CHARS
+        )));
+    }
+    Ok(())
+}
+
+/// Directory of a profile inside the config directory
+pub fn profile_dir(config_dir: &Path, name: &str) -> Result<PathBuf> {
+    validate_name(name)?;
+    if name == DEFAULT_PROFILE {
+        return Ok(config_dir.to_path_buf());
+    }
+    Ok(config_dir.join(PROFILES_DIR).join(name))
+}
+
+/// Names of all profiles, the default first and the rest sorted
+pub fn list_profiles(config_dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let profiles_dir = config_dir.join(PROFILES_DIR);
+    if profiles_dir.is_dir() {
+        for entry in std::fs::read_dir(&profiles_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && name != DEFAULT_PROFILE && validate_name(&name).is_ok() {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT_PROFILE.to_string());
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_names() {
+        assert!(validate_name("work").is_ok());
+        assert!(validate_name("side_project-2").is_ok());
+        for invalid in ["", "../etc", "a/b", "-flag", "naïve", &"x".repeat(33)] {
+            assert!(validate_name(invalid).is_err(), "{}", invalid);
+        }
+
+        let base = Path::new("/home/alice/.nano-messenger");
+        assert_eq!(profile_dir(base, DEFAULT_PROFILE).unwrap(), base);
+        assert_eq!(profile_dir(base, "work").unwrap(), base.join("profiles/work"));
+        assert!(profile_dir(base, "..").is_err());
+    }
+
+    #[test]
+    fn test_list_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(list_profiles(dir.path()).unwrap(), vec![DEFAULT_PROFILE]);
+
+        for name in ["work", "home", "bad name"] {
+            std::fs::create_dir_all(dir.path().join(PROFILES_DIR).join(name)).unwrap();
+        }
+        std::fs::write(dir.path().join(PROFILES_DIR).join("notes.txt"), "").unwrap();
+        assert_eq!(list_profiles(dir.path()).unwrap(), vec![DEFAULT_PROFILE, "home", "work"]);
+    }
+}
@@ -1,11 +1,23 @@
 use crate::crypto::{UserPublicKeys, CryptoMode, UnifiedPublicKeys, HybridUserPublicKeys};
+use crate::content::MessageContent;
+use crate::disappearing::DisappearingTimer;
+use crate::rich::RichContent;
 use crate::error::{NanoError, Result};
+use crate::devices::{DeviceCertificate, DeviceList};
+use crate::mls::{MlsCiphersuite, MlsMessage};
+use crate::onion::OnionPacket;
+use crate::blobs::BlobGrant;
 use chrono::{DateTime, Utc};
 use ed25519_dalek::Signature;
 use serde::{Deserialize, Serialize};
 use base64::{Engine as _, engine::general_purpose};
 use rand;
 
+/// Start of a relay's error reply for an envelope it already holds
+///
+/// Senders retrying after a lost reply take it to mean the first attempt arrived.
+pub const REPLAY_REJECTION: &str = "Envelope already stored:";
+
 /// The outer message envelope sent over TCP
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageEnvelope {
@@ -191,6 +203,16 @@ pub struct MessagePayload {
     pub sig: String,          // Base64 encoded signature of the above fields
     #[serde(skip_serializing_if = "Option::is_none")]
     pub crypto_mode: Option<CryptoMode>, // Crypto mode used (for Session 3+)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceCertificate>, // Set when a linked device signed for the identity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<MessageContent>, // Edit, deletion or reaction; `body` is then a fallback text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disappearing: Option<DisappearingTimer>, // New timer for the conversation; `body` announces it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rich: Option<RichContent>, // Replies, mentions, formatting and attachments; `body` renders them as text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_key: Option<String>, // Base64 X25519 key of the sender's primary device, offering a conversation
 }
 
 impl MessagePayload {
@@ -208,6 +230,11 @@ impl MessagePayload {
             counter,
             sig: String::new(), // Will be filled in after signing
             crypto_mode: None,  // Will be set based on signing method
+            device: None,
+            content: None,
+            disappearing: None,
+            rich: None,
+            conversation_key: None,
         }
     }
 
@@ -227,6 +254,11 @@ impl MessagePayload {
             counter,
             sig: String::new(),
             crypto_mode: Some(crypto_mode),
+            device: None,
+            content: None,
+            disappearing: None,
+            rich: None,
+            conversation_key: None,
         }
     }
 
@@ -240,6 +272,15 @@ impl MessagePayload {
             room: Option<String>,
             counter: u64,
             crypto_mode: Option<CryptoMode>,
+            // Omitted when unset so plain messages keep their signatures
+            #[serde(skip_serializing_if = "Option::is_none")]
+            content: Option<MessageContent>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            disappearing: Option<DisappearingTimer>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            rich: Option<RichContent>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            conversation_key: Option<String>,
         }
 
         let signable = SignablePayload {
@@ -249,6 +290,10 @@ impl MessagePayload {
             room: self.room.clone(),
             counter: self.counter,
             crypto_mode: self.crypto_mode,
+            content: self.content.clone(),
+            disappearing: self.disappearing,
+            rich: self.rich.clone(),
+            conversation_key: self.conversation_key.clone(),
         };
 
         serde_json::to_vec(&signable).map_err(Into::into)
@@ -311,7 +356,17 @@ impl MessagePayload {
         }
 
         let signature = Signature::from_bytes(&sig_bytes.try_into().unwrap());
-        let verifying_key = UserPublicKeys::from_public_key_string(&self.from_pubkey)?;
+        let verifying_key = match &self.device {
+            // Linked devices sign with their own key, certified by the identity
+            Some(device) => {
+                device.verify()?;
+                if device.identity != self.from_pubkey {
+                    return Err(NanoError::Crypto("Device certificate is for another identity".to_string()));
+                }
+                device.public_keys.verifying_key
+            }
+            None => UserPublicKeys::from_public_key_string(&self.from_pubkey)?,
+        };
         
         crate::crypto::verify_signature(&verifying_key, &data, &signature)
     }
@@ -403,6 +458,8 @@ pub struct UsernameClaim {
     pub username: String,
     pub public_keys: UserPublicKeys,
     pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relays: Vec<String>, // Relays the user accepts mail on, in order of preference
     pub sig: String, // Base64 encoded signature
 }
 
@@ -413,10 +470,17 @@ impl UsernameClaim {
             username,
             public_keys,
             timestamp: Utc::now().timestamp(),
+            relays: Vec::new(),
             sig: String::new(),
         }
     }
 
+    /// Advertise the relays the user accepts mail on; sign afterwards
+    pub fn with_relays(mut self, relays: Vec<String>) -> Self {
+        self.relays = relays;
+        self
+    }
+
     /// Get the data that should be signed
     pub fn signable_data(&self) -> Result<Vec<u8>> {
         #[derive(Serialize)]
@@ -425,6 +489,9 @@ impl UsernameClaim {
             username: String,
             public_keys: UserPublicKeys,
             timestamp: i64,
+            // Omitted when empty so claims without relays keep their signatures
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            relays: Vec<String>,
         }
 
         let signable = SignableClaim {
@@ -432,6 +499,7 @@ impl UsernameClaim {
             username: self.username.clone(),
             public_keys: self.public_keys.clone(),
             timestamp: self.timestamp,
+            relays: self.relays.clone(),
         };
 
         serde_json::to_vec(&signable).map_err(Into::into)
@@ -508,6 +576,17 @@ pub enum ProtocolMessage {
         public_keys: Option<UserPublicKeys>,
     },
     
+    /// Client looks up the full signed claim for a username or pubkey
+    #[serde(rename = "lookup_claim")]
+    LookupClaim { username: String },
+    
+    /// Relay responds with the claim, if one was published
+    #[serde(rename = "claim_result")]
+    ClaimResult {
+        username: String,
+        claim: Option<UsernameClaim>,
+    },
+    
     /// Relay responds with quantum-safe username lookup result (Session 3+)
     #[serde(rename = "quantum_username_result")]
     QuantumUsernameResult {
@@ -515,6 +594,86 @@ pub enum ProtocolMessage {
         public_keys: Option<UnifiedPublicKeys>,
     },
     
+    /// Client or relay hands an onion packet to the next relay on its route
+    #[serde(rename = "relay_onion")]
+    RelayOnion { packet: OnionPacket },
+    
+    /// Client asks a relay for its onion routing key
+    #[serde(rename = "get_relay_info")]
+    GetRelayInfo,
+    
+    /// Relay responds with its hex-encoded X25519 onion key
+    #[serde(rename = "relay_info")]
+    RelayInfo { onion_key: String },
+    
+    /// Client sends an MLS proposal, commit, welcome or application message to an inbox
+    #[serde(rename = "send_mls_message")]
+    SendMlsMessage {
+        inbox_id: String,
+        ciphersuite: MlsCiphersuite,
+        message: MlsMessage,
+    },
+    
+    /// Client publishes its identity's signed device list
+    #[serde(rename = "publish_device_list")]
+    PublishDeviceList { list: DeviceList },
+    
+    /// Client looks up the devices linked to an identity
+    #[serde(rename = "lookup_devices")]
+    LookupDevices { identity: String },
+    
+    /// Relay responds with the latest device list, if one was published
+    #[serde(rename = "device_list_result")]
+    DeviceListResult {
+        identity: String,
+        list: Option<DeviceList>,
+    },
+    
+    /// Client reserves a blob of `size` bytes
+    #[serde(rename = "upload_blob")]
+    UploadBlob { size: u64 },
+    
+    /// Relay responds with the new blob's capability tokens
+    #[serde(rename = "blob_created")]
+    BlobCreated { grant: BlobGrant },
+    
+    /// Client appends ciphertext to a blob at a byte offset
+    #[serde(rename = "upload_chunk")]
+    UploadChunk {
+        blob_id: String,
+        token: String, // Upload token
+        offset: u64,
+        data: String, // Base64 ciphertext
+    },
+    
+    /// Relay responds with how many bytes of the blob it holds
+    #[serde(rename = "blob_progress")]
+    BlobProgress { blob_id: String, received: u64 },
+    
+    /// Client reads a range of a finished blob
+    #[serde(rename = "download_blob")]
+    DownloadBlob {
+        blob_id: String,
+        token: String, // Download token
+        offset: u64,
+        length: u64,
+    },
+    
+    /// Relay responds with the requested range
+    #[serde(rename = "blob_data")]
+    BlobData {
+        blob_id: String,
+        offset: u64,
+        data: String, // Base64 ciphertext
+    },
+    
+    /// Client drops a blob it uploaded
+    #[serde(rename = "delete_blob")]
+    DeleteBlob {
+        blob_id: String,
+        token: String, // Upload token
+    },
+    
     /// Generic success response
     #[serde(rename = "success")]
     Success { message: String },
@@ -580,6 +739,44 @@ mod tests {
         assert!(payload.verify_signature().is_err());
     }
 
+    #[test]
+    fn test_payload_content_is_signed() {
+        use crate::content::{MessageRef, Reaction};
+
+        let keypair = UserKeyPair::generate();
+        let target = MessageRef::new(keypair.public_key_string(), 1_700_000_000);
+        let mut payload = MessagePayload::new(keypair.public_key_string(), "Reacted 👍".to_string(), 1, None);
+        payload.content = Some(MessageContent::React { target, reaction: Some(Reaction::Like) });
+        payload.sign(&keypair.signing_key).unwrap();
+
+        let decoded = MessagePayload::from_json(&payload.to_json().unwrap()).unwrap();
+        decoded.verify_signature().unwrap();
+
+        // Swapping the reaction breaks the signature
+        let mut tampered = decoded.clone();
+        if let Some(MessageContent::React { reaction, .. }) = &mut tampered.content {
+            *reaction = Some(Reaction::Angry);
+        }
+        assert!(tampered.verify_signature().is_err());
+    }
+
+    #[test]
+    fn test_linked_device_payload_signing() {
+        let identity = UserKeyPair::generate();
+        let laptop = UserKeyPair::generate();
+        let certificate =
+            DeviceCertificate::issue(&identity, "laptop".to_string(), laptop.public_keys()).unwrap();
+
+        let mut payload = MessagePayload::new(identity.public_key_string(), "Hi".to_string(), 1, None);
+        payload.device = Some(certificate);
+        payload.sign(&laptop.signing_key).unwrap();
+        payload.verify_signature().unwrap();
+
+        // The certificate must belong to the claimed identity
+        payload.from_pubkey = UserKeyPair::generate().public_key_string();
+        assert!(payload.verify_signature().is_err());
+    }
+
     #[test]
     fn test_username_claim() {
         let keypair = UserKeyPair::generate();
@@ -596,4 +793,21 @@ mod tests {
         let decoded = UsernameClaim::from_json(&json).unwrap();
         assert_eq!(decoded.username, claim.username);
     }
+
+    #[test]
+    fn test_username_claim_relays_are_signed() {
+        let keypair = UserKeyPair::generate();
+        let mut claim = UsernameClaim::new("alice2024".to_string(), keypair.public_keys())
+            .with_relays(vec!["relay-a:7733".to_string(), "relay-b:7733".to_string()]);
+        claim.sign(&keypair.signing_key).unwrap();
+
+        let decoded = UsernameClaim::from_json(&claim.to_json().unwrap()).unwrap();
+        decoded.verify_signature().unwrap();
+        assert_eq!(decoded.relays.len(), 2);
+
+        // A relay cannot redirect mail by rewriting the list
+        let mut tampered = decoded;
+        tampered.relays = vec!["evil:7733".to_string()];
+        assert!(tampered.verify_signature().is_err());
+    }
 }
@@ -0,0 +1,129 @@
+//! Delivery receipts, read receipts and typing indicators
+//!
+//! These travel like any other direct message: a signed, end-to-end encrypted
+//! `MessagePayload` whose `room` is `RECEIPT_ROOM` and whose body is a
+//! `ConversationControl`. Receipts name messages by the payload timestamp the
+//! sender signed, which both sides store as the message timestamp.
+
+use crate::error::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Room marking payloads that carry a `ConversationControl`
+pub const RECEIPT_ROOM: &str = "receipts";
+/// How long a typing indicator lasts unless the sender refreshes it
+pub const TYPING_TIMEOUT_SECS: i64 = 8;
+
+/// How far an outgoing message has got, as acknowledged by its recipient
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReceiptStatus {
+    Delivered, // Stored on one of the recipient's devices
+    Read,      // Shown to the recipient
+}
+
+/// Receipt or typing notice about a direct conversation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConversationControl {
+    /// Messages with these payload timestamps arrived
+    Delivered { timestamps: Vec<i64> },
+
+    /// Messages with these payload timestamps were read
+    Read { timestamps: Vec<i64> },
+
+    /// The sender started or stopped typing
+    Typing { active: bool },
+}
+
+impl ConversationControl {
+    /// Receipt status this control acknowledges, if it is a receipt
+    pub fn receipt(&self) -> Option<(ReceiptStatus, &[i64])> {
+        match self {
+            ConversationControl::Delivered { timestamps } => Some((ReceiptStatus::Delivered, timestamps)),
+            ConversationControl::Read { timestamps } => Some((ReceiptStatus::Read, timestamps)),
+            ConversationControl::Typing { .. } => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(Into::into)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(Into::into)
+    }
+}
+
+/// Latest typing notice from each contact
+///
+/// Notices are kept by the time they were sent, so envelopes refetched from
+/// the relay never revive an indicator that has since ended.
+#[derive(Debug, Default)]
+pub struct TypingIndicators {
+    latest: HashMap<String, (DateTime<Utc>, bool)>, // pubkey -> (sent at, active)
+}
+
+impl TypingIndicators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a typing notice `pubkey` sent at `sent_at`; false if it is already known or superseded
+    pub fn update(&mut self, pubkey: &str, active: bool, sent_at: DateTime<Utc>) -> bool {
+        match self.latest.get(pubkey) {
+            Some(&(latest, _)) if latest > sent_at => return false,
+            Some(&known) if known == (sent_at, active) => return false,
+            _ => {}
+        }
+        self.latest.insert(pubkey.to_string(), (sent_at, active));
+        true
+    }
+
+    /// A message `pubkey` sent at `sent_at` ends their typing indicator
+    pub fn clear(&mut self, pubkey: &str, sent_at: DateTime<Utc>) {
+        self.update(pubkey, false, sent_at);
+    }
+
+    pub fn is_typing(&self, pubkey: &str, now: DateTime<Utc>) -> bool {
+        self.latest
+            .get(pubkey)
+            .is_some_and(|(sent_at, active)| *active && *sent_at + Duration::seconds(TYPING_TIMEOUT_SECS) > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_round_trip() {
+        let read = ConversationControl::Read { timestamps: vec![1_700_000_000, 1_700_000_005] };
+        let json = read.to_json().unwrap();
+        assert!(json.contains("\"type\":\"read\""));
+        assert_eq!(ConversationControl::from_json(&json).unwrap(), read);
+        assert_eq!(read.receipt(), Some((ReceiptStatus::Read, &[1_700_000_000, 1_700_000_005][..])));
+
+        let typing = ConversationControl::from_json(r#"{"type":"typing","active":true}"#).unwrap();
+        assert_eq!(typing, ConversationControl::Typing { active: true });
+        assert!(typing.receipt().is_none());
+        assert!(ReceiptStatus::Read > ReceiptStatus::Delivered);
+    }
+
+    #[test]
+    fn test_typing_indicators_expire() {
+        let mut typing = TypingIndicators::new();
+        let now = Utc::now();
+        assert!(typing.update("pubkey:alice", true, now));
+        assert!(!typing.update("pubkey:alice", true, now));
+        assert!(typing.is_typing("pubkey:alice", now));
+        assert!(!typing.is_typing("pubkey:alice", now + Duration::seconds(TYPING_TIMEOUT_SECS)));
+        assert!(!typing.is_typing("pubkey:bob", now));
+
+        // A message ends the indicator, and the refetched older notice does not revive it
+        typing.clear("pubkey:alice", now + Duration::seconds(2));
+        assert!(!typing.is_typing("pubkey:alice", now));
+        assert!(!typing.update("pubkey:alice", true, now));
+        assert!(!typing.is_typing("pubkey:alice", now));
+    }
+}
@@ -0,0 +1,581 @@
+use crate::crypto::hash_sha256;
+use crate::error::{NanoError, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Configuration for relay-side nonce replay protection
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayConfig {
+    /// Nonces expected per inbox within one rotation window
+    pub expected_nonces: usize,
+    /// Most nonces held per inbox within one window; past it the oldest generation is dropped early
+    pub max_nonces: usize,
+    /// Target false positive rate of each Bloom filter generation
+    pub false_positive_rate: f64,
+    /// Minimum time a nonce is remembered (remembered for up to twice this long)
+    pub window: Duration,
+    /// Maximum number of inboxes tracked at once
+    pub max_inboxes: usize,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            expected_nonces: 1000,
+            max_nonces: 16_000,
+            false_positive_rate: 0.0001,
+            window: Duration::from_secs(86400),
+            max_inboxes: 100_000,
+        }
+    }
+}
+
+/// Fixed-size Bloom filter keyed by SHA256 double hashing
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: usize,
+    num_hashes: u32,
+    items: usize,
+}
+
+impl BloomFilter {
+    /// Create a filter sized for `expected_items` at the given false positive rate
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let p = false_positive_rate.clamp(1e-12, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let num_bits = ((-n * p.ln()) / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().clamp(1.0, 32.0) as u32;
+
+        Self {
+            bits: vec![0; num_bits.div_ceil(64)],
+            num_bits,
+            num_hashes,
+            items: 0,
+        }
+    }
+
+    fn indexes(&self, item: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        let hash = hash_sha256(item);
+        let h1 = u64::from_be_bytes(hash[0..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(hash[8..16].try_into().unwrap()) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits as u64) as usize)
+    }
+
+    /// Insert an item into the filter
+    pub fn insert(&mut self, item: &[u8]) {
+        let indexes: Vec<usize> = self.indexes(item).collect();
+        for index in indexes {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+        self.items += 1;
+    }
+
+    /// Check whether an item may have been inserted (no false negatives)
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.indexes(item)
+            .all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+    }
+
+    /// Number of items inserted so far
+    pub fn len(&self) -> usize {
+        self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    /// Approximate memory used by the bit array
+    pub fn size_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+/// Replay cache key for an envelope: a hash of its ciphertext
+///
+/// Envelope nonces are not covered by any signature, so a captured ciphertext
+/// could be posted again under a fresh nonce. The ciphertext itself cannot
+/// change without breaking decryption.
+pub fn payload_digest(payload: &[u8]) -> String {
+    general_purpose::STANDARD.encode(&hash_sha256(payload)[..16])
+}
+
+/// Outcome of recording a nonce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceCheck {
+    Fresh,
+    Replayed,
+}
+
+/// Bloom filters holding one generation of nonces
+///
+/// A full filter is followed by one twice its size at half its false positive
+/// rate, so the generation grows instead of forgetting and the combined rate
+/// stays under the target.
+#[derive(Debug, Clone)]
+struct Generation {
+    filters: Vec<BloomFilter>,
+}
+
+impl Generation {
+    fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        Self {
+            filters: vec![Self::stage(0, expected_items, false_positive_rate)],
+        }
+    }
+
+    /// Filter for the `stage`th time the generation grows
+    fn stage(stage: usize, expected_items: usize, false_positive_rate: f64) -> BloomFilter {
+        BloomFilter::new(expected_items.max(1) << stage, false_positive_rate * 0.5f64.powi(stage as i32 + 1))
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.filters.iter().any(|filter| filter.contains(item))
+    }
+
+    fn insert(&mut self, item: &[u8], expected_items: usize, false_positive_rate: f64) {
+        let stage = self.filters.len() - 1;
+        if self.filters[stage].len() >= expected_items.max(1) << stage {
+            self.filters.push(Self::stage(stage + 1, expected_items, false_positive_rate));
+        }
+        self.filters.last_mut().expect("a generation has a filter").insert(item);
+    }
+
+    fn len(&self) -> usize {
+        self.filters.iter().map(BloomFilter::len).sum()
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.filters.iter().map(BloomFilter::size_bytes).sum()
+    }
+}
+
+/// Two-generation Bloom filter that forgets entries after one to two windows
+///
+/// New nonces go into the current generation. When the window elapses, the
+/// current generation becomes the previous one and the old previous generation
+/// is dropped. A generation that passes `expected_items` grows rather than
+/// rotating, so ordinary bursts forget nothing early. Only once it holds
+/// `max_items` does it rotate ahead of the clock, shedding the oldest nonces
+/// instead of refusing new envelopes.
+#[derive(Debug, Clone)]
+pub struct RotatingBloomFilter {
+    current: Generation,
+    previous: Generation,
+    rotated_at: Instant,
+    last_seen: Instant,
+    expected_items: usize,
+    max_items: usize,
+    false_positive_rate: f64,
+    window: Duration,
+}
+
+impl RotatingBloomFilter {
+    pub fn new(expected_items: usize, max_items: usize, false_positive_rate: f64, window: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            current: Generation::new(expected_items, false_positive_rate),
+            previous: Generation::new(expected_items, false_positive_rate),
+            rotated_at: now,
+            last_seen: now,
+            expected_items,
+            max_items: max_items.max(expected_items),
+            false_positive_rate,
+            window,
+        }
+    }
+
+    fn rotate_if_needed(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.rotated_at);
+
+        if elapsed >= self.window * 2 {
+            // Both generations are stale
+            self.previous = Generation::new(self.expected_items, self.false_positive_rate);
+            self.current = Generation::new(self.expected_items, self.false_positive_rate);
+            self.rotated_at = now;
+        } else if elapsed >= self.window {
+            let fresh = Generation::new(self.expected_items, self.false_positive_rate);
+            self.previous = std::mem::replace(&mut self.current, fresh);
+            self.rotated_at = now;
+        }
+    }
+
+    /// Check whether the item was seen within the window
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.current.contains(item) || self.previous.contains(item)
+    }
+
+    /// Record the item unless it was already present
+    pub fn check_and_insert(&mut self, item: &[u8]) -> NonceCheck {
+        self.check_and_insert_at(item, Instant::now())
+    }
+
+    fn check_and_insert_at(&mut self, item: &[u8], now: Instant) -> NonceCheck {
+        self.rotate_if_needed(now);
+        self.last_seen = now;
+
+        if self.contains(item) {
+            return NonceCheck::Replayed;
+        }
+        if self.current.len() >= self.max_items {
+            let fresh = Generation::new(self.expected_items, self.false_positive_rate);
+            self.previous = std::mem::replace(&mut self.current, fresh);
+            self.rotated_at = now;
+        }
+
+        self.current.insert(item, self.expected_items, self.false_positive_rate);
+        NonceCheck::Fresh
+    }
+
+    /// Whether nothing has been inserted for longer than both generations live
+    pub fn is_stale(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) >= self.window * 2
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.current.size_bytes() + self.previous.size_bytes()
+    }
+}
+
+/// Per-inbox seen-nonce cache used by the relay to reject replayed envelopes
+pub struct NonceCache {
+    config: ReplayConfig,
+    inboxes: HashMap<String, RotatingBloomFilter>,
+    replays_rejected: u64,
+}
+
+impl NonceCache {
+    pub fn new(config: ReplayConfig) -> Self {
+        Self {
+            config,
+            inboxes: HashMap::new(),
+            replays_rejected: 0,
+        }
+    }
+
+    /// Record an envelope nonce for an inbox
+    pub fn check_and_insert(&mut self, inbox_id: &str, nonce: &str) -> NonceCheck {
+        if !self.inboxes.contains_key(inbox_id) && self.inboxes.len() >= self.config.max_inboxes {
+            self.prune();
+            if self.inboxes.len() >= self.config.max_inboxes {
+                self.evict_least_recent();
+            }
+        }
+
+        let config = self.config;
+        let filter = self.inboxes.entry(inbox_id.to_string()).or_insert_with(|| {
+            RotatingBloomFilter::new(config.expected_nonces, config.max_nonces, config.false_positive_rate, config.window)
+        });
+
+        let check = filter.check_and_insert(nonce.as_bytes());
+        if check == NonceCheck::Replayed {
+            self.replays_rejected += 1;
+        }
+        check
+    }
+
+    /// Check an envelope's replay key, usually its `payload_digest`, and map a replay to an error
+    pub fn verify(&mut self, inbox_id: &str, nonce: &str) -> Result<()> {
+        if nonce.is_empty() {
+            return Err(NanoError::ReplayDetected("Envelope nonce is missing".to_string()));
+        }
+
+        match self.check_and_insert(inbox_id, nonce) {
+            NonceCheck::Fresh => Ok(()),
+            NonceCheck::Replayed => Err(NanoError::ReplayDetected(format!(
+                "Nonce already seen for inbox {}",
+                &inbox_id[..inbox_id.len().min(8)]
+            ))),
+        }
+    }
+
+    /// Drop filters for inboxes that have been idle for longer than the window
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.inboxes.retain(|_, filter| !filter.is_stale(now));
+    }
+
+    fn evict_least_recent(&mut self) {
+        let oldest = self.inboxes
+            .iter()
+            .min_by_key(|(_, filter)| filter.last_seen)
+            .map(|(inbox_id, _)| inbox_id.clone());
+
+        if let Some(inbox_id) = oldest {
+            self.inboxes.remove(&inbox_id);
+        }
+    }
+
+    /// Number of inboxes currently tracked
+    pub fn tracked_inboxes(&self) -> usize {
+        self.inboxes.len()
+    }
+
+    /// Total replays rejected since startup
+    pub fn replays_rejected(&self) -> u64 {
+        self.replays_rejected
+    }
+
+    /// Approximate memory used by all filters
+    pub fn size_bytes(&self) -> usize {
+        self.inboxes.values().map(|f| f.size_bytes()).sum()
+    }
+}
+
+/// Acceptable age and clock skew for incoming payload timestamps
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FreshnessWindow {
+    pub max_age_secs: i64,
+    pub max_future_skew_secs: i64,
+}
+
+impl Default for FreshnessWindow {
+    fn default() -> Self {
+        Self {
+            max_age_secs: 7 * 86400,
+            max_future_skew_secs: 300,
+        }
+    }
+}
+
+impl FreshnessWindow {
+    /// Check a payload timestamp against the window relative to `now`
+    pub fn check(&self, timestamp: i64, now: i64) -> Result<()> {
+        if timestamp > now + self.max_future_skew_secs {
+            return Err(NanoError::ReplayDetected(format!(
+                "Message timestamp is {}s in the future",
+                timestamp - now
+            )));
+        }
+
+        if now - timestamp > self.max_age_secs {
+            return Err(NanoError::ReplayDetected(format!(
+                "Message is older than {}s",
+                self.max_age_secs
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of counters below the highest seen that are still tracked
+pub const COUNTER_WINDOW_SIZE: u64 = 64;
+
+/// Sliding anti-replay window over `MessagePayload.counter` values
+///
+/// Tracks the highest counter seen and a bitmap of the 64 counters below it,
+/// so out-of-order delivery within the window is accepted exactly once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterWindow {
+    highest: u64,
+    bitmap: u64,
+    initialized: bool,
+}
+
+impl CounterWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check whether a counter would be accepted without recording it
+    pub fn check(&self, counter: u64) -> bool {
+        if !self.initialized || counter > self.highest {
+            return true;
+        }
+
+        let offset = self.highest - counter;
+        if offset >= COUNTER_WINDOW_SIZE {
+            return false;
+        }
+
+        self.bitmap & (1 << offset) == 0
+    }
+
+    /// Record a counter, returning `false` if it is a replay or too old
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if !self.check(counter) {
+            return false;
+        }
+
+        if !self.initialized {
+            self.highest = counter;
+            self.bitmap = 1;
+            self.initialized = true;
+        } else if counter > self.highest {
+            let shift = counter - self.highest;
+            self.bitmap = if shift >= COUNTER_WINDOW_SIZE { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.highest = counter;
+        } else {
+            self.bitmap |= 1 << (self.highest - counter);
+        }
+
+        true
+    }
+
+    /// Highest counter accepted so far
+    pub fn highest(&self) -> Option<u64> {
+        self.initialized.then_some(self.highest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(100, 0.001);
+        filter.insert(b"nonce-1");
+
+        assert!(filter.contains(b"nonce-1"));
+        assert!(!filter.contains(b"nonce-2"));
+        assert_eq!(filter.len(), 1);
+    }
+
+    #[test]
+    fn test_rotating_filter_forgets_after_two_windows() {
+        let window = Duration::from_secs(60);
+        let mut filter = RotatingBloomFilter::new(100, 1000, 0.001, window);
+        let start = Instant::now();
+
+        assert_eq!(filter.check_and_insert_at(b"nonce", start), NonceCheck::Fresh);
+        assert_eq!(filter.check_and_insert_at(b"nonce", start), NonceCheck::Replayed);
+
+        // Still remembered in the previous generation after one rotation
+        assert_eq!(filter.check_and_insert_at(b"nonce", start + window), NonceCheck::Replayed);
+
+        // Forgotten once both generations have rotated out
+        assert_eq!(filter.check_and_insert_at(b"nonce", start + window * 3), NonceCheck::Fresh);
+    }
+
+    #[test]
+    fn test_flood_does_not_evict_nonces_within_window() {
+        let window = Duration::from_secs(60);
+        let mut filter = RotatingBloomFilter::new(10, 100, 0.001, window);
+        let start = Instant::now();
+        assert_eq!(filter.check_and_insert_at(b"real", start), NonceCheck::Fresh);
+
+        // Past the expected count the generation grows instead of rotating
+        for i in 0..99u32 {
+            assert_eq!(filter.check_and_insert_at(&i.to_be_bytes(), start), NonceCheck::Fresh);
+        }
+        assert_eq!(filter.check_and_insert_at(b"real", start), NonceCheck::Replayed);
+
+        // At its limit it rotates early, still remembering the full generation
+        assert_eq!(filter.check_and_insert_at(b"flood", start), NonceCheck::Fresh);
+        assert_eq!(filter.check_and_insert_at(b"real", start + window / 2), NonceCheck::Replayed);
+        assert_eq!(filter.check_and_insert_at(b"flood", start + window / 2), NonceCheck::Replayed);
+    }
+
+    #[test]
+    fn test_full_filter_sheds_oldest_nonces() {
+        let mut filter = RotatingBloomFilter::new(100, 1000, 0.0001, Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(filter.check_and_insert_at(b"oldest", start), NonceCheck::Fresh);
+
+        // Two generations' worth of new nonces push it out instead of being refused
+        for i in 0..2000u32 {
+            assert_eq!(filter.check_and_insert_at(&i.to_be_bytes(), start), NonceCheck::Fresh);
+        }
+        assert_eq!(filter.check_and_insert_at(b"oldest", start), NonceCheck::Fresh);
+        assert_eq!(filter.check_and_insert_at(&1999u32.to_be_bytes(), start), NonceCheck::Replayed);
+    }
+
+    #[test]
+    fn test_nonce_cache_rejects_replay_per_inbox() {
+        let mut cache = NonceCache::new(ReplayConfig::default());
+
+        assert!(cache.verify("inbox_a_0000", "abc").is_ok());
+        assert!(cache.verify("inbox_a_0000", "abc").is_err());
+
+        // Same nonce in a different inbox is tracked separately
+        assert!(cache.verify("inbox_b_0000", "abc").is_ok());
+        assert_eq!(cache.replays_rejected(), 1);
+        assert_eq!(cache.tracked_inboxes(), 2);
+    }
+
+    #[test]
+    fn test_nonce_cache_limits_each_inbox() {
+        let config = ReplayConfig {
+            expected_nonces: 1,
+            max_nonces: 2,
+            ..ReplayConfig::default()
+        };
+        let mut cache = NonceCache::new(config);
+
+        // A full inbox keeps accepting envelopes, remembering the most recent ones
+        assert!(cache.verify("inbox_a_0000", "a").is_ok());
+        assert!(cache.verify("inbox_a_0000", "b").is_ok());
+        assert!(cache.verify("inbox_a_0000", "c").is_ok());
+        assert!(cache.verify("inbox_a_0000", "b").is_err());
+        assert!(cache.verify("inbox_b_0000", "c").is_ok());
+        assert_eq!(cache.replays_rejected(), 1);
+    }
+
+    #[test]
+    fn test_payload_digest_ignores_nonce() {
+        use crate::protocol::MessageEnvelope;
+
+        // The same ciphertext posted twice gets two nonces but one replay key
+        let first = MessageEnvelope::new("inbox".to_string(), b"ciphertext".to_vec());
+        let second = MessageEnvelope::new("inbox".to_string(), b"ciphertext".to_vec());
+        assert_ne!(first.nonce, second.nonce);
+
+        let mut cache = NonceCache::new(ReplayConfig::default());
+        assert!(cache.verify("inbox", &payload_digest(&first.decode_payload().unwrap())).is_ok());
+        assert!(cache.verify("inbox", &payload_digest(&second.decode_payload().unwrap())).is_err());
+        assert_ne!(payload_digest(b"ciphertext"), payload_digest(b"other ciphertext"));
+    }
+
+    #[test]
+    fn test_nonce_cache_is_bounded() {
+        let config = ReplayConfig {
+            max_inboxes: 2,
+            ..ReplayConfig::default()
+        };
+        let mut cache = NonceCache::new(config);
+
+        cache.check_and_insert("inbox1", "n");
+        cache.check_and_insert("inbox2", "n");
+        cache.check_and_insert("inbox3", "n");
+
+        assert_eq!(cache.tracked_inboxes(), 2);
+    }
+
+    #[test]
+    fn test_counter_window() {
+        let mut window = CounterWindow::new();
+
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+
+        // Out-of-order delivery inside the window is accepted once
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+
+        assert!(window.accept(100));
+        assert_eq!(window.highest(), Some(100));
+
+        // Too far behind the highest counter
+        assert!(!window.accept(10));
+        assert!(window.accept(99));
+    }
+
+    #[test]
+    fn test_freshness_window() {
+        let window = FreshnessWindow::default();
+        let now = 1_700_000_000;
+
+        assert!(window.check(now, now).is_ok());
+        assert!(window.check(now + 10_000, now).is_err());
+        assert!(window.check(now - 8 * 86400, now).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Message requests: first-contact messages from senders not yet accepted
+//!
+//! Messages from unknown keys wait here, grouped by sender, instead of
+//! joining the conversation list. A request is accepted, moving its messages
+//! into the conversation, or blocked. Reporting blocks too and hands back the
+//! sender's signed payloads, which anyone can check against the sender's key.
+
+use crate::crypto::{decrypt_symmetric, encrypt_symmetric};
+use crate::error::{NanoError, Result};
+use crate::protocol::MessagePayload;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Senders with a request waiting; messages from further senders are dropped
+pub const MAX_REQUEST_SENDERS: usize = 100;
+
+/// Messages kept per waiting sender
+pub const MAX_REQUEST_MESSAGES: usize = 20;
+
+/// Version of the encrypted request store
+const REQUEST_STORE_VERSION: u32 = 1;
+
+/// A message held until its sender is accepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub payload: MessagePayload, // As signed by the sender
+    pub received_at: DateTime<Utc>,
+}
+
+/// Everything one unknown sender sent us
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRequest {
+    pub sender: String,
+    pub first_seen: DateTime<Utc>,
+    pub messages: Vec<PendingMessage>, // Oldest first
+}
+
+impl MessageRequest {
+    pub fn last_seen(&self) -> DateTime<Utc> {
+        self.messages.last().map_or(self.first_seen, |message| message.received_at)
+    }
+}
+
+/// Signed evidence of a reported sender's messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbuseReport {
+    pub sender: String,
+    pub reported_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub messages: Vec<MessagePayload>,
+}
+
+impl AbuseReport {
+    /// Check every message was signed by the reported sender
+    pub fn verify(&self) -> Result<()> {
+        for payload in &self.messages {
+            if payload.from_pubkey != self.sender {
+                return Err(NanoError::Crypto(format!("Reported message from {} instead of {}", payload.from_pubkey, self.sender)));
+            }
+            payload.verify_signature()?;
+        }
+        Ok(())
+    }
+}
+
+/// Waiting requests, keyed by sender
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestQueue {
+    requests: HashMap<String, MessageRequest>,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn get(&self, sender: &str) -> Option<&MessageRequest> {
+        self.requests.get(sender)
+    }
+
+    /// Waiting requests, most recently active first
+    pub fn list(&self) -> Vec<&MessageRequest> {
+        let mut requests: Vec<&MessageRequest> = self.requests.values().collect();
+        requests.sort_by(|a, b| b.last_seen().cmp(&a.last_seen()).then_with(|| a.sender.cmp(&b.sender)));
+        requests
+    }
+
+    /// Queue a verified payload, returning whether it was new and there was room for it
+    pub fn add(&mut self, payload: MessagePayload, received_at: DateTime<Utc>) -> bool {
+        if !self.requests.contains_key(&payload.from_pubkey) && self.requests.len() >= MAX_REQUEST_SENDERS {
+            return false;
+        }
+        let request = self.requests.entry(payload.from_pubkey.clone()).or_insert_with(|| MessageRequest {
+            sender: payload.from_pubkey.clone(),
+            first_seen: received_at,
+            messages: Vec::new(),
+        });
+        // Relays hand out the same envelope until it expires
+        let duplicate = request.messages.iter()
+            .any(|queued| queued.payload.timestamp == payload.timestamp && queued.payload.counter == payload.counter);
+        if duplicate || request.messages.len() >= MAX_REQUEST_MESSAGES {
+            return false;
+        }
+        request.messages.push(PendingMessage { payload, received_at });
+        true
+    }
+
+    /// Remove a sender's request to accept or block it
+    pub fn take(&mut self, sender: &str) -> Result<MessageRequest> {
+        self.requests.remove(sender)
+            .ok_or_else(|| NanoError::Protocol(format!("No message request from {}", sender)))
+    }
+
+    /// Remove a sender's request and turn its messages into a report
+    pub fn report(&mut self, sender: &str, reason: Option<String>, now: DateTime<Utc>) -> Result<AbuseReport> {
+        let request = self.take(sender)?;
+        Ok(AbuseReport {
+            sender: request.sender,
+            reported_at: now,
+            reason,
+            messages: request.messages.into_iter().map(|message| message.payload).collect(),
+        })
+    }
+
+    /// Serialize and encrypt the queue for storage at rest
+    pub fn to_encrypted(&self, storage_key: &[u8; 32]) -> Result<Vec<u8>> {
+        #[derive(Serialize)]
+        struct RequestStoreRef<'a> {
+            version: u32,
+            queue: &'a RequestQueue,
+        }
+
+        let store = RequestStoreRef {
+            version: REQUEST_STORE_VERSION,
+            queue: self,
+        };
+        encrypt_symmetric(storage_key, &serde_json::to_vec(&store)?)
+    }
+
+    /// Decrypt a queue written by `to_encrypted`
+    pub fn from_encrypted(storage_key: &[u8; 32], data: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct RequestStore {
+            version: u32,
+            queue: RequestQueue,
+        }
+
+        let store: RequestStore = serde_json::from_slice(&decrypt_symmetric(storage_key, data)?)?;
+        if store.version != REQUEST_STORE_VERSION {
+            return Err(NanoError::Storage(format!("Unsupported request store version {}", store.version)));
+        }
+        Ok(store.queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::UserKeyPair;
+
+    fn signed(keypair: &UserKeyPair, body: &str, counter: u64) -> MessagePayload {
+        let mut payload = MessagePayload::new(keypair.public_key_string(), body.to_string(), counter, None);
+        payload.sign(&keypair.signing_key).unwrap();
+        payload
+    }
+
+    #[test]
+    fn test_queue_and_take() {
+        let mut queue = RequestQueue::new();
+        let stranger = UserKeyPair::generate();
+        let sender = stranger.public_key_string();
+
+        assert!(queue.add(signed(&stranger, "hi", 0), Utc::now()));
+        assert!(!queue.add(signed(&stranger, "hi", 0), Utc::now()));
+        assert!(queue.add(signed(&stranger, "are you there?", 1), Utc::now()));
+        assert_eq!(queue.get(&sender).unwrap().messages.len(), 2);
+
+        // Each sender's request is capped
+        for counter in 2..MAX_REQUEST_MESSAGES as u64 + 5 {
+            queue.add(signed(&stranger, "spam", counter), Utc::now());
+        }
+        assert_eq!(queue.get(&sender).unwrap().messages.len(), MAX_REQUEST_MESSAGES);
+
+        let key = [7u8; 32];
+        let restored = RequestQueue::from_encrypted(&key, &queue.to_encrypted(&key).unwrap()).unwrap();
+        assert_eq!(restored.list()[0].sender, sender);
+        assert!(RequestQueue::from_encrypted(&[8u8; 32], &queue.to_encrypted(&key).unwrap()).is_err());
+
+        let request = queue.take(&sender).unwrap();
+        assert_eq!(request.messages[0].payload.body, "hi");
+        assert!(queue.is_empty());
+        assert!(queue.take(&sender).is_err());
+    }
+
+    #[test]
+    fn test_report_is_verifiable() {
+        let mut queue = RequestQueue::new();
+        let stranger = UserKeyPair::generate();
+        let sender = stranger.public_key_string();
+        queue.add(signed(&stranger, "buy now", 0), Utc::now());
+
+        let report = queue.report(&sender, Some("spam".to_string()), Utc::now()).unwrap();
+        assert!(queue.get(&sender).is_none());
+        report.verify().unwrap();
+
+        // Altered or misattributed messages do not verify
+        let mut altered = report.clone();
+        altered.messages[0].body = "something else".to_string();
+        assert!(altered.verify().is_err());
+        let mut misattributed = report;
+        misattributed.sender = UserKeyPair::generate().public_key_string();
+        assert!(misattributed.verify().is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! Rich message content: replies, mentions, formatting and attachments
+//!
+//! A payload whose `rich` is set carries its text together with structured
+//! extras. The payload `body` then holds a plain rendering of the whole
+//! message, which is what clients that predate rich content, or that do not
+//! know its `version`, show instead.
+
+use crate::blobs::BlobLink;
+use crate::content::MessageRef;
+use crate::error::{NanoError, Result};
+use crate::media::encryption::FileKey;
+use crate::media::metadata::{FileMetadata, FileReference};
+use crate::media::storage::FileId;
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Schema version this client writes and understands
+pub const RICH_CONTENT_VERSION: u32 = 1;
+
+/// Longest quote of the replied-to message carried with a reply, in characters
+pub const MAX_QUOTE_CHARS: usize = 80;
+
+/// Markup markers, longest first so `**` is not read as two italics
+const MARKERS: [(&str, TextStyle); 4] = [
+    ("**", TextStyle::Bold),
+    ("~~", TextStyle::Strikethrough),
+    ("`", TextStyle::Code),
+    ("_", TextStyle::Italic),
+];
+
+/// Message text with its structured extras, carried in `MessagePayload::rich`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RichContent {
+    pub version: u32,
+    pub text: String, // Text without markup; ranges below index its bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyTo>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Mention>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<FormatSpan>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentPointer>,
+}
+
+/// Message being replied to, quoted so it shows even where it is unknown
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyTo {
+    pub target: MessageRef,
+    pub quote: String,
+}
+
+/// Participant named in the text, e.g. the `@bob` at `start..end`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    pub pubkey: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Style applied to the text at `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatSpan {
+    pub style: TextStyle,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextStyle {
+    Bold,
+    Italic,
+    Code,
+    Strikethrough,
+}
+
+impl TextStyle {
+    /// Markup that surrounds text in this style
+    pub fn marker(&self) -> &'static str {
+        match self {
+            TextStyle::Bold => "**",
+            TextStyle::Italic => "_",
+            TextStyle::Code => "`",
+            TextStyle::Strikethrough => "~~",
+        }
+    }
+}
+
+/// File uploaded by `attachments::upload`, the relay blob holding it, and how to open it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttachmentPointer {
+    pub file_id: FileId,
+    pub reference_id: Uuid, // Sender's `FileReference`; the blob link is what grants the download
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,    // Plaintext size in bytes
+    pub hash: String, // Hex content hash, checked after decryption
+    pub key: String,  // Base64 file key; the message itself is end-to-end encrypted
+    #[serde(default = "default_chunks")]
+    pub chunks: u32, // Encrypted chunks the file was uploaded in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<BlobLink>, // Relay blob holding the chunks, once uploaded
+}
+
+fn default_chunks() -> u32 {
+    1
+}
+
+impl AttachmentPointer {
+    /// Point at a sealed file, sharing the key it was encrypted with
+    pub fn new(metadata: &FileMetadata, reference: &FileReference, key: &FileKey) -> Self {
+        Self {
+            file_id: metadata.file_id,
+            reference_id: reference.reference_id,
+            name: metadata.original_name.clone(),
+            mime_type: metadata.mime_type.clone(),
+            size: metadata.file_size,
+            hash: metadata.checksum.clone(),
+            key: general_purpose::STANDARD.encode(key),
+            chunks: default_chunks(),
+            link: None,
+        }
+    }
+
+    pub fn file_key(&self) -> Result<FileKey> {
+        general_purpose::STANDARD
+            .decode(&self.key)
+            .ok()
+            .and_then(|key| FileKey::try_from(key.as_slice()).ok())
+            .ok_or_else(|| NanoError::Protocol(format!("Invalid key for attachment '{}'", self.name)))
+    }
+}
+
+impl fmt::Display for AttachmentPointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "📎 {} ({}, {})", self.name, format_size(self.size), self.mime_type)
+    }
+}
+
+impl RichContent {
+    pub fn new(text: String) -> Self {
+        Self {
+            version: RICH_CONTENT_VERSION,
+            text,
+            reply_to: None,
+            mentions: Vec::new(),
+            spans: Vec::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Parse `**bold**`, `_italic_`, `` `code` ``, `~~struck~~` and `@name` mentions
+    ///
+    /// Markers without a closing partner stay literal text, as does anything
+    /// inside code and any `@name` that `resolve` does not know.
+    pub fn from_markup(markup: &str, resolve: impl Fn(&str) -> Option<String>) -> Self {
+        let mut rich = Self::new(String::new());
+        let mut open: Vec<(TextStyle, usize)> = Vec::new();
+        let mut rest = markup;
+
+        while let Some(c) = rest.chars().next() {
+            let in_code = open.iter().any(|(style, _)| *style == TextStyle::Code);
+            let at_word_start = rich.text.chars().next_back().is_none_or(|prev| !prev.is_alphanumeric());
+
+            let marker = MARKERS.iter().find(|(marker, style)| {
+                rest.starts_with(marker) && (!in_code || *style == TextStyle::Code)
+            });
+            if let Some(&(marker, style)) = marker {
+                if let Some(index) = open.iter().position(|(open_style, _)| *open_style == style) {
+                    let (_, start) = open.remove(index);
+                    rich.spans.push(FormatSpan { style, start, end: rich.text.len() });
+                    rest = &rest[marker.len()..];
+                    continue;
+                }
+                // Only open what closes later; `snake_case` is not italic
+                let opens = rest[marker.len()..].contains(marker) && (style != TextStyle::Italic || at_word_start);
+                if opens {
+                    open.push((style, rich.text.len()));
+                    rest = &rest[marker.len()..];
+                    continue;
+                }
+            }
+
+            if c == '@' && !in_code && at_word_start {
+                let name_len = rest[1..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                    .unwrap_or(rest.len() - 1);
+                if let Some(pubkey) = (name_len > 0).then(|| resolve(&rest[1..1 + name_len])).flatten() {
+                    let start = rich.text.len();
+                    rich.text.push_str(&rest[..1 + name_len]);
+                    rich.mentions.push(Mention { pubkey, start, end: rich.text.len() });
+                    rest = &rest[1 + name_len..];
+                    continue;
+                }
+            }
+
+            rich.text.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+
+        rich.spans.sort_by_key(|span| (span.start, span.end));
+        rich
+    }
+
+    /// Reply to `target`, quoting the start of its text
+    pub fn with_reply(mut self, target: MessageRef, text: &str) -> Self {
+        let mut quote: String = text.chars().take(MAX_QUOTE_CHARS).collect();
+        if quote.len() < text.len() {
+            quote.push('…');
+        }
+        self.reply_to = Some(ReplyTo { target, quote });
+        self
+    }
+
+    pub fn with_attachment(mut self, attachment: AttachmentPointer) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// Whether plain text says it all, so the message needs no rich content
+    pub fn is_plain(&self) -> bool {
+        self.reply_to.is_none() && self.mentions.is_empty() && self.spans.is_empty() && self.attachments.is_empty()
+    }
+
+    pub fn mentions_pubkey(&self, pubkey: &str) -> bool {
+        self.mentions.iter().any(|mention| mention.pubkey == pubkey)
+    }
+
+    /// Check the version is understood and every range lies on the text's character boundaries
+    pub fn validate(&self) -> Result<()> {
+        if self.version > RICH_CONTENT_VERSION {
+            return Err(NanoError::Protocol(format!("Unsupported rich content version {}", self.version)));
+        }
+        let ranges = self.mentions.iter().map(|mention| (mention.start, mention.end))
+            .chain(self.spans.iter().map(|span| (span.start, span.end)));
+        for (start, end) in ranges {
+            if start > end || !self.text.is_char_boundary(start) || !self.text.is_char_boundary(end) {
+                return Err(NanoError::Protocol(format!("Invalid text range {}..{}", start, end)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the text after an edit; formatting and mentions described the old one
+    pub fn replace_text(&mut self, text: String) {
+        self.text = text;
+        self.mentions.clear();
+        self.spans.clear();
+    }
+
+    /// One-line rendering: reply quote, text in markup, then attachments
+    pub fn render(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(reply) = &self.reply_to {
+            parts.push(format!("↩ \"{}\"", reply.quote));
+        }
+        if !self.text.is_empty() {
+            parts.push(self.marked_up_text());
+        }
+        parts.extend(self.attachments.iter().map(ToString::to_string));
+        parts.join(" · ")
+    }
+
+    /// Text for the payload body, shown by clients that do not understand `rich`
+    pub fn fallback_text(&self) -> String {
+        self.render()
+    }
+
+    /// Text with its spans written back as markup, closing markers before opening ones at the same place
+    fn marked_up_text(&self) -> String {
+        let mut marks: Vec<(usize, bool, &str)> = self
+            .spans
+            .iter()
+            .flat_map(|span| [(span.start, true, span.style.marker()), (span.end, false, span.style.marker())])
+            .collect();
+        marks.sort_by_key(|&(position, opens, _)| (position, opens));
+
+        let mut text = String::with_capacity(self.text.len() + marks.len() * 2);
+        let mut copied = 0;
+        for (position, _, marker) in marks {
+            text.push_str(&self.text[copied..position]);
+            text.push_str(marker);
+            copied = position;
+        }
+        text.push_str(&self.text[copied..]);
+        text
+    }
+}
+
+/// Size in the largest unit that keeps it at least 1, e.g. "1.5 MB"
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(name: &str) -> Option<String> {
+        (name == "bob").then(|| "pubkey:bob".to_string())
+    }
+
+    #[test]
+    fn test_markup_parsing() {
+        let rich = RichContent::from_markup("hi @bob, **this** is _really_ `a_b` and @carol's snake_case", resolve);
+        assert_eq!(rich.text, "hi @bob, this is really a_b and @carol's snake_case");
+        assert_eq!(rich.mentions, vec![Mention { pubkey: "pubkey:bob".to_string(), start: 3, end: 7 }]);
+        let styled: Vec<(TextStyle, &str)> = rich.spans.iter().map(|span| (span.style, &rich.text[span.start..span.end])).collect();
+        assert_eq!(styled, vec![(TextStyle::Bold, "this"), (TextStyle::Italic, "really"), (TextStyle::Code, "a_b")]);
+        assert!(rich.mentions_pubkey("pubkey:bob"));
+        rich.validate().unwrap();
+
+        // Unclosed markers are literal, and plain text stays plain
+        let plain = RichContent::from_markup("2 ** 3 costs $5_", resolve);
+        assert_eq!(plain.text, "2 ** 3 costs $5_");
+        assert!(plain.is_plain());
+    }
+
+    #[test]
+    fn test_fallback_rendering() {
+        let key: FileKey = [7; 32];
+        let metadata = FileMetadata {
+            original_name: "cat.png".to_string(),
+            mime_type: "image/png".to_string(),
+            file_size: 1536,
+            checksum: "ab12".to_string(),
+            ..Default::default()
+        };
+        let reference = FileReference::new(metadata.file_id, "pubkey:alice".to_string(), None, None);
+        let attachment = AttachmentPointer::new(&metadata, &reference, &key);
+        assert_eq!(attachment.file_key().unwrap(), key);
+
+        let rich = RichContent::from_markup("look **here**", resolve)
+            .with_reply(MessageRef::new("pubkey:bob".to_string(), 1_700_000_000), &"x".repeat(100))
+            .with_attachment(attachment);
+        let quote = format!("{}…", "x".repeat(MAX_QUOTE_CHARS));
+        assert_eq!(rich.fallback_text(), format!("↩ \"{}\" · look **here** · 📎 cat.png (1.5 KB, image/png)", quote));
+
+        // Unknown fields and newer versions are tolerated by parsing but rejected by validation
+        let mut json: serde_json::Value = serde_json::to_value(&rich).unwrap();
+        json["version"] = (RICH_CONTENT_VERSION + 1).into();
+        json["stickers"] = serde_json::json!(["🐈"]);
+        let newer: RichContent = serde_json::from_value(json).unwrap();
+        assert!(newer.validate().is_err());
+
+        let mut broken = rich.clone();
+        broken.spans.push(FormatSpan { style: TextStyle::Bold, start: 3, end: 99 });
+        assert!(broken.validate().is_err());
+    }
+}
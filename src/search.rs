@@ -0,0 +1,282 @@
+//! Full-text message search: normalization, query syntax and a ranked inverted index
+//!
+//! Queries are plain words, `"quoted phrases"` and filters:
+//! `from:<pubkey>`, `in:<conversation>`, `before:<date>`, `after:<date>` and
+//! `mode:<crypto mode>`. Dates are `YYYY-MM-DD` or RFC 3339. Every word and
+//! phrase must match; results are ranked with BM25.
+
+use crate::crypto::CryptoMode;
+use crate::error::{NanoError, Result};
+use crate::messages::StoredMessage;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::HashMap;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Compatibility-decompose, strip diacritics and lowercase, so "Café" matches "cafe"
+pub fn normalize(text: &str) -> String {
+    text.nfkd().filter(|c| !is_combining_mark(*c)).flat_map(char::to_lowercase).collect()
+}
+
+/// Split normalized text into alphanumeric tokens
+pub fn tokenize(text: &str) -> Vec<String> {
+    normalize(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Positions of each token in `text`, and how many tokens it has
+pub fn token_positions(text: &str) -> (HashMap<String, Vec<u32>>, u32) {
+    let tokens = tokenize(text);
+    let mut positions: HashMap<String, Vec<u32>> = HashMap::new();
+    for (position, token) in tokens.iter().enumerate() {
+        positions.entry(token.clone()).or_default().push(position as u32);
+    }
+    (positions, tokens.len() as u32)
+}
+
+/// A parsed search query
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub phrases: Vec<Vec<String>>,
+    pub from: Option<String>,
+    pub conversation: Option<String>,
+    pub before: Option<DateTime<Utc>>,
+    pub after: Option<DateTime<Utc>>,
+    pub mode: Option<CryptoMode>,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut rest = query.trim_start();
+
+        while !rest.is_empty() {
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let phrase = tokenize(&quoted[..end]);
+                match phrase.len() {
+                    0 => {}
+                    1 => parsed.terms.extend(phrase),
+                    _ => parsed.phrases.push(phrase),
+                }
+                rest = quoted.get(end + 1..).unwrap_or("").trim_start();
+                continue;
+            }
+
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let word = &rest[..end];
+            rest = rest[end..].trim_start();
+
+            match word.split_once(':') {
+                Some(("from", value)) if !value.is_empty() => parsed.from = Some(value.to_string()),
+                Some(("in", value)) if !value.is_empty() => parsed.conversation = Some(value.to_string()),
+                Some(("before", value)) => parsed.before = Some(parse_date(value)?),
+                Some(("after", value)) => parsed.after = Some(parse_date(value)?),
+                Some(("mode", value)) => parsed.mode = Some(value.parse()?),
+                _ => parsed.terms.extend(tokenize(word)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Every distinct token of the query's words and phrases
+    pub fn tokens(&self) -> Vec<&String> {
+        let mut tokens: Vec<&String> = self.terms.iter().chain(self.phrases.iter().flatten()).collect();
+        tokens.sort();
+        tokens.dedup();
+        tokens
+    }
+
+    /// Whether the query has words or phrases to rank by
+    pub fn has_text(&self) -> bool {
+        !self.terms.is_empty() || !self.phrases.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Check the non-text filters against a message
+    pub fn matches_filters(&self, message: &StoredMessage) -> bool {
+        self.from.as_ref().is_none_or(|from| &message.from_pubkey == from)
+            && self
+                .conversation
+                .as_ref()
+                .is_none_or(|conversation| message.conversation_id.contains(conversation.as_str()))
+            && self.before.is_none_or(|before| message.timestamp < before)
+            && self.after.is_none_or(|after| message.timestamp >= after)
+            // Messages without a recorded mode carry classical signatures
+            && self.mode.is_none_or(|mode| message.crypto_mode.unwrap_or(CryptoMode::Classical) == mode)
+    }
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| NanoError::Protocol(format!("Invalid date '{}': use YYYY-MM-DD or RFC 3339", value)))
+}
+
+/// Inverted index from normalized tokens to message positions
+///
+/// Either the whole index, or the slice of a stored one that a query needs
+/// (see `partial`), which ranks the same way.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, HashMap<String, Vec<u32>>>, // token -> message_id -> positions
+    lengths: HashMap<String, u32>,                        // message_id -> token count
+    documents: u64,
+    total_length: u64,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index holding some tokens' postings and the lengths of their messages, with
+    /// `documents` and `total_length` counted over every indexed message
+    pub fn partial(
+        postings: HashMap<String, HashMap<String, Vec<u32>>>,
+        lengths: HashMap<String, u32>,
+        documents: u64,
+        total_length: u64,
+    ) -> Self {
+        Self { postings, lengths, documents, total_length }
+    }
+
+    /// Index a message's text; re-adding the same ID is a no-op
+    pub fn add(&mut self, message_id: &str, text: &str) {
+        if self.lengths.contains_key(message_id) {
+            return;
+        }
+
+        let (positions, length) = token_positions(text);
+        for (token, positions) in positions {
+            self.postings.entry(token).or_default().insert(message_id.to_string(), positions);
+        }
+        self.lengths.insert(message_id.to_string(), length);
+        self.documents += 1;
+        self.total_length += length as u64;
+    }
+
+    /// Drop a message from the index, e.g. once it is edited or deleted
+    pub fn remove(&mut self, message_id: &str) {
+        let Some(length) = self.lengths.remove(message_id) else {
+            return;
+        };
+        self.documents -= 1;
+        self.total_length -= length as u64;
+        self.postings.retain(|_, messages| {
+            messages.remove(message_id);
+            !messages.is_empty()
+        });
+    }
+
+    fn positions(&self, token: &str, message_id: &str) -> Option<&Vec<u32>> {
+        self.postings.get(token)?.get(message_id)
+    }
+
+    fn contains_phrase(&self, phrase: &[String], message_id: &str) -> bool {
+        let Some(starts) = self.positions(&phrase[0], message_id) else {
+            return false;
+        };
+        starts.iter().any(|&start| {
+            phrase[1..].iter().enumerate().all(|(offset, token)| {
+                self.positions(token, message_id)
+                    .is_some_and(|positions| positions.contains(&(start + offset as u32 + 1)))
+            })
+        })
+    }
+
+    /// BM25 scores for messages containing every term and phrase of the query
+    pub fn score(&self, query: &SearchQuery) -> HashMap<String, f64> {
+        let tokens = query.tokens();
+
+        let Some(rarest) = tokens.iter().min_by_key(|token| self.postings.get(**token).map_or(0, HashMap::len)) else {
+            return HashMap::new();
+        };
+        let Some(candidates) = self.postings.get(*rarest) else {
+            return HashMap::new();
+        };
+
+        let documents = self.documents as f64;
+        let average_length = self.total_length as f64 / documents.max(1.0);
+
+        candidates
+            .keys()
+            .filter(|id| tokens.iter().all(|token| self.positions(token, id).is_some()))
+            .filter(|id| query.phrases.iter().all(|phrase| self.contains_phrase(phrase, id)))
+            .map(|id| {
+                let length = self.lengths[id.as_str()] as f64;
+                let score = tokens
+                    .iter()
+                    .map(|token| {
+                        let postings = &self.postings[token.as_str()];
+                        let df = postings.len() as f64;
+                        let tf = postings[id.as_str()].len() as f64;
+                        let idf = (1.0 + (documents - df + 0.5) / (df + 0.5)).ln();
+                        idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * length / average_length))
+                    })
+                    .sum();
+                (id.clone(), score)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_and_tokenize() {
+        assert_eq!(tokenize("Café au LAIT, s'il vous plaît!"), ["cafe", "au", "lait", "s", "il", "vous", "plait"]);
+        // Compatibility forms fold to their plain equivalents
+        assert_eq!(tokenize("ｆｕｌｌ ﬁle"), ["full", "file"]);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = SearchQuery::parse(r#"lunch "see you soon" from:pubkey:abc after:2024-01-02 mode:hybrid"#).unwrap();
+        assert_eq!(query.terms, ["lunch"]);
+        assert_eq!(query.phrases, [vec!["see", "you", "soon"]]);
+        assert_eq!(query.from.as_deref(), Some("pubkey:abc"));
+        assert_eq!(query.after.unwrap().to_rfc3339(), "2024-01-02T00:00:00+00:00");
+        assert_eq!(query.mode, Some(CryptoMode::Hybrid));
+
+        assert!(SearchQuery::parse("before:yesterday").is_err());
+        assert!(SearchQuery::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_phrase_and_ranking() {
+        let mut index = SearchIndex::new();
+        index.add("1", "see you soon at the cafe");
+        index.add("2", "soon you will see");
+        index.add("3", "cafe cafe cafe tomorrow");
+        index.add("4", "meeting tomorrow");
+
+        let phrase = SearchQuery::parse(r#""see you soon""#).unwrap();
+        assert_eq!(index.score(&phrase).into_keys().collect::<Vec<_>>(), ["1"]);
+
+        // More occurrences in a shorter message rank higher
+        let scores = index.score(&SearchQuery::parse("Café").unwrap());
+        assert_eq!(scores.len(), 2);
+        assert!(scores["3"] > scores["1"]);
+
+        // Every term is required
+        assert!(index.score(&SearchQuery::parse("cafe meeting").unwrap()).is_empty());
+    }
+}
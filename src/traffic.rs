@@ -0,0 +1,275 @@
+use crate::crypto::{encrypt_symmetric, PaddingScheme, UserKeyPair};
+use crate::error::Result;
+use crate::inbox::derive_conversation_inbox;
+use crate::protocol::{MessageEnvelope, MessagePayload};
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Configuration for client cover traffic
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CoverTrafficConfig {
+    /// Whether the client sends dummy envelopes
+    pub enabled: bool,
+    /// Mean seconds between dummy envelopes (Poisson process)
+    pub mean_interval_secs: f64,
+    /// Maximum body length of dummy messages before padding
+    pub max_body_len: usize,
+}
+
+impl Default for CoverTrafficConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mean_interval_secs: 60.0,
+            max_body_len: 280,
+        }
+    }
+}
+
+/// Exponentially distributed delays, giving a Poisson send process
+#[derive(Debug, Clone, Copy)]
+pub struct PoissonSchedule {
+    mean_interval_secs: f64,
+}
+
+impl PoissonSchedule {
+    pub fn new(mean_interval_secs: f64) -> Self {
+        Self {
+            mean_interval_secs: mean_interval_secs.max(0.001),
+        }
+    }
+
+    /// Sample the delay until the next event
+    pub fn next_delay<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        // 1 - U lies in (0, 1], so the logarithm is finite
+        let u: f64 = 1.0 - rng.gen::<f64>();
+        Duration::from_secs_f64(-u.ln() * self.mean_interval_secs)
+    }
+}
+
+/// Builds dummy envelopes that look like ordinary conversation messages
+///
+/// A dummy is a real signed `MessagePayload` from a throwaway identity,
+/// padded and encrypted under a random key and addressed to a random
+/// conversation inbox. Nobody holds the key or polls the inbox, so the relay
+/// simply lets it age out.
+pub struct CoverTrafficGenerator {
+    config: CoverTrafficConfig,
+    padding: PaddingScheme,
+    identity: UserKeyPair,
+}
+
+impl CoverTrafficGenerator {
+    pub fn new(config: CoverTrafficConfig, padding: PaddingScheme) -> Self {
+        Self {
+            config,
+            padding,
+            identity: UserKeyPair::generate(),
+        }
+    }
+
+    /// Send schedule derived from the configured mean interval
+    pub fn schedule(&self) -> PoissonSchedule {
+        PoissonSchedule::new(self.config.mean_interval_secs)
+    }
+
+    /// Create one dummy envelope
+    pub fn dummy_envelope(&self) -> Result<MessageEnvelope> {
+        let mut rng = rand::thread_rng();
+
+        let body_len = rng.gen_range(1..=self.config.max_body_len.max(1));
+        let body: String = (0..body_len)
+            .map(|_| *b"abcdefghijklmnopqrstuvwxyz ".choose(&mut rng).unwrap() as char)
+            .collect();
+
+        let mut payload = MessagePayload::new(
+            self.identity.public_key_string(),
+            body,
+            rng.gen_range(1..1000),
+            None,
+        );
+        payload.sign(&self.identity.signing_key)?;
+
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+
+        let padded = self.padding.pad(payload.to_json()?.as_bytes())?;
+        let encrypted = encrypt_symmetric(&secret, &padded)?;
+        let inbox_id = derive_conversation_inbox(&secret, rng.gen());
+
+        Ok(MessageEnvelope::new(inbox_id, encrypted))
+    }
+}
+
+/// Relay-side mixing configuration
+#[derive(Debug, Clone, Copy)]
+pub struct MixConfig {
+    /// Minimum delay before a message becomes fetchable
+    pub min_delay: Duration,
+    /// Maximum delay before a message becomes fetchable (zero disables mixing)
+    pub max_delay: Duration,
+    /// How often the pool is flushed into inboxes
+    pub flush_interval: Duration,
+}
+
+impl Default for MixConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            flush_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+impl MixConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.max_delay.is_zero()
+    }
+
+    /// Sample a delay uniformly between the minimum and maximum
+    pub fn sample_delay<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        if self.max_delay <= self.min_delay {
+            return self.min_delay;
+        }
+        let span = (self.max_delay - self.min_delay).as_secs_f64();
+        self.min_delay + Duration::from_secs_f64(rng.gen::<f64>() * span)
+    }
+}
+
+/// Pool that holds messages for a random delay and releases them in shuffled batches
+pub struct MixPool<T> {
+    config: MixConfig,
+    pending: Vec<(Instant, T)>,
+}
+
+impl<T> MixPool<T> {
+    pub fn new(config: MixConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Add a message with a randomized release time
+    pub fn push(&mut self, item: T) {
+        self.push_at(item, Instant::now());
+    }
+
+    fn push_at(&mut self, item: T, now: Instant) {
+        let delay = self.config.sample_delay(&mut rand::thread_rng());
+        self.pending.push((now + delay, item));
+    }
+
+    /// Remove all messages whose delay has elapsed, in random order
+    pub fn drain_ready(&mut self) -> Vec<T> {
+        self.drain_ready_at(Instant::now())
+    }
+
+    fn drain_ready_at(&mut self, now: Instant) -> Vec<T> {
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(release_at, _)| *release_at <= now);
+        self.pending = waiting;
+
+        let mut batch: Vec<T> = ready.into_iter().map(|(_, item)| item).collect();
+        batch.shuffle(&mut rand::thread_rng());
+        batch
+    }
+
+    /// Number of messages waiting to be released
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::decrypt_symmetric;
+
+    #[test]
+    fn test_poisson_schedule_mean() {
+        let schedule = PoissonSchedule::new(2.0);
+        let mut rng = rand::thread_rng();
+
+        let samples = 20_000;
+        let total: f64 = (0..samples)
+            .map(|_| schedule.next_delay(&mut rng).as_secs_f64())
+            .sum();
+        let mean = total / samples as f64;
+
+        assert!((mean - 2.0).abs() < 0.15, "mean was {}", mean);
+    }
+
+    #[test]
+    fn test_dummy_envelopes_match_real_shape() {
+        let config = CoverTrafficConfig {
+            enabled: true,
+            max_body_len: 5,
+            ..CoverTrafficConfig::default()
+        };
+        let generator = CoverTrafficGenerator::new(config, PaddingScheme::Buckets);
+
+        // A real padded conversation message of similar length
+        let sender = UserKeyPair::generate();
+        let mut payload = MessagePayload::new(sender.public_key_string(), "hello".to_string(), 3, None);
+        payload.sign(&sender.signing_key).unwrap();
+        let secret = [7u8; 32];
+        let padded = PaddingScheme::Buckets.pad(payload.to_json().unwrap().as_bytes()).unwrap();
+        let real = MessageEnvelope::new(derive_conversation_inbox(&secret, 3), encrypt_symmetric(&secret, &padded).unwrap());
+
+        let first = generator.dummy_envelope().unwrap();
+        let second = generator.dummy_envelope().unwrap();
+
+        assert_eq!(first.version, real.version);
+        assert_eq!(first.inbox_id.len(), real.inbox_id.len());
+        assert_eq!(first.payload.len(), real.payload.len());
+        assert_ne!(first.inbox_id, second.inbox_id);
+        assert_ne!(first.nonce, second.nonce);
+
+        // Cannot be opened with any key the recipient would try
+        assert!(decrypt_symmetric(&secret, &first.decode_payload().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_mix_pool_delays_and_batches() {
+        let config = MixConfig {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            ..MixConfig::default()
+        };
+        let mut pool = MixPool::new(config);
+        let start = Instant::now();
+
+        for i in 0..10 {
+            pool.push_at(i, start);
+        }
+
+        // Nothing is released before the minimum delay
+        assert!(pool.drain_ready_at(start).is_empty());
+        assert_eq!(pool.len(), 10);
+
+        // Everything is released after the maximum delay
+        let mut released = pool.drain_ready_at(start + Duration::from_secs(5));
+        released.sort();
+        assert_eq!(released, (0..10).collect::<Vec<_>>());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_mixing_disabled_by_default() {
+        let config = MixConfig::default();
+        assert!(!config.is_enabled());
+
+        let mut pool = MixPool::new(config);
+        pool.push("msg");
+        assert_eq!(pool.drain_ready(), vec!["msg"]);
+    }
+}
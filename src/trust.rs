@@ -0,0 +1,295 @@
+//! Trust-on-first-use pinning of contacts' keys
+//!
+//! The first keys seen for a contact are pinned. When a later lookup returns
+//! different keys, the contact's policy decides what happens: `warn` and
+//! `auto-accept` repin to the new keys, `block` keeps the old pin and refuses
+//! the new keys until they are accepted by hand. Every change is kept in the
+//! contact's history either way.
+//!
+//! Contacts are pinned under the name they were looked up by, a username or a
+//! pubkey, so a username moving to another identity is caught as well as an
+//! identity rotating its encryption key.
+
+use crate::crypto::{hash_sha256, UserPublicKeys};
+use crate::error::{NanoError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do when a contact's keys change
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyChangePolicy {
+    /// Use the new keys and tell the user
+    #[default]
+    Warn,
+    /// Refuse the new keys until the user accepts them
+    Block,
+    /// Use the new keys quietly
+    AutoAccept,
+}
+
+impl fmt::Display for KeyChangePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Block => "block",
+            Self::AutoAccept => "auto-accept",
+        })
+    }
+}
+
+impl FromStr for KeyChangePolicy {
+    type Err = NanoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "warn" => Ok(Self::Warn),
+            "block" => Ok(Self::Block),
+            "auto-accept" | "auto" => Ok(Self::AutoAccept),
+            other => Err(NanoError::Protocol(format!(
+                "Unknown key change policy '{}' (use warn, block or auto-accept)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Short, comparable digest of both of a contact's public keys
+pub fn fingerprint(public_keys: &UserPublicKeys) -> String {
+    let mut keys = public_keys.verifying_key.to_bytes().to_vec();
+    keys.extend_from_slice(&public_keys.x25519_key.to_bytes());
+    let hex: String = hash_sha256(&keys)[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex.as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).expect("hex is ASCII"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Keys trusted for a contact
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyPin {
+    pub pubkey: String,
+    pub fingerprint: String,
+    pub pinned_at: DateTime<Utc>,
+    /// Whether the user compared the fingerprint with the contact
+    pub verified: bool,
+}
+
+impl KeyPin {
+    fn new(public_keys: &UserPublicKeys, now: DateTime<Utc>) -> Self {
+        Self {
+            pubkey: public_keys.public_key_string(),
+            fingerprint: fingerprint(public_keys),
+            pinned_at: now,
+            verified: false,
+        }
+    }
+}
+
+/// Keys for a contact that differ from the pinned ones
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyChange {
+    pub contact: String,
+    pub old_pubkey: String,
+    pub old_fingerprint: String,
+    pub new_pubkey: String,
+    pub new_fingerprint: String,
+    pub seen_at: DateTime<Utc>,
+    /// Whether the new keys replaced the pin
+    pub accepted: bool,
+}
+
+/// Outcome of checking looked-up keys against the pin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// Nothing was pinned yet; these keys are now
+    FirstUse,
+    Unchanged,
+    /// The keys changed and were accepted under the contact's policy
+    Changed(KeyChange),
+    /// The keys changed and wait to be accepted
+    Blocked(KeyChange),
+    /// The same blocked change was seen before
+    StillBlocked,
+}
+
+/// Pinned keys, key change history and policies of one identity
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    #[serde(default)]
+    pub default_policy: KeyChangePolicy,
+    #[serde(default)]
+    policies: HashMap<String, KeyChangePolicy>, // contact -> policy overriding the default
+    #[serde(default)]
+    pins: HashMap<String, KeyPin>,
+    #[serde(default)]
+    pending: HashMap<String, (KeyChange, UserPublicKeys)>, // contact -> blocked change and its keys
+    #[serde(default)]
+    history: Vec<KeyChange>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn policy(&self, contact: &str) -> KeyChangePolicy {
+        self.policies.get(contact).copied().unwrap_or(self.default_policy)
+    }
+
+    /// Override the policy for one contact, or clear the override with `None`
+    pub fn set_policy(&mut self, contact: &str, policy: Option<KeyChangePolicy>) {
+        match policy {
+            Some(policy) => self.policies.insert(contact.to_string(), policy),
+            None => self.policies.remove(contact),
+        };
+    }
+
+    pub fn pin(&self, contact: &str) -> Option<&KeyPin> {
+        self.pins.get(contact)
+    }
+
+    /// Pinned contacts, sorted by name
+    pub fn pins(&self) -> Vec<(&str, &KeyPin)> {
+        let mut pins: Vec<(&str, &KeyPin)> = self.pins.iter().map(|(contact, pin)| (contact.as_str(), pin)).collect();
+        pins.sort_by_key(|(contact, _)| *contact);
+        pins
+    }
+
+    pub fn pending(&self, contact: &str) -> Option<&KeyChange> {
+        self.pending.get(contact).map(|(change, _)| change)
+    }
+
+    /// Key changes seen for a contact, oldest first
+    pub fn history(&self, contact: &str) -> Vec<&KeyChange> {
+        self.history.iter().filter(|change| change.contact == contact).collect()
+    }
+
+    /// Compare keys looked up for `contact` with the pin, applying the contact's policy
+    pub fn check(&mut self, contact: &str, public_keys: &UserPublicKeys, now: DateTime<Utc>) -> KeyCheck {
+        let Some(pin) = self.pins.get(contact) else {
+            self.pins.insert(contact.to_string(), KeyPin::new(public_keys, now));
+            return KeyCheck::FirstUse;
+        };
+        let new_fingerprint = fingerprint(public_keys);
+        if pin.fingerprint == new_fingerprint {
+            // The pinned keys are back, so a blocked change no longer applies
+            self.pending.remove(contact);
+            return KeyCheck::Unchanged;
+        }
+        if self.pending(contact).is_some_and(|change| change.new_fingerprint == new_fingerprint) {
+            return KeyCheck::StillBlocked;
+        }
+
+        let mut change = KeyChange {
+            contact: contact.to_string(),
+            old_pubkey: pin.pubkey.clone(),
+            old_fingerprint: pin.fingerprint.clone(),
+            new_pubkey: public_keys.public_key_string(),
+            new_fingerprint,
+            seen_at: now,
+            accepted: false,
+        };
+        if self.policy(contact) == KeyChangePolicy::Block {
+            self.history.push(change.clone());
+            self.pending.insert(contact.to_string(), (change.clone(), public_keys.clone()));
+            return KeyCheck::Blocked(change);
+        }
+
+        change.accepted = true;
+        self.history.push(change.clone());
+        self.pending.remove(contact);
+        self.pins.insert(contact.to_string(), KeyPin::new(public_keys, now));
+        KeyCheck::Changed(change)
+    }
+
+    /// Pin the keys of a blocked change, marking them verified
+    pub fn accept(&mut self, contact: &str, now: DateTime<Utc>) -> Result<KeyChange> {
+        let (mut change, public_keys) = self.pending.remove(contact)
+            .ok_or_else(|| NanoError::Protocol(format!("No key change waiting for {}", contact)))?;
+        change.accepted = true;
+        if let Some(recorded) = self.history.iter_mut().rev().find(|recorded| recorded.contact == contact && recorded.seen_at == change.seen_at) {
+            recorded.accepted = true;
+        }
+
+        let mut pin = KeyPin::new(&public_keys, now);
+        pin.verified = true;
+        self.pins.insert(contact.to_string(), pin);
+        Ok(change)
+    }
+
+    /// Mark the pinned keys as verified, checking them against a fingerprint if one is given
+    pub fn verify(&mut self, contact: &str, fingerprint: Option<&str>) -> Result<()> {
+        let pin = self.pins.get_mut(contact)
+            .ok_or_else(|| NanoError::Protocol(format!("No keys pinned for {}", contact)))?;
+        let normalize = |fingerprint: &str| -> String {
+            fingerprint.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase()
+        };
+        if fingerprint.is_some_and(|fingerprint| normalize(fingerprint) != normalize(&pin.fingerprint)) {
+            return Err(NanoError::Crypto(format!("Fingerprint does not match the keys pinned for {}", contact)));
+        }
+        pin.verified = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::UserKeyPair;
+
+    fn now() -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    #[test]
+    fn test_pin_and_warn() {
+        let mut trust = TrustStore::new();
+        let first = UserKeyPair::generate().public_keys();
+        let second = UserKeyPair::generate().public_keys();
+
+        assert_eq!(trust.check("alice", &first, now()), KeyCheck::FirstUse);
+        assert_eq!(trust.check("alice", &first, now()), KeyCheck::Unchanged);
+        trust.verify("alice", Some(&fingerprint(&first).to_uppercase())).unwrap();
+        assert!(trust.pin("alice").unwrap().verified);
+        assert!(trust.verify("alice", Some("0000")).is_err());
+
+        // Warn repins, and the new keys start out unverified
+        let KeyCheck::Changed(change) = trust.check("alice", &second, now()) else {
+            panic!("keys changed");
+        };
+        assert_eq!((change.old_pubkey, change.new_pubkey), (first.public_key_string(), second.public_key_string()));
+        assert_eq!(trust.pin("alice").unwrap().fingerprint, fingerprint(&second));
+        assert!(!trust.pin("alice").unwrap().verified);
+        assert_eq!(trust.history("alice").len(), 1);
+        assert_eq!(fingerprint(&first).len(), 39);
+    }
+
+    #[test]
+    fn test_block_until_accepted() {
+        let mut trust = TrustStore::new();
+        trust.set_policy("bob", Some(KeyChangePolicy::Block));
+        let first = UserKeyPair::generate().public_keys();
+        let second = UserKeyPair::generate().public_keys();
+
+        trust.check("bob", &first, now());
+        assert!(matches!(trust.check("bob", &second, now()), KeyCheck::Blocked(_)));
+        assert_eq!(trust.check("bob", &second, now()), KeyCheck::StillBlocked);
+        assert_eq!(trust.pin("bob").unwrap().fingerprint, fingerprint(&first));
+        assert_eq!(trust.history("bob").len(), 1);
+
+        let change = trust.accept("bob", now()).unwrap();
+        assert!(change.accepted && trust.history("bob")[0].accepted);
+        assert_eq!(trust.check("bob", &second, now()), KeyCheck::Unchanged);
+        assert!(trust.pin("bob").unwrap().verified);
+        assert!(trust.accept("bob", now()).is_err());
+
+        // Other contacts keep the default
+        assert_eq!(trust.policy("carol"), KeyChangePolicy::Warn);
+        assert_eq!("auto-accept".parse::<KeyChangePolicy>().unwrap(), KeyChangePolicy::AutoAccept);
+    }
+}
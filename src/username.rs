@@ -46,8 +46,23 @@ impl UsernameRegistry {
     }
 
     /// Look up a username and return the associated public keys
+    ///
+    /// A `pubkey:` string resolves to the full keys of whichever claim it made.
     pub fn lookup_username(&self, username: &str) -> Option<&UserPublicKeys> {
-        self.claims.get(username).map(|claim| &claim.public_keys)
+        self.find_claim(username).map(|claim| &claim.public_keys)
+    }
+
+    /// The claim for a username, or for the identity behind a `pubkey:` string
+    pub fn find_claim(&self, username: &str) -> Option<&UsernameClaim> {
+        if let Some(claim) = self.claims.get(username) {
+            return Some(claim);
+        }
+        if !username.starts_with("pubkey:") {
+            return None;
+        }
+        self.claims
+            .values()
+            .find(|claim| claim.public_keys.public_key_string() == username)
     }
 
     /// Get all registered usernames
@@ -107,10 +122,19 @@ pub fn validate_username(username: &str) -> Result<()> {
 
 /// Create a username claim for a given keypair
 pub fn create_username_claim(username: &str, keypair: &UserKeyPair) -> Result<UsernameClaim> {
+    create_username_claim_with_relays(username, keypair, Vec::new())
+}
+
+/// Create a username claim that also advertises the relays the user accepts mail on
+pub fn create_username_claim_with_relays(
+    username: &str,
+    keypair: &UserKeyPair,
+    relays: Vec<String>,
+) -> Result<UsernameClaim> {
     validate_username(username)?;
     
     let public_keys = keypair.public_keys();
-    let mut claim = UsernameClaim::new(username.to_string(), public_keys);
+    let mut claim = UsernameClaim::new(username.to_string(), public_keys).with_relays(relays);
     claim.sign(&keypair.signing_key)?;
     
     Ok(claim)
@@ -158,6 +182,12 @@ mod tests {
         assert!(registry.lookup_username("bob2024").is_some());
         assert!(registry.lookup_username("charlie").is_none());
         
+        // A pubkey string resolves to the claimed keys, for replying to a sender
+        let alice_pubkey = alice_keypair.public_key_string();
+        assert_eq!(registry.lookup_username(&alice_pubkey).unwrap().public_key_string(), alice_pubkey);
+        assert!(registry.lookup_username(&UserKeyPair::generate().public_key_string()).is_none());
+        assert_eq!(registry.find_claim(&alice_pubkey).unwrap().username, "alice2024");
+        
         // Test duplicate username with different key (should fail)
         let charlie_keypair = UserKeyPair::generate();
         let charlie_claim = create_username_claim("alice2024", &charlie_keypair).unwrap();
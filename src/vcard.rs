@@ -0,0 +1,361 @@
+//! Contact import and export as vCard 4.0 (RFC 6350)
+//!
+//! Nickname, memo and labels use the standard `NICKNAME`, `NOTE` and
+//! `CATEGORIES` properties, so other address books show them. The rest uses
+//! extension properties:
+//!
+//! - `X-NANO-PUBKEY`: the contact's identity key; cards without one are skipped
+//! - `X-NANO-USERNAME`: their last known username
+//! - `X-NANO-STATUS`: `allowed`, `blocked` or `unknown`
+//! - `X-NANO-FAVORITE`: `true` for favorites
+//!
+//! Importing merges into the existing contacts: labels are added, a card's
+//! nickname, memo and status replace the current ones when set, and a
+//! username is only recorded if it does not already belong to someone else.
+
+use crate::contacts::{Contact, ContactManager, ContactStatus};
+use crate::crypto::ClassicalUserPublicKeys;
+use crate::error::{NanoError, Result};
+
+/// Longest line written before folding, in bytes
+const LINE_LIMIT: usize = 75;
+
+/// One contact as it appears in a vCard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactCard {
+    pub pubkey: String,
+    pub name: String,
+    pub nickname: String,
+    pub memo: String,
+    pub labels: Vec<String>,
+    pub username: Option<String>,
+    pub status: ContactStatus,
+    pub favorite: bool,
+}
+
+impl ContactCard {
+    pub fn from_contact(contact: &Contact) -> Self {
+        let metadata = contact.metadata.as_ref();
+        Self {
+            pubkey: contact.permission.pubkey.clone(),
+            name: contact.display_name().to_string(),
+            nickname: metadata.map(|metadata| metadata.nickname.clone()).unwrap_or_default(),
+            memo: metadata.map(|metadata| metadata.memo.clone()).unwrap_or_default(),
+            labels: contact.labels().to_vec(),
+            username: contact.username.clone(),
+            status: contact.permission.status.clone(),
+            favorite: contact.is_favorite(),
+        }
+    }
+
+    /// The card as vCard text with CRLF line endings
+    pub fn to_vcard(&self) -> String {
+        let mut lines = vec!["BEGIN:VCARD".to_string(), "VERSION:4.0".to_string()];
+        lines.push(format!("FN:{}", escape(&self.name)));
+        if !self.nickname.is_empty() {
+            lines.push(format!("NICKNAME:{}", escape(&self.nickname)));
+        }
+        if !self.memo.is_empty() {
+            lines.push(format!("NOTE:{}", escape(&self.memo)));
+        }
+        if !self.labels.is_empty() {
+            let labels: Vec<String> = self.labels.iter().map(|label| escape(label)).collect();
+            lines.push(format!("CATEGORIES:{}", labels.join(",")));
+        }
+        lines.push(format!("X-NANO-PUBKEY:{}", self.pubkey));
+        if let Some(username) = &self.username {
+            lines.push(format!("X-NANO-USERNAME:{}", escape(username)));
+        }
+        lines.push(format!("X-NANO-STATUS:{}", status_name(&self.status)));
+        if self.favorite {
+            lines.push("X-NANO-FAVORITE:true".to_string());
+        }
+        lines.push("END:VCARD".to_string());
+
+        lines.iter().map(|line| fold(line)).collect()
+    }
+}
+
+/// All contacts as one vCard file, sorted by name
+pub fn export(manager: &ContactManager) -> String {
+    let mut cards: Vec<ContactCard> = manager.list_contacts().iter().map(ContactCard::from_contact).collect();
+    cards.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()).then_with(|| a.pubkey.cmp(&b.pubkey)));
+    cards.iter().map(ContactCard::to_vcard).collect()
+}
+
+/// Cards in a vCard file, and how many were skipped for having no public key
+pub fn parse(text: &str) -> Result<(Vec<ContactCard>, usize)> {
+    let mut cards = Vec::new();
+    let mut skipped = 0;
+    let mut current: Option<Vec<(String, String)>> = None;
+
+    for line in unfold(text) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (name, value) = split_property(&line)
+            .ok_or_else(|| NanoError::Protocol(format!("Malformed vCard line: {}", line)))?;
+
+        match (name.as_str(), current.as_mut()) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VCARD") => current = Some(Vec::new()),
+            ("END", Some(properties)) if value.eq_ignore_ascii_case("VCARD") => {
+                match card_from_properties(properties)? {
+                    Some(card) => cards.push(card),
+                    None => skipped += 1,
+                }
+                current = None;
+            }
+            ("BEGIN" | "END", _) => return Err(NanoError::Protocol("Unbalanced BEGIN/END in vCard".to_string())),
+            (_, Some(properties)) => properties.push((name, value)),
+            (_, None) => return Err(NanoError::Protocol("vCard property outside of a card".to_string())),
+        }
+    }
+
+    if current.is_some() {
+        return Err(NanoError::Protocol("vCard file ends inside a card".to_string()));
+    }
+    Ok((cards, skipped))
+}
+
+/// Merge cards into `manager`, returning how many contacts were new
+pub fn import(manager: &mut ContactManager, cards: &[ContactCard]) -> Result<usize> {
+    let mut added = 0;
+    for card in cards {
+        let existing = manager.get_contact(&card.pubkey);
+        if existing.is_none() {
+            added += 1;
+        }
+        if existing.is_none() || card.status != ContactStatus::Unknown {
+            manager.set_status(card.pubkey.clone(), card.status.clone());
+        }
+
+        let nickname = Some(card.nickname.clone()).filter(|nickname| !nickname.is_empty());
+        let memo = Some(card.memo.clone()).filter(|memo| !memo.is_empty());
+        manager.update_metadata(&card.pubkey, nickname, memo)?;
+        for label in &card.labels {
+            manager.add_label(&card.pubkey, label)?;
+        }
+        if card.favorite {
+            manager.set_favorite(&card.pubkey, true);
+        }
+
+        if let Some(username) = &card.username {
+            if manager.get_pubkey_for_username(username).is_none() {
+                manager.set_username(card.pubkey.clone(), username.clone());
+            }
+        }
+    }
+    Ok(added)
+}
+
+fn card_from_properties(properties: &[(String, String)]) -> Result<Option<ContactCard>> {
+    let value = |wanted: &str| {
+        properties.iter().find(|(name, _)| name == wanted).map(|(_, value)| value.as_str())
+    };
+    let Some(pubkey) = value("X-NANO-PUBKEY").map(str::trim) else {
+        return Ok(None);
+    };
+    ClassicalUserPublicKeys::from_public_key_string(pubkey)
+        .map_err(|_| NanoError::Protocol(format!("Invalid public key in vCard: {}", pubkey)))?;
+
+    let status = match value("X-NANO-STATUS").map(|status| status.trim().to_lowercase()).as_deref() {
+        None | Some("unknown") => ContactStatus::Unknown,
+        Some("allowed") => ContactStatus::Allowed,
+        Some("blocked") => ContactStatus::Blocked,
+        Some(other) => return Err(NanoError::Protocol(format!("Unknown contact status in vCard: {}", other))),
+    };
+
+    let name = value("FN").map(unescape).unwrap_or_default();
+    let username = value("X-NANO-USERNAME").map(unescape).filter(|username| !username.is_empty());
+    // Cards from other address books may only carry a formatted name
+    let nickname = match value("NICKNAME") {
+        Some(nicknames) => split_list(nicknames).into_iter().next().unwrap_or_default(),
+        None if name != pubkey && Some(&name) != username.as_ref() => name.clone(),
+        None => String::new(),
+    };
+    let labels = properties
+        .iter()
+        .filter(|(name, _)| name == "CATEGORIES")
+        .flat_map(|(_, value)| split_list(value))
+        .map(|label| label.trim().to_string())
+        .filter(|label| !label.is_empty())
+        .collect();
+
+    Ok(Some(ContactCard {
+        pubkey: pubkey.to_string(),
+        name,
+        nickname,
+        memo: value("NOTE").map(unescape).unwrap_or_default(),
+        labels,
+        username,
+        status,
+        favorite: value("X-NANO-FAVORITE").is_some_and(|favorite| favorite.trim().eq_ignore_ascii_case("true")),
+    }))
+}
+
+fn status_name(status: &ContactStatus) -> &'static str {
+    match status {
+        ContactStatus::Allowed => "allowed",
+        ContactStatus::Blocked => "blocked",
+        ContactStatus::Unknown => "unknown",
+    }
+}
+
+/// Upper-cased property name without group or parameters, and the raw value
+fn split_property(line: &str) -> Option<(String, String)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| {
+        match c {
+            '"' => quoted = !quoted,
+            ':' if !quoted => return Some(i),
+            _ => {}
+        }
+        None
+    })?;
+    let name = line[..colon].split(';').next()?;
+    let name = name.rsplit('.').next()?.trim().to_uppercase();
+    Some((name, line[colon + 1..].to_string()))
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Values of a comma-separated property, unescaped
+fn split_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                items.push(unescape(&value[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(unescape(&value[start..]));
+    items
+}
+
+/// A content line folded to the line limit, CRLF-terminated
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > LINE_LIMIT {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+/// Content lines with folded continuations joined back up
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)) {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::UserKeyPair;
+
+    #[test]
+    fn test_export_and_import() {
+        let mut manager = ContactManager::new();
+        let alice = UserKeyPair::generate().public_key_string();
+        let bob = UserKeyPair::generate().public_key_string();
+        manager.allow_contact(alice.clone()).unwrap();
+        manager.block_contact(bob.clone()).unwrap();
+        manager.set_metadata(alice.clone(), "Alice; K.".to_string(), "Met at the crag,\nbelays well".to_string()).unwrap();
+        manager.add_label(&alice, "climbing").unwrap();
+        manager.add_label(&alice, "Work team").unwrap();
+        manager.set_favorite(&alice, true);
+        manager.set_username(alice.clone(), "alice2024".to_string());
+
+        let exported = export(&manager);
+        assert!(exported.contains("CATEGORIES:climbing,Work team\r\n"));
+        assert!(exported.lines().all(|line| line.len() <= LINE_LIMIT + 1));
+
+        let (cards, skipped) = parse(&exported).unwrap();
+        assert_eq!((cards.len(), skipped), (2, 0));
+        let mut restored = ContactManager::new();
+        assert_eq!(import(&mut restored, &cards).unwrap(), 2);
+
+        let contact = restored.get_contact(&alice).unwrap();
+        assert_eq!(ContactCard::from_contact(&contact), ContactCard::from_contact(&manager.get_contact(&alice).unwrap()));
+        assert!(restored.is_blocked(&bob));
+
+        // Importing again merges rather than duplicating
+        assert_eq!(import(&mut restored, &cards).unwrap(), 0);
+        assert_eq!(restored.get_contact(&alice).unwrap().labels().len(), 2);
+    }
+
+    #[test]
+    fn test_foreign_cards() {
+        let carol = UserKeyPair::generate().public_key_string();
+        let text = format!(
+            "BEGIN:VCARD\nVERSION:3.0\nFN:Dentist\nTEL:555-0100\nEND:VCARD\n\
+             BEGIN:VCARD\nVERSION:4.0\nitem1.FN:Carol\nCATEGORIES:friends,\n  family\n\
+             X-NANO-PUBKEY:{}\nEND:VCARD\n",
+            carol
+        );
+        let (cards, skipped) = parse(&text).unwrap();
+        assert_eq!(skipped, 1);
+        assert_eq!(cards[0].nickname, "Carol");
+        assert_eq!(cards[0].labels, ["friends", "family"]);
+        assert_eq!(cards[0].status, ContactStatus::Unknown);
+
+        // A known username is never reassigned by an import
+        let mut manager = ContactManager::new();
+        manager.set_username("pubkey:someone".to_string(), "carol".to_string());
+        let card = ContactCard { username: Some("carol".to_string()), ..cards[0].clone() };
+        import(&mut manager, &[card]).unwrap();
+        assert_eq!(manager.get_pubkey_for_username("carol"), Some("pubkey:someone"));
+
+        assert!(parse("BEGIN:VCARD\nX-NANO-PUBKEY:pubkey:bogus\nEND:VCARD").is_err());
+        assert!(parse("BEGIN:VCARD\nFN:Unterminated").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! End-to-end file attachment test against a local relay
+//!
+//! Runs the real `nano-relay` and `nano-client` binaries: alice sends bob a
+//! file, bob accepts her message request and downloads the attachment from
+//! the relay.
+
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Relay child process, killed when dropped
+struct Relay {
+    child: Child,
+    address: String,
+}
+
+impl Relay {
+    fn spawn() -> Self {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_nano-relay"))
+            .args(["--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("relay starts");
+        let address = format!("127.0.0.1:{}", port);
+
+        for _ in 0..100 {
+            if TcpStream::connect(&address).is_ok() {
+                return Self { child, address };
+            }
+            sleep(Duration::from_millis(50));
+        }
+        panic!("relay did not start on {}", address);
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn client(config_dir: &Path, relay: &Relay, args: &[&str]) -> String {
+    let output: Output = Command::new(env!("CARGO_BIN_EXE_nano-client"))
+        .arg("--config-dir")
+        .arg(config_dir)
+        .args(["--relay", &relay.address])
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(
+        output.status.success(),
+        "nano-client {:?} failed:\n{}\n{}",
+        args,
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+#[test]
+fn test_send_and_download_file() {
+    let relay = Relay::spawn();
+    let dir = tempfile::tempdir().unwrap();
+    let alice = dir.path().join("alice");
+    let bob = dir.path().join("bob");
+    let suffix = relay.address.rsplit(':').next().unwrap();
+    let bob_name = format!("bob{}", suffix);
+
+    for (config_dir, name) in [(&alice, format!("alice{}", suffix)), (&bob, bob_name.clone())] {
+        client(config_dir, &relay, &["init"]);
+        client(config_dir, &relay, &["claim-username", &name]);
+    }
+
+    // Larger than one chunk, so the blob holds several encrypted chunks
+    let content: Vec<u8> = (0..600_000u32).map(|i| (i * 7 % 256) as u8).collect();
+    let path = dir.path().join("report.pdf");
+    std::fs::write(&path, &content).unwrap();
+    client(&alice, &relay, &["send-file", &bob_name, path.to_str().unwrap(), "--caption", "Quarterly report"]);
+
+    // Alice is a stranger to bob, so her message waits as a request until he accepts it
+    let received = client(&bob, &relay, &["receive"]);
+    let sender = received
+        .lines()
+        .find_map(|line| line.strip_prefix("📨 New message request from "))
+        .expect("message arrives as a request");
+    assert!(client(&bob, &relay, &["requests", "show", sender]).contains("Quarterly report · 📎 report.pdf"));
+    client(&bob, &relay, &["requests", "accept", sender]);
+
+    let messages = client(&bob, &relay, &["messages", "--ids"]);
+    assert!(messages.contains("Quarterly report · 📎 report.pdf"), "{}", messages);
+    let message_id = messages
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("id: "))
+        .expect("received message has an ID");
+
+    let output = dir.path().join("downloads");
+    client(&bob, &relay, &["download", message_id, "--output", output.to_str().unwrap()]);
+    assert_eq!(std::fs::read(output.join("report.pdf")).unwrap(), content);
+}
@@ -441,6 +441,7 @@ fn test_downgrade_attack_resistance() {
         minimum_mode: CryptoMode::Hybrid, // Minimum higher than current
         allow_auto_upgrade: false,
         adaptive_mode: false,
+        padding: PaddingScheme::Padme,
     };
     
     assert!(invalid_config.validate().is_err(),